@@ -11,7 +11,17 @@ fn filter_parsing(c: &mut Criterion) {
     c.bench_function("parsing a filter list", |b| {
         b.iter(|| {
             black_box(
-                blackhole::filter::rules::Rules::parse(Path::new("benches/test.txt")).unwrap(),
+                blackhole::__private::filter::rules::Rules::parse(Path::new("benches/test.txt")).unwrap(),
+            )
+        })
+    });
+}
+
+fn filter_loading(c: &mut Criterion) {
+    c.bench_function("loading a filter list", |b| {
+        b.iter(|| {
+            black_box(
+                blackhole::__private::filter::rules::Rules::load(Path::new("benches/test.txt"), None).unwrap(),
             )
         })
     });
@@ -19,10 +29,10 @@ fn filter_parsing(c: &mut Criterion) {
 
 fn filter_checking(c: &mut Criterion) {
     c.bench_function("checking a filter list", |b| {
-        let mut filter = blackhole::filter::Filter::default();
+        let mut filter = blackhole::__private::filter::Filter::default();
         let entries =
-            blackhole::filter::rules::Rules::parse(Path::new("benches/test.txt")).unwrap();
-        filter.rules.insert(entries);
+            blackhole::__private::filter::rules::Rules::parse(Path::new("benches/test.txt")).unwrap();
+        filter.rules.insert(entries, None);
 
         let request = Request::new(
             MessageRequest::read(&mut BinDecoder::new(&[
@@ -40,5 +50,5 @@ fn filter_checking(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, filter_parsing, filter_checking);
+criterion_group!(benches, filter_parsing, filter_loading, filter_checking);
 criterion_main!(benches);
@@ -12,12 +12,16 @@ type Rule = {
     action: unknown;
     domain: string;
     ty: "Deny" | "Allow";
+    list?: { name: string; url: string };
+    zone?: boolean;
 };
 
 interface Request {
     answers: Answer[];
     cached: boolean;
+    protocol: string;
     client: string;
+    name?: string;
     elapsed: number;
     question: string;
     query_type: string;
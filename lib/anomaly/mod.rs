@@ -0,0 +1,415 @@
+use std::{
+    fmt::Display,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use ahash::AHashMap;
+use hickory_proto::op::ResponseCode;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{config::Config, metrics};
+
+static DETECTOR: LazyLock<Mutex<Detector>> = LazyLock::new(Mutex::default);
+
+const fn default_enabled() -> bool {
+    true
+}
+
+const fn default_window() -> Duration {
+    Duration::from_mins(1)
+}
+
+const fn default_factor() -> usize {
+    3
+}
+
+const fn default_min_rate() -> usize {
+    10
+}
+
+const fn default_min_requests() -> usize {
+    20
+}
+
+///
+/// Options for detecting bursts of SERVFAIL or NXDOMAIN responses
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Options {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// How long each window responses are counted over is
+    #[serde(with = "humantime_serde", default = "default_window")]
+    pub window: Duration,
+    /// How many times the usual rate the rate in a window needs to be to count
+    /// as a burst
+    #[serde(default = "default_factor")]
+    pub factor: usize,
+    /// The lowest rate (as a percentage of all responses) that counts as a burst
+    #[serde(default = "default_min_rate")]
+    pub min_rate: usize,
+    /// Windows with fewer requests than this are never considered a burst
+    #[serde(default = "default_min_requests")]
+    pub min_requests: usize,
+    /// Where to POST to whenever a burst starts or ends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            window: default_window(),
+            factor: default_factor(),
+            min_rate: default_min_rate(),
+            min_requests: default_min_requests(),
+            webhook: None,
+        }
+    }
+}
+
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    ServFail,
+    NXDomain,
+}
+
+impl Kind {
+    const ALL: [Self; 2] = [Self::ServFail, Self::NXDomain];
+
+    const fn from_code(code: ResponseCode) -> Option<Self> {
+        match code {
+            ResponseCode::ServFail => Some(Self::ServFail),
+            ResponseCode::NXDomain => Some(Self::NXDomain),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::ServFail => "servfail",
+            Self::NXDomain => "nxdomain",
+        })
+    }
+}
+
+///
+/// An ongoing burst of responses of a particular kind
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq))]
+#[derive(Serialize, Clone)]
+pub struct Anomaly {
+    pub kind: Kind,
+    /// The percentage of responses of this kind in the window the burst was detected in
+    pub rate: usize,
+    /// The percentage of responses that are usually of this kind
+    pub baseline: usize,
+    /// How many requests were made in the window the burst was detected in
+    pub requests: usize,
+    /// The client with the most responses of this kind in that window
+    pub client: Option<String>,
+    pub since: SystemTime,
+}
+
+#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq))]
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Event {
+    Raised(Anomaly),
+    Cleared { kind: Kind },
+}
+
+#[derive(Default)]
+struct Window {
+    started: Option<Instant>,
+    total: usize,
+    counts: AHashMap<Kind, usize>,
+    clients: AHashMap<Kind, AHashMap<String, usize>>,
+}
+
+///
+/// Compares the rate of each kind of failure in a window against the rate in
+/// previous (non-bursting) windows
+///
+#[derive(Default)]
+struct Detector {
+    window: Window,
+    baseline: AHashMap<Kind, usize>,
+    active: AHashMap<Kind, Anomaly>,
+}
+
+impl Detector {
+    fn observe(
+        &mut self,
+        options: &Options,
+        now: Instant,
+        code: ResponseCode,
+        client: &str,
+    ) -> Vec<Event> {
+        let started = *self.window.started.get_or_insert(now);
+
+        let events = if now.duration_since(started) >= options.window {
+            let events = self.evaluate(options);
+            self.window.started = Some(now);
+            events
+        } else {
+            Vec::new()
+        };
+
+        self.window.total += 1;
+        if let Some(kind) = Kind::from_code(code) {
+            *self.window.counts.entry(kind).or_default() += 1;
+            *self
+                .window
+                .clients
+                .entry(kind)
+                .or_default()
+                .entry(client.to_string())
+                .or_default() += 1;
+        }
+
+        events
+    }
+
+    ///
+    /// Close the current window, returning any bursts that have started or ended
+    ///
+    fn evaluate(&mut self, options: &Options) -> Vec<Event> {
+        let mut window = std::mem::take(&mut self.window);
+        let mut events = Vec::new();
+
+        if window.total < options.min_requests {
+            return events;
+        }
+
+        for kind in Kind::ALL {
+            let count = window.counts.get(&kind).copied().unwrap_or_default();
+            let rate = count * 100 / window.total;
+
+            // Nothing is a burst until we know what's usual
+            let Some(baseline) = self.baseline.get(&kind).copied() else {
+                self.baseline.insert(kind, rate);
+                continue;
+            };
+
+            if rate > (baseline * options.factor).max(options.min_rate) {
+                if !self.active.contains_key(&kind) {
+                    let anomaly = Anomaly {
+                        kind,
+                        rate,
+                        baseline,
+                        requests: window.total,
+                        client: window.clients.remove(&kind).and_then(|clients| {
+                            clients
+                                .into_iter()
+                                .max_by_key(|(_, count)| *count)
+                                .map(|(client, _)| client)
+                        }),
+                        since: SystemTime::now(),
+                    };
+
+                    self.active.insert(kind, anomaly.clone());
+                    events.push(Event::Raised(anomaly));
+                }
+            } else {
+                if self.active.remove(&kind).is_some() {
+                    events.push(Event::Cleared { kind });
+                }
+
+                // Bursts are left out, otherwise a long enough one would become the norm
+                self.baseline.insert(kind, (baseline * 4 + rate) / 5);
+            }
+        }
+
+        events
+    }
+}
+
+fn report(options: &Options, event: Event) {
+    match &event {
+        Event::Raised(anomaly) => {
+            warn!(
+                "Burst of {} responses: {}% of {} requests, usually {}% (mostly for {})",
+                anomaly.kind,
+                anomaly.rate,
+                anomaly.requests,
+                anomaly.baseline,
+                anomaly.client.as_deref().unwrap_or("no one")
+            );
+            metrics::ANOMALIES
+                .get_or_create(&metrics::Burst {
+                    kind: anomaly.kind.to_string(),
+                })
+                .set(1);
+        }
+        Event::Cleared { kind } => {
+            info!("Burst of {kind} responses has ended");
+            metrics::ANOMALIES
+                .get_or_create(&metrics::Burst {
+                    kind: kind.to_string(),
+                })
+                .set(0);
+        }
+    }
+
+    if let Some(webhook) = options.webhook.clone() {
+        tokio::spawn(async move {
+            if let Err(err) = reqwest::Client::new()
+                .post(&webhook)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&event).unwrap_or_default())
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+            {
+                error!("Unable to notify {webhook} of an anomaly: {err}");
+            }
+        });
+    }
+}
+
+///
+/// Record the response code of a request we answered
+///
+pub async fn record(code: ResponseCode, client: &str) {
+    let options = Config::get(|config| config.anomalies.clone()).await;
+    if !options.enabled {
+        return;
+    }
+
+    let events = DETECTOR
+        .lock()
+        .map(|mut detector| detector.observe(&options, Instant::now(), code, client))
+        .unwrap_or_default();
+
+    for event in events {
+        report(&options, event);
+    }
+}
+
+///
+/// The bursts that are currently ongoing
+///
+pub fn active() -> Vec<Anomaly> {
+    DETECTOR
+        .lock()
+        .map(|detector| detector.active.values().cloned().collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use hickory_proto::op::ResponseCode;
+    use pretty_assertions::assert_eq;
+
+    use super::{Detector, Event, Kind, Options};
+
+    fn window(
+        detector: &mut Detector,
+        options: &Options,
+        start: Instant,
+        responses: &[(ResponseCode, &str, usize)],
+    ) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        for (code, client, count) in responses {
+            for _ in 0..*count {
+                events.extend(detector.observe(options, start, *code, client));
+            }
+        }
+
+        events
+    }
+
+    #[test]
+    fn bursts() {
+        let options = Options::default();
+        let mut detector = Detector::default();
+        let start = Instant::now();
+        let usual = [
+            (ResponseCode::NoError, "10.0.0.1", 95),
+            (ResponseCode::NXDomain, "10.0.0.1", 5),
+        ];
+
+        // The first two windows establish the baseline
+        assert_eq!(window(&mut detector, &options, start, &usual), []);
+        let next = start + options.window;
+        assert_eq!(window(&mut detector, &options, next, &usual), []);
+
+        let next = next + options.window;
+        assert_eq!(
+            window(
+                &mut detector,
+                &options,
+                next,
+                &[
+                    (ResponseCode::NoError, "10.0.0.1", 50),
+                    (ResponseCode::NXDomain, "10.0.0.2", 45),
+                    (ResponseCode::NXDomain, "10.0.0.1", 5),
+                ]
+            ),
+            []
+        );
+
+        let next = next + options.window;
+        let events = window(&mut detector, &options, next, &usual);
+        let [Event::Raised(anomaly)] = events.as_slice() else {
+            panic!("Expected a single burst to be raised, got {events:?}");
+        };
+        assert_eq!(anomaly.kind, Kind::NXDomain);
+        assert_eq!(anomaly.rate, 50);
+        assert_eq!(anomaly.baseline, 5);
+        assert_eq!(anomaly.client.as_deref(), Some("10.0.0.2"));
+
+        // It's only raised once, and cleared once things are back to normal
+        let next = next + options.window;
+        assert_eq!(
+            window(&mut detector, &options, next, &usual),
+            [Event::Cleared {
+                kind: Kind::NXDomain
+            }]
+        );
+        assert!(detector.active.is_empty());
+    }
+
+    #[test]
+    fn quiet_windows() {
+        let options = Options::default();
+        let mut detector = Detector::default();
+        let start = Instant::now();
+
+        window(
+            &mut detector,
+            &options,
+            start,
+            &[(ResponseCode::NoError, "10.0.0.1", 100)],
+        );
+        window(
+            &mut detector,
+            &options,
+            start + Duration::from_mins(1),
+            &[(ResponseCode::ServFail, "10.0.0.1", 10)],
+        );
+
+        // Too few requests to say anything about
+        assert_eq!(
+            window(
+                &mut detector,
+                &options,
+                start + Duration::from_mins(2),
+                &[(ResponseCode::NoError, "10.0.0.1", 1)],
+            ),
+            []
+        );
+    }
+}
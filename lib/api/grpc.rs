@@ -0,0 +1,362 @@
+//!
+//! The management API over gRPC, for anything embedding Blackhole that would
+//! rather stream the requests than poll for them.
+//!
+
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::Stream;
+use tokio::sync::{broadcast::error::RecvError, watch::Receiver};
+use tonic::{Response, Status};
+use tracing::info;
+
+use crate::{config::Config, filter::Filter, statistics::Statistics};
+
+use self::proto::{
+    blackhole_server::{Blackhole, BlackholeServer},
+    Empty,
+};
+use super::{v1, Grpc};
+
+#[allow(clippy::all, clippy::pedantic, clippy::nursery)]
+pub mod proto {
+    tonic::include_proto!("blackhole.v1");
+}
+
+type Result<T> = std::result::Result<Response<T>, Status>;
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| {
+        u64::try_from(since.as_millis()).unwrap_or(u64::MAX)
+    })
+}
+
+impl From<&v1::Average> for proto::Average {
+    fn from(average: &v1::Average) -> Self {
+        Self {
+            count: average.count as u64,
+            average: average.average as u64,
+        }
+    }
+}
+
+impl From<v1::Totals> for proto::TotalsReply {
+    fn from(totals: v1::Totals) -> Self {
+        Self {
+            requests: totals.requests as u64,
+            blocked: totals.blocked as u64,
+            average: Some((&totals.average).into()),
+            cache: Some(proto::Cache {
+                size: totals.cache.size as u64,
+                hits: totals.cache.hits as u64,
+                misses: totals.cache.misses as u64,
+                entries: totals.cache.entries as u64,
+                evictions: totals.cache.evictions as u64,
+                expirations: totals.cache.expirations as u64,
+            }),
+            protocols: totals
+                .protocols
+                .iter()
+                .map(|(protocol, average)| (protocol.clone(), average.into()))
+                .collect(),
+        }
+    }
+}
+
+impl From<v1::Request> for proto::Request {
+    fn from(request: v1::Request) -> Self {
+        Self {
+            client: request.client,
+            name: request.name,
+            mac: request.mac,
+            question: request.question,
+            r#type: request.query_type,
+            answers: request.answers,
+            rule: request.rule.map(|rule| proto::Rule {
+                domain: rule.domain,
+                kind: rule.kind,
+                list: rule.list,
+            }),
+            blocked: request.blocked,
+            status: request.status,
+            elapsed: request.elapsed as u64,
+            timings: Some(proto::Timings {
+                filter: request.timings.filter.map(|elapsed| elapsed as u64),
+                cache: request.timings.cache.map(|elapsed| elapsed as u64),
+                upstream: request.timings.upstream.map(|elapsed| elapsed as u64),
+            }),
+            timestamp: request.timestamp,
+            cached: request.cached,
+            protocol: request.protocol,
+        }
+    }
+}
+
+impl From<crate::filter::Blocking> for proto::Blocking {
+    fn from(blocking: crate::filter::Blocking) -> Self {
+        Self {
+            enabled: blocking.enabled,
+            until: blocking.until.map(millis),
+        }
+    }
+}
+
+impl From<crate::filter::Status> for proto::Status {
+    fn from(status: crate::filter::Status) -> Self {
+        Self {
+            code: status.code.map(u32::from),
+            fetched: status.fetched.map(millis),
+            entries: status.entries as u64,
+            error: status.error,
+        }
+    }
+}
+
+struct Service;
+
+#[tonic::async_trait]
+impl Blackhole for Service {
+    type StreamStream =
+        Pin<Box<dyn Stream<Item = std::result::Result<proto::Request, Status>> + Send>>;
+
+    async fn totals(&self, _: tonic::Request<Empty>) -> Result<proto::TotalsReply> {
+        Ok(Response::new(
+            v1::Totals::from(&Statistics::statistics()).into(),
+        ))
+    }
+
+    async fn stream(&self, _: tonic::Request<Empty>) -> Result<Self::StreamStream> {
+        let stream = futures::stream::unfold(Statistics::subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(request) => {
+                        return Some((Ok(v1::Request::from(request).into()), receiver));
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Stream lagging behind, skipped {skipped} requests");
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn get_config(&self, _: tonic::Request<Empty>) -> Result<proto::Config> {
+        let config = Config::get(Clone::clone).await;
+
+        toml::to_string_pretty(&config)
+            .map(|toml| Response::new(proto::Config { toml }))
+            .map_err(|err| Status::internal(err.to_string()))
+    }
+
+    async fn set_config(&self, request: tonic::Request<proto::Config>) -> Result<proto::Problems> {
+        let config = toml::from_str::<Config>(&request.into_inner().toml)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+
+        let problems = config.validate();
+        if problems.is_empty() {
+            Config::set(|current| *current = config.clone())
+                .await
+                .map_err(|err| Status::internal(err.to_string()))?;
+        }
+
+        Ok(Response::new(proto::Problems {
+            problems: problems
+                .into_iter()
+                .map(|problem| proto::Problem {
+                    key: problem.key,
+                    reason: problem.reason,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn filters(&self, _: tonic::Request<Empty>) -> Result<proto::Lists> {
+        let mut statuses = Filter::statuses().await;
+        let lists = Config::get(|config| config.filters.clone())
+            .await
+            .into_iter()
+            .map(|list| proto::List {
+                status: statuses.remove(&list.to_string()).map(Into::into),
+                name: list.name,
+                url: list.url,
+                enabled: list.enabled,
+            })
+            .collect();
+
+        Ok(Response::new(proto::Lists { lists }))
+    }
+
+    async fn add_filter(&self, request: tonic::Request<proto::List>) -> Result<Empty> {
+        let list = request.into_inner();
+        let list = crate::filter::List {
+            name: list.name,
+            url: list.url,
+            enabled: list.enabled,
+            schedule: None,
+            entries: 0,
+        };
+
+        Config::set(|config| {
+            config.filters.insert(list.clone());
+        })
+        .await
+        .map(|()| Response::new(Empty {}))
+        .map_err(|err| Status::internal(err.to_string()))
+    }
+
+    async fn remove_filter(&self, request: tonic::Request<proto::FilterName>) -> Result<Empty> {
+        let name = request.into_inner().name;
+
+        Config::set(|config| config.filters.retain(|list| list.name != name))
+            .await
+            .map(|()| Response::new(Empty {}))
+            .map_err(|err| Status::internal(err.to_string()))
+    }
+
+    async fn toggle_filter(
+        &self,
+        request: tonic::Request<proto::ToggleFilterRequest>,
+    ) -> Result<proto::List> {
+        let proto::ToggleFilterRequest { name, enabled } = request.into_inner();
+
+        let Some(mut list) = Config::get(|config| {
+            config
+                .filters
+                .iter()
+                .find(|list| list.name == name)
+                .cloned()
+        })
+        .await
+        else {
+            return Err(Status::not_found(format!("There's no list named {name}")));
+        };
+
+        list.enabled = enabled;
+
+        Config::set(|config| {
+            config.filters.replace(list.clone());
+        })
+        .await
+        .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Response::new(proto::List {
+            status: Filter::statuses()
+                .await
+                .remove(&list.to_string())
+                .map(Into::into),
+            name: list.name,
+            url: list.url,
+            enabled: list.enabled,
+        }))
+    }
+
+    async fn refresh_filters(
+        &self,
+        request: tonic::Request<proto::RefreshFiltersRequest>,
+    ) -> Result<Empty> {
+        match Filter::refresh(request.into_inner().name).await {
+            Ok(true) => Ok(Response::new(Empty {})),
+            Ok(false) => Err(Status::not_found("There's no list by that name")),
+            Err(err) => Err(Status::internal(err.to_string())),
+        }
+    }
+
+    async fn get_blocking(&self, _: tonic::Request<Empty>) -> Result<proto::Blocking> {
+        Ok(Response::new(Filter::blocking().into()))
+    }
+
+    async fn set_blocking(
+        &self,
+        request: tonic::Request<proto::SetBlockingRequest>,
+    ) -> Result<proto::Blocking> {
+        let proto::SetBlockingRequest { enabled, duration } = request.into_inner();
+
+        let blocking = if enabled {
+            Filter::resume().await
+        } else {
+            Filter::pause(duration.map(Duration::from_secs))
+                .await
+                .ok_or_else(|| Status::invalid_argument("duration is out of range"))?
+        };
+
+        Ok(Response::new(blocking.into()))
+    }
+}
+
+///
+/// Serve the management API over gRPC until we're shutting down
+///
+/// # Errors
+/// If the address is already in use
+///
+#[coverage(off)]
+pub async fn run(
+    options: Grpc,
+    mut shutdown_signal: Receiver<bool>,
+) -> std::result::Result<(), tonic::transport::Error> {
+    let address = SocketAddr::from((options.address, options.port));
+    info!("Running gRPC API on {address}");
+
+    tonic::transport::Server::builder()
+        .add_service(BlackholeServer::new(Service))
+        .serve_with_shutdown(address, async move {
+            let _ = shutdown_signal.changed().await;
+        })
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+    use tonic::Code;
+
+    use super::{
+        proto::{blackhole_server::Blackhole, Empty, ToggleFilterRequest},
+        Service,
+    };
+    use crate::statistics;
+
+    #[test]
+    fn request() {
+        let request = super::proto::Request::from(super::v1::Request::from(statistics::Request {
+            client: String::from("127.0.0.1"),
+            question: String::from("example.com"),
+            ..Default::default()
+        }));
+
+        assert_eq!(request.client, "127.0.0.1");
+        assert_eq!(request.question, "example.com");
+        assert_eq!(request.rule, None);
+        assert!(!request.blocked);
+    }
+
+    #[tokio::test]
+    async fn blocking() {
+        let blocking = Service
+            .get_blocking(tonic::Request::new(Empty {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(blocking.enabled);
+        assert_eq!(blocking.until, None);
+    }
+
+    #[tokio::test]
+    async fn toggle_unknown_filter() {
+        let status = Service
+            .toggle_filter(tonic::Request::new(ToggleFilterRequest {
+                name: String::from("not a list"),
+                enabled: false,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+}
@@ -1,14 +1,143 @@
-use std::net::Ipv6Addr;
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    path::PathBuf,
+    sync::LazyLock,
+};
 
 use prometheus_client::encoding::text::encode;
 use serde::{Deserialize, Serialize};
 use tokio::sync::watch::Receiver;
+use tracing::info;
 use warp::{
-    body::BodyDeserializeError, filters::BoxedFilter, http::Response, hyper::header::CONTENT_TYPE,
-    reply::json, Filter, Rejection, Reply,
+    body::BodyDeserializeError,
+    filters::BoxedFilter,
+    http::{Response, StatusCode},
+    hyper::header::CONTENT_TYPE,
+    reply::{json, with_status},
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    config::Config,
+    health::Health,
+    metrics::REGISTRY,
+    schedule::{Sched, Scheduler},
+    tasks::{self, Task},
 };
 
-use crate::metrics::REGISTRY;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod v1;
+
+///
+/// A description of the API (OpenAPI 3), which is kept by hand alongside the
+/// routes below, so needs updating along with them
+///
+static OPENAPI: LazyLock<serde_json::Value> = LazyLock::new(|| {
+    let mut openapi: serde_json::Value =
+        serde_json::from_str(include_str!("openapi.json")).unwrap_or_default();
+    openapi["info"]["version"] = env!("CARGO_PKG_VERSION").into();
+    openapi
+});
+
+/// Swagger UI, for browsing the description of the API
+const DOCS: &str = include_str!("docs.html");
+
+/// A page for keeping an eye on things without deploying the web client
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+const fn default_address() -> IpAddr {
+    IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+}
+
+const fn default_port() -> u16 {
+    5000
+}
+
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+const fn default_grpc_port() -> u16 {
+    50051
+}
+
+///
+/// Where to serve the management API over gRPC, which is only available when
+/// built with the `grpc` feature
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Grpc {
+    #[serde(default = "default_address")]
+    pub address: IpAddr,
+    #[serde(default = "default_grpc_port")]
+    pub port: u16,
+}
+
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Options {
+    #[serde(default = "default_address")]
+    pub address: IpAddr,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<Tls>,
+    /// The origins browsers may call the API from (e.g. a dashboard served
+    /// from elsewhere), or `*` for any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub origins: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grpc: Option<Grpc>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            address: default_address(),
+            port: default_port(),
+            tls: None,
+            origins: Vec::new(),
+            grpc: None,
+        }
+    }
+}
+
+///
+/// Let browsers call the API from the origins, should there be any (CORS)
+///
+fn cors(origins: &[String]) -> Option<warp::cors::Builder> {
+    if origins.is_empty() {
+        return None;
+    }
+
+    let cors = warp::cors()
+        .allow_methods(["GET", "POST", "PATCH", "DELETE"])
+        .allow_headers(["content-type"])
+        .expose_headers([statistics::TOTAL_COUNT, "deprecation", "link"]);
+
+    Some(if origins.iter().any(|origin| origin == "*") {
+        cors.allow_any_origin()
+    } else {
+        cors.allow_origins(origins.iter().map(String::as_str))
+    })
+}
+
+///
+/// Mark the reply as coming from one of the unversioned paths, which are only
+/// kept until the next release, pointing to v1 instead
+///
+fn deprecated(reply: impl Reply) -> impl Reply {
+    warp::reply::with_header(
+        warp::reply::with_header(reply, "Deprecation", "true"),
+        "Link",
+        "</api/v1>; rel=\"successor-version\"",
+    )
+}
 
 #[derive(Serialize, Deserialize)]
 struct Timespan {
@@ -23,16 +152,26 @@ impl Server {
     ///
     /// # Errors
     /// This may error out in the case that the port we're trying to bind to is already in
-    /// use.
+    /// use, or if TLS is configured and the certificate or key can't be loaded.
     ///
     #[coverage(off)]
     pub async fn run(self, mut shutdown_signal: Receiver<bool>) -> Result<(), warp::Error> {
+        let Options {
+            address,
+            port,
+            tls,
+            origins,
+            ..
+        } = Config::get(|config| config.api.clone()).await;
+
         let api = warp::path("api")
             .and(
-                Self::statistics()
-                    .or(Self::filters())
-                    .or(Self::config())
-                    .or(Self::metrics()),
+                warp::path("v1")
+                    .and(Self::statistics_v1().or(Self::routes()))
+                    .or(Self::statistics()
+                        .or(Self::stream())
+                        .or(Self::routes())
+                        .map(deprecated)),
             )
             .recover(|err: Rejection| async move {
                 #[derive(Serialize)]
@@ -61,28 +200,127 @@ impl Server {
                     },
                 )
             });
+        let api = Self::dashboard().or(api);
+
+        let api = match cors(&origins) {
+            Some(cors) => api
+                .with(cors)
+                .map(|reply| Box::new(reply) as Box<dyn Reply>)
+                .boxed(),
+            None => api.map(|reply| Box::new(reply) as Box<dyn Reply>).boxed(),
+        };
 
-        warp::serve(api)
-            .try_bind_with_graceful_shutdown((Ipv6Addr::UNSPECIFIED, 5000), async move {
-                let _ = shutdown_signal.changed().await;
-            })?
-            .1
-            .await;
+        let shutdown = async move {
+            let _ = shutdown_signal.changed().await;
+        };
+
+        if let Some(Tls { cert, key }) = tls {
+            let (address, server) = warp::serve(api)
+                .tls()
+                .cert_path(cert)
+                .key_path(key)
+                .try_bind_with_graceful_shutdown((address, port), shutdown)?;
+
+            info!("Running API on https://{address}");
+            server.await;
+        } else {
+            let (address, server) =
+                warp::serve(api).try_bind_with_graceful_shutdown((address, port), shutdown)?;
+
+            info!("Running API on http://{address}");
+            server.await;
+        }
 
         Ok(())
     }
 
+    ///
+    /// Everything besides the statistics, which is the same across versions
+    ///
+    fn routes() -> BoxedFilter<(impl Reply,)> {
+        Self::filters()
+            .or(Self::rules())
+            .or(Self::config())
+            .or(Self::health())
+            .or(Self::export())
+            .or(Self::schedules())
+            .or(Self::tasks())
+            .or(Self::resolve())
+            .or(Self::anomalies())
+            .or(Self::blocking())
+            .or(Self::metrics())
+            .or(Self::docs())
+            .boxed()
+    }
+
+    ///
+    /// The statistics from v1 on, along with the stream of requests
+    ///
+    fn statistics_v1() -> BoxedFilter<(impl Reply,)> {
+        warp::path!("statistics" / "requests")
+            .and(warp::query::<crate::statistics::Query>())
+            .map(|query| v1::requests(&query))
+            .or(warp::path!("statistics" / "top")
+                .and(warp::query::<crate::statistics::Top>())
+                .map(|top| statistics::top(&top)))
+            .unify()
+            .or(warp::path!("statistics" / "history")
+                .and(warp::query::<crate::statistics::Span>())
+                .map(|span| statistics::history(&span)))
+            .unify()
+            .or(warp::path!("statistics").map(v1::totals))
+            .unify()
+            .or(warp::path("stream").and(warp::get()).map(|| {
+                warp::sse::reply(
+                    warp::sse::keep_alive().stream(statistics::stream(v1::Request::from)),
+                )
+            }))
+            .boxed()
+    }
+
     fn statistics() -> BoxedFilter<(impl Reply,)> {
-        warp::path!("statistics" / String)
-            .and(warp::query::<Timespan>())
-            .map(|statistic: String, params| statistics::statistic(&statistic, &params))
+        warp::path!("statistics" / "requests")
+            .and(warp::query::<crate::statistics::Query>())
+            .map(|query| statistics::requests(&query))
+            .or(warp::path!("statistics" / "top")
+                .and(warp::query::<crate::statistics::Top>())
+                .map(|top| statistics::top(&top)))
+            .unify()
+            .or(warp::path!("statistics" / "history")
+                .and(warp::query::<crate::statistics::Span>())
+                .map(|span| statistics::history(&span)))
+            .unify()
+            .or(warp::path!("statistics" / String)
+                .and(warp::query::<Timespan>())
+                .map(|statistic: String, params| statistics::statistic(&statistic, &params)))
+            .unify()
             .or(warp::path("statistics").map(statistics::all))
             .boxed()
     }
 
+    ///
+    /// The config, along with exporting it (e.g. as a backup, or to move to
+    /// another host) and importing it again
+    ///
     fn config() -> BoxedFilter<(impl Reply,)> {
-        warp::path("config")
-            .and(warp::get().and_then(config::get))
+        warp::path!("config" / "validate")
+            .and(warp::post())
+            .and(warp::body::json())
+            .map(config::validate)
+            .or(warp::path!("config" / "export")
+                .and(warp::get())
+                .and(warp::query::<config::Export>())
+                .and_then(config::export))
+            .or(warp::path!("config" / "import")
+                .and(warp::post())
+                .and(warp::header::optional::<String>("content-type"))
+                .and(warp::body::bytes())
+                .and_then(config::import))
+            .or(warp::path!("import" / "pihole")
+                .and(warp::post())
+                .and(warp::body::json())
+                .and_then(config::pihole))
+            .or(warp::path("config").and(warp::get().and_then(config::get)))
             .or(warp::path("config")
                 .and(warp::post())
                 .and(warp::body::json())
@@ -90,6 +328,119 @@ impl Server {
             .boxed()
     }
 
+    fn export() -> BoxedFilter<(impl Reply,)> {
+        warp::path!("export" / "zone")
+            .and(warp::get())
+            .then(|| async {
+                warp::reply::with_header(
+                    crate::filter::Filter::zone().await,
+                    CONTENT_TYPE,
+                    "text/dns",
+                )
+            })
+            .boxed()
+    }
+
+    fn stream() -> BoxedFilter<(impl Reply,)> {
+        warp::path("stream")
+            .and(warp::get())
+            .map(|| {
+                warp::sse::reply(warp::sse::keep_alive().stream(statistics::stream(
+                    std::convert::identity::<crate::statistics::Request>,
+                )))
+            })
+            .boxed()
+    }
+
+    ///
+    /// When each scheduled task is next due, changing how often they run, and
+    /// running one now, even if it would otherwise be deferred due to load
+    ///
+    fn schedules() -> BoxedFilter<(impl Reply,)> {
+        warp::path!("schedules")
+            .and(warp::get())
+            .then(|| async { json(&Scheduler::status().await) })
+            .or(warp::path!("schedules")
+                .and(warp::post())
+                .and(warp::body::json())
+                .and_then(schedules::set))
+            .or(warp::path!("schedules" / Sched)
+                .and(warp::delete())
+                .and_then(schedules::remove))
+            .or(warp::path!("schedules" / Sched / "run")
+                .and(warp::post())
+                .then(|schedule| async move {
+                    if Scheduler::force(schedule).await {
+                        StatusCode::ACCEPTED
+                    } else {
+                        StatusCode::NOT_FOUND
+                    }
+                }))
+            .boxed()
+    }
+
+    ///
+    /// Run a maintenance task in the background, and check on how it went
+    ///
+    fn tasks() -> BoxedFilter<(impl Reply,)> {
+        warp::path!("tasks")
+            .and(warp::get())
+            .map(|| json(&tasks::recent()).into_response())
+            .or(warp::path!("tasks" / u64)
+                .and(warp::get())
+                .map(|id| match tasks::status(id) {
+                    Some(run) => json(&run).into_response(),
+                    None => with_status(warp::reply(), StatusCode::NOT_FOUND).into_response(),
+                }))
+            .or(warp::path!("tasks" / Task)
+                .and(warp::post())
+                .map(|task| with_status(json(&tasks::start(task)), StatusCode::ACCEPTED)))
+            .boxed()
+    }
+
+    ///
+    /// Resolve a name as if it were queried, showing how it was answered
+    ///
+    fn resolve() -> BoxedFilter<(impl Reply,)> {
+        warp::path!("resolve" / String)
+            .and(warp::get())
+            .and(warp::query::<resolve::Params>())
+            .then(resolve::resolve)
+            .boxed()
+    }
+
+    ///
+    /// Any ongoing bursts of SERVFAIL or NXDOMAIN responses
+    ///
+    fn anomalies() -> BoxedFilter<(impl Reply,)> {
+        warp::path("anomalies")
+            .and(warp::get())
+            .map(|| json(&crate::anomaly::active()))
+            .boxed()
+    }
+
+    ///
+    /// Whether domains are being blocked, and turning it off (optionally for a
+    /// while) or back on
+    ///
+    fn blocking() -> BoxedFilter<(impl Reply,)> {
+        warp::path("blocking")
+            .and(warp::get())
+            .map(|| json(&crate::filter::Filter::blocking()))
+            .or(warp::path("blocking")
+                .and(warp::post())
+                .and(warp::body::json())
+                .then(blocking::toggle))
+            .boxed()
+    }
+
+    fn health() -> BoxedFilter<(impl Reply,)> {
+        warp::path("health")
+            .and(warp::get())
+            .map(|| json(&Health::status()))
+            .boxed()
+    }
+
     fn metrics() -> BoxedFilter<(impl Reply,)> {
         warp::path("metrics")
             .and(warp::get())
@@ -101,15 +452,64 @@ impl Server {
                         "application/openmetrics-text; version=1.0.0; charset=utf-8",
                     ),
                 );
+                crate::statistics::Statistics::record_latency_metrics();
                 encode(response.body_mut(), &REGISTRY.read().unwrap()).unwrap();
                 response
             })
             .boxed()
     }
 
+    ///
+    /// The dashboard, at the root rather than under `/api`
+    ///
+    fn dashboard() -> BoxedFilter<(impl Reply,)> {
+        warp::path::end()
+            .and(warp::get())
+            .map(|| warp::reply::html(DASHBOARD))
+            .boxed()
+    }
+
+    ///
+    /// The description of the API, along with a page for browsing it
+    ///
+    fn docs() -> BoxedFilter<(impl Reply,)> {
+        warp::path!("openapi.json")
+            .and(warp::get())
+            .map(|| json(&*OPENAPI))
+            .or(warp::path!("docs")
+                .and(warp::get())
+                .map(|| warp::reply::html(DOCS)))
+            .boxed()
+    }
+
+    fn rules() -> BoxedFilter<(impl Reply,)> {
+        warp::path("rules")
+            .and(warp::get().and_then(rules::all))
+            .or(warp::path("rules")
+                .and(warp::post())
+                .and(warp::body::json())
+                .and_then(rules::add))
+            .or(warp::path("rules")
+                .and(warp::delete())
+                .and(warp::body::json())
+                .and_then(rules::remove))
+            .boxed()
+    }
+
     fn filters() -> BoxedFilter<(impl Reply,)> {
-        warp::path("filters")
-            .and(warp::get().and_then(filters::all))
+        warp::path!("filters" / "export")
+            .and(warp::get())
+            .and(warp::query::<filters::Export>())
+            .then(filters::export)
+            .or(warp::path!("filters" / String / "entries")
+                .and(warp::get())
+                .and(warp::query::<filters::Entries>())
+                .then(filters::entries))
+            .or(warp::path!("filters" / "refresh")
+                .and(warp::post())
+                .and(warp::query::<filters::Refresh>())
+                .and_then(filters::refresh))
+            .or(warp::path("filters").and(warp::get().and_then(filters::all)))
             .or(warp::path("filters")
                 .and(warp::post())
                 .and(warp::body::json())
@@ -118,25 +518,85 @@ impl Server {
                 .and(warp::delete())
                 .and(warp::body::json())
                 .and_then(filters::remove))
+            .or(warp::path!("filters" / String)
+                .and(warp::patch())
+                .and(warp::body::json())
+                .and_then(filters::toggle))
             .boxed()
     }
 }
 
 mod statistics {
+    use std::convert::Infallible;
+
     use ahash::AHashMap;
+    use futures::Stream;
+    use tokio::sync::broadcast::error::RecvError;
     use warp::{
-        http::Response,
-        reply::{json, Reply},
+        http::{Response, StatusCode},
+        reply::{json, with_status, Reply},
+        sse::Event,
     };
 
-    use crate::statistics::Statistics;
+    use serde::Serialize;
+
+    use crate::statistics::{Query, Request, Span, Statistic, Statistics, Top};
 
     use super::Timespan;
 
+    /// The total number of requests matching a query, regardless of pagination
+    pub(super) const TOTAL_COUNT: &str = "X-Total-Count";
+
     pub(super) fn all() -> Response<warp::hyper::Body> {
         json(&Statistics::statistics()).into_response()
     }
 
+    ///
+    /// A stream of every request handled from now on, as Server-Sent Events,
+    /// each described as the API version does
+    ///
+    pub(super) fn stream<T: Serialize + 'static>(
+        describe: fn(Request) -> T,
+    ) -> impl Stream<Item = Result<Event, Infallible>> + Send + 'static {
+        futures::stream::unfold(Statistics::subscribe(), move |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(request) => match Event::default().json_data(describe(request)) {
+                        Ok(event) => return Some((Ok(event), receiver)),
+                        Err(err) => tracing::error!("{err}"),
+                    },
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Stream lagging behind, skipped {skipped} requests");
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    pub(super) fn requests(query: &Query) -> Response<warp::hyper::Body> {
+        if let Err(err) = query.validate() {
+            return with_status(err, StatusCode::BAD_REQUEST).into_response();
+        }
+
+        Statistics::requests(query).map_or_else(
+            || json(&AHashMap::<&str, String>::default()).into_response(),
+            |(total, requests)| {
+                let mut response = json(&Statistic::Requests(requests.into())).into_response();
+                response.headers_mut().insert(TOTAL_COUNT, total.into());
+                response
+            },
+        )
+    }
+
+    pub(super) fn top(top: &Top) -> Response<warp::hyper::Body> {
+        json(&Statistics::top(top)).into_response()
+    }
+
+    pub(super) fn history(span: &Span) -> Response<warp::hyper::Body> {
+        json(&Statistics::history(span)).into_response()
+    }
+
     pub(super) fn statistic(statistic: &str, params: &Timespan) -> Response<warp::hyper::Body> {
         Statistics::retrieve(&statistic.to_ascii_lowercase(), params.from, params.to).map_or_else(
             || json(&AHashMap::<&str, String>::default()).into_response(),
@@ -146,20 +606,143 @@ mod statistics {
 }
 
 mod config {
+    use serde::Deserialize;
     use warp::{
-        http::Response,
-        reply::{json, Reply},
+        http::{
+            header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+            Response, StatusCode,
+        },
+        hyper::body::Bytes,
+        reply::{json, with_header, with_status, Reply},
     };
 
-    use crate::{config::Config, filter};
+    use crate::config::{Config, Error, Problem, Teleporter};
+
+    #[derive(Deserialize, Default, Clone, Copy)]
+    #[serde(rename_all = "lowercase")]
+    pub(super) enum Format {
+        /// The same as the config file
+        #[default]
+        Toml,
+        Json,
+    }
+
+    #[derive(Deserialize)]
+    pub(super) struct Export {
+        #[serde(default)]
+        format: Format,
+    }
 
     pub(super) async fn get() -> Result<Response<warp::hyper::Body>, warp::Rejection> {
-        let mut config = Config::get(Clone::clone).await;
-        config.filters = filter::Filter::lists();
+        let config = Config::get(Clone::clone).await;
 
         Ok(json(&config).into_response())
     }
 
+    ///
+    /// Check whether the config would be valid, without applying it
+    ///
+    pub(super) fn validate(body: serde_json::Value) -> Response<warp::hyper::Body> {
+        let problems = serde_json::from_value::<Config>(body).map_or_else(
+            |err| vec![Problem::new("config", err.to_string())],
+            |config| config.validate(),
+        );
+
+        let status = if problems.is_empty() {
+            StatusCode::OK
+        } else {
+            StatusCode::UNPROCESSABLE_ENTITY
+        };
+
+        with_status(json(&problems), status).into_response()
+    }
+
+    ///
+    /// The whole config (including the custom rules, local records and client
+    /// names) as a file that can be imported again
+    ///
+    pub(super) async fn export(
+        export: Export,
+    ) -> Result<Response<warp::hyper::Body>, warp::Rejection> {
+        let config = Config::get(Clone::clone).await;
+
+        let (response, extension) = match export.format {
+            Format::Toml => (
+                with_header(
+                    toml::to_string_pretty(&config)
+                        .map_err(|err| warp::reject::custom(Error::from(err)))?,
+                    CONTENT_TYPE,
+                    "application/toml",
+                )
+                .into_response(),
+                "toml",
+            ),
+            Format::Json => (json(&config).into_response(), "json"),
+        };
+
+        Ok(with_header(
+            response,
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"blackhole.{extension}\""),
+        )
+        .into_response())
+    }
+
+    ///
+    /// Replace the config with one that was exported, either as TOML or JSON
+    /// (going by the content type), so long as it's valid
+    ///
+    pub(super) async fn import(
+        content_type: Option<String>,
+        body: Bytes,
+    ) -> Result<Response<warp::hyper::Body>, warp::Rejection> {
+        let is_json = content_type.is_some_and(|content_type| content_type.contains("json"));
+
+        let imported = match std::str::from_utf8(&body) {
+            Ok(body) if is_json => {
+                serde_json::from_str::<Config>(body).map_err(|err| err.to_string())
+            }
+            Ok(body) => toml::from_str::<Config>(body).map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+
+        let problems = imported.as_ref().map_or_else(
+            |err| vec![Problem::new("config", err.clone())],
+            Config::validate,
+        );
+
+        match imported {
+            Ok(imported) if problems.is_empty() => Config::set(|config| *config = imported.clone())
+                .await
+                .map(|()| Response::default())
+                .map_err(warp::reject::custom),
+            _ => Ok(with_status(json(&problems), StatusCode::UNPROCESSABLE_ENTITY).into_response()),
+        }
+    }
+
+    ///
+    /// Add the lists, rules and local records from a Pi-hole Teleporter
+    /// archive to the config
+    ///
+    pub(super) async fn pihole(
+        teleporter: Teleporter,
+    ) -> Result<Response<warp::hyper::Body>, warp::Rejection> {
+        let mut config = Config::get(Clone::clone).await;
+        let imported = teleporter.import(&mut config);
+
+        let problems = config.validate();
+        if !problems.is_empty() {
+            return Ok(
+                with_status(json(&problems), StatusCode::UNPROCESSABLE_ENTITY).into_response(),
+            );
+        }
+
+        Config::set(|current| *current = config.clone())
+            .await
+            .map(|()| json(&imported).into_response())
+            .map_err(warp::reject::custom)
+    }
+
     pub(super) async fn update(
         body: Config,
     ) -> Result<Response<warp::hyper::Body>, warp::Rejection> {
@@ -174,15 +757,68 @@ mod config {
 }
 
 mod filters {
+    use percent_encoding::percent_decode_str;
+    use serde::{Deserialize, Serialize};
     use warp::{
-        http::Response,
-        reply::{json, Reply},
+        http::{Response, StatusCode},
+        hyper::header::CONTENT_TYPE,
+        reply::{json, with_status, Reply},
     };
 
-    use crate::config::Config;
+    use crate::{
+        config::{self, Config},
+        filter::{export::Format, rules::Source, Filter, List, Status},
+    };
+
+    use super::statistics::TOTAL_COUNT;
+
+    #[derive(Deserialize)]
+    pub(super) struct Toggle {
+        enabled: bool,
+    }
+
+    const fn default_limit() -> usize {
+        100
+    }
+
+    #[derive(Deserialize)]
+    pub(super) struct Entries {
+        /// Only include domains containing this
+        search: Option<String>,
+        #[serde(default = "default_limit")]
+        limit: usize,
+    }
+
+    #[derive(Deserialize)]
+    pub(super) struct Export {
+        #[serde(default)]
+        format: Format,
+    }
+
+    #[derive(Deserialize)]
+    pub(super) struct Refresh {
+        /// Only refresh the list with this name, rather than all of them
+        name: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    struct Listing {
+        #[serde(flatten)]
+        list: List,
+        status: Option<Status>,
+    }
 
     pub(super) async fn all() -> Result<Response<warp::hyper::Body>, warp::Rejection> {
-        let filters = Config::get(|config| config.filters.clone()).await;
+        let mut statuses = Filter::statuses().await;
+        let filters = Config::get(|config| config.filters.clone())
+            .await
+            .into_iter()
+            .map(|list| Listing {
+                status: statuses.remove(&list.to_string()),
+                list,
+            })
+            .collect::<Vec<_>>();
+
         Ok(json(&filters).into_response())
     }
 
@@ -213,48 +849,319 @@ mod filters {
         .map(|()| Response::default())
         .map_err(warp::reject::custom)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::sync::LazyLock;
+    ///
+    /// Enable or disable a list by name, holding on to it (and whatever has
+    /// been downloaded of it) either way
+    ///
+    pub(super) async fn toggle(
+        name: String,
+        toggle: Toggle,
+    ) -> Result<Response<warp::hyper::Body>, warp::Rejection> {
+        let name = percent_decode_str(&name).decode_utf8_lossy();
+
+        let Some(mut list) = Config::get(|config| {
+            config
+                .filters
+                .iter()
+                .find(|list| list.name == name)
+                .cloned()
+        })
+        .await
+        else {
+            return Ok(with_status(warp::reply(), StatusCode::NOT_FOUND).into_response());
+        };
 
-    use ahash::AHashMap;
-    use pretty_assertions::assert_eq;
-    use tokio::sync::Mutex;
-    use warp::hyper::header::CONTENT_TYPE;
+        list.enabled = toggle.enabled;
 
-    use crate::{
-        config::Config,
-        statistics::{Statistic, Statistics, REQUESTS},
-    };
+        Config::set(|config| {
+            config.filters.replace(list.clone());
+        })
+        .await
+        .map(|()| json(&list).into_response())
+        .map_err(warp::reject::custom)
+    }
 
-    static WORKER: LazyLock<Mutex<bool>> = LazyLock::new(Mutex::default);
+    ///
+    /// The rules loaded from a list, so that it can be searched without
+    /// needing to go through the list itself
+    ///
+    pub(super) async fn entries(name: String, entries: Entries) -> Response<warp::hyper::Body> {
+        let name = percent_decode_str(&name).decode_utf8_lossy();
 
-    #[tokio::test]
-    async fn statistics() {
-        let filter = super::Server::statistics();
+        let known = name == Source::builtin().name
+            || Config::get(|config| config.filters.iter().any(|list| list.name == name)).await;
+        if !known {
+            return with_status(warp::reply(), StatusCode::NOT_FOUND).into_response();
+        }
 
-        let worker = WORKER.lock().await;
-        let response = warp::test::request()
-            .path("/statistics/requests")
-            .reply(&filter)
-            .await;
-        drop(worker);
+        let (total, rules) = Filter::contents(&name, entries.search.as_deref(), entries.limit);
 
-        assert_eq!(response.status(), 200);
-        assert!(response.headers().contains_key(CONTENT_TYPE));
-        assert_eq!(
-            response.headers().get(CONTENT_TYPE).unwrap(),
-            "application/json"
-        );
-        assert_eq!(response.body(), "{}");
+        let mut response = json(&rules).into_response();
+        response.headers_mut().insert(TOTAL_COUNT, total.into());
+        response
     }
 
-    #[tokio::test]
-    async fn all() {
-        let filter = super::Server::statistics();
-
+    ///
+    /// The domains currently being blocked, as a list other devices can use
+    ///
+    pub(super) async fn export(export: Export) -> Response<warp::hyper::Body> {
+        warp::reply::with_header(
+            Filter::blocklist(export.format).await,
+            CONTENT_TYPE,
+            "text/plain",
+        )
+        .into_response()
+    }
+
+    ///
+    /// Download the lists again and load them, without waiting for them to
+    /// be due
+    ///
+    pub(super) async fn refresh(
+        refresh: Refresh,
+    ) -> Result<Response<warp::hyper::Body>, warp::Rejection> {
+        match Filter::refresh(refresh.name).await {
+            Ok(true) => Ok(Response::default()),
+            Ok(false) => Ok(with_status(warp::reply(), StatusCode::NOT_FOUND).into_response()),
+            Err(err) => Err(warp::reject::custom(config::Error::from(err))),
+        }
+    }
+}
+
+mod rules {
+    use serde::Deserialize;
+    use warp::{
+        http::Response,
+        reply::{json, Reply},
+    };
+
+    use crate::{filter::Custom, FilterHandle};
+
+    #[derive(Deserialize)]
+    pub(super) struct Remove {
+        domain: String,
+    }
+
+    pub(super) async fn all() -> Result<Response<warp::hyper::Body>, warp::Rejection> {
+        let rules = FilterHandle(()).rules().await;
+        Ok(json(&rules).into_response())
+    }
+
+    ///
+    /// Add a rule, replacing any existing rule for the same domain
+    ///
+    pub(super) async fn add(rule: Custom) -> Result<Response<warp::hyper::Body>, warp::Rejection> {
+        #[cfg(debug_assertions)]
+        tracing::debug!("Adding rule: {rule:#?}");
+
+        FilterHandle(())
+            .add_rule(rule)
+            .await
+            .map(|()| Response::default())
+            .map_err(warp::reject::custom)
+    }
+
+    pub(super) async fn remove(
+        rule: Remove,
+    ) -> Result<Response<warp::hyper::Body>, warp::Rejection> {
+        #[cfg(debug_assertions)]
+        tracing::debug!("Removing rule: {}", rule.domain);
+
+        FilterHandle(())
+            .remove_rule(&rule.domain)
+            .await
+            .map(|()| Response::default())
+            .map_err(warp::reject::custom)
+    }
+}
+
+mod schedules {
+    use warp::{
+        http::{Response, StatusCode},
+        reply::{json, with_status, Reply},
+    };
+
+    use crate::{
+        config::{Config, Problem},
+        schedule::{Sched, Schedule},
+    };
+
+    ///
+    /// Schedule a task, replacing how often it runs should it already be
+    /// scheduled
+    ///
+    pub(super) async fn set(
+        schedule: Schedule,
+    ) -> Result<Response<warp::hyper::Body>, warp::Rejection> {
+        #[cfg(debug_assertions)]
+        tracing::debug!("Scheduling: {schedule:#?}");
+
+        if schedule.schedule.is_zero() {
+            let problem = Problem::new(
+                "schedule",
+                format!("{:?} needs a schedule longer than 0s", schedule.name),
+            );
+
+            return Ok(
+                with_status(json(&[problem]), StatusCode::UNPROCESSABLE_ENTITY).into_response(),
+            );
+        }
+
+        Config::set(|config| {
+            config
+                .schedules
+                .retain(|existing| existing.name != schedule.name);
+            config.schedules.push(schedule.clone());
+        })
+        .await
+        .map(|()| Response::default())
+        .map_err(warp::reject::custom)
+    }
+
+    ///
+    /// Stop running a task on a schedule
+    ///
+    pub(super) async fn remove(
+        name: Sched,
+    ) -> Result<Response<warp::hyper::Body>, warp::Rejection> {
+        let scheduled = Config::get(|config| {
+            config
+                .schedules
+                .iter()
+                .any(|schedule| schedule.name == name)
+        })
+        .await;
+        if !scheduled {
+            return Ok(with_status(warp::reply(), StatusCode::NOT_FOUND).into_response());
+        }
+
+        Config::set(|config| config.schedules.retain(|schedule| schedule.name != name))
+            .await
+            .map(|()| Response::default())
+            .map_err(warp::reject::custom)
+    }
+}
+
+mod blocking {
+    use std::time::Duration;
+
+    use serde::Deserialize;
+    use warp::{
+        http::{Response, StatusCode},
+        reply::{json, with_status, Reply},
+    };
+
+    use crate::filter::Filter;
+
+    #[derive(Deserialize)]
+    pub(super) struct Toggle {
+        enabled: bool,
+        /// How long to disable blocking for, or indefinitely if not given
+        #[serde(with = "humantime_serde", default)]
+        duration: Option<Duration>,
+    }
+
+    pub(super) async fn toggle(toggle: Toggle) -> Response<warp::hyper::Body> {
+        let blocking = if toggle.enabled {
+            Filter::resume().await
+        } else if let Some(blocking) = Filter::pause(toggle.duration).await {
+            blocking
+        } else {
+            return with_status("duration is out of range", StatusCode::BAD_REQUEST)
+                .into_response();
+        };
+
+        json(&blocking).into_response()
+    }
+}
+
+mod resolve {
+    use std::str::FromStr;
+
+    use hickory_proto::rr::{Name, RecordType};
+    use serde::Deserialize;
+    use warp::{
+        http::{Response, StatusCode},
+        reply::{json, with_status, Reply},
+    };
+
+    use crate::dns::Server;
+
+    #[derive(Deserialize)]
+    pub(super) struct Params {
+        #[serde(rename = "type")]
+        query_type: Option<String>,
+        /// Bypass the cache, repopulating it with the answer
+        #[serde(default)]
+        fresh: bool,
+    }
+
+    pub(super) async fn resolve(name: String, params: Params) -> Response<warp::hyper::Body> {
+        let query_type = params
+            .query_type
+            .as_deref()
+            .map_or(Ok(RecordType::A), |ty| {
+                RecordType::from_str(&ty.to_ascii_uppercase())
+            });
+
+        let (name, query_type) = match Name::from_str(&name).map(|name| (name, query_type)) {
+            Ok((name, Ok(query_type))) => (name, query_type),
+            Ok((_, Err(err))) | Err(err) => {
+                return with_status(err.to_string(), StatusCode::BAD_REQUEST).into_response();
+            }
+        };
+
+        match Server.resolve(name, query_type, params.fresh).await {
+            Ok(trace) => json(&trace).into_response(),
+            Err(err) => {
+                with_status(err.to_string(), StatusCode::INTERNAL_SERVER_ERROR).into_response()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::LazyLock;
+
+    use ahash::AHashMap;
+    use pretty_assertions::assert_eq;
+    use tokio::sync::Mutex;
+    use warp::hyper::header::CONTENT_TYPE;
+
+    use crate::{
+        config::Config,
+        filter::{rules::Kind, Custom},
+        statistics::{Statistic, Statistics, REQUESTS},
+    };
+
+    static WORKER: LazyLock<Mutex<bool>> = LazyLock::new(Mutex::default);
+
+    #[tokio::test]
+    async fn statistics() {
+        let filter = super::Server::statistics();
+
+        let worker = WORKER.lock().await;
+        let response = warp::test::request()
+            .path("/statistics/requests")
+            .reply(&filter)
+            .await;
+        drop(worker);
+
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().contains_key(CONTENT_TYPE));
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        assert_eq!(response.body(), "{}");
+    }
+
+    #[tokio::test]
+    async fn all() {
+        let filter = super::Server::statistics();
+
         let worker = WORKER.lock().await;
 
         let request = crate::statistics::Request {
@@ -263,9 +1170,10 @@ mod test {
         let average = crate::statistics::Average {
             count: 1,
             average: 1,
+            ..Default::default()
         };
 
-        Statistics::record(Statistic::Request(request.clone()));
+        Statistics::record(Statistic::Request(Box::new(request.clone())));
         Statistics::record(Statistic::Average(average.clone()));
 
         let response = warp::test::request()
@@ -300,7 +1208,7 @@ mod test {
             ..Default::default()
         };
 
-        Statistics::record(Statistic::Request(request.clone()));
+        Statistics::record(Statistic::Request(Box::new(request.clone())));
 
         let response = warp::test::request()
             .path("/statistics/requests")
@@ -325,6 +1233,362 @@ mod test {
         drop(worker);
     }
 
+    #[tokio::test]
+    async fn requests_v1() {
+        let filter = super::Server::statistics_v1();
+
+        let worker = WORKER.lock().await;
+        Statistics::record(Statistic::Request(Box::new(crate::statistics::Request {
+            question: String::from("example.com."),
+            timestamp: std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_500),
+            ..Default::default()
+        })));
+
+        let response = warp::test::request()
+            .path("/statistics/requests")
+            .reply(&filter)
+            .await;
+
+        Statistics::clear();
+        drop(worker);
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get("X-Total-Count").unwrap(), "1");
+
+        let body = serde_json::from_slice::<serde_json::Value>(response.body()).unwrap();
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["requests"][0]["question"], "example.com.");
+        assert_eq!(body["requests"][0]["timestamp"], 1_500);
+        assert_eq!(body["requests"][0]["blocked"], false);
+    }
+
+    #[tokio::test]
+    async fn cors() {
+        let filter = warp::Filter::with(
+            super::Server::health(),
+            super::cors(&[String::from("http://dashboard.lan:8080")]).unwrap(),
+        );
+
+        let response = warp::test::request()
+            .method("OPTIONS")
+            .path("/health")
+            .header("origin", "http://dashboard.lan:8080")
+            .header("access-control-request-method", "GET")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response
+                .headers()
+                .get("access-control-allow-origin")
+                .unwrap(),
+            "http://dashboard.lan:8080"
+        );
+
+        let response = warp::test::request()
+            .path("/health")
+            .header("origin", "http://elsewhere.lan")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 403);
+
+        assert!(super::cors(&[]).is_none());
+    }
+
+    #[tokio::test]
+    async fn docs() {
+        let filter = super::Server::docs();
+
+        let response = warp::test::request()
+            .path("/openapi.json")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let openapi = serde_json::from_slice::<serde_json::Value>(response.body()).unwrap();
+        assert_eq!(openapi["info"]["version"], env!("CARGO_PKG_VERSION"));
+        assert!(openapi["paths"]["/filters/export"]["get"].is_object());
+
+        let response = warp::test::request().path("/docs").reply(&filter).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+    }
+
+    #[tokio::test]
+    async fn dashboard() {
+        let filter = super::Server::dashboard();
+
+        let response = warp::test::request().path("/").reply(&filter).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert!(String::from_utf8_lossy(response.body()).contains("/api/v1"));
+
+        let response = warp::test::request().path("/api").reply(&filter).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn schedules() {
+        let filter = super::Server::schedules();
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/schedules/unknown/run")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        // Nothing is scheduled as the scheduler isn't running
+        let response = warp::test::request()
+            .method("POST")
+            .path("/schedules/filters/run")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let response = warp::test::request()
+            .path("/schedules/filters/run")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 405);
+
+        let response = warp::test::request()
+            .path("/schedules")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let statuses: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(statuses.is_array());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/schedules")
+            .json(&serde_json::json!({ "name": "Logs", "schedule": "0s" }))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 422);
+
+        // Not scheduled, so there's nothing to remove
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/schedules/statistics")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn tasks() {
+        let filter = super::Server::tasks();
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/tasks/unknown")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/tasks/cache-flush")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 202);
+
+        let run: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(run["task"], "cache-flush");
+
+        let response = warp::test::request()
+            .path(&format!("/tasks/{}", run["id"]))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request().path("/tasks/0").reply(&filter).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn blocking() {
+        let filter = super::Server::blocking();
+
+        // Too long to ever be up, so blocking is left alone
+        let response = warp::test::request()
+            .method("POST")
+            .path("/blocking")
+            .json(&serde_json::json!({ "enabled": false, "duration": "300000000000y" }))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 400);
+        assert!(crate::filter::Filter::blocking().enabled);
+    }
+
+    #[tokio::test]
+    async fn rules() {
+        let filter = super::Server::rules();
+
+        let worker = WORKER.lock().await;
+        *crate::config::CONFIG_FILE.write().await = Some(String::from("config.toml"));
+
+        let add = |kind: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/rules")
+                .json(&serde_json::json!({ "domain": "custom.example.com", "kind": kind }))
+                .reply(&filter)
+        };
+
+        assert_eq!(add("deny").await.status(), 200);
+        assert_eq!(add("allow").await.status(), 200);
+
+        let rules = Config::get(|config| config.rules.clone()).await;
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/rules")
+            .json(&serde_json::json!({ "domain": "custom.example.com" }))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let remaining = Config::get(|config| config.rules.clone()).await;
+        drop(worker);
+
+        assert_eq!(
+            rules,
+            vec![Custom {
+                domain: String::from("custom.example.com"),
+                kind: Kind::Allow,
+                ..Default::default()
+            }]
+        );
+        assert!(remaining.is_empty());
+    }
+
+    #[tokio::test]
+    async fn resolve() {
+        let filter = super::Server::resolve();
+
+        let worker = WORKER.lock().await;
+        *crate::config::CONFIG_FILE.write().await = Some(String::from("config.toml"));
+
+        crate::FilterHandle(())
+            .add_rule(Custom {
+                domain: String::from("resolve.example.com"),
+                v4: Some("192.0.2.1".parse().unwrap()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let response = warp::test::request()
+            .path("/resolve/resolve.example.com?type=a&fresh=true")
+            .reply(&filter)
+            .await;
+        let invalid = warp::test::request()
+            .path("/resolve/resolve.example.com?type=bogus")
+            .reply(&filter)
+            .await;
+
+        crate::FilterHandle(())
+            .remove_rule("resolve.example.com")
+            .await
+            .unwrap();
+        drop(worker);
+
+        assert_eq!(response.status(), 200);
+        let trace: crate::statistics::Request = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(trace.question, "resolve.example.com.");
+        assert_eq!(trace.status, "No Error");
+        assert!(!trace.cached);
+        assert_eq!(
+            trace
+                .answers
+                .iter()
+                .map(|answer| answer.data().unwrap().to_string())
+                .collect::<Vec<_>>(),
+            ["192.0.2.1"]
+        );
+
+        assert_eq!(invalid.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn health() {
+        let filter = super::Server::health();
+
+        let response = warp::test::request().path("/health").reply(&filter).await;
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert_eq!(
+            serde_json::from_str::<crate::health::Status>(&body).unwrap(),
+            crate::health::Health::status()
+        );
+    }
+
+    #[tokio::test]
+    async fn query_requests() {
+        let filter = super::Server::statistics();
+
+        let worker = WORKER.lock().await;
+
+        for client in ["10.0.0.1", "10.0.0.2", "10.0.0.1"] {
+            Statistics::record(Statistic::Request(Box::new(crate::statistics::Request {
+                client: String::from(client),
+                ..Default::default()
+            })));
+        }
+
+        let response = warp::test::request()
+            .path("/statistics/requests?client=10.0.0.1&limit=1")
+            .reply(&filter)
+            .await;
+
+        Statistics::clear();
+        drop(worker);
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get("X-Total-Count").unwrap(), "2");
+
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        match serde_json::from_str::<Statistic>(&body).unwrap() {
+            Statistic::Requests(requests) => {
+                assert_eq!(requests.len(), 1);
+                assert_eq!(
+                    requests.iter().next().map(|request| request.client),
+                    Some(String::from("10.0.0.1"))
+                );
+            }
+            statistic => panic!("Unexpected statistic: {statistic:?}"),
+        }
+
+        for path in [
+            "/statistics/requests?since=18446744073709551615",
+            "/statistics/requests?until=18446744073709551615",
+        ] {
+            let response = warp::test::request().path(path).reply(&filter).await;
+            assert_eq!(response.status(), 400);
+        }
+
+        let response = warp::test::request()
+            .path("/statistics/requests?since=18446744073709551615")
+            .reply(&super::Server::statistics_v1())
+            .await;
+        assert_eq!(response.status(), 400);
+    }
+
     #[tokio::test]
     async fn config() {
         let filter = super::Server::config();
@@ -350,6 +1614,50 @@ mod test {
         assert_eq!(serde_json::from_str::<Config>(&body).unwrap(), config);
     }
 
+    #[tokio::test]
+    async fn validate_config() {
+        let filter = super::Server::config();
+
+        let mut config = crate::config::Config::default();
+        let valid = warp::test::request()
+            .path("/config/validate")
+            .method("POST")
+            .json(&config)
+            .reply(&filter)
+            .await;
+
+        config.api.port = config.port;
+        let conflicting = warp::test::request()
+            .path("/config/validate")
+            .method("POST")
+            .json(&config)
+            .reply(&filter)
+            .await;
+
+        let malformed = warp::test::request()
+            .path("/config/validate")
+            .method("POST")
+            .json(&serde_json::json!({ "schedule": [{ "name": "Unknown" }] }))
+            .reply(&filter)
+            .await;
+
+        assert_eq!(valid.status(), 200);
+        assert_eq!(valid.body(), "[]");
+
+        assert_eq!(conflicting.status(), 422);
+        let problems: Vec<crate::config::Problem> =
+            serde_json::from_slice(conflicting.body()).unwrap();
+        assert_eq!(
+            problems
+                .into_iter()
+                .map(|problem| problem.key)
+                .collect::<Vec<_>>(),
+            ["api.port"]
+        );
+
+        assert_eq!(malformed.status(), 422);
+    }
+
     #[tokio::test]
     async fn update_config() {
         let filter = super::Server::config();
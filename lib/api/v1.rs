@@ -0,0 +1,252 @@
+//!
+//! What the API responds with from v1 on. These are kept apart from the types
+//! used internally, so that those can change without breaking anyone using
+//! the API, whether over HTTP or gRPC.
+//!
+
+use std::{collections::BTreeMap, time::UNIX_EPOCH};
+
+use ahash::AHashMap;
+use serde::Serialize;
+use warp::{
+    http::{Response, StatusCode},
+    reply::{json, with_status, Reply},
+};
+
+use crate::statistics::{
+    self, Query, Statistic, Statistics, AVERAGE_REQUEST_TIME, BLOCKED, CACHE, PROTOCOLS, REQUESTS,
+};
+
+use super::statistics::TOTAL_COUNT;
+
+///
+/// The rule a request matched
+///
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+#[derive(Serialize)]
+pub(super) struct Rule {
+    pub(super) domain: String,
+    pub(super) kind: String,
+    /// The name of the list the rule came from, if it came from one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) list: Option<String>,
+}
+
+///
+/// A request that was handled
+///
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+#[derive(Serialize)]
+pub(super) struct Request {
+    pub(super) client: String,
+    /// The client's friendly name, should it have one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) name: Option<String>,
+    /// The client's hardware address, should its DHCP lease give it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) mac: Option<String>,
+    pub(super) question: String,
+    #[serde(rename = "type")]
+    pub(super) query_type: String,
+    /// Each of the answers as it would appear in a zone file
+    pub(super) answers: Vec<String>,
+    pub(super) rule: Option<Rule>,
+    pub(super) blocked: bool,
+    pub(super) status: String,
+    /// How long it took to answer, in nanoseconds
+    pub(super) elapsed: usize,
+    /// How long each stage of answering it took, in nanoseconds
+    pub(super) timings: statistics::Timings,
+    /// When it was answered, in milliseconds since the epoch
+    pub(super) timestamp: u64,
+    pub(super) cached: bool,
+    pub(super) protocol: String,
+}
+
+impl From<statistics::Request> for Request {
+    fn from(request: statistics::Request) -> Self {
+        Self {
+            blocked: request.blocked(),
+            client: request.client,
+            name: request.name,
+            mac: request.mac,
+            question: request.question,
+            query_type: request.query_type.to_string(),
+            answers: request.answers.iter().map(ToString::to_string).collect(),
+            rule: request.rule.map(|rule| Rule {
+                domain: rule.domain().to_string(),
+                kind: rule.kind.to_string(),
+                list: rule.list().map(|source| source.name.clone()),
+            }),
+            status: request.status,
+            elapsed: request.elapsed,
+            timings: request.timings,
+            timestamp: request
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| {
+                    u64::try_from(since.as_millis()).unwrap_or(u64::MAX)
+                }),
+            cached: request.cached,
+            protocol: request.protocol,
+        }
+    }
+}
+
+///
+/// A page of the request log, most recent first
+///
+#[derive(Serialize)]
+struct Requests {
+    /// How many requests matched, regardless of the page
+    total: usize,
+    requests: Vec<Request>,
+}
+
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+#[derive(Serialize, Default)]
+pub(super) struct Average {
+    pub(super) count: usize,
+    /// In nanoseconds
+    pub(super) average: usize,
+    /// Over the last few minutes, overall and by outcome
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) latency: Option<statistics::Latencies>,
+}
+
+impl From<&statistics::Average> for Average {
+    fn from(average: &statistics::Average) -> Self {
+        Self {
+            count: average.count,
+            average: average.average,
+            latency: average.latency.clone(),
+        }
+    }
+}
+
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+#[derive(Serialize, Default)]
+pub(super) struct Cache {
+    /// Roughly how much memory the cache takes up, in bytes
+    pub(super) size: usize,
+    /// How many responses are cached
+    pub(super) entries: usize,
+    pub(super) hits: usize,
+    pub(super) misses: usize,
+    pub(super) evictions: usize,
+    pub(super) expirations: usize,
+}
+
+///
+/// The running totals
+///
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+#[derive(Serialize, Default)]
+pub(super) struct Totals {
+    /// How many requests are in the log
+    pub(super) requests: usize,
+    pub(super) blocked: usize,
+    /// How long requests took to answer
+    pub(super) average: Average,
+    pub(super) cache: Cache,
+    /// How long requests over each of the protocols took to answer
+    pub(super) protocols: BTreeMap<String, Average>,
+}
+
+impl From<&AHashMap<&'static str, Statistic>> for Totals {
+    fn from(statistics: &AHashMap<&'static str, Statistic>) -> Self {
+        let mut totals = Self::default();
+
+        for (name, statistic) in statistics {
+            match (*name, statistic) {
+                (REQUESTS, Statistic::Requests(requests)) => totals.requests = requests.len(),
+                (BLOCKED, Statistic::Count(blocked)) => totals.blocked = *blocked,
+                (AVERAGE_REQUEST_TIME, Statistic::Average(average)) => {
+                    totals.average = average.into();
+                }
+                (CACHE, Statistic::Cache(cache)) => {
+                    totals.cache = Cache {
+                        size: cache.size,
+                        entries: cache.entries,
+                        hits: cache.hits,
+                        misses: cache.misses,
+                        evictions: cache.evictions,
+                        expirations: cache.expirations,
+                    };
+                }
+                (PROTOCOLS, Statistic::Protocols(protocols)) => {
+                    totals.protocols = protocols
+                        .iter()
+                        .map(|(protocol, average)| (protocol.clone(), average.into()))
+                        .collect();
+                }
+                _ => {}
+            }
+        }
+
+        totals
+    }
+}
+
+pub(super) fn totals() -> Response<warp::hyper::Body> {
+    json(&Totals::from(&Statistics::statistics())).into_response()
+}
+
+pub(super) fn requests(query: &Query) -> Response<warp::hyper::Body> {
+    if let Err(err) = query.validate() {
+        return with_status(err, StatusCode::BAD_REQUEST).into_response();
+    }
+
+    let (total, requests) = Statistics::requests(query).unwrap_or_default();
+
+    let mut response = json(&Requests {
+        total,
+        requests: requests.into_iter().map(Request::from).collect(),
+    })
+    .into_response();
+    response.headers_mut().insert(TOTAL_COUNT, total.into());
+
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use ahash::AHashMap;
+    use pretty_assertions::assert_eq;
+
+    use super::{Average, Totals};
+    use crate::statistics::{self, Statistic, AVERAGE_REQUEST_TIME, BLOCKED, REQUESTS};
+
+    #[test]
+    fn totals() {
+        let statistics = AHashMap::from_iter([
+            (
+                REQUESTS,
+                Statistic::Requests(vec![statistics::Request::default(); 3].into()),
+            ),
+            (BLOCKED, Statistic::Count(1)),
+            (
+                AVERAGE_REQUEST_TIME,
+                Statistic::Average(statistics::Average {
+                    count: 3,
+                    average: 1_500,
+                    ..Default::default()
+                }),
+            ),
+        ]);
+
+        assert_eq!(
+            Totals::from(&statistics),
+            Totals {
+                requests: 3,
+                blocked: 1,
+                average: Average {
+                    count: 3,
+                    average: 1_500,
+                    latency: None,
+                },
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -3,7 +3,10 @@ use std::sync::LazyLock;
 use std::time::{Duration, Instant};
 
 use ahash::AHashMap;
-use hickory_proto::{rr::RecordType, xfer::DnsResponse};
+use hickory_proto::{
+    rr::{Name, RecordType},
+    xfer::DnsResponse,
+};
 use hickory_server::server::Request;
 use lru_cache::LruCache;
 use tokio::sync::RwLock;
@@ -11,23 +14,128 @@ use tokio::sync::RwLock;
 use crate::statistics::{self, Statistic, Statistics};
 
 type PacketExpires = (DnsResponse, Vec<Instant>);
-type Entry = AHashMap<RecordType, PacketExpires>;
+type Entry = AHashMap<Variant, PacketExpires>;
+
+///
+/// Which of the responses for a name this is, as the same name can have a
+/// different answer for each type, and with or without DNSSEC records
+///
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Variant {
+    query_type: RecordType,
+    /// Whether the DO bit was set, asking for DNSSEC records
+    dnssec_ok: bool,
+}
+
+impl Variant {
+    fn of(request: &Request) -> Self {
+        Self {
+            query_type: request.query().query_type(),
+            dnssec_ok: request.edns().is_some_and(|edns| edns.dnssec_ok()),
+        }
+    }
+}
+
+///
+/// Names are cached regardless of case, or whether they're fully qualified
+///
+fn key(name: &Name) -> String {
+    let mut name = name.to_lowercase();
+    name.set_fqdn(true);
+    name.to_string()
+}
 
 pub struct Cache {
     cache: LruCache<String, Entry>,
+    /// How many responses are cached, across every name
+    entries: usize,
+    /// Roughly how much memory the cached responses take up
+    memory: usize,
 }
 
 impl Default for Cache {
     fn default() -> Self {
         Self {
             cache: LruCache::new(1024),
+            entries: 0,
+            memory: 0,
         }
     }
 }
 
 static CACHE: LazyLock<RwLock<Cache>> = LazyLock::new(RwLock::default);
 
+///
+/// Roughly how much memory the cached response takes up
+///
+fn size(response: &PacketExpires) -> usize {
+    // Responses are kept both parsed and as they came over the wire, which
+    // take up about as much as each other
+    size_of::<(Variant, PacketExpires)>()
+        + 2 * response.0.as_buffer().len()
+        + response.1.capacity() * size_of::<Instant>()
+}
+
+///
+/// Roughly how much memory a name takes up, not including its responses
+///
+fn name_size(name: &str) -> usize {
+    name.len() + size_of::<Entry>()
+}
+
+fn expired(expires: &[Instant], now: Instant) -> bool {
+    expires.iter().any(|expire| *expire < now)
+}
+
 impl Cache {
+    ///
+    /// The cache as it currently is, to be recorded along with whatever
+    /// happened to it
+    ///
+    fn statistic(&self) -> statistics::Cache {
+        statistics::Cache {
+            size: self.memory,
+            entries: self.entries,
+            ..Default::default()
+        }
+    }
+
+    ///
+    /// Forget the response, should it be cached
+    ///
+    fn remove(&mut self, name: &str, variant: Variant) {
+        let Some(entry) = self.cache.get_mut(name) else {
+            return;
+        };
+
+        if let Some(response) = entry.remove(&variant) {
+            self.entries -= 1;
+            self.memory = self.memory.saturating_sub(size(&response));
+        }
+
+        if entry.is_empty() {
+            self.cache.remove(name);
+            self.memory = self.memory.saturating_sub(name_size(name));
+        }
+    }
+
+    ///
+    /// Forget the least recently used name, and every response for it,
+    /// returning how many responses that was
+    ///
+    fn evict(&mut self) -> usize {
+        let Some((name, entry)) = self.cache.remove_lru() else {
+            return 0;
+        };
+
+        self.entries -= entry.len();
+        self.memory = self
+            .memory
+            .saturating_sub(name_size(&name) + entry.values().map(size).sum::<usize>());
+
+        entry.len()
+    }
+
     ///
     /// Retrieve an entry from the cache, if it exists
     ///
@@ -36,74 +144,246 @@ impl Cache {
     /// records does not have a TTL (e.g. [`OPT`])
     ///
     pub async fn get(request: &Request) -> Option<DnsResponse> {
+        let name = key(request.query().original().name());
+        let variant = Variant::of(request);
+        let now = Instant::now();
+
         let (ref response, expires) = {
             let mut cache = CACHE.write().await;
-            cache
+            let (response, expires) = cache
                 .cache
-                .get_mut(&request.query().original().name().to_string())
-                .and_then(|entry| entry.get_mut(&request.query().query_type()))?
-                .clone()
-        };
+                .get_mut(&name)
+                .and_then(|entry| entry.get(&variant))?
+                .clone();
 
-        let mut resp = response.clone().into_message();
+            if expired(&expires, now) {
+                cache.remove(&name, variant);
 
-        let now = Instant::now();
+                Statistics::record(Statistic::Cache(statistics::Cache {
+                    expirations: 1,
+                    ..cache.statistic()
+                }));
+
+                return None;
+            }
 
-        expires.iter().all(|expire| *expire >= now).then(|| {
             Statistics::record(Statistic::Cache(statistics::Cache {
                 hits: 1,
-                misses: 0,
-                size: 0,
+                ..cache.statistic()
             }));
 
-            resp.answers_mut()
-                .iter_mut()
-                .zip(expires)
-                .for_each(|(answer, expire)| {
-                    answer
-                        .set_ttl(u32::try_from((expire - now).as_secs()).expect("Invalid expiry"));
-                });
+            (response, expires)
+        };
 
-            response.clone()
-        })
-    }
+        let mut resp = response.clone().into_message();
 
-    pub async fn insert(response: &DnsResponse) {
-        let mut cache = CACHE.write().await;
+        resp.answers_mut()
+            .iter_mut()
+            .zip(expires)
+            .for_each(|(answer, expire)| {
+                answer.set_ttl(u32::try_from((expire - now).as_secs()).expect("Invalid expiry"));
+            });
 
-        let key = response.queries()[0].name().to_string();
-        let sub_key = response.queries()[0].query_type();
+        Some(response.clone())
+    }
 
-        let exists = cache
-            .cache
-            .get_mut(&key)
-            .map(|inner| inner.contains_key(&sub_key));
+    ///
+    /// Cache the response, replacing whatever was cached for it before,
+    /// returning how many responses had to be evicted to make room
+    ///
+    fn put(&mut self, response: &DnsResponse, dnssec_ok: bool, now: Instant) -> usize {
+        let key = key(response.queries()[0].name());
+        let sub_key = Variant {
+            query_type: response.queries()[0].query_type(),
+            dnssec_ok,
+        };
 
-        Statistics::record(Statistic::Cache(statistics::Cache {
-            hits: 0,
-            misses: 1,
-            size: match exists {
-                Some(true) => 0,
-                Some(false) => size_of::<Entry>(),
-                None => key.capacity() + size_of::<Entry>(),
-            },
-        }));
+        self.remove(&key, sub_key);
+
+        let evictions =
+            if !self.cache.contains_key(&key) && self.cache.len() >= self.cache.capacity() {
+                self.evict()
+            } else {
+                0
+            };
 
-        let now = Instant::now();
         let value = response
             .answers()
             .iter()
             .map(|answer| now + Duration::from_secs(answer.ttl().into()))
             .collect();
 
-        if let Some(entry) = cache.cache.get_mut(&key) {
-            *entry.entry(sub_key).or_insert((response.clone(), value)) =
-                (response.clone(), value.clone());
+        let cached = (response.clone(), value);
+        self.entries += 1;
+        self.memory += size(&cached);
+
+        if let Some(entry) = self.cache.get_mut(&key) {
+            entry.insert(sub_key, cached);
         } else {
+            self.memory += name_size(&key);
+
             let mut entry = AHashMap::default();
-            entry.insert(sub_key, (response.clone(), value));
+            entry.insert(sub_key, cached);
+
+            self.cache.insert(key, entry);
+        }
+
+        evictions
+    }
+
+    ///
+    /// Forget every response that had expired by `now`, returning how many
+    /// there were
+    ///
+    fn expire(&mut self, now: Instant) -> usize {
+        let mut expirations = 0;
+        let mut memory = 0;
+        let mut empty = Vec::new();
+
+        // Going through the entries this way doesn't count as them being used
+        for (name, entry) in self.cache.iter_mut() {
+            entry.retain(|_, response| {
+                let stale = expired(&response.1, now);
+                if stale {
+                    expirations += 1;
+                    memory += size(response);
+                }
+                !stale
+            });
+
+            if entry.is_empty() {
+                empty.push(name.clone());
+            }
+        }
+
+        for name in empty {
+            self.cache.remove(&name);
+            memory += name_size(&name);
+        }
+
+        self.entries -= expirations;
+        self.memory = self.memory.saturating_sub(memory);
+
+        expirations
+    }
+
+    ///
+    /// Cache the response to the request
+    ///
+    pub async fn insert(request: &Request, response: &DnsResponse) {
+        let mut cache = CACHE.write().await;
+        let evictions = cache.put(response, Variant::of(request).dnssec_ok, Instant::now());
+
+        Statistics::record(Statistic::Cache(statistics::Cache {
+            misses: 1,
+            evictions,
+            ..cache.statistic()
+        }));
+    }
+
+    ///
+    /// Forget every response that has expired, which would otherwise only be
+    /// forgotten once it's asked for again (or evicted)
+    ///
+    pub async fn sweep() {
+        let mut cache = CACHE.write().await;
+
+        let expirations = cache.expire(Instant::now());
+        if expirations > 0 {
+            Statistics::record(Statistic::Cache(statistics::Cache {
+                expirations,
+                ..cache.statistic()
+            }));
+        }
+    }
+
+    ///
+    /// Forget everything that's been cached, so that every request is answered
+    /// fresh from upstream until it's cached again
+    ///
+    pub async fn clear() {
+        let mut cache = CACHE.write().await;
+        cache.cache.clear();
+        cache.entries = 0;
+        cache.memory = 0;
 
-            cache.cache.insert(key, entry);
+        Statistics::record(Statistic::Cache(cache.statistic()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use hickory_proto::{
+        op::{Message, Query},
+        rr::{rdata::A, Name, RData, Record, RecordType},
+        xfer::DnsResponse,
+    };
+    use pretty_assertions::assert_eq;
+
+    use super::Cache;
+
+    fn response(name: &str, ttl: u32) -> DnsResponse {
+        let name = Name::from_ascii(name).unwrap();
+        let mut message = Message::new();
+        message
+            .add_query(Query::query(name.clone(), RecordType::A))
+            .add_answer(Record::from_rdata(name, ttl, RData::A(A::new(10, 0, 0, 1))));
+
+        DnsResponse::from_message(message).unwrap()
+    }
+
+    #[test]
+    fn expire() {
+        let now = Instant::now();
+        let mut cache = Cache::default();
+
+        cache.put(&response("short.example.com.", 1), false, now);
+        cache.put(&response("long.example.com.", 300), false, now);
+        assert_eq!(cache.entries, 2);
+
+        let memory = cache.memory;
+        assert_eq!(cache.expire(now + Duration::from_secs(2)), 1);
+        assert_eq!(cache.entries, 1);
+        assert!(cache.memory < memory);
+        assert!(!cache.cache.contains_key("short.example.com."));
+
+        assert_eq!(cache.expire(now + Duration::from_secs(600)), 1);
+        assert_eq!((cache.entries, cache.memory), (0, 0));
+    }
+
+    #[test]
+    fn evict() {
+        let now = Instant::now();
+        let mut cache = Cache::default();
+
+        for n in 0..cache.cache.capacity() {
+            assert_eq!(
+                cache.put(&response(&format!("{n}.example.com."), 300), false, now),
+                0
+            );
         }
+        assert_eq!(
+            cache.put(&response("full.example.com.", 300), false, now),
+            1
+        );
+        assert_eq!(cache.entries, cache.cache.capacity());
+        assert!(!cache.cache.contains_key("0.example.com."));
+    }
+
+    #[test]
+    fn keys() {
+        let now = Instant::now();
+        let mut cache = Cache::default();
+
+        cache.put(&response("Example.COM.", 300), false, now);
+        cache.put(&response("example.com", 300), false, now);
+        assert_eq!(cache.entries, 1);
+        assert!(cache.cache.contains_key("example.com."));
+
+        // Responses with DNSSEC records are kept apart from those without
+        cache.put(&response("example.com.", 300), true, now);
+        assert_eq!(cache.entries, 2);
     }
 }
@@ -0,0 +1,171 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use hickory_proto::{
+    rr::rdata::opt::{EdnsCode, EdnsOption},
+    serialize::binary::BinEncodable,
+};
+use hickory_server::server::Request;
+use ipnet::IpNet;
+
+///
+/// Who a request came from, worked out once and the same way for everything
+/// that treats clients differently (policies, the request log, metrics and the
+/// like)
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Identity {
+    /// Where the request came from, which is the forwarder should it have
+    /// come through one
+    pub peer: IpAddr,
+    /// The client itself, which is the peer unless a forwarder we trust
+    /// passed the client's address along
+    pub address: IpAddr,
+    /// The client's hardware address, from its DHCP lease
+    pub mac: Option<String>,
+}
+
+impl Identity {
+    ///
+    /// Who the request came from, going by the EDNS Client Subnet option
+    /// (RFC 7871) for requests from the forwarders, should it give the whole
+    /// of the client's address
+    ///
+    pub fn of(request: &Request, forwarders: &[IpNet]) -> Self {
+        let peer = request.src().ip().to_canonical();
+
+        let address = forwarders
+            .iter()
+            .any(|forwarder| forwarder.contains(&peer))
+            .then(|| {
+                // The option's parsed when hickory understands it, and left as
+                // it came over the wire otherwise
+                let option = match request.edns()?.option(EdnsCode::Subnet)? {
+                    EdnsOption::Subnet(subnet) => subnet.to_bytes().ok()?,
+                    EdnsOption::Unknown(_, data) => data.clone(),
+                    _ => return None,
+                };
+
+                subnet(&option)
+            })
+            .flatten()
+            .unwrap_or(peer);
+
+        Self {
+            peer,
+            address,
+            mac: None,
+        }
+    }
+}
+
+impl Display for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.address.fmt(f)
+    }
+}
+
+///
+/// The address in an EDNS Client Subnet option, should it be for a single
+/// address rather than a whole network
+///
+fn subnet(option: &[u8]) -> Option<IpAddr> {
+    let (&[family_high, family_low, source, _scope], address) = option.split_first_chunk()?;
+
+    let address = match (u16::from_be_bytes([family_high, family_low]), source) {
+        (1, 32) => IpAddr::from(Ipv4Addr::from(<[u8; 4]>::try_from(address).ok()?)),
+        (2, 128) => IpAddr::from(Ipv6Addr::from(<[u8; 16]>::try_from(address).ok()?)),
+        _ => return None,
+    };
+
+    Some(address.to_canonical())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+    use hickory_proto::{
+        op::{Edns, Message, Query},
+        rr::{
+            rdata::opt::{EdnsCode, EdnsOption},
+            Name, RecordType,
+        },
+        serialize::binary::{BinDecodable, BinDecoder},
+    };
+    use hickory_server::{
+        authority::MessageRequest,
+        server::{Protocol, Request},
+    };
+    use pretty_assertions::assert_eq;
+
+    use super::Identity;
+
+    fn request(from: &str, subnet: &[u8]) -> Request {
+        let mut edns = Edns::new();
+        edns.options_mut().insert(EdnsOption::Unknown(
+            u16::from(EdnsCode::Subnet),
+            subnet.to_vec(),
+        ));
+
+        let mut message = Message::new();
+        message
+            .add_query(Query::query(
+                Name::from_ascii("example.com.").unwrap(),
+                RecordType::A,
+            ))
+            .set_edns(edns);
+
+        let bytes = message.to_vec().unwrap();
+        Request::new(
+            MessageRequest::read(&mut BinDecoder::new(&bytes)).unwrap(),
+            SocketAddr::new(from.parse().unwrap(), 5353),
+            Protocol::Udp,
+        )
+    }
+
+    #[test]
+    fn of() {
+        let forwarders = ["10.0.0.0/24".parse().unwrap()];
+        let ecs = [0, 1, 32, 0, 192, 168, 1, 20];
+
+        // Passed along by a forwarder we trust
+        let identity = Identity::of(&request("10.0.0.1", &ecs), &forwarders);
+        assert_eq!(identity.peer, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(identity.address, "192.168.1.20".parse::<IpAddr>().unwrap());
+
+        // Anyone else could claim to be anyone
+        let identity = Identity::of(&request("172.16.0.1", &ecs), &forwarders);
+        assert_eq!(identity.address, "172.16.0.1".parse::<IpAddr>().unwrap());
+
+        // A whole network doesn't identify a client
+        let identity = Identity::of(
+            &request("10.0.0.1", &[0, 1, 24, 0, 192, 168, 1]),
+            &forwarders,
+        );
+        assert_eq!(identity.address, "10.0.0.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn subnet() {
+        assert_eq!(
+            super::subnet(&[0, 1, 32, 0, 192, 168, 1, 20]),
+            Some("192.168.1.20".parse::<IpAddr>().unwrap())
+        );
+
+        let mut mapped = vec![0, 2, 128, 0];
+        mapped.extend("::ffff:10.0.0.1".parse::<Ipv6Addr>().unwrap().octets());
+        assert_eq!(
+            super::subnet(&mapped),
+            Some("10.0.0.1".parse::<IpAddr>().unwrap())
+        );
+
+        // Only whole addresses identify a client
+        assert_eq!(super::subnet(&[0, 1, 24, 0, 192, 168, 1]), None);
+        assert_eq!(super::subnet(&[0, 1, 32, 0, 192, 168]), None);
+        assert_eq!(super::subnet(&[0, 1]), None);
+    }
+}
@@ -0,0 +1,529 @@
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use ahash::AHashMap;
+use hickory_proto::rr::Name;
+use hickory_server::server::Request;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    config::Config,
+    dns::Server,
+    filter::rules::{Action, Kind, Rule, Source},
+};
+
+pub use identity::Identity;
+
+mod identity;
+
+static NAMES: LazyLock<Mutex<Names>> = LazyLock::new(Mutex::default);
+
+///
+/// How long the name a reverse lookup found (or that it didn't find one) is
+/// remembered for
+///
+const REVERSE_TTL: Duration = Duration::from_hours(1);
+
+///
+/// The most clients the names reverse lookups found are remembered for at once
+///
+const REVERSE_LIMIT: usize = 4096;
+
+///
+/// How often the lease file is checked for changes
+///
+const LEASES_INTERVAL: Duration = Duration::from_secs(30);
+
+///
+/// Options for giving clients friendly names, which are shown alongside their
+/// address in the request log and metrics
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    /// Look up names for clients that aren't named any other way with reverse
+    /// (PTR) lookups through the upstreams
+    #[serde(default)]
+    pub reverse: bool,
+    /// A dnsmasq lease file to read the names clients gave over DHCP from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leases: Option<PathBuf>,
+    /// Names for particular clients, which take precedence over any other
+    #[serde(default)]
+    pub names: BTreeMap<IpAddr, String>,
+    /// Answer reverse (PTR) lookups for addresses on the local network with
+    /// the names above and those from the lease file, rather than forwarding
+    /// them upstream
+    #[serde(default)]
+    pub ptr: bool,
+    /// Forwarders (e.g. another resolver on the network) whose requests are
+    /// put down to the client they pass along in the EDNS Client Subnet
+    /// option, rather than to the forwarder itself
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forwarders: Vec<IpNet>,
+}
+
+///
+/// What a client's DHCP lease says about it
+///
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+#[derive(Default)]
+struct Lease {
+    name: Option<String>,
+    mac: Option<String>,
+}
+
+#[derive(Default)]
+struct Leases {
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    checked: Option<Instant>,
+    leases: AHashMap<IpAddr, Lease>,
+}
+
+impl Leases {
+    ///
+    /// The client's lease, as of when the lease file was last read
+    ///
+    fn get(&self, path: &Path, client: IpAddr) -> Option<&Lease> {
+        self.leases
+            .get(&client)
+            .filter(|_| self.path.as_deref() == Some(path))
+    }
+
+    ///
+    /// Whether the lease file is due to be checked for changes, which counts as
+    /// it having been checked, so that there's only ever the one check at once
+    ///
+    fn due(&mut self, path: &Path, now: Instant) -> bool {
+        let due = self.path.as_deref() != Some(path)
+            || self
+                .checked
+                .map_or(true, |checked| now.duration_since(checked) >= LEASES_INTERVAL);
+
+        if due {
+            self.checked = Some(now);
+        }
+
+        due
+    }
+
+    fn changed(&self, path: &Path, modified: Option<SystemTime>) -> bool {
+        self.path.as_deref() != Some(path) || modified.is_none() || modified != self.modified
+    }
+}
+
+///
+/// Read the lease file again, should it be due a check and have changed. It's
+/// read without holding on to the names, so that nothing's held up by it.
+///
+async fn refresh(path: &Path) {
+    if !NAMES
+        .lock()
+        .is_ok_and(|mut names| names.leases.due(path, Instant::now()))
+    {
+        return;
+    }
+
+    let modified = tokio::fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .ok();
+
+    if !NAMES
+        .lock()
+        .is_ok_and(|names| names.leases.changed(path, modified))
+    {
+        return;
+    }
+
+    let leases = match tokio::fs::read_to_string(path).await {
+        Ok(leases) => parse(&leases),
+        Err(err) => {
+            warn!("Unable to read leases from {}: {err}", path.display());
+            AHashMap::new()
+        }
+    };
+
+    if let Ok(mut names) = NAMES.lock() {
+        names.leases.path = Some(path.to_path_buf());
+        names.leases.modified = modified;
+        names.leases.leases = leases;
+    }
+}
+
+///
+/// Parse a dnsmasq lease file, which has a lease per line in the form
+/// `<expiry> <mac> <address> <hostname> <client id>`, with a hostname of `*`
+/// when the client didn't give one. DHCPv6 leases have an IAID where the MAC
+/// would be
+///
+fn parse(leases: &str) -> AHashMap<IpAddr, Lease> {
+    leases
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            let mac = fields.next().filter(|mac| mac.contains(':'));
+            let address = fields.next()?.parse().ok()?;
+            let name = fields.next().filter(|name| *name != "*");
+
+            Some((
+                address,
+                Lease {
+                    name: name.map(ToString::to_string),
+                    mac: mac.map(ToString::to_string),
+                },
+            ))
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct Names {
+    leases: Leases,
+    reverse: AHashMap<IpAddr, (Option<String>, Instant)>,
+}
+
+impl Names {
+    ///
+    /// The name we already know for the client, and whether a reverse lookup
+    /// should be made for it
+    ///
+    fn find(
+        &mut self,
+        client: IpAddr,
+        leases: Option<&Path>,
+        reverse: bool,
+        now: Instant,
+    ) -> (Option<String>, bool) {
+        if let Some(name) = leases
+            .and_then(|leases| self.leases.get(leases, client))
+            .and_then(|lease| lease.name.clone())
+        {
+            return (Some(name), false);
+        }
+
+        if !reverse {
+            return (None, false);
+        }
+
+        match self.reverse.get(&client) {
+            Some((name, at)) if now.duration_since(*at) < REVERSE_TTL => (name.clone(), false),
+            _ => {
+                self.reverse
+                    .retain(|_, (_, at)| now.duration_since(*at) < REVERSE_TTL);
+                // Making room by forgetting whoever was looked up longest ago,
+                // so that a flood of new clients can't have this grow unbounded
+                if self.reverse.len() >= REVERSE_LIMIT {
+                    let oldest = self
+                        .reverse
+                        .iter()
+                        .min_by_key(|(_, (_, at))| *at)
+                        .map(|(client, _)| *client);
+                    if let Some(oldest) = oldest {
+                        self.reverse.remove(&oldest);
+                    }
+                }
+                // Remembered straight away, so that there's only ever the one
+                // lookup in flight for each client
+                self.reverse.insert(client, (None, now));
+                (None, true)
+            }
+        }
+    }
+}
+
+///
+/// The friendly name of a client, if it has one.
+///
+/// Reverse lookups are made in the background, so that the request which
+/// prompted one isn't held up by it, meaning that the first few requests from
+/// a client will go without a name
+///
+pub async fn name(client: IpAddr) -> Option<String> {
+    let (name, leases, reverse) = Config::get(|config| {
+        (
+            config.clients.names.get(&client).cloned(),
+            config.clients.leases.clone(),
+            config.clients.reverse,
+        )
+    })
+    .await;
+
+    if name.is_some() {
+        return name;
+    }
+
+    if let Some(leases) = &leases {
+        refresh(leases).await;
+    }
+
+    let (name, lookup) = NAMES
+        .lock()
+        .map(|mut names| names.find(client, leases.as_deref(), reverse, Instant::now()))
+        .unwrap_or_default();
+
+    if lookup {
+        tokio::spawn(async move {
+            let name = Server::reverse(client)
+                .await
+                .map(|name| name.to_string().trim_end_matches('.').to_string());
+
+            if let Ok(mut names) = NAMES.lock() {
+                // Unless it's since been forgotten to make room for another
+                if let Some(entry) = names.reverse.get_mut(&client) {
+                    *entry = (name, Instant::now());
+                }
+            }
+        });
+    }
+
+    name
+}
+
+///
+/// Who the request came from, with their hardware address should the lease
+/// file have it
+///
+pub async fn identify(request: &Request) -> Identity {
+    let (forwarders, leases) = Config::get(|config| {
+        (
+            config.clients.forwarders.clone(),
+            config.clients.leases.clone(),
+        )
+    })
+    .await;
+
+    let mut identity = Identity::of(request, &forwarders);
+
+    if let Some(leases) = leases {
+        refresh(&leases).await;
+
+        identity.mac = NAMES.lock().ok().and_then(|names| {
+            names
+                .leases
+                .get(&leases, identity.address)
+                .and_then(|lease| lease.mac.clone())
+        });
+    }
+
+    identity
+}
+
+///
+/// Whether the address belongs to the local network, which the upstreams
+/// can't know anything about
+///
+fn local(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        IpAddr::V6(ip) => ip.is_unique_local() || ip.is_unicast_link_local() || ip.is_loopback(),
+    }
+}
+
+///
+/// The address on the local network a reverse lookup is for, if it's for one
+///
+fn address(name: &Name) -> Option<IpAddr> {
+    let network = name.parse_arpa_name().ok()?;
+
+    // Names for a whole network (e.g. 168.192.in-addr.arpa) don't have a PTR
+    Some(network.addr())
+        .filter(|_| network.prefix_len() == network.max_prefix_len())
+        .filter(|ip| local(*ip))
+}
+
+///
+/// The rule answering a reverse lookup for a client on the local network
+/// with the name it's known by here, from the config or the lease file.
+///
+/// Unlike [`name`], the upstreams are never asked, so anything we don't know
+/// the name of is left to them.
+///
+pub async fn ptr(name: &Name) -> Option<Rule> {
+    let client = address(name)?;
+
+    let (enabled, known, leases) = Config::get(|config| {
+        (
+            config.clients.ptr,
+            config.clients.names.get(&client).cloned(),
+            config.clients.leases.clone(),
+        )
+    })
+    .await;
+
+    if !enabled {
+        return None;
+    }
+
+    if let (None, Some(leases)) = (&known, &leases) {
+        refresh(leases).await;
+    }
+
+    let known = known.or_else(|| {
+        NAMES
+            .lock()
+            .ok()?
+            .find(client, leases.as_deref(), false, Instant::now())
+            .0
+    })?;
+
+    Some(Rule {
+        domain: name.to_string().trim_end_matches('.').to_string(),
+        kind: Kind::Allow,
+        action: Some(Box::new(Action {
+            ptr: Some(known),
+            ..Default::default()
+        })),
+        list: Some(Arc::new(Source {
+            name: String::from("Clients"),
+            url: String::from("builtin"),
+        })),
+        zone: false,
+        important: false,
+        scope: None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::IpAddr,
+        path::Path,
+        time::{Duration, Instant},
+    };
+
+    use hickory_proto::rr::Name;
+    use pretty_assertions::assert_eq;
+
+    use super::{address, parse, refresh, Lease, Names, NAMES, REVERSE_LIMIT, REVERSE_TTL};
+
+    #[test]
+    fn leases() {
+        let leases = parse(
+            "1712345678 aa:bb:cc:dd:ee:ff 192.168.1.20 laptop 01:aa:bb:cc:dd:ee:ff\n\
+             1712345678 aa:bb:cc:dd:ee:00 192.168.1.21 * *\n\
+             duid 00:01:00:01:2d:8c:2f:1a:aa:bb:cc:dd:ee:ff\n\
+             1712345678 1234 fd00::20 phone 00:01:00:01\n",
+        );
+
+        assert_eq!(leases.len(), 3);
+        assert_eq!(
+            leases.get(&"192.168.1.20".parse::<IpAddr>().unwrap()),
+            Some(&Lease {
+                name: Some(String::from("laptop")),
+                mac: Some(String::from("aa:bb:cc:dd:ee:ff")),
+            })
+        );
+        assert_eq!(
+            leases.get(&"192.168.1.21".parse::<IpAddr>().unwrap()),
+            Some(&Lease {
+                name: None,
+                mac: Some(String::from("aa:bb:cc:dd:ee:00")),
+            })
+        );
+        assert_eq!(
+            leases.get(&"fd00::20".parse::<IpAddr>().unwrap()),
+            Some(&Lease {
+                name: Some(String::from("phone")),
+                mac: None,
+            })
+        );
+    }
+
+    #[test]
+    fn reverse() {
+        let client = "192.168.1.30".parse().unwrap();
+        let now = Instant::now();
+        let mut names = Names::default();
+
+        assert_eq!(names.find(client, None, false, now), (None, false));
+        assert_eq!(names.find(client, None, true, now), (None, true));
+        // Already being looked up
+        assert_eq!(names.find(client, None, true, now), (None, false));
+
+        names
+            .reverse
+            .insert(client, (Some(String::from("printer")), now));
+        assert_eq!(
+            names.find(client, None, true, now),
+            (Some(String::from("printer")), false)
+        );
+        assert_eq!(names.find(client, None, true, now + REVERSE_TTL), (None, true));
+    }
+
+    #[test]
+    fn reverse_limit() {
+        let now = Instant::now();
+        let mut names = Names::default();
+
+        let oldest = IpAddr::from([10, 0, 0, 0]);
+        names.reverse.insert(oldest, (None, now));
+        for idx in 1..REVERSE_LIMIT {
+            let client = IpAddr::from([10, 0, (idx >> 8) as u8, idx as u8]);
+            names
+                .reverse
+                .insert(client, (None, now + Duration::from_millis(1)));
+        }
+
+        let client = "192.168.1.30".parse().unwrap();
+        assert_eq!(names.find(client, None, true, now), (None, true));
+        assert_eq!(names.reverse.len(), REVERSE_LIMIT);
+        assert!(!names.reverse.contains_key(&oldest));
+        assert!(names.reverse.contains_key(&client));
+    }
+
+    #[tokio::test]
+    async fn refreshing() {
+        let path = std::env::temp_dir().join("blackhole-leases");
+        std::fs::write(
+            &path,
+            "1712345678 aa:bb:cc:dd:ee:ff 192.168.1.20 laptop 01:aa:bb:cc:dd:ee:ff\n",
+        )
+        .unwrap();
+
+        refresh(&path).await;
+
+        let client = "192.168.1.20".parse().unwrap();
+        let now = Instant::now();
+        assert_eq!(
+            NAMES.lock().unwrap().find(client, Some(&path), false, now),
+            (Some(String::from("laptop")), false)
+        );
+        // Only leases from the file that's configured are used
+        assert_eq!(
+            NAMES
+                .lock()
+                .unwrap()
+                .find(client, Some(Path::new("/nonexistent")), false, now),
+            (None, false)
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn addresses() {
+        let arpa = |name: &str| address(&Name::from_ascii(name).unwrap());
+
+        assert_eq!(
+            arpa("20.1.168.192.in-addr.arpa."),
+            Some("192.168.1.20".parse().unwrap())
+        );
+        assert_eq!(
+            address(&Name::from("fd00::20".parse::<IpAddr>().unwrap())),
+            Some("fd00::20".parse().unwrap())
+        );
+        // Only addresses on the local network, and not whole networks
+        assert_eq!(arpa("8.8.8.8.in-addr.arpa."), None);
+        assert_eq!(arpa("168.192.in-addr.arpa."), None);
+        assert_eq!(arpa("nas.home.arpa."), None);
+    }
+}
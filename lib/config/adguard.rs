@@ -0,0 +1,344 @@
+use std::net::{IpAddr, SocketAddr};
+
+use hickory_proto::rr::Name;
+use serde::Deserialize;
+
+use crate::{
+    dns::{Protocol, Upstream},
+    filter::{patterns::Pattern, rules::Kind, Custom, List},
+};
+
+use super::{Config, Imported};
+
+const fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct Rewrite {
+    domain: String,
+    answer: String,
+}
+
+#[derive(Deserialize, Default)]
+struct Dns {
+    #[serde(default)]
+    upstream_dns: Vec<String>,
+    /// Where versions before v0.107.30 kept the rewrites
+    #[serde(default)]
+    rewrites: Vec<Rewrite>,
+}
+
+#[derive(Deserialize, Default)]
+struct Filtering {
+    #[serde(default)]
+    rewrites: Vec<Rewrite>,
+}
+
+#[derive(Deserialize)]
+struct Filter {
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    url: String,
+    #[serde(default)]
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Client {
+    name: String,
+    /// Addresses, networks, MAC addresses or ClientIDs
+    #[serde(default)]
+    ids: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct Clients {
+    #[serde(default)]
+    persistent: Vec<Client>,
+}
+
+///
+/// The parts of an AdGuard Home config (`AdGuardHome.yaml`) we can make use of
+///
+#[derive(Deserialize, Default)]
+pub struct AdGuard {
+    #[serde(default)]
+    dns: Dns,
+    #[serde(default)]
+    filtering: Filtering,
+    #[serde(default)]
+    filters: Vec<Filter>,
+    /// Lists of domains to allow, which we have no equivalent for
+    #[serde(default)]
+    whitelist_filters: Vec<Filter>,
+    #[serde(default)]
+    user_rules: Vec<String>,
+    #[serde(default)]
+    clients: Clients,
+}
+
+impl AdGuard {
+    ///
+    /// Add everything in the config to ours, leaving alone anything already
+    /// configured for the same list or domain
+    ///
+    pub fn import(&self, config: &mut Config) -> Imported {
+        let mut imported = Imported::default();
+
+        for upstream in &self.dns.upstream_dns {
+            let upstream = upstream.trim();
+            if upstream.is_empty() || upstream.starts_with('#') {
+                continue;
+            }
+
+            match self::upstream(upstream) {
+                Some(upstream) => {
+                    if config.upstreams.insert(upstream) {
+                        imported.upstreams += 1;
+                    }
+                }
+                None => imported.skipped.push(upstream.to_string()),
+            }
+        }
+
+        for filter in &self.filters {
+            imported.list(
+                config,
+                List {
+                    name: if filter.name.is_empty() {
+                        filter.url.clone()
+                    } else {
+                        filter.name.clone()
+                    },
+                    url: filter.url.clone(),
+                    enabled: filter.enabled,
+                    schedule: None,
+                    entries: 0,
+                },
+            );
+        }
+
+        imported.skipped.extend(
+            self.whitelist_filters
+                .iter()
+                .map(|filter| filter.url.clone()),
+        );
+
+        for rewrite in self.dns.rewrites.iter().chain(&self.filtering.rewrites) {
+            match self::rewrite(rewrite) {
+                Some(rule) => imported.rule(config, rule),
+                None => imported.skipped.push(rewrite.domain.clone()),
+            }
+        }
+
+        for line in &self.user_rules {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('!') || line.starts_with('#') {
+                continue;
+            }
+
+            let (kind, rule) = line
+                .strip_prefix("@@")
+                .map_or((Kind::Deny, line), |rule| (Kind::Allow, rule));
+
+            if let Some(pattern) = rule
+                .strip_prefix('/')
+                .and_then(|rule| rule.strip_suffix('/'))
+            {
+                imported.pattern(
+                    config,
+                    Pattern {
+                        pattern: pattern.to_string(),
+                        kind,
+                    },
+                );
+            } else if let Some(domain) = rule
+                .strip_prefix("||")
+                .and_then(|rule| rule.strip_suffix('^'))
+                .filter(|domain| Name::from_ascii(domain).is_ok())
+            {
+                imported.rule(
+                    config,
+                    Custom {
+                        domain: domain.to_lowercase(),
+                        kind,
+                        zone: true,
+                        ..Default::default()
+                    },
+                );
+            } else {
+                // Modifiers, hosts entries, etc.
+                imported.skipped.push(line.to_string());
+            }
+        }
+
+        for client in &self.clients.persistent {
+            for id in &client.ids {
+                match id.parse::<IpAddr>() {
+                    Ok(ip) if !config.clients.names.contains_key(&ip) => {
+                        config.clients.names.insert(ip, client.name.clone());
+                        imported.clients += 1;
+                    }
+                    _ => imported.skipped.push(format!("{} ({id})", client.name)),
+                }
+            }
+        }
+
+        imported
+    }
+}
+
+///
+/// The upstream, should it be plain DNS, e.g. `8.8.8.8`, `tcp://1.1.1.1` or
+/// `[2620:fe::fe]:53`. Encrypted upstreams, those for particular domains and
+/// those given by name aren't supported.
+///
+fn upstream(upstream: &str) -> Option<Upstream> {
+    let (protocol, address) = if let Some(address) = upstream.strip_prefix("tcp://") {
+        (Protocol::Tcp, address)
+    } else {
+        (
+            Protocol::Udp,
+            upstream.strip_prefix("udp://").unwrap_or(upstream),
+        )
+    };
+
+    let (ip, port) = address
+        .parse::<SocketAddr>()
+        .map(|address| (address.ip(), address.port()))
+        .or_else(|_| address.parse::<IpAddr>().map(|ip| (ip, 53)))
+        .ok()?;
+
+    Some(Upstream { ip, port, protocol })
+}
+
+///
+/// The rewrite as a rule, should it answer with an address or another domain.
+/// Those answering with `A` or `AAAA` (i.e. keeping the upstream's answer)
+/// have no equivalent.
+///
+fn rewrite(rewrite: &Rewrite) -> Option<Custom> {
+    let mut rule = Custom {
+        domain: rewrite.domain.to_lowercase(),
+        ..Default::default()
+    };
+
+    match rewrite.answer.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => rule.v4 = Some(ip),
+        Ok(IpAddr::V6(ip)) => rule.v6 = Some(ip),
+        Err(_) if matches!(rewrite.answer.as_str(), "A" | "AAAA") => return None,
+        Err(_) => {
+            Name::from_ascii(&rewrite.answer).ok()?;
+            rule.cname = Some(rewrite.answer.to_lowercase());
+        }
+    }
+
+    Name::from_ascii(&rule.domain).is_ok().then_some(rule)
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::AdGuard;
+    use crate::{
+        config::{Config, Imported},
+        filter::rules::Kind,
+    };
+
+    const CONFIG: &str = r"
+dns:
+  port: 53
+  upstream_dns:
+    - 9.9.9.9
+    - tcp://1.1.1.1:5353
+    - '[2620:fe::fe]:53'
+    - https://dns10.quad9.net/dns-query
+    - '# A comment'
+  rewrites:
+    - domain: nas.lan
+      answer: 192.168.1.10
+filtering:
+  rewrites:
+    - domain: files.lan
+      answer: nas.lan
+    - domain: example.com
+      answer: A
+filters:
+  - enabled: true
+    url: https://adguardteam.github.io/HostlistsRegistry/assets/filter_1.txt
+    name: AdGuard DNS filter
+    id: 1
+whitelist_filters:
+  - enabled: true
+    url: https://example.com/allowed.txt
+    name: Allowed
+    id: 2
+user_rules:
+  - '! A comment'
+  - '||ads.example.com^'
+  - '@@||s.youtube.com^'
+  - '/^ad[sx]?[0-9]*\./'
+  - '||tracker.example.com^$client=laptop'
+clients:
+  persistent:
+    - name: Laptop
+      ids:
+        - 192.168.1.5
+        - aa:bb:cc:dd:ee:ff
+";
+
+    #[test]
+    fn import() {
+        let adguard: AdGuard = serde_yaml::from_str(CONFIG).unwrap();
+        let mut config = Config::default();
+
+        assert_eq!(
+            adguard.import(&mut config),
+            Imported {
+                upstreams: 3,
+                lists: 1,
+                rules: 4,
+                patterns: 1,
+                clients: 1,
+                skipped: vec![
+                    String::from("https://dns10.quad9.net/dns-query"),
+                    String::from("https://example.com/allowed.txt"),
+                    String::from("example.com"),
+                    String::from("||tracker.example.com^$client=laptop"),
+                    String::from("Laptop (aa:bb:cc:dd:ee:ff)"),
+                ],
+            }
+        );
+
+        let upstream = config
+            .upstreams
+            .iter()
+            .find(|upstream| upstream.port == 5353)
+            .unwrap();
+        assert_eq!(upstream.ip, "1.1.1.1".parse::<std::net::IpAddr>().unwrap());
+        assert_eq!(upstream.protocol, crate::dns::Protocol::Tcp);
+
+        let ads = config
+            .rules
+            .iter()
+            .find(|rule| rule.domain == "ads.example.com")
+            .unwrap();
+        assert_eq!(ads.kind, Kind::Deny);
+        assert!(ads.zone);
+
+        let files = config
+            .rules
+            .iter()
+            .find(|rule| rule.domain == "files.lan")
+            .unwrap();
+        assert_eq!(files.cname.as_deref(), Some("nas.lan"));
+
+        assert_eq!(
+            config.clients.names.get(&"192.168.1.5".parse().unwrap()),
+            Some(&String::from("Laptop"))
+        );
+        assert!(config.validate().is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+use serde::Serialize;
+
+use crate::filter::{patterns::Pattern, Custom, List};
+
+use super::Config;
+
+///
+/// How much was imported from another DNS filter's config
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq))]
+#[derive(Serialize, Default)]
+pub struct Imported {
+    pub upstreams: usize,
+    pub lists: usize,
+    pub rules: usize,
+    pub patterns: usize,
+    pub clients: usize,
+    /// Entries we couldn't translate, e.g. regexes using Pi-hole's own
+    /// extensions, or anything already in the config
+    pub skipped: Vec<String>,
+}
+
+impl Imported {
+    ///
+    /// Add the list, unless there's already one for the same URL
+    ///
+    pub(super) fn list(&mut self, config: &mut Config, list: List) {
+        if config
+            .filters
+            .iter()
+            .any(|existing| existing.url == list.url)
+        {
+            self.skipped.push(list.url);
+        } else {
+            config.filters.insert(list);
+            self.lists += 1;
+        }
+    }
+
+    ///
+    /// Add the rule, unless there's already one for the same domain
+    ///
+    pub(super) fn rule(&mut self, config: &mut Config, rule: Custom) {
+        if config
+            .rules
+            .iter()
+            .any(|existing| existing.domain == rule.domain)
+        {
+            self.skipped.push(rule.domain);
+        } else {
+            config.rules.push(rule);
+            self.rules += 1;
+        }
+    }
+
+    ///
+    /// Add the pattern, unless it's already there or isn't valid
+    ///
+    pub(super) fn pattern(&mut self, config: &mut Config, pattern: Pattern) {
+        if regex::Regex::new(&pattern.pattern).is_err()
+            || config
+                .patterns
+                .iter()
+                .any(|existing| existing.pattern == pattern.pattern)
+        {
+            self.skipped.push(pattern.pattern);
+        } else {
+            config.patterns.push(pattern);
+            self.patterns += 1;
+        }
+    }
+}
@@ -2,21 +2,38 @@ use std::{
     collections::HashSet,
     fmt::Debug,
     path::{Path, PathBuf},
+    str::FromStr,
     sync::LazyLock,
+    time::Duration,
 };
 
 use ahash::AHashSet;
+use hickory_proto::rr::Name;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::sync::RwLock;
-use tracing::{error, info, instrument};
+use tracing::{error, info, instrument, warn};
 
 use crate::{
-    dns::Upstream,
+    anomaly, api, clients,
+    dns::{self, Acl, Upstream},
+    exporter,
     filter::{self, Filter, List},
-    schedule::Schedule,
+    health::Health,
+    logging, metrics, safesearch,
+    schedule::{self, Sched, Schedule, Scheduler},
+    statistics,
 };
 
+pub use crate::dns::Protocol;
+pub use adguard::AdGuard;
+pub use import::Imported;
+pub use pihole::Teleporter;
+
+mod adguard;
+mod import;
+mod pihole;
+
 pub static CONFIG: LazyLock<RwLock<Config>> = LazyLock::new(RwLock::default);
 pub(crate) static CONFIG_FILE: LazyLock<RwLock<Option<String>>> = LazyLock::new(RwLock::default);
 
@@ -33,6 +50,9 @@ pub enum Error {
 
     #[error("There was an issue updating the filters: {0}")]
     FilterError(#[from] filter::Error),
+
+    #[error("Invalid value for {0}: {1}")]
+    Invalid(String, String),
 }
 
 impl warp::reject::Reject for Error {}
@@ -41,12 +61,16 @@ const fn default_port() -> u16 {
     53
 }
 
+const fn default_use_builtin_list() -> bool {
+    true
+}
+
 fn default_path() -> String {
     String::from("/config/config.toml")
 }
 
 #[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq))]
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     #[serde(default = "default_port")]
     pub port: u16,
@@ -56,6 +80,101 @@ pub struct Config {
     pub filters: AHashSet<List>,
     #[serde(alias = "schedule", rename(serialize = "schedule"))]
     pub schedules: Vec<Schedule>,
+    #[serde(default)]
+    pub api: api::Options,
+    #[serde(default = "default_use_builtin_list")]
+    pub use_builtin_list: bool,
+    #[serde(default)]
+    pub auto_ptr: bool,
+    #[serde(default)]
+    pub padding: bool,
+    #[serde(default)]
+    pub minimal_responses: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_ttl: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_ttl: Option<u32>,
+    #[serde(default)]
+    pub metrics: metrics::Options,
+    #[serde(default)]
+    pub scheduler: schedule::Options,
+    #[serde(default)]
+    pub downloads: filter::Downloads,
+    #[serde(default)]
+    pub acl: Acl,
+    #[serde(default)]
+    pub rules: Vec<filter::Custom>,
+    #[serde(default)]
+    pub anomalies: anomaly::Options,
+    #[serde(default)]
+    pub statistics: statistics::Options,
+    #[serde(default)]
+    pub exporter: exporter::Options,
+    #[serde(default)]
+    pub logging: logging::Options,
+    #[serde(default)]
+    pub policy: filter::Policy,
+    #[serde(default)]
+    pub clients: clients::Options,
+    #[serde(alias = "pattern", rename(serialize = "pattern"), default)]
+    pub patterns: Vec<filter::patterns::Pattern>,
+    #[serde(default)]
+    pub safesearch: safesearch::Options,
+    #[serde(alias = "window", rename(serialize = "window"), default)]
+    pub windows: Vec<schedule::Window>,
+    #[serde(default)]
+    pub dns64: dns::Dns64,
+    #[serde(default)]
+    pub special_use: dns::SpecialUse,
+    #[serde(alias = "query_type", rename(serialize = "query_type"), default)]
+    pub query_types: Vec<dns::TypeFilter>,
+    #[serde(default)]
+    pub resolver: dns::Resolver,
+    #[serde(default)]
+    pub bind: dns::Bind,
+    #[serde(alias = "listener", rename(serialize = "listener"), default)]
+    pub listeners: Vec<dns::Listener>,
+    #[serde(default)]
+    pub queue: dns::Queue,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: default_port(),
+            upstreams: HashSet::default(),
+            filters: AHashSet::default(),
+            schedules: Vec::default(),
+            api: api::Options::default(),
+            use_builtin_list: default_use_builtin_list(),
+            auto_ptr: false,
+            padding: false,
+            minimal_responses: false,
+            min_ttl: None,
+            max_ttl: None,
+            metrics: metrics::Options::default(),
+            scheduler: schedule::Options::default(),
+            downloads: filter::Downloads::default(),
+            acl: Acl::default(),
+            rules: Vec::default(),
+            anomalies: anomaly::Options::default(),
+            statistics: statistics::Options::default(),
+            exporter: exporter::Options::default(),
+            logging: logging::Options::default(),
+            policy: filter::Policy::default(),
+            clients: clients::Options::default(),
+            patterns: Vec::default(),
+            safesearch: safesearch::Options::default(),
+            windows: Vec::default(),
+            dns64: dns::Dns64::default(),
+            special_use: dns::SpecialUse::default(),
+            query_types: Vec::default(),
+            resolver: dns::Resolver::default(),
+            bind: dns::Bind::default(),
+            listeners: Vec::default(),
+            queue: dns::Queue::default(),
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -94,14 +213,451 @@ impl Load for PathBuf {
         config.upstreams.extend(conf.upstreams);
         config.filters.extend(conf.filters);
         config.schedules.extend(conf.schedules);
+        config.rules.extend(conf.rules);
+        config.patterns.extend(conf.patterns);
+        config.windows.extend(conf.windows);
+        config.query_types.extend(conf.query_types);
+        config.listeners.extend(conf.listeners);
 
         config.port = conf.port;
+        config.api = conf.api;
+        config.use_builtin_list = conf.use_builtin_list;
+        config.auto_ptr = conf.auto_ptr;
+        config.padding = conf.padding;
+        config.minimal_responses = conf.minimal_responses;
+        config.min_ttl = conf.min_ttl;
+        config.max_ttl = conf.max_ttl;
+        config.metrics = conf.metrics;
+        config.scheduler = conf.scheduler;
+        config.downloads = conf.downloads;
+        config.acl = conf.acl;
+        config.anomalies = conf.anomalies;
+        config.statistics = conf.statistics;
+        config.exporter = conf.exporter;
+        config.logging = conf.logging;
+        config.policy = conf.policy;
+        config.clients = conf.clients;
+        config.safesearch = conf.safesearch;
+        config.dns64 = conf.dns64;
+        config.special_use = conf.special_use;
+        config.resolver = conf.resolver;
+        config.bind = conf.bind;
+        config.queue = conf.queue;
+
+        Ok(())
+    }
+}
+
+///
+/// Load each profile in turn, with the second taking precedence over the first
+///
+#[async_trait::async_trait]
+impl<A: Load + Send + Sync, B: Load + Send + Sync> Load for (A, B) {
+    async fn load(&self, config: &mut Config) -> Result<(), Error> {
+        self.0.load(config).await?;
+        self.1.load(config).await
+    }
+}
+
+/// The prefix of the environment variables we load config from
+const ENVIRONMENT_PREFIX: &str = "BLACKHOLE_";
+
+///
+/// Overrides from environment variables, where `BLACKHOLE_<KEY>` sets the config
+/// key `<key>`, and nested keys are separated with a double underscore (e.g.
+/// `BLACKHOLE_API__PORT=8080`).
+///
+/// Values are parsed as TOML, falling back to a string should that fail. As a
+/// shorthand, the upstreams can also be given as a comma separated list (e.g.
+/// `BLACKHOLE_UPSTREAMS=1.1.1.1,8.8.8.8:53`).
+///
+pub struct Environment;
+
+#[async_trait::async_trait]
+impl Load for Environment {
+    ///
+    /// # Errors
+    /// If any of the values aren't valid for the key they're setting
+    ///
+    async fn load(&self, config: &mut Config) -> Result<(), Error> {
+        overlay(
+            config,
+            std::env::vars().filter_map(|(key, value)| {
+                key.strip_prefix(ENVIRONMENT_PREFIX)
+                    .map(|key| (key.to_string(), value))
+            }),
+        )
+    }
+}
+
+///
+/// Apply each of the overrides, where the key is the path to the config key,
+/// separated by double underscores
+///
+fn overlay<I>(config: &mut Config, overrides: I) -> Result<(), Error>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut table = toml::Table::try_from(&*config)?;
+
+    for (key, raw) in overrides {
+        let path = key
+            .to_ascii_lowercase()
+            .split("__")
+            .map(|segment| {
+                // Collections are serialised under their singular names
+                match segment {
+                    "upstreams" => "upstream",
+                    "filters" => "filter",
+                    "schedules" => "schedule",
+                    segment => segment,
+                }
+                .to_string()
+            })
+            .collect::<Vec<_>>();
+
+        let value = toml::from_str::<toml::Table>(&format!("value = {raw}"))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .map_or_else(
+                || {
+                    if path == ["upstream"] {
+                        upstreams(&raw).map_err(|err| Error::Invalid(key.clone(), err))
+                    } else {
+                        Ok(toml::Value::String(raw.clone()))
+                    }
+                },
+                Ok,
+            )?;
+
+        let Some((last, parents)) = path.split_last() else {
+            continue;
+        };
+
+        let mut current = &mut table;
+        for parent in parents {
+            current = match current
+                .entry(parent.clone())
+                .or_insert_with(|| toml::Value::Table(toml::Table::default()))
+            {
+                toml::Value::Table(table) => table,
+                _ => return Err(Error::Invalid(key, String::from("not a table"))),
+            };
+        }
+
+        current.insert(last.clone(), value);
+    }
+
+    *config = table.try_into()?;
+
+    Ok(())
+}
+
+///
+/// Parse a comma separated list of upstreams
+///
+fn upstreams(list: &str) -> Result<toml::Value, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|upstream| !upstream.is_empty())
+        .map(|upstream| {
+            Upstream::from_str(upstream)
+                .and_then(|upstream| toml::Value::try_from(upstream).map_err(|err| err.to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(toml::Value::Array)
+}
+
+///
+/// Overrides for the most commonly changed options, e.g. from the command line
+///
+#[derive(Clone, Default)]
+pub struct Overrides {
+    pub port: Option<u16>,
+    pub upstreams: Vec<String>,
+    pub api_port: Option<u16>,
+}
+
+#[async_trait::async_trait]
+impl Load for Overrides {
+    ///
+    /// # Errors
+    /// If any of the upstreams are invalid
+    ///
+    async fn load(&self, config: &mut Config) -> Result<(), Error> {
+        if !self.upstreams.is_empty() {
+            config.upstreams = self
+                .upstreams
+                .iter()
+                .map(|upstream| {
+                    Upstream::from_str(upstream)
+                        .map_err(|err| Error::Invalid(String::from("upstream"), err))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+
+        if let Some(port) = self.api_port {
+            config.api.port = port;
+        }
 
         Ok(())
     }
 }
 
+///
+/// Something wrong with the config, along with the key it concerns
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq))]
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Problem {
+    pub key: String,
+    pub reason: String,
+}
+
+impl Problem {
+    pub(crate) fn new(key: &str, reason: impl Into<String>) -> Self {
+        Self {
+            key: key.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.key, self.reason)
+    }
+}
+
 impl Config {
+    ///
+    /// Check the config for anything that would stop us from running with it,
+    /// e.g. unusable upstreams, conflicting ports, or unreadable certificates
+    ///
+    pub fn validate(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+
+        if self.port == 0 {
+            problems.push(Problem::new(
+                "port",
+                "The DNS server needs a port to serve on",
+            ));
+        }
+
+        for (key, timeout) in [
+            ("bind.tcp_timeout", self.bind.tcp_timeout),
+            ("bind.tcp_read_timeout", self.bind.tcp_read_timeout),
+        ] {
+            if timeout.is_zero() {
+                problems.push(Problem::new(
+                    key,
+                    "TCP connections need to be kept open long enough for a query",
+                ));
+            }
+        }
+
+        if self.bind.tcp_connections == 0 {
+            problems.push(Problem::new(
+                "bind.tcp_connections",
+                "At least one TCP connection needs to be allowed",
+            ));
+        }
+
+        if self.queue.in_flight == 0 {
+            problems.push(Problem::new(
+                "queue.in_flight",
+                "At least one request needs to be answered at a time",
+            ));
+        }
+
+        if self.bind.udp_workers == 0 {
+            problems.push(Problem::new(
+                "bind.udp_workers",
+                "UDP needs at least one socket to be served on",
+            ));
+        } else if self.bind.udp_workers > 1 && cfg!(not(unix)) {
+            problems.push(Problem::new(
+                "bind.udp_workers",
+                "More than one socket per address needs SO_REUSEPORT, which is only on Unix",
+            ));
+        }
+
+        for dns::Listener { address, port, .. } in &self.listeners {
+            if *port == 0 {
+                problems.push(Problem::new(
+                    "listener",
+                    format!("The listener on {address} needs a port to serve on"),
+                ));
+            }
+        }
+
+        if self.api.port == self.port {
+            problems.push(Problem::new(
+                "api.port",
+                format!("Port {} is already used by the DNS server", self.port),
+            ));
+        }
+
+        if let Some(tls) = &self.api.tls {
+            for (key, path) in [("api.tls.cert", &tls.cert), ("api.tls.key", &tls.key)] {
+                if let Err(err) = std::fs::File::open(path) {
+                    problems.push(Problem::new(
+                        key,
+                        format!("Unable to read {}: {err}", path.display()),
+                    ));
+                }
+            }
+        }
+
+        if let Some(grpc) = &self.api.grpc {
+            if cfg!(not(feature = "grpc")) {
+                problems.push(Problem::new(
+                    "api.grpc",
+                    "Blackhole was built without gRPC support (the grpc feature)",
+                ));
+            } else if grpc.port == self.port || grpc.port == self.api.port {
+                problems.push(Problem::new(
+                    "api.grpc.port",
+                    format!("Port {} is already in use", grpc.port),
+                ));
+            }
+        }
+
+        for origin in &self.api.origins {
+            let valid = origin == "*"
+                || origin.split_once("://").is_some_and(|(scheme, host)| {
+                    matches!(scheme, "http" | "https") && !host.is_empty() && !host.contains('/')
+                });
+
+            if !valid {
+                problems.push(Problem::new(
+                    "api.origins",
+                    format!("'{origin}' isn't an origin, e.g. https://dashboard.example.com"),
+                ));
+            }
+        }
+
+        if let Some(http) = &self.exporter.http {
+            if !(http.url.starts_with("http://") || http.url.starts_with("https://")) {
+                problems.push(Problem::new(
+                    "exporter.http.url",
+                    format!("'{}' isn't an http(s) URL", http.url),
+                ));
+            }
+
+            if http.batch == 0 {
+                problems.push(Problem::new(
+                    "exporter.http.batch",
+                    "Batches need to hold at least one request",
+                ));
+            }
+        }
+
+        if let Some(syslog) = &self.logging.syslog {
+            if let Err(err) = syslog.address.parse::<logging::Transport>() {
+                problems.push(Problem::new("logging.syslog.address", err));
+            }
+        }
+
+        for upstream in &self.upstreams {
+            if upstream.ip.is_unspecified() || upstream.ip.is_multicast() || upstream.port == 0 {
+                problems.push(Problem::new(
+                    "upstream",
+                    format!(
+                        "{}:{} isn't an address we can forward requests to",
+                        upstream.ip, upstream.port
+                    ),
+                ));
+            }
+        }
+
+        if let (Some(min), Some(max)) = (self.min_ttl, self.max_ttl) {
+            if min > max {
+                problems.push(Problem::new(
+                    "min_ttl",
+                    format!("{min}s is longer than the max_ttl of {max}s"),
+                ));
+            }
+        }
+
+        if !dns::DNS64_PREFIX_LENGTHS.contains(&self.dns64.prefix.prefix_len()) {
+            problems.push(Problem::new(
+                "dns64.prefix",
+                format!(
+                    "{} isn't a NAT64 prefix, which must be one of /32, /40, /48, /56, /64 or /96",
+                    self.dns64.prefix
+                ),
+            ));
+        }
+
+        for list in &self.filters {
+            let scheme = list.url.split_once("://").map(|(scheme, _)| scheme);
+            if list.url.is_empty()
+                || scheme.is_some_and(|scheme| !matches!(scheme, "http" | "https" | "file"))
+            {
+                problems.push(Problem::new(
+                    "filter",
+                    format!("{} has an unsupported url: '{}'", list.name, list.url),
+                ));
+            }
+        }
+
+        for pattern in &self.patterns {
+            if let Err(err) = regex::Regex::new(&pattern.pattern) {
+                problems.push(Problem::new(
+                    "pattern",
+                    format!("'{}' isn't a valid pattern: {err}", pattern.pattern),
+                ));
+            }
+        }
+
+        let mut scheduled = AHashSet::new();
+        for schedule in &self.schedules {
+            if !scheduled.insert(&schedule.name) {
+                problems.push(Problem::new(
+                    "schedule",
+                    format!("{:?} is scheduled more than once", schedule.name),
+                ));
+            }
+
+            if schedule.schedule.is_zero() {
+                problems.push(Problem::new(
+                    "schedule",
+                    format!("{:?} needs a schedule longer than 0s", schedule.name),
+                ));
+            }
+        }
+
+        for rule in &self.rules {
+            if Name::from_ascii(&rule.domain).is_err() || rule.domain.is_empty() {
+                problems.push(Problem::new(
+                    "rules",
+                    format!("'{}' isn't a valid domain", rule.domain),
+                ));
+            }
+
+            if let Some(cname) = rule
+                .cname
+                .as_ref()
+                .filter(|cname| Name::from_ascii(cname).is_err())
+            {
+                problems.push(Problem::new(
+                    "rules",
+                    format!(
+                        "{} redirects to '{cname}', which isn't a valid domain",
+                        rule.domain
+                    ),
+                ));
+            }
+        }
+
+        problems
+    }
+
     ///
     /// Load a configuration profile
     ///
@@ -118,11 +674,15 @@ impl Config {
     ///
     /// Save the config to disk
     ///
+    /// The config is first written to a temporary file which then replaces
+    /// the existing one, so a failed write never leaves a truncated config behind.
+    ///
     /// # Errors
     /// While this should be unlikely, it is possible for this to
     /// result in an error if:
     ///  - There is no disk space left
     ///  - The config file is not writable
+    ///  - Writes are currently suspended, see [`Health`]
     ///
     pub async fn save() -> Result<(), Error> {
         let file = CONFIG_FILE
@@ -133,10 +693,16 @@ impl Config {
 
         tracing::debug!("Saving to {file}");
 
-        std::fs::write(
-            Path::new(&*file),
-            toml::to_string_pretty(&*CONFIG.read().await)?,
-        )?;
+        let contents = toml::to_string_pretty(&*CONFIG.read().await)?;
+        let path = Path::new(&*file);
+        let temporary = path.with_extension("toml.tmp");
+
+        Health::persist(|| {
+            std::fs::write(&temporary, contents).and_then(|()| std::fs::rename(&temporary, path))
+        })
+        .inspect_err(|_| {
+            std::fs::remove_file(&temporary).unwrap_or_default();
+        })?;
 
         Ok(())
     }
@@ -167,20 +733,223 @@ impl Config {
         let old_config = CONFIG.read().await.clone();
         func(&mut *CONFIG.write().await);
         if let Err(err) = Self::save().await {
+            // Saving never touches the existing file unless it succeeds, so
+            // there's nothing on disk to restore, only our in-memory copy
             error!("{err}");
             *CONFIG.write().await = old_config;
-            match Self::save().await {
-                Ok(()) => Err(err),
-                Err(e) => Err(e),
-            }
+            Err(err)
         } else {
             let config = CONFIG.read().await.clone();
+            Self::apply(old_config, &config).await
+        }
+    }
 
-            if old_config.filters != config.filters {
-                Filter::reset(Some(old_config.filters)).await;
+    ///
+    /// Reload the configuration profile from scratch, applying anything that
+    /// has changed without needing a restart
+    ///
+    /// # Errors
+    /// If the configuration profile fails to load or isn't valid (in which case
+    /// the current config is kept), or the filters fail to update
+    ///
+    pub async fn reload<C: Load + 'static + Send + Sync>(loader: &C) -> Result<(), Error> {
+        let mut config = Self::default();
+        loader.load(&mut config).await?;
+
+        if let Some(problem) = config.validate().into_iter().next() {
+            return Err(Error::Invalid(problem.key, problem.reason));
+        }
+
+        let old_config = std::mem::replace(&mut *CONFIG.write().await, config.clone());
+        Self::apply(old_config, &config).await
+    }
+
+    ///
+    /// Bring everything in line with the config, after it changed from the old one
+    ///
+    async fn apply(old_config: Self, config: &Self) -> Result<(), Error> {
+        if old_config.metrics != config.metrics {
+            metrics::configure(&config.metrics);
+        }
+
+        if old_config.statistics != config.statistics {
+            statistics::configure(&config.statistics);
+        }
+
+        if old_config.exporter != config.exporter {
+            exporter::configure(&config.exporter);
+        }
+
+        if old_config.logging != config.logging {
+            logging::configure(&config.logging);
+        }
+
+        if old_config.queue != config.queue {
+            dns::queue::configure(&config.queue);
+        }
+
+        if old_config.port != config.port
+            || old_config.bind != config.bind
+            || old_config.listeners != config.listeners
+        {
+            dns::REBIND.notify_one();
+        }
+
+        if old_config.api != config.api {
+            warn!("Changes to the API options will only take effect after a restart");
+        }
+
+        if old_config.schedules != config.schedules {
+            Scheduler::reschedule(config.schedules.clone()).await;
+        }
+
+        // Lists are told apart by their name and URL alone, so being enabled or
+        // disabled doesn't count as a change to the lists themselves
+        let toggled = old_config.filters.iter().any(|list| {
+            config
+                .filters
+                .get(list)
+                .is_some_and(|new| new.enabled != list.enabled)
+        });
+
+        let windowed = |config: &Self| {
+            config
+                .windows
+                .iter()
+                .flat_map(|window| window.lists.clone())
+                .collect::<AHashSet<_>>()
+        };
+
+        if old_config.windows != config.windows {
+            Scheduler::once(Sched::Windows, Duration::ZERO).await;
+        }
+
+        if old_config.filters != config.filters {
+            Filter::reset(Some(old_config.filters)).await;
+        } else if toggled
+            || windowed(&old_config) != windowed(config)
+            || old_config.rules != config.rules
+            || old_config.patterns != config.patterns
+            || old_config.auto_ptr != config.auto_ptr
+            || old_config.use_builtin_list != config.use_builtin_list
+        {
+            if toggled {
+                // Only those lists just enabled will need downloading
+                Filter::update().await;
             }
 
-            Ok(())
+            Filter::import().await?;
         }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use pretty_assertions::assert_eq;
+
+    use super::{overlay, Config, Error};
+
+    fn overrides(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| ((*key).to_string(), (*value).to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn environment() {
+        let mut config = Config::default();
+
+        overlay(
+            &mut config,
+            overrides(&[
+                ("PORT", "5353"),
+                ("UPSTREAMS", "1.1.1.1, 8.8.8.8:5353"),
+                ("API__PORT", "8080"),
+                ("AUTO_PTR", "true"),
+                ("SCHEDULER__WINDOW", "5m"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(config.port, 5353);
+        assert_eq!(config.api.port, 8080);
+        assert!(config.auto_ptr);
+        assert_eq!(config.scheduler.window, Duration::from_mins(5));
+
+        let mut upstreams = config
+            .upstreams
+            .iter()
+            .map(|upstream| format!("{}:{}", upstream.ip, upstream.port))
+            .collect::<Vec<_>>();
+        upstreams.sort();
+        assert_eq!(upstreams, ["1.1.1.1:53", "8.8.8.8:5353"]);
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(Config::default().validate(), []);
+
+        let mut config = Config::default();
+        overlay(
+            &mut config,
+            overrides(&[
+                ("API__PORT", "53"),
+                ("BIND__TCP_TIMEOUT", "0s"),
+                ("QUEUE__IN_FLIGHT", "0"),
+                (
+                    "API__ORIGINS",
+                    "[\"https://dashboard.lan\", \"dashboard.lan\"]",
+                ),
+                ("API__GRPC", "{ port = 53 }"),
+                ("EXPORTER__HTTP", "{ url = \"collector.lan\" }"),
+                ("LOGGING__SYSLOG", "{ address = \"syslog.lan:514\" }"),
+                ("UPSTREAMS", "0.0.0.0"),
+                ("SCHEDULES", "[{ name = \"Logs\", schedule = \"0s\" }]"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            config
+                .validate()
+                .into_iter()
+                .map(|problem| problem.key)
+                .collect::<Vec<_>>(),
+            [
+                "bind.tcp_timeout",
+                "queue.in_flight",
+                "api.port",
+                if cfg!(feature = "grpc") {
+                    "api.grpc.port"
+                } else {
+                    "api.grpc"
+                },
+                "api.origins",
+                "exporter.http.url",
+                "logging.syslog.address",
+                "upstream",
+                "schedule"
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_environment() {
+        let mut config = Config::default();
+
+        assert!(matches!(
+            overlay(&mut config, overrides(&[("UPSTREAMS", "not an upstream")])),
+            Err(Error::Invalid(..))
+        ));
+        assert!(matches!(
+            overlay(&mut config, overrides(&[("PORT", "not a port")])),
+            Err(Error::Deserialization(..))
+        ));
+        assert_eq!(config, Config::default());
     }
 }
@@ -0,0 +1,277 @@
+use std::net::IpAddr;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::filter::{patterns::Pattern, rules::Kind, Custom, List};
+
+use super::{Config, Imported};
+
+const fn default_enabled() -> bool {
+    true
+}
+
+///
+/// Pi-hole keeps its flags in SQLite, so they're exported as 0 or 1, though
+/// booleans are accepted too
+///
+fn enabled<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Bool(enabled) => enabled,
+        serde_json::Value::Number(number) => number.as_u64() != Some(0),
+        _ => true,
+    })
+}
+
+#[derive(Deserialize)]
+struct Adlist {
+    address: String,
+    #[serde(default = "default_enabled", deserialize_with = "enabled")]
+    enabled: bool,
+    #[serde(default)]
+    comment: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Domain {
+    domain: String,
+    #[serde(default = "default_enabled", deserialize_with = "enabled")]
+    enabled: bool,
+}
+
+///
+/// The parts of a Pi-hole Teleporter archive we can make use of, keyed by the
+/// names of the files within it
+///
+#[derive(Deserialize, Default)]
+pub struct Teleporter {
+    #[serde(rename = "adlist.json", default)]
+    adlists: Vec<Adlist>,
+    #[serde(rename = "whitelist.exact.json", default)]
+    whitelist: Vec<Domain>,
+    #[serde(rename = "blacklist.exact.json", default)]
+    blacklist: Vec<Domain>,
+    #[serde(rename = "whitelist.regex.json", default)]
+    regex_whitelist: Vec<Domain>,
+    #[serde(rename = "blacklist.regex.json", default)]
+    regex_blacklist: Vec<Domain>,
+    /// The local DNS records, in hosts format
+    #[serde(rename = "custom.list", default)]
+    custom: String,
+    /// The local CNAME records, as `cname=<domain>,<target>` lines
+    #[serde(rename = "05-pihole-custom-cname.conf", default)]
+    cnames: String,
+}
+
+impl Teleporter {
+    ///
+    /// Add everything in the archive to the config, leaving alone anything
+    /// already configured for the same list or domain. Disabled entries are
+    /// only carried over for lists, as rules and patterns can't be disabled.
+    ///
+    pub fn import(&self, config: &mut Config) -> Imported {
+        let mut imported = Imported::default();
+
+        for adlist in &self.adlists {
+            imported.list(
+                config,
+                List {
+                    name: adlist
+                        .comment
+                        .clone()
+                        .filter(|comment| !comment.trim().is_empty())
+                        .unwrap_or_else(|| adlist.address.clone()),
+                    url: adlist.address.clone(),
+                    enabled: adlist.enabled,
+                    schedule: None,
+                    entries: 0,
+                },
+            );
+        }
+
+        let domains = self
+            .whitelist
+            .iter()
+            .map(|domain| (domain, Kind::Allow))
+            .chain(self.blacklist.iter().map(|domain| (domain, Kind::Deny)))
+            .filter(|(domain, _)| domain.enabled)
+            .map(|(domain, kind)| Custom {
+                domain: domain.domain.clone(),
+                kind,
+                ..Default::default()
+            });
+
+        for rule in domains.chain(self.records()) {
+            imported.rule(config, rule);
+        }
+
+        let patterns = self
+            .regex_whitelist
+            .iter()
+            .map(|domain| (domain, Kind::Allow))
+            .chain(
+                self.regex_blacklist
+                    .iter()
+                    .map(|domain| (domain, Kind::Deny)),
+            )
+            .filter(|(domain, _)| domain.enabled);
+
+        for (domain, kind) in patterns {
+            // Pi-hole's extensions (e.g. `;querytype=AAAA`) have no equivalent
+            if domain.domain.contains(';') {
+                imported.skipped.push(domain.domain.clone());
+                continue;
+            }
+
+            imported.pattern(
+                config,
+                Pattern {
+                    pattern: domain.domain.clone(),
+                    kind,
+                },
+            );
+        }
+
+        imported
+    }
+
+    ///
+    /// The local DNS and CNAME records as rules, with the addresses of a domain
+    /// given more than once merged into the one rule
+    ///
+    fn records(&self) -> Vec<Custom> {
+        let mut records: Vec<Custom> = Vec::new();
+
+        let hosts = self.custom.lines().filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let ip = fields.next()?.parse::<IpAddr>().ok()?;
+            Some(fields.map(move |domain| (ip, domain.to_lowercase())))
+        });
+
+        for (ip, domain) in hosts.flatten() {
+            let index = records
+                .iter()
+                .position(|record| record.domain == domain)
+                .unwrap_or_else(|| {
+                    records.push(Custom {
+                        domain,
+                        ..Default::default()
+                    });
+                    records.len() - 1
+                });
+
+            match ip {
+                IpAddr::V4(ip) => records[index].v4 = Some(ip),
+                IpAddr::V6(ip) => records[index].v6 = Some(ip),
+            }
+        }
+
+        records.extend(self.cnames.lines().filter_map(|line| {
+            let mut fields = line.trim().strip_prefix("cname=")?.split(',');
+            let (domain, target) = (fields.next()?, fields.next()?);
+
+            Some(Custom {
+                domain: domain.trim().to_lowercase(),
+                cname: Some(target.trim().to_lowercase()),
+                ..Default::default()
+            })
+        }));
+
+        records
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::{Imported, Teleporter};
+    use crate::{
+        config::Config,
+        filter::{rules::Kind, Custom},
+    };
+
+    #[test]
+    fn import() {
+        let teleporter: Teleporter = serde_json::from_value(serde_json::json!({
+            "adlist.json": [
+                {
+                    "id": 1,
+                    "address": "https://raw.githubusercontent.com/StevenBlack/hosts/master/hosts",
+                    "enabled": 1,
+                    "comment": "Migrated from /etc/pihole/adlists.list"
+                },
+                { "id": 2, "address": "https://example.com/list.txt", "enabled": 0, "comment": null }
+            ],
+            "whitelist.exact.json": [
+                { "id": 1, "type": 0, "domain": "s.youtube.com", "enabled": 1 },
+                { "id": 2, "type": 0, "domain": "ignored.com", "enabled": 0 }
+            ],
+            "blacklist.exact.json": [
+                { "id": 3, "type": 1, "domain": "ads.example.com", "enabled": 1 }
+            ],
+            "blacklist.regex.json": [
+                { "id": 4, "type": 3, "domain": "^ad[sx]?[0-9]*\\.", "enabled": 1 },
+                { "id": 5, "type": 3, "domain": "^tracker\\.;querytype=AAAA", "enabled": 1 }
+            ],
+            "custom.list": "192.168.1.10 nas.lan\n# A comment\nfd00::10 nas.lan\n192.168.1.1 router.lan gateway.lan\n",
+            "05-pihole-custom-cname.conf": "cname=files.lan,nas.lan\n"
+        }))
+        .unwrap();
+
+        let mut config = Config::default();
+        config.rules.push(Custom {
+            domain: String::from("ads.example.com"),
+            kind: Kind::Allow,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            teleporter.import(&mut config),
+            Imported {
+                lists: 2,
+                rules: 5,
+                patterns: 1,
+                skipped: vec![
+                    String::from("ads.example.com"),
+                    String::from("^tracker\\.;querytype=AAAA")
+                ],
+                ..Default::default()
+            }
+        );
+
+        let disabled = config
+            .filters
+            .iter()
+            .find(|list| list.url == "https://example.com/list.txt")
+            .unwrap();
+        assert_eq!(disabled.name, disabled.url);
+        assert!(!disabled.enabled);
+
+        let nas = config
+            .rules
+            .iter()
+            .find(|rule| rule.domain == "nas.lan")
+            .unwrap();
+        assert_eq!(nas.v4, Some("192.168.1.10".parse().unwrap()));
+        assert_eq!(nas.v6, Some("fd00::10".parse().unwrap()));
+        assert_eq!(nas.kind, Kind::None);
+
+        let files = config
+            .rules
+            .iter()
+            .find(|rule| rule.domain == "files.lan")
+            .unwrap();
+        assert_eq!(files.cname.as_deref(), Some("nas.lan"));
+
+        // Importing again changes nothing
+        let imported = teleporter.import(&mut config);
+        assert_eq!(
+            (imported.lists, imported.rules, imported.patterns),
+            (0, 0, 0)
+        );
+        assert!(config.validate().is_empty());
+    }
+}
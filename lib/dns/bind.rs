@@ -0,0 +1,221 @@
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{tcp::Limits, Protocol};
+
+const fn default_tcp_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+const fn default_tcp_read_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+const fn default_tcp_connections() -> usize {
+    512
+}
+
+const fn default_udp_workers() -> usize {
+    1
+}
+
+///
+/// The addresses the DNS server listens on, should it not be listening on
+/// every interface
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Bind {
+    /// The addresses to listen on over both UDP and TCP. If empty, every
+    /// interface is listened on.
+    #[serde(default)]
+    pub addresses: Vec<IpAddr>,
+    /// The addresses to listen on over UDP, in place of those above
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub udp: Option<Vec<IpAddr>>,
+    /// The addresses to listen on over TCP, in place of those above
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<Vec<IpAddr>>,
+    /// How long a TCP connection is kept open without a query, for clients
+    /// that send several queries over the one connection (RFC 7766)
+    #[serde(with = "humantime_serde", default = "default_tcp_timeout")]
+    pub tcp_timeout: Duration,
+    /// How long a query sent over TCP has to arrive in full, once it's started
+    /// arriving, and how long the client has to take each response
+    #[serde(with = "humantime_serde", default = "default_tcp_read_timeout")]
+    pub tcp_read_timeout: Duration,
+    /// How many TCP connections may be open at once, with any more closed as
+    /// soon as they're accepted
+    #[serde(default = "default_tcp_connections")]
+    pub tcp_connections: usize,
+    /// How many sockets each UDP address is served on, which the kernel
+    /// spreads queries across (with SO_REUSEPORT), rather than every query
+    /// being received through the one socket
+    #[serde(default = "default_udp_workers")]
+    pub udp_workers: usize,
+}
+
+impl Default for Bind {
+    fn default() -> Self {
+        Self {
+            addresses: Vec::default(),
+            udp: None,
+            tcp: None,
+            tcp_timeout: default_tcp_timeout(),
+            tcp_read_timeout: default_tcp_read_timeout(),
+            tcp_connections: default_tcp_connections(),
+            udp_workers: default_udp_workers(),
+        }
+    }
+}
+
+///
+/// Somewhere else to listen for queries, on top of the port on the addresses
+/// above, e.g. port 5353 on localhost for a local stub resolver
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Listener {
+    pub address: IpAddr,
+    pub port: u16,
+    /// Either "udp" or "tcp". If not given, both.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<Protocol>,
+}
+
+impl Listener {
+    fn sockets(&self) -> Vec<(SocketAddr, Protocol)> {
+        let address = SocketAddr::new(self.address, self.port);
+
+        match self.protocol {
+            Some(protocol) => vec![(address, protocol)],
+            None => vec![(address, Protocol::Udp), (address, Protocol::Tcp)],
+        }
+    }
+}
+
+fn or_everywhere(addresses: &[IpAddr]) -> Vec<IpAddr> {
+    if addresses.is_empty() {
+        vec![IpAddr::V6(Ipv6Addr::UNSPECIFIED)]
+    } else {
+        addresses.to_vec()
+    }
+}
+
+impl Bind {
+    ///
+    /// How long TCP connections may stay open, and how many there may be
+    ///
+    pub(crate) fn tcp_limits(&self) -> Limits {
+        Limits {
+            idle: self.tcp_timeout,
+            read: self.tcp_read_timeout,
+            connections: self.tcp_connections,
+        }
+    }
+
+    ///
+    /// The addresses to listen on over UDP
+    ///
+    pub fn udp(&self) -> Vec<IpAddr> {
+        or_everywhere(self.udp.as_deref().unwrap_or(&self.addresses))
+    }
+
+    ///
+    /// The addresses to listen on over TCP
+    ///
+    pub fn tcp(&self) -> Vec<IpAddr> {
+        or_everywhere(self.tcp.as_deref().unwrap_or(&self.addresses))
+    }
+
+    ///
+    /// Every socket to listen on, with the port on each of the addresses
+    /// followed by the listeners. Any socket given more than once is only
+    /// listened on the once.
+    ///
+    pub fn sockets(&self, port: u16, listeners: &[Listener]) -> Vec<(SocketAddr, Protocol)> {
+        let sockets = self
+            .udp()
+            .into_iter()
+            .map(|ip| (SocketAddr::new(ip, port), Protocol::Udp))
+            .chain(
+                self.tcp()
+                    .into_iter()
+                    .map(|ip| (SocketAddr::new(ip, port), Protocol::Tcp)),
+            )
+            .chain(listeners.iter().flat_map(Listener::sockets));
+
+        let mut unique = Vec::new();
+        for socket in sockets {
+            if !unique.contains(&socket) {
+                unique.push(socket);
+            }
+        }
+
+        unique
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv6Addr};
+
+    use pretty_assertions::assert_eq;
+
+    use super::{Bind, Listener, Protocol};
+
+    #[test]
+    fn addresses() {
+        assert_eq!(Bind::default().udp(), [IpAddr::V6(Ipv6Addr::UNSPECIFIED)]);
+
+        let lan = "192.168.1.2".parse::<IpAddr>().unwrap();
+        let localhost = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let bind = Bind {
+            addresses: vec![lan],
+            tcp: Some(vec![lan, localhost]),
+            ..Default::default()
+        };
+
+        assert_eq!(bind.udp(), [lan]);
+        assert_eq!(bind.tcp(), [lan, localhost]);
+    }
+
+    #[test]
+    fn sockets() {
+        let localhost = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let bind = Bind {
+            addresses: vec![localhost],
+            ..Default::default()
+        };
+
+        let sockets = bind.sockets(
+            53,
+            &[
+                Listener {
+                    address: localhost,
+                    port: 5353,
+                    protocol: None,
+                },
+                Listener {
+                    address: localhost,
+                    port: 53,
+                    protocol: Some(Protocol::Tcp),
+                },
+            ],
+        );
+
+        assert_eq!(
+            sockets,
+            [
+                ("127.0.0.1:53".parse().unwrap(), Protocol::Udp),
+                ("127.0.0.1:53".parse().unwrap(), Protocol::Tcp),
+                ("127.0.0.1:5353".parse().unwrap(), Protocol::Udp),
+                ("127.0.0.1:5353".parse().unwrap(), Protocol::Tcp),
+            ]
+        );
+    }
+}
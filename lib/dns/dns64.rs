@@ -0,0 +1,161 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use hickory_proto::rr::{rdata::AAAA, RData, Record, RecordType};
+use ipnet::Ipv6Net;
+use serde::{Deserialize, Serialize};
+
+///
+/// The Well-Known Prefix set aside for NAT64 (RFC 6052), 64:ff9b::/96
+///
+const WELL_KNOWN_PREFIX: (Ipv6Addr, u8) = (Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0), 96);
+
+///
+/// The prefix lengths an IPv4 address can be embedded within (RFC 6052)
+///
+pub(crate) const PREFIX_LENGTHS: [u8; 6] = [32, 40, 48, 56, 64, 96];
+
+fn default_prefix() -> Ipv6Net {
+    Ipv6Net::new(WELL_KNOWN_PREFIX.0, WELL_KNOWN_PREFIX.1).unwrap()
+}
+
+///
+/// Options for synthesising AAAA records for names that only have A records
+/// (RFC 6147), so that clients on IPv6-only networks can reach them through a
+/// NAT64 gateway
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Dns64 {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The NAT64 gateway's prefix, which must be one of /32, /40, /48, /56,
+    /// /64 or /96
+    #[serde(default = "default_prefix")]
+    pub prefix: Ipv6Net,
+}
+
+impl Default for Dns64 {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            prefix: default_prefix(),
+        }
+    }
+}
+
+impl Dns64 {
+    ///
+    /// Embed the IPv4 address within the prefix, skipping over bits 64 to 71
+    /// as RFC 6052 requires. Returns None for addresses that can't be reached
+    /// through the Well-Known Prefix, as it's only for global addresses.
+    ///
+    pub fn synthesise(&self, ip: Ipv4Addr) -> Option<Ipv6Addr> {
+        let well_known = (self.prefix.network(), self.prefix.prefix_len()) == WELL_KNOWN_PREFIX;
+        if well_known && !ip.is_global() {
+            return None;
+        }
+
+        let mut octets = self.prefix.network().octets();
+        let mut position = usize::from(self.prefix.prefix_len() / 8);
+        for octet in ip.octets() {
+            if position == 8 {
+                position += 1;
+            }
+
+            *octets.get_mut(position)? = octet;
+            position += 1;
+        }
+
+        Some(Ipv6Addr::from(octets))
+    }
+
+    ///
+    /// The answers to an A query, with each A record swapped for its AAAA
+    /// equivalent. Anything else (e.g. the CNAMEs leading to them) is kept.
+    ///
+    pub fn answers(&self, answers: &[Record]) -> Vec<Record> {
+        answers
+            .iter()
+            .filter_map(|record| match record.data() {
+                Some(RData::A(a)) => {
+                    let ip = self.synthesise(a.0)?;
+                    Some(
+                        record
+                            .clone()
+                            .set_rr_type(RecordType::AAAA)
+                            .set_data(Some(RData::AAAA(AAAA(ip))))
+                            .clone(),
+                    )
+                }
+                _ => Some(record.clone()),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hickory_proto::rr::{
+        rdata::{A, AAAA, CNAME},
+        Name, RData, Record,
+    };
+    use pretty_assertions::assert_eq;
+
+    use super::Dns64;
+
+    fn dns64(prefix: &str) -> Dns64 {
+        Dns64 {
+            enabled: true,
+            prefix: prefix.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn synthesise() {
+        let ip = "192.0.2.33".parse().unwrap();
+
+        // The examples from RFC 6052, section 2.4
+        for (prefix, expected) in [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::/96", "2001:db8:122:344::192.0.2.33"),
+        ] {
+            assert_eq!(
+                dns64(prefix).synthesise(ip),
+                Some(expected.parse().unwrap()),
+                "{prefix}"
+            );
+        }
+
+        let dns64 = Dns64::default();
+        assert_eq!(
+            dns64.synthesise("93.184.216.34".parse().unwrap()),
+            Some("64:ff9b::5db8:d822".parse().unwrap())
+        );
+        // Only global addresses can be reached through the Well-Known Prefix
+        assert_eq!(dns64.synthesise("192.168.1.10".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn answers() {
+        let name = Name::from_ascii("www.example.com.").unwrap();
+        let target = Name::from_ascii("example.com.").unwrap();
+
+        let answers = Dns64::default().answers(&[
+            Record::from_rdata(name, 300, RData::CNAME(CNAME(target.clone()))),
+            Record::from_rdata(target.clone(), 60, RData::A(A::new(93, 184, 216, 34))),
+            Record::from_rdata(target, 60, RData::A(A::new(10, 0, 0, 1))),
+        ]);
+
+        assert_eq!(answers.len(), 2);
+        assert!(matches!(answers[0].data(), Some(RData::CNAME(_))));
+        assert_eq!(
+            answers[1].data(),
+            Some(&RData::AAAA(AAAA("64:ff9b::5db8:d822".parse().unwrap())))
+        );
+        assert_eq!(answers[1].ttl(), 60);
+    }
+}
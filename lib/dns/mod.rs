@@ -1,48 +1,223 @@
 use std::{
-    net::IpAddr,
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        LazyLock,
+    },
     time::{Instant, SystemTime},
 };
 
 use hickory_proto::{
-    op::{Message, MessageType, ResponseCode},
-    rr::{Record, RecordType},
-    xfer::DnsResponse,
+    error::ProtoError,
+    op::{Edns, Message, MessageType, Query, ResponseCode},
+    rr::{
+        rdata::opt::{EdnsCode, EdnsOption},
+        Name, Record, RecordType,
+    },
+    serialize::binary::{BinDecodable, BinDecoder},
+    xfer::{DnsHandle, DnsRequest, DnsRequestOptions, DnsResponse, FirstAnswer},
 };
 use hickory_resolver::{
-    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    config::{NameServerConfig, NameServerConfigGroup, ResolverConfig, ResolverOpts},
     error::{
         ResolveError,
         ResolveErrorKind::{
             Io, Message as ResolverMessage, Msg, NoConnections, NoRecordsFound, Proto, Timeout,
         },
     },
+    name_server::{NameServerPool, TokioConnectionProvider},
     TokioAsyncResolver,
 };
 use hickory_server::{
-    authority::MessageResponseBuilder,
-    server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
+    authority::{MessageRequest, MessageResponseBuilder},
+    server::{Protocol as Transport, Request, RequestHandler, ResponseHandler, ResponseInfo},
 };
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
-use tracing::error;
+use tokio::sync::Notify;
+use tracing::{debug, error};
 
 use crate::{
+    anomaly,
     cache::Cache,
+    clients::{self, Identity},
     config::Config,
     filter::{rules::Rule, Filter},
+    metrics, safesearch,
     statistics::{self, Average, Statistics},
 };
 
+pub use bind::{Bind, Listener};
+pub use dns64::Dns64;
+pub(crate) use dns64::PREFIX_LENGTHS as DNS64_PREFIX_LENGTHS;
+pub use queue::Queue;
+pub use resolver::{Resolver, Strategy};
+pub use special::SpecialUse;
+pub(crate) use tcp::Tcp;
+pub use types::{TypeAction, TypeFilter};
+
+mod bind;
+mod dns64;
+pub(crate) mod queue;
+mod resolver;
+mod special;
+mod tcp;
+mod types;
+
 const fn default_port() -> u16 {
     53
 }
 
+/// The size of the blocks responses are padded to, as recommended by RFC 8467
+const RESPONSE_BLOCK_SIZE: usize = 468;
+
+/// The largest response we'll take from the upstreams over UDP when asking
+/// them with EDNS ourselves, as recommended by DNS Flag Day 2020
+const UPSTREAM_PAYLOAD: u16 = 1232;
+
+/// Notified when the port we serve DNS on has changed, so that we rebind
+pub(crate) static REBIND: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// The number of requests currently being answered
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+/// Notified whenever the last request being answered finishes
+static DRAINED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+///
+/// Marks a request as being answered for as long as it's held
+///
+struct InFlight;
+
+impl InFlight {
+    fn start() -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if IN_FLIGHT.fetch_sub(1, Ordering::SeqCst) == 1 {
+            DRAINED.notify_waiters();
+        }
+    }
+}
+
+///
+/// The number of requests currently being answered
+///
+#[inline]
+pub(crate) fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::SeqCst)
+}
+
+///
+/// Wait until there are no requests being answered
+///
+pub(crate) async fn drained() {
+    loop {
+        let notified = DRAINED.notified();
+        tokio::pin!(notified);
+        // Register interest before checking, so a request finishing in between
+        // can't be missed
+        notified.as_mut().enable();
+
+        if in_flight() == 0 {
+            return;
+        }
+
+        notified.await;
+    }
+}
+
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Copy, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// UDP, falling back to TCP for truncated responses
+    #[default]
+    Udp,
+    Tcp,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Udp => "udp",
+            Self::Tcp => "tcp",
+        })
+    }
+}
+
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rejection {
+    /// Answer with REFUSED
+    #[default]
+    Refuse,
+    /// Don't answer at all
+    Drop,
+}
+
+///
+/// Which clients are allowed to query us
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Acl {
+    /// The networks clients may query from. If empty, anyone may.
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// The networks clients may never query from, even if they're also allowed
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+    /// What to do with queries from anywhere else
+    #[serde(default)]
+    pub action: Rejection,
+}
+
+impl Acl {
+    #[inline]
+    pub fn allows(&self, client: IpAddr) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|network| network.contains(&client)))
+            && !self.deny.iter().any(|network| network.contains(&client))
+    }
+}
+
 #[cfg_attr(any(debug_assertions, test), derive(Debug))]
 #[derive(Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Upstream {
     pub ip: IpAddr,
     #[serde(default = "default_port")]
     pub port: u16,
+    #[serde(default)]
+    pub protocol: Protocol,
+}
+
+impl Upstream {
+    fn nameservers(&self) -> NameServerConfigGroup {
+        match self.protocol {
+            Protocol::Udp => NameServerConfigGroup::from_ips_clear(&[self.ip], self.port, true),
+            Protocol::Tcp => NameServerConfigGroup::from(vec![NameServerConfig {
+                trust_negative_responses: true,
+                ..NameServerConfig::new(
+                    SocketAddr::new(self.ip, self.port),
+                    hickory_resolver::config::Protocol::Tcp,
+                )
+            }]),
+        }
+    }
+
+    fn labels(&self) -> metrics::Upstream {
+        metrics::Upstream {
+            ip: self.ip.to_string(),
+            port: self.port,
+            protocol: self.protocol.to_string(),
+        }
+    }
 }
 
 impl FromStr for Upstream {
@@ -53,106 +228,668 @@ impl FromStr for Upstream {
             Some((ip, port)) => Ok(Self {
                 ip: ip.parse().map_err(|e| format!("{e}"))?,
                 port: port.parse().map_err(|_| "invalid port".to_string())?,
+                protocol: Protocol::default(),
             }),
             None => Ok(Self {
                 ip: value.parse().map_err(|e| format!("{e}"))?,
                 port: default_port(),
+                protocol: Protocol::default(),
             }),
         }
     }
 }
 
+///
+/// The EDNS to respond with so that the response is padded out to a multiple
+/// of the recommended block size (RFC 7830, RFC 8467)
+///
+fn padding(message: &mut Message) -> Edns {
+    let mut edns = Edns::new();
+    message.set_edns(edns.clone());
+
+    // Account for the header of the padding option too
+    let length = message.to_vec().map_or(0, |message| message.len()) + 4;
+
+    edns.options_mut().insert(EdnsOption::Unknown(
+        u16::from(EdnsCode::Padding),
+        vec![0; (RESPONSE_BLOCK_SIZE - length % RESPONSE_BLOCK_SIZE) % RESPONSE_BLOCK_SIZE],
+    ));
+
+    edns
+}
+
+///
+/// The response, with the TTL of each answer kept within the bounds. Zero TTLs
+/// would otherwise have clients asking again for every connection they make.
+///
+fn clamp_ttls(response: &DnsResponse, min: Option<u32>, max: Option<u32>) -> DnsResponse {
+    let mut message = response.clone().into_message();
+
+    for answer in message.answers_mut() {
+        let ttl = answer
+            .ttl()
+            .max(min.unwrap_or(0))
+            .min(max.unwrap_or(u32::MAX));
+        answer.set_ttl(ttl);
+    }
+
+    DnsResponse::new(message.clone(), message.to_vec().unwrap_or_default())
+}
+
+///
+/// Clamp the TTLs of what came from the upstreams, which has to happen before
+/// it's cached so that it's served from the cache clamped too. What was already
+/// cached, or that we answered ourselves, is left as it is.
+///
+fn clamp_upstream(
+    stat: &statistics::Request,
+    response: &mut DnsResponse,
+    min: Option<u32>,
+    max: Option<u32>,
+) {
+    if (min.is_some() || max.is_some())
+        && !stat.cached
+        && !stat.rule.as_ref().is_some_and(Rule::answers_locally)
+    {
+        *response = clamp_ttls(response, min, max);
+    }
+}
+
+///
+/// Strip the response down to its answers, leaving out the authority and
+/// additional sections. Answers to ANY queries are cut down to a single RRset
+/// (RFC 8482), rather than everything there is for the name
+///
+fn minimize(message: &mut Message, query_type: RecordType) {
+    message.take_name_servers();
+    message.take_additionals();
+
+    if query_type == RecordType::ANY {
+        let answers = message.take_answers();
+        let rrset = answers
+            .first()
+            .map(|answer| (answer.name().clone(), answer.record_type()));
+
+        message.insert_answers(
+            answers
+                .into_iter()
+                .filter(|answer| {
+                    rrset.as_ref().is_some_and(|(name, record_type)| {
+                        answer.name() == name && answer.record_type() == *record_type
+                    })
+                })
+                .collect(),
+        );
+    }
+}
+
+///
+/// Whether the name exists, but without any AAAA records (NODATA). Names that
+/// don't exist at all (NXDOMAIN) are never given synthesised records
+/// (RFC 6147, section 5.1.2).
+///
+fn nodata(response: &Result<DnsResponse, ResolveError>) -> bool {
+    match response {
+        Ok(response) => {
+            response.response_code() == ResponseCode::NoError
+                && !response
+                    .answers()
+                    .iter()
+                    .any(|answer| answer.record_type() == RecordType::AAAA)
+        }
+        Err(err) => matches!(
+            err.kind(),
+            NoRecordsFound {
+                response_code: ResponseCode::NoError,
+                ..
+            }
+        ),
+    }
+}
+
+///
+/// Whether the upstream answered, even if there wasn't anything to answer with
+///
+fn answered(result: &Result<DnsResponse, ResolveError>) -> bool {
+    match result {
+        Ok(_) => true,
+        Err(err) => matches!(err.kind(), NoRecordsFound { .. }),
+    }
+}
+
 pub struct Server;
 
 impl Server {
-    async fn forward(&self, request: &Request) -> Result<DnsResponse, ResolveError> {
-        let nameservers = Config::get(|config| config.upstreams.clone())
+    ///
+    /// Forward the request to each of the upstreams in turn until one of them
+    /// answers. When racing, the first few are asked all at once, and whichever
+    /// answers first wins, with the rest only asked should they all fail.
+    ///
+    async fn forward(
+        &self,
+        request: &Request,
+        name: &Name,
+        query_type: RecordType,
+    ) -> Result<DnsResponse, ResolveError> {
+        let (upstreams, options) =
+            Config::get(|config| (config.upstreams.clone(), config.resolver.clone())).await;
+
+        let upstreams = upstreams.into_iter().collect::<Vec<_>>();
+        let opts = options.opts();
+        let subnet = options.client_subnet.option(request);
+        let subnet = subnet.as_ref();
+
+        let raced = match options.strategy {
+            Strategy::Sequential => 0,
+            Strategy::Race => upstreams.len().min(resolver::RACED),
+        };
+        let (raced, rest) = upstreams.split_at(raced);
+
+        let mut result = Err(ResolveError::from(NoConnections));
+
+        if !raced.is_empty() {
+            // The losers are cancelled as soon as there's a winner
+            result = futures::future::select_ok(raced.iter().map(|upstream| {
+                Box::pin(async {
+                    let result =
+                        Self::ask(upstream, opts.clone(), request, name, query_type, subnet).await;
+                    if answered(&result) {
+                        Ok(result)
+                    } else {
+                        Err(result)
+                    }
+                })
+            }))
             .await
-            .iter()
-            .fold(
-                NameServerConfigGroup::default(),
-                |mut groups, &Upstream { ip, port }| {
-                    groups.merge(NameServerConfigGroup::from_ips_clear(&[ip], port, true));
-                    groups
-                },
+            .map_or_else(|result| result, |(result, _)| result);
+        }
+
+        for upstream in rest {
+            if answered(&result) {
+                break;
+            }
+
+            result = Self::ask(upstream, opts.clone(), request, name, query_type, subnet).await;
+        }
+
+        result
+    }
+
+    ///
+    /// Ask the upstream, recording how long it took (or whether it failed)
+    ///
+    async fn ask(
+        upstream: &Upstream,
+        opts: ResolverOpts,
+        request: &Request,
+        name: &Name,
+        query_type: RecordType,
+        subnet: Option<&EdnsOption>,
+    ) -> Result<DnsResponse, ResolveError> {
+        let labels = upstream.labels();
+        let timer = Instant::now();
+
+        let result = Self::lookup(upstream, opts, request, name, query_type, subnet).await;
+
+        if answered(&result) {
+            metrics::UPSTREAM_DURATION
+                .get_or_create(&labels)
+                .observe(timer.elapsed().as_nanos() as f64);
+        } else if let Err(err) = &result {
+            error!("Upstream {}:{} failed: {err}", upstream.ip, upstream.port);
+            metrics::UPSTREAM_ERRORS.get_or_create(&labels).inc();
+        }
+
+        result
+    }
+
+    ///
+    /// The name the upstreams have for an address, asking each of them in turn
+    /// until one of them answers
+    ///
+    pub(crate) async fn reverse(ip: IpAddr) -> Option<Name> {
+        let (upstreams, opts) =
+            Config::get(|config| (config.upstreams.clone(), config.resolver.opts())).await;
+
+        for upstream in upstreams {
+            let resolver = TokioAsyncResolver::tokio(
+                ResolverConfig::from_parts(None, vec![], upstream.nameservers()),
+                opts.clone(),
             );
 
-        let resolver = TokioAsyncResolver::tokio(
-            ResolverConfig::from_parts(None, vec![], nameservers),
-            ResolverOpts::default(),
-        );
+            match resolver.reverse_lookup(ip).await {
+                Ok(names) => return names.iter().next().map(|name| name.0.clone()),
+                Err(err) if matches!(err.kind(), NoRecordsFound { .. }) => return None,
+                Err(err) => debug!(
+                    "Upstream {}:{} couldn't look up {ip}: {err}",
+                    upstream.ip, upstream.port
+                ),
+            }
+        }
+
+        None
+    }
+
+    async fn lookup(
+        upstream: &Upstream,
+        opts: ResolverOpts,
+        request: &Request,
+        name: &Name,
+        query_type: RecordType,
+        subnet: Option<&EdnsOption>,
+    ) -> Result<DnsResponse, ResolveError> {
+        let (query, answers) = match subnet {
+            Some(subnet) => Self::query(upstream, opts, name, query_type, subnet).await?,
+            None => {
+                let resolver = TokioAsyncResolver::tokio(
+                    ResolverConfig::from_parts(None, vec![], upstream.nameservers()),
+                    opts,
+                );
+
+                let response = resolver.lookup(name.clone(), query_type).await?;
+                (response.query().clone(), response.records().to_vec())
+            }
+        };
 
         DnsResponse::from_message(
-            resolver
-                .lookup(request.query().name(), request.query().query_type())
-                .await
-                .map(|response| {
-                    Message::new()
-                        .set_header(
-                            *request
-                                .header()
-                                .clone()
-                                .set_answer_count(
-                                    u16::try_from(response.records().len()).unwrap_or_default(),
-                                )
-                                .set_message_type(MessageType::Response)
-                                .set_response_code(ResponseCode::NoError),
-                        )
-                        .add_answers(response.records().to_vec())
-                        .add_query(response.query().clone())
+            Message::new()
+                .set_header(
+                    *request
+                        .header()
                         .clone()
-                })?,
+                        .set_answer_count(u16::try_from(answers.len()).unwrap_or_default())
+                        .set_message_type(MessageType::Response)
+                        .set_response_code(ResponseCode::NoError),
+                )
+                .add_answers(answers)
+                .add_query(query)
+                .clone(),
         )
         .map_err(Into::into)
     }
 
+    ///
+    /// Ask the upstream directly, with the EDNS Client Subnet option, as the
+    /// resolver has no way of sending one
+    ///
+    async fn query(
+        upstream: &Upstream,
+        opts: ResolverOpts,
+        name: &Name,
+        query_type: RecordType,
+        subnet: &EdnsOption,
+    ) -> Result<(Query, Vec<Record>), ResolveError> {
+        let pool = NameServerPool::from_config(
+            upstream.nameservers(),
+            opts,
+            TokioConnectionProvider::default(),
+        );
+
+        let query = Query::query(name.clone(), query_type);
+
+        let mut edns = Edns::new();
+        edns.set_max_payload(UPSTREAM_PAYLOAD);
+        edns.options_mut().insert(subnet.clone());
+
+        let mut message = Message::new();
+        message
+            .set_recursion_desired(true)
+            .add_query(query.clone())
+            .set_edns(edns);
+
+        let response = pool
+            .send(DnsRequest::new(message, DnsRequestOptions::default()))
+            .first_answer()
+            .await?;
+
+        Ok((query, response.answers().to_vec()))
+    }
+
+    async fn reject<R: ResponseHandler>(
+        client: IpAddr,
+        rejection: Rejection,
+        request: &Request,
+        mut response_handle: R,
+    ) -> ResponseInfo {
+        // Rejected clients could be anyone, so they're only logged rather than
+        // each getting their own series
+        debug!("Rejecting request from {client}");
+        metrics::REJECTED.inc();
+
+        match rejection {
+            Rejection::Refuse => response_handle
+                .send_response(
+                    MessageResponseBuilder::from_message_request(request)
+                        .error_msg(request.header(), ResponseCode::Refused),
+                )
+                .await
+                .unwrap_or_else(|err| {
+                    error!("{err}");
+                    (*request.header()).into()
+                }),
+            Rejection::Drop => (*request.header()).into(),
+        }
+    }
+
+    ///
+    /// Answer with the rule's CNAME, along with whatever its target resolves to
+    ///
+    async fn redirect(
+        &self,
+        rule: &Rule,
+        target: &Name,
+        request: &Request,
+    ) -> Result<DnsResponse, ResolveError> {
+        let mut response = rule.apply(request).into_message();
+
+        let resolved = self
+            .forward(request, target, request.query().query_type())
+            .await;
+
+        match resolved {
+            Ok(resolved) => {
+                response.add_answers(resolved.answers().iter().cloned());
+            }
+            // The CNAME on its own is still a perfectly valid answer
+            Err(err) if matches!(err.kind(), NoRecordsFound { .. }) => {}
+            Err(err) => return Err(err),
+        }
+
+        DnsResponse::from_message(response).map_err(Into::into)
+    }
+
+    ///
+    /// Answer an AAAA query with the name's A records embedded within the NAT64
+    /// prefix (RFC 6147), or None if it hasn't any that can be
+    ///
+    async fn synthesise(
+        &self,
+        request: &Request,
+        name: &Name,
+        dns64: &Dns64,
+    ) -> Option<DnsResponse> {
+        let resolved = self.forward(request, name, RecordType::A).await.ok()?;
+
+        let answers = dns64.answers(resolved.answers());
+        if !answers
+            .iter()
+            .any(|answer| answer.record_type() == RecordType::AAAA)
+        {
+            return None;
+        }
+
+        let message = Message::new()
+            .set_header(
+                *resolved
+                    .header()
+                    .clone()
+                    .set_answer_count(u16::try_from(answers.len()).unwrap_or_default()),
+            )
+            .add_query(request.query().original().clone())
+            .add_answers(answers)
+            .clone();
+
+        DnsResponse::from_message(message).ok()
+    }
+
+    ///
+    /// Work out the answer to the request from the client, be it from a rule,
+    /// the cache, or upstream. Fresh answers always come from upstream,
+    /// bypassing the cache.
+    ///
+    async fn answer(
+        &self,
+        request: &Request,
+        client: IpAddr,
+        stat: &mut statistics::Request,
+        fresh: bool,
+    ) -> Result<DnsResponse, ResolveError> {
+        let timer = Instant::now();
+
+        // Check the fiter first, as we need to check it anyways if it's in the cache
+        // TODO: Does it make sense to also cache the filter result?
+        let (policy, safesearch, dns64, special_use) = Config::get(|config| {
+            (
+                config.policy.clone(),
+                config.safesearch.clone(),
+                config.dns64.clone(),
+                config.special_use.clone(),
+            )
+        })
+        .await;
+        let rule = match Filter::check(request, client, &policy) {
+            Some(rule) if rule.answers_locally() => Some(rule),
+            // SafeSearch only gives way to rules we'd answer ourselves anyways
+            rule if safesearch.enforced(client) => {
+                safesearch::rule(request.query().original().name()).or(rule)
+            }
+            rule => rule,
+        };
+        // Local records (and the PTRs generated for them) come before the
+        // names clients are known by
+        let rule = match rule {
+            Some(rule) if rule.answers_locally() => Some(rule),
+            rule if request.query().query_type() == RecordType::PTR => {
+                clients::ptr(request.query().original().name())
+                    .await
+                    .or(rule)
+            }
+            rule => rule,
+        };
+        stat.rule(rule.clone());
+        stat.timings.filter = Some(timer.elapsed().as_nanos() as usize);
+
+        if let Some(rule) = rule.filter(Rule::answers_locally) {
+            return match rule.cname() {
+                Some(target) => self.redirect(&rule, &target, request).await,
+                None => Ok(rule.apply(request)),
+            };
+        }
+
+        // Rules can still answer for names within the special-use domains (e.g.
+        // hosts on the local network), but nothing else about them goes upstream
+        if let Some(response) = special_use.answer(request) {
+            return Ok(response);
+        }
+
+        let cached = if fresh {
+            None
+        } else {
+            let timer = Instant::now();
+            let cached = Cache::get(request).await;
+            stat.timings.cache = Some(timer.elapsed().as_nanos() as usize);
+            cached
+        };
+
+        if let Some(response) = cached {
+            stat.cached(true);
+            return Ok(response);
+        }
+
+        let timer = Instant::now();
+        let name = Name::from(request.query().name().clone());
+        let query_type = request.query().query_type();
+        let response = self.forward(request, &name, query_type).await;
+
+        // Only once we know there aren't any AAAA records is one synthesised
+        let synthesise = dns64.enabled && query_type == RecordType::AAAA && nodata(&response);
+
+        let response = if synthesise {
+            self.synthesise(request, &name, &dns64)
+                .await
+                .map_or(response, Ok)
+        } else {
+            response
+        };
+        stat.timings.upstream = Some(timer.elapsed().as_nanos() as usize);
+
+        response
+    }
+
+    ///
+    /// Resolve the name through the same pipeline as any other request, without
+    /// recording it, returning what was decided along the way
+    ///
+    /// # Errors
+    /// If a request for the name can't be constructed
+    ///
+    pub async fn resolve(
+        &self,
+        name: Name,
+        query_type: RecordType,
+        fresh: bool,
+    ) -> Result<statistics::Request, ProtoError> {
+        let mut message = Message::new();
+        message
+            .set_recursion_desired(true)
+            .add_query(Query::query(name, query_type));
+
+        let request = Request::new(
+            MessageRequest::read(&mut BinDecoder::new(&message.to_vec()?))?,
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            Transport::Udp,
+        );
+
+        let identity = Identity::of(&request, &[]);
+
+        let mut stat = statistics::Request::default();
+        stat.client(identity.to_string())
+            .question(request.query().original().name().to_string())
+            .query_type(query_type)
+            .protocol(request.protocol().to_string());
+
+        let timer = Instant::now();
+        let mut response = self
+            .answer(&request, identity.address, &mut stat, fresh)
+            .await;
+
+        let code = match &mut response {
+            Ok(response) => {
+                let (min_ttl, max_ttl) =
+                    Config::get(|config| (config.min_ttl, config.max_ttl)).await;
+                clamp_upstream(&stat, response, min_ttl, max_ttl);
+
+                stat.answers(response.answers());
+
+                if Self::should_cache(&stat, response) {
+                    Cache::insert(&request, response).await;
+                }
+
+                response.response_code()
+            }
+            Err(err) => Self::error_code(err),
+        };
+
+        stat.elapsed(timer.elapsed().as_nanos() as usize)
+            .code(code.to_string());
+
+        Ok(stat)
+    }
+
+    ///
+    /// We should only ever cache responses that:
+    /// a) Are not already in the cache
+    /// b) Weren't a failure (otherwise we're likely to retrieve invalid responses)
+    /// c) We didn't answer ourselves
+    /// d) Have answers, as there would be no TTL for the entry to expire by
+    ///
+    fn should_cache(stat: &statistics::Request, response: &DnsResponse) -> bool {
+        !stat.cached
+            && response.response_code() != ResponseCode::ServFail
+            && !stat.rule.as_ref().is_some_and(Rule::answers_locally)
+            && !response.answers().is_empty()
+    }
+
+    fn error_code(err: &ResolveError) -> ResponseCode {
+        match err.kind() {
+            NoRecordsFound { .. } => ResponseCode::NXDomain,
+            ResolverMessage(_) | Msg(_) | NoConnections | Io(_) | Proto(_) | Timeout => {
+                error!("{err}");
+                ResponseCode::ServFail
+            }
+            _ => ResponseCode::ServFail,
+        }
+    }
+
+    ///
+    /// Responses are only padded over encrypted transports, where padding
+    /// actually hides something, and when the client supports EDNS
+    ///
+    async fn should_pad(request: &Request) -> bool {
+        request.edns().is_some()
+            && matches!(
+                request.protocol(),
+                Transport::Tls | Transport::Https | Transport::Quic | Transport::H3
+            )
+            && Config::get(|config| config.padding).await
+    }
+
     async fn create_response<R: ResponseHandler>(
         stat: &mut statistics::Request,
         request: &Request,
         response: &mut Result<DnsResponse, ResolveError>,
+        strip_ech: bool,
         mut response_handle: R,
     ) -> Result<ResponseInfo, std::io::Error> {
-        let builder = MessageResponseBuilder::from_message_request(request);
+        let mut builder = MessageResponseBuilder::from_message_request(request);
+        let pad = Self::should_pad(request).await;
 
         match response.as_mut() {
             Ok(response) => {
+                let (min_ttl, max_ttl) =
+                    Config::get(|config| (config.min_ttl, config.max_ttl)).await;
+                clamp_upstream(stat, response, min_ttl, max_ttl);
+
                 let mut resp = response.clone().into_message();
                 resp.set_id(request.id());
-                stat.answers(response.answers());
+                if Config::get(|config| config.minimal_responses).await {
+                    minimize(&mut resp, request.query().query_type());
+                }
+                // Only what's sent is stripped, as what's cached is shared with
+                // clients that the stripping doesn't apply to
+                if strip_ech {
+                    let answers = types::strip_ech(resp.answers());
+                    resp.insert_answers(answers);
+                }
+                stat.answers(resp.answers());
 
-                if !stat.cached
-                    && resp.response_code() != ResponseCode::ServFail
-                    && stat.rule.is_none()
-                {
-                    // We should only ever cache requests that:
-                    // a) Are not already in the cache
-                    // b) The response wasn't a failure (otherwise we're likely to retrieve invalid responses)
-                    // c) There's no rule for the request
-                    Cache::insert(&*response).await;
+                if pad {
+                    builder.edns(padding(
+                        Message::new()
+                            .set_header(*resp.header())
+                            .add_query(request.query().original().clone())
+                            .add_answers(resp.answers().iter().cloned())
+                            .add_name_servers(resp.name_servers().iter().cloned())
+                            .add_additionals(resp.additionals().iter().cloned()),
+                    ));
+                }
+
+                if Self::should_cache(stat, response) {
+                    Cache::insert(request, &*response).await;
                 }
 
                 response_handle
                     .send_response(builder.build(
                         *resp.header(),
                         resp.answers(),
-                        request.name_servers(),
+                        resp.name_servers(),
                         &[],
-                        request.additionals(),
+                        resp.additionals(),
                     ))
                     .await
             }
             Err(err) => {
-                let response = match err.kind() {
-                    NoRecordsFound { .. } => {
-                        builder.error_msg(request.header(), ResponseCode::NXDomain)
-                    }
-                    ResolverMessage(_) | Msg(_) | NoConnections | Io(_) | Proto(_) | Timeout => {
-                        error!("{err}");
-                        builder.error_msg(request.header(), ResponseCode::ServFail)
-                    }
-                    _ => builder.error_msg(request.header(), ResponseCode::ServFail),
-                };
-                response_handle.send_response(response).await
+                if pad {
+                    builder.edns(padding(
+                        Message::new()
+                            .set_header(*request.header())
+                            .add_query(request.query().original().clone()),
+                    ));
+                }
+
+                response_handle
+                    .send_response(builder.error_msg(request.header(), Self::error_code(err)))
+                    .await
             }
         }
     }
@@ -160,46 +897,79 @@ impl Server {
 
 #[async_trait::async_trait]
 impl RequestHandler for Server {
+    ///
+    /// Requests only ever reach us with exactly one question, those with none
+    /// (or more than one) are answered with a FORMERR while being parsed, so
+    /// it's always safe to look at `request.query()` here
+    ///
     async fn handle_request<R: ResponseHandler>(
         &self,
         request: &Request,
         response_handle: R,
     ) -> ResponseInfo {
+        let Some(_turn) = queue::admit().await else {
+            return (*request.header()).into();
+        };
+        let _in_flight = InFlight::start();
+        let identity = clients::identify(request).await;
+        let client = identity.address;
+
+        let query_type = request.query().query_type();
+        let (rejection, action) = Config::get(|config| {
+            (
+                // Whoever is actually connecting has to be let in, regardless
+                // of who they're asking for
+                (!config.acl.allows(identity.peer)).then_some(config.acl.action),
+                types::action(&config.query_types, query_type, client),
+            )
+        })
+        .await;
+
+        if let Some(rejection) = rejection {
+            return Self::reject(identity.peer, rejection, request, response_handle).await;
+        }
+
         let mut stat = statistics::Request::default();
-        stat.client(request.src().ip().to_canonical().to_string())
+        stat.client(identity.to_string())
+            .name(clients::name(client).await)
+            .mac(identity.mac.clone())
             .question(request.query().original().name().to_string())
-            .query_type(request.query().original().query_type());
+            .query_type(request.query().original().query_type())
+            .protocol(request.protocol().to_string());
 
         let timer = Instant::now();
 
-        // Check the fiter first, as we need to check it anyways if it's in the cache
-        // TODO: Does it make sense to also cache the filter result?
-        let mut response = if let Some(rule) = Filter::check(request) {
-            stat.rule(Some(rule.clone()));
-            Ok(rule.apply(request))
-        } else if let Some(response) = Cache::get(request).await {
-            stat.cached(true);
-            Ok(response)
-        } else {
-            self.forward(request).await
+        let mut response = match action.and_then(TypeAction::refusal) {
+            Some(code) => Ok(types::refuse(request, code)),
+            None => self.answer(request, client, &mut stat, false).await,
         };
 
-        let response = Self::create_response(&mut stat, request, &mut response, response_handle)
-            .await
-            .unwrap_or_else(|err| {
-                error!("{err}");
-                (*request.header()).into()
-            });
+        let strip_ech = action == Some(TypeAction::StripEch);
+        let response = Self::create_response(
+            &mut stat,
+            request,
+            &mut response,
+            strip_ech,
+            response_handle,
+        )
+        .await
+        .unwrap_or_else(|err| {
+            error!("{err}");
+            (*request.header()).into()
+        });
+
+        anomaly::record(response.response_code(), &identity.to_string()).await;
 
         let elapsed = timer.elapsed().as_nanos() as usize;
 
         stat.elapsed(elapsed)
             .code(response.response_code().to_string());
 
-        Statistics::record(crate::statistics::Statistic::Request(stat));
+        Statistics::record(crate::statistics::Statistic::Request(Box::new(stat)));
         Statistics::record(crate::statistics::Statistic::Average(Average {
             count: 1,
             average: elapsed,
+            latency: None,
         }));
 
         response
@@ -213,6 +983,18 @@ impl statistics::Request {
         self
     }
 
+    #[inline]
+    fn name(&mut self, name: Option<String>) -> &mut Self {
+        self.name = name;
+        self
+    }
+
+    #[inline]
+    fn mac(&mut self, mac: Option<String>) -> &mut Self {
+        self.mac = mac;
+        self
+    }
+
     #[inline]
     fn query_type(&mut self, query_type: RecordType) -> &mut Self {
         self.query_type = query_type;
@@ -254,20 +1036,313 @@ impl statistics::Request {
         self.cached = cached;
         self
     }
+
+    #[inline]
+    fn protocol(&mut self, protocol: String) -> &mut Self {
+        self.protocol = protocol;
+        self
+    }
 }
 
 impl Default for statistics::Request {
     fn default() -> Self {
         Self {
             client: String::default(),
+            name: None,
+            mac: None,
             question: String::default(),
             query_type: RecordType::A,
             answers: Vec::default(),
             rule: Option::default(),
             status: String::default(),
             elapsed: 0,
+            timings: statistics::Timings::default(),
             timestamp: SystemTime::now(),
             cached: false,
+            protocol: String::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use hickory_proto::{
+        error::ProtoErrorKind,
+        op::{Header, Message, Query, ResponseCode},
+        rr::{
+            rdata::{A, NS},
+            Name, RData, Record, RecordType,
+        },
+        serialize::binary::{BinDecodable, BinDecoder},
+        xfer::DnsResponse,
+    };
+    use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+    use hickory_server::{
+        authority::MessageRequest,
+        server::{Protocol as Transport, Request},
+    };
+    use pretty_assertions::assert_eq;
+
+    use crate::{cache::Cache, statistics};
+
+    use super::{
+        clamp_ttls, clamp_upstream, drained, in_flight, minimize, nodata, padding, Acl, InFlight,
+        Protocol, Upstream, RESPONSE_BLOCK_SIZE,
+    };
+
+    #[test]
+    fn dns64_only_for_nodata() {
+        let query = Query::query(Name::from_ascii("example.com.").unwrap(), RecordType::AAAA);
+        let negative = |response_code| {
+            Err(ResolveError::from(ResolveErrorKind::NoRecordsFound {
+                query: Box::new(query.clone()),
+                soa: None,
+                negative_ttl: None,
+                response_code,
+                trusted: true,
+            }))
+        };
+
+        assert!(nodata(&negative(ResponseCode::NoError)));
+        // The name doesn't exist, so there's nothing to synthesise for
+        assert!(!nodata(&negative(ResponseCode::NXDomain)));
+
+        let mut message = Message::new();
+        message.add_query(query.clone());
+        assert!(nodata(&Ok(
+            DnsResponse::from_message(message.clone()).unwrap()
+        )));
+
+        message.set_response_code(ResponseCode::NXDomain);
+        assert!(!nodata(&Ok(DnsResponse::from_message(message).unwrap())));
+    }
+
+    #[test]
+    fn padding_to_block_size() {
+        let mut message = Message::new();
+        message.set_header(Header::new()).add_query(Query::query(
+            Name::from_ascii("example.com.").unwrap(),
+            RecordType::A,
+        ));
+
+        let edns = padding(&mut message);
+        message.set_edns(edns);
+
+        assert_eq!(message.to_vec().unwrap().len() % RESPONSE_BLOCK_SIZE, 0);
+    }
+
+    #[test]
+    fn ttl_clamping() {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let mut message = Message::new();
+        message
+            .add_query(Query::query(name.clone(), RecordType::A))
+            .add_answers([0, 300, 172_800].map(|ttl| {
+                Record::from_rdata(name.clone(), ttl, RData::A(A::new(93, 184, 216, 34)))
+            }));
+        let response = DnsResponse::from_message(message).unwrap();
+
+        let ttls = |response: DnsResponse| {
+            response
+                .answers()
+                .iter()
+                .map(Record::ttl)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            ttls(clamp_ttls(&response, Some(60), Some(86_400))),
+            [60, 300, 86_400]
+        );
+        assert_eq!(
+            ttls(clamp_ttls(&response, Some(60), None)),
+            [60, 300, 172_800]
+        );
+        assert_eq!(ttls(clamp_ttls(&response, None, None)), [0, 300, 172_800]);
+    }
+
+    #[tokio::test]
+    async fn ttls_clamped_before_caching() {
+        let name = Name::from_ascii("clamped.example.com.").unwrap();
+        let mut message = Message::new();
+        message.add_query(Query::query(name.clone(), RecordType::A));
+
+        let request = Request::new(
+            MessageRequest::read(&mut BinDecoder::new(&message.to_vec().unwrap())).unwrap(),
+            "127.0.0.1:53".parse().unwrap(),
+            Transport::Udp,
+        );
+
+        message.add_answer(Record::from_rdata(
+            name,
+            0,
+            RData::A(A::new(93, 184, 216, 34)),
+        ));
+        let mut response = DnsResponse::from_message(message).unwrap();
+
+        // Already cached, and so already clamped
+        let mut stat = statistics::Request {
+            cached: true,
+            ..Default::default()
+        };
+        clamp_upstream(&stat, &mut response, Some(60), None);
+        assert_eq!(response.answers()[0].ttl(), 0);
+
+        stat.cached = false;
+        clamp_upstream(&stat, &mut response, Some(60), None);
+        Cache::insert(&request, &response).await;
+
+        let cached = Cache::get(&request).await.unwrap();
+        assert_eq!(cached.answers()[0].ttl(), 60);
+    }
+
+    #[test]
+    fn minimal_responses() {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let ns = Name::from_ascii("ns.example.com.").unwrap();
+        let mut message = Message::new();
+        message
+            .add_query(Query::query(name.clone(), RecordType::ANY))
+            .add_answers([
+                Record::from_rdata(name.clone(), 300, RData::A(A::new(10, 0, 0, 1))),
+                Record::from_rdata(name.clone(), 300, RData::A(A::new(10, 0, 0, 2))),
+                Record::from_rdata(name.clone(), 300, RData::NS(NS(ns.clone()))),
+            ])
+            .add_name_server(Record::from_rdata(name, 300, RData::NS(NS(ns.clone()))))
+            .add_additional(Record::from_rdata(ns, 300, RData::A(A::new(10, 0, 0, 53))));
+
+        let mut any = message.clone();
+        minimize(&mut any, RecordType::ANY);
+        assert_eq!(any.answers(), &message.answers()[..2]);
+        assert!(any.name_servers().is_empty());
+        assert!(any.additionals().is_empty());
+
+        let mut other = message.clone();
+        minimize(&mut other, RecordType::A);
+        assert_eq!(other.answers(), message.answers());
+        assert!(other.additionals().is_empty());
+    }
+
+    #[test]
+    fn truncation_falls_back_to_tcp() {
+        let upstream = Upstream {
+            ip: "1.1.1.1".parse().unwrap(),
+            port: 53,
+            protocol: Protocol::Udp,
+        };
+
+        // Truncated UDP responses are retried over TCP, so it needs to be there
+        // to retry over
+        let protocols = upstream
+            .nameservers()
+            .iter()
+            .map(|nameserver| nameserver.protocol)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            protocols,
+            [
+                hickory_resolver::config::Protocol::Udp,
+                hickory_resolver::config::Protocol::Tcp
+            ]
+        );
+    }
+
+    #[test]
+    fn acl() {
+        assert!(Acl::default().allows("203.0.113.1".parse().unwrap()));
+
+        let acl = Acl {
+            allow: vec![
+                "192.168.0.0/16".parse().unwrap(),
+                "fd00::/8".parse().unwrap(),
+            ],
+            ..Default::default()
+        };
+
+        assert!(acl.allows("192.168.1.10".parse().unwrap()));
+        assert!(acl.allows("fd00::1".parse().unwrap()));
+        assert!(!acl.allows("203.0.113.1".parse().unwrap()));
+        assert!(!acl.allows("2001:db8::1".parse().unwrap()));
+
+        let acl = Acl {
+            deny: vec!["192.168.1.0/24".parse().unwrap()],
+            ..acl
+        };
+
+        assert!(acl.allows("192.168.2.10".parse().unwrap()));
+        assert!(!acl.allows("192.168.1.10".parse().unwrap()));
+
+        let acl = Acl {
+            deny: vec!["203.0.113.0/24".parse().unwrap()],
+            ..Default::default()
+        };
+
+        assert!(acl.allows("192.168.1.10".parse().unwrap()));
+        assert!(!acl.allows("203.0.113.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn draining() {
+        let request = InFlight::start();
+        assert!(in_flight() >= 1);
+
+        let drained = tokio::spawn(drained());
+        tokio::task::yield_now().await;
+        assert!(!drained.is_finished());
+
+        drop(request);
+        tokio::time::timeout(Duration::from_secs(1), drained)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn question_count() {
+        for count in 0..4 {
+            let mut message = Message::new();
+            message.set_header(Header::new());
+            for idx in 0..count {
+                message.add_query(Query::query(
+                    Name::from_ascii(format!("{idx}.example.com.")).unwrap(),
+                    RecordType::A,
+                ));
+            }
+
+            let bytes = message.to_vec().unwrap();
+            let request = MessageRequest::read(&mut BinDecoder::new(&bytes));
+
+            if count == 1 {
+                assert_eq!(
+                    request.unwrap().query().name().to_string(),
+                    "0.example.com."
+                );
+            } else {
+                assert!(
+                    matches!(
+                        request.map_err(|err| err.kind().clone()),
+                        Err(ProtoErrorKind::FormError { .. })
+                    ),
+                    "{count} questions should be a format error"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_questions() {
+        let mut message = Message::new();
+        message.set_header(Header::new()).add_query(Query::query(
+            Name::from_ascii("example.com.").unwrap(),
+            RecordType::A,
+        ));
+
+        let bytes = message.to_vec().unwrap();
+        for len in 0..bytes.len() {
+            // Anything cut short must be rejected, rather than panicking
+            assert!(MessageRequest::read(&mut BinDecoder::new(&bytes[..len])).is_err());
         }
     }
 }
@@ -0,0 +1,121 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Semaphore, SemaphorePermit, TryAcquireError};
+use tracing::debug;
+
+use crate::metrics;
+
+const fn default_in_flight() -> usize {
+    1024
+}
+
+const fn default_waiting() -> usize {
+    4096
+}
+
+///
+/// How many requests are answered at once, and how many more may wait to be,
+/// so that a flood of requests can't take up every bit of memory
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Queue {
+    /// How many requests are answered at once
+    #[serde(default = "default_in_flight")]
+    pub in_flight: usize,
+    /// How many requests may wait for one of those to be answered, with any
+    /// more dropped without an answer
+    #[serde(default = "default_waiting")]
+    pub waiting: usize,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self {
+            in_flight: default_in_flight(),
+            waiting: default_waiting(),
+        }
+    }
+}
+
+/// A permit for each request that may be answered at once
+static ANSWERING: Semaphore = Semaphore::const_new(0);
+/// How many permits there are in all, including those in use
+static PERMITS: AtomicUsize = AtomicUsize::new(0);
+static WAITING: AtomicUsize = AtomicUsize::new(0);
+static MAX_WAITING: AtomicUsize = AtomicUsize::new(0);
+
+///
+/// Apply any changes to the limits
+///
+pub fn configure(queue: &Queue) {
+    MAX_WAITING.store(queue.waiting, Ordering::Relaxed);
+
+    let permits = PERMITS.swap(queue.in_flight, Ordering::SeqCst);
+    if queue.in_flight > permits {
+        ANSWERING.add_permits(queue.in_flight - permits);
+    } else if permits > queue.in_flight {
+        // Permits in use can only be taken back once they're done with
+        let excess = permits - queue.in_flight;
+        let forgotten = ANSWERING.forget_permits(excess);
+
+        if forgotten < excess {
+            tokio::spawn(async move {
+                let excess = u32::try_from(excess - forgotten).unwrap_or(u32::MAX);
+                if let Ok(permits) = ANSWERING.acquire_many(excess).await {
+                    permits.forget();
+                }
+            });
+        }
+    }
+}
+
+fn record(outcome: &str) {
+    metrics::QUEUE
+        .get_or_create(&metrics::Queued {
+            outcome: outcome.to_string(),
+        })
+        .inc();
+}
+
+///
+/// A request's turn to be answered, which lasts until it's dropped
+///
+pub(super) struct Turn {
+    _permit: Option<SemaphorePermit<'static>>,
+}
+
+///
+/// Wait for the request's turn to be answered, should too many be answered
+/// already. If too many are waiting as it is, the request is to be dropped.
+///
+pub(super) async fn admit() -> Option<Turn> {
+    // Nothing's limited until the limits have been configured
+    if PERMITS.load(Ordering::SeqCst) == 0 {
+        return Some(Turn { _permit: None });
+    }
+
+    match ANSWERING.try_acquire() {
+        Ok(permit) => {
+            return Some(Turn {
+                _permit: Some(permit),
+            });
+        }
+        Err(TryAcquireError::Closed) => return Some(Turn { _permit: None }),
+        Err(TryAcquireError::NoPermits) => {}
+    }
+
+    if WAITING.fetch_add(1, Ordering::SeqCst) >= MAX_WAITING.load(Ordering::Relaxed) {
+        WAITING.fetch_sub(1, Ordering::SeqCst);
+        debug!("Too many requests waiting to be answered, dropping one");
+        record("dropped");
+        return None;
+    }
+
+    record("queued");
+    let permit = ANSWERING.acquire().await.ok();
+    WAITING.fetch_sub(1, Ordering::SeqCst);
+
+    Some(Turn { _permit: permit })
+}
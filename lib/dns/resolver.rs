@@ -0,0 +1,169 @@
+use std::{net::IpAddr, time::Duration};
+
+use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use hickory_resolver::config::ResolverOpts;
+use hickory_server::server::Request;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+///
+/// How many upstreams are asked at once when racing them
+///
+pub(crate) const RACED: usize = 2;
+
+const fn default_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+const fn default_attempts() -> usize {
+    2
+}
+
+///
+/// How the upstreams are asked
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    /// Each in turn, moving on to the next only when one fails
+    #[default]
+    Sequential,
+    /// The first two at once, going with whichever answers first, which helps
+    /// when one of them is occasionally slow
+    Race,
+}
+
+///
+/// What the upstreams are told about the network a request came from, with the
+/// EDNS Client Subnet option (RFC 7871)
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientSubnet {
+    /// Nothing at all, for the most privacy
+    #[default]
+    Strip,
+    /// Whatever the client sent, should it have sent the option, which lets
+    /// CDNs answer with somewhere close to the client
+    Forward,
+    /// The same network for every request (e.g. the /24 of our public
+    /// address), which helps CDNs without giving anything away about clients
+    Fixed(IpNet),
+}
+
+impl ClientSubnet {
+    ///
+    /// The option to ask the upstreams about the request with, if any
+    ///
+    pub(crate) fn option(self, request: &Request) -> Option<EdnsOption> {
+        match self {
+            Self::Strip => None,
+            Self::Forward => request.edns()?.option(EdnsCode::Subnet).cloned(),
+            Self::Fixed(network) => Some(EdnsOption::Unknown(
+                u16::from(EdnsCode::Subnet),
+                encode(network),
+            )),
+        }
+    }
+}
+
+///
+/// The network as it goes in the option: the address family, the source and
+/// scope prefix lengths, and then only as much of the address as the prefix
+/// covers
+///
+fn encode(network: IpNet) -> Vec<u8> {
+    let network = network.trunc();
+    let (family, address) = match network.addr() {
+        IpAddr::V4(ip) => (1_u16, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+    };
+    let prefix = network.prefix_len();
+
+    let mut option = family.to_be_bytes().to_vec();
+    option.extend([prefix, 0]);
+    option.extend(&address[..usize::from(prefix).div_ceil(8)]);
+
+    option
+}
+
+///
+/// Options for how requests are forwarded to the upstreams
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Resolver {
+    /// Tell the upstreams we support EDNS(0), which lets them send larger
+    /// responses over UDP rather than having us retry over TCP
+    #[serde(default)]
+    pub edns0: bool,
+    /// How long to wait for an upstream to answer
+    #[serde(with = "humantime_serde", default = "default_timeout")]
+    pub timeout: Duration,
+    /// How many times to ask an upstream before moving on to the next one
+    #[serde(default = "default_attempts")]
+    pub attempts: usize,
+    #[serde(default)]
+    pub strategy: Strategy,
+    #[serde(default)]
+    pub client_subnet: ClientSubnet,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self {
+            edns0: false,
+            timeout: default_timeout(),
+            attempts: default_attempts(),
+            strategy: Strategy::default(),
+            client_subnet: ClientSubnet::default(),
+        }
+    }
+}
+
+impl Resolver {
+    ///
+    /// The options for the resolvers asking the upstreams
+    ///
+    pub fn opts(&self) -> ResolverOpts {
+        let mut opts = ResolverOpts::default();
+        opts.edns0 = self.edns0;
+        opts.timeout = self.timeout;
+        opts.attempts = self.attempts;
+
+        opts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hickory_resolver::config::ResolverOpts;
+    use pretty_assertions::assert_eq;
+
+    use super::{encode, Resolver};
+
+    #[test]
+    fn defaults() {
+        // Nothing changes unless asked to
+        let (opts, defaults) = (Resolver::default().opts(), ResolverOpts::default());
+
+        assert_eq!(opts.edns0, defaults.edns0);
+        assert_eq!(opts.timeout, defaults.timeout);
+        assert_eq!(opts.attempts, defaults.attempts);
+    }
+
+    #[test]
+    fn subnets() {
+        assert_eq!(
+            encode("203.0.113.7/24".parse().unwrap()),
+            vec![0, 1, 24, 0, 203, 0, 113]
+        );
+        assert_eq!(
+            encode("2001:db8:1234:5678::/56".parse().unwrap()),
+            vec![0, 2, 56, 0, 0x20, 0x01, 0x0d, 0xb8, 0x12, 0x34, 0x56]
+        );
+        assert_eq!(encode("0.0.0.0/0".parse().unwrap()), vec![0, 1, 0, 0]);
+    }
+}
@@ -0,0 +1,214 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use hickory_proto::{
+    op::{Message, MessageType, ResponseCode},
+    rr::{Name, RData, Record, RecordType},
+    xfer::DnsResponse,
+};
+use hickory_server::server::Request;
+use serde::{Deserialize, Serialize};
+
+use crate::filter::rules::TTL;
+
+///
+/// The special-use domains (RFC 6761 and those since) which only have meaning
+/// on the local network, if anywhere, so have no business going upstream
+///
+const BUILTIN: [&str; 8] = [
+    "alt",
+    "home.arpa",
+    "internal",
+    "invalid",
+    "local",
+    "localhost",
+    "onion",
+    "test",
+];
+
+const fn default_enabled() -> bool {
+    true
+}
+
+///
+/// Options for answering queries for special-use domains ourselves, rather
+/// than forwarding them upstream
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SpecialUse {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// More domains to answer for, on top of those built in (e.g. "lan")
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// Those of the built in domains to forward regardless, e.g. "local" when
+    /// the upstream proxies mDNS
+    #[serde(default)]
+    pub forward: Vec<String>,
+}
+
+impl Default for SpecialUse {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            domains: Vec::new(),
+            forward: Vec::new(),
+        }
+    }
+}
+
+impl SpecialUse {
+    ///
+    /// The special-use domain the name falls within, if any
+    ///
+    fn zone(&self, name: &Name) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+
+        let name = name.to_lowercase().to_string();
+        let name = name.trim_end_matches('.');
+
+        BUILTIN
+            .iter()
+            .copied()
+            .filter(|domain| {
+                !self
+                    .forward
+                    .iter()
+                    .any(|forward| forward.trim_matches('.').eq_ignore_ascii_case(domain))
+            })
+            .chain(self.domains.iter().map(String::as_str))
+            .map(|domain| domain.trim_matches('.').to_lowercase())
+            .find(|domain| {
+                name == domain
+                    || name
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|name| name.ends_with('.'))
+            })
+    }
+
+    ///
+    /// How we answer a query for the name, should it fall within one of the
+    /// special-use domains. Names under localhost resolve to the loopback
+    /// addresses (RFC 6761, section 6.3), and anything else doesn't exist.
+    ///
+    fn records(&self, name: &Name, query_type: RecordType) -> Option<(ResponseCode, Vec<Record>)> {
+        let zone = self.zone(name)?;
+
+        if zone != "localhost" {
+            return Some((ResponseCode::NXDomain, Vec::new()));
+        }
+
+        let loopback = match query_type {
+            RecordType::A => Some(RData::A(Ipv4Addr::LOCALHOST.into())),
+            RecordType::AAAA => Some(RData::AAAA(Ipv6Addr::LOCALHOST.into())),
+            _ => None,
+        };
+
+        Some((
+            ResponseCode::NoError,
+            loopback
+                .map(|data| Record::from_rdata(name.clone(), TTL, data))
+                .into_iter()
+                .collect(),
+        ))
+    }
+
+    ///
+    /// Our answer to the request, should it be for a special-use domain
+    ///
+    pub fn answer(&self, request: &Request) -> Option<DnsResponse> {
+        let query = request.query();
+        let (code, answers) = self.records(query.original().name(), query.query_type())?;
+
+        let message = Message::new()
+            .set_header(
+                *request
+                    .header()
+                    .clone()
+                    .set_answer_count(answers.len().try_into().unwrap_or_default())
+                    .set_message_type(MessageType::Response)
+                    .set_response_code(code),
+            )
+            .add_answers(answers)
+            .add_query(query.original().clone())
+            .clone();
+
+        DnsResponse::from_message(message).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hickory_proto::{
+        op::ResponseCode,
+        rr::{Name, RData, RecordType},
+    };
+    use pretty_assertions::assert_eq;
+
+    use super::SpecialUse;
+
+    fn name(name: &str) -> Name {
+        Name::from_ascii(name).unwrap()
+    }
+
+    #[test]
+    fn zones() {
+        let special = SpecialUse {
+            domains: vec![String::from("lan.")],
+            forward: vec![String::from("local")],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            special.zone(&name("printer.home.arpa.")),
+            Some(String::from("home.arpa"))
+        );
+        assert_eq!(
+            special.zone(&name("Facebook.ONION.")),
+            Some(String::from("onion"))
+        );
+        assert_eq!(special.zone(&name("nas.lan.")), Some(String::from("lan")));
+        assert_eq!(
+            special.zone(&name("invalid.")),
+            Some(String::from("invalid"))
+        );
+        // Forwarded regardless
+        assert_eq!(special.zone(&name("printer.local.")), None);
+        assert_eq!(special.zone(&name("contest.")), None);
+        assert_eq!(special.zone(&name("example.com.")), None);
+
+        let disabled = SpecialUse {
+            enabled: false,
+            ..Default::default()
+        };
+        assert_eq!(disabled.zone(&name("printer.home.arpa.")), None);
+    }
+
+    #[test]
+    fn records() {
+        let special = SpecialUse::default();
+
+        let (code, answers) = special
+            .records(&name("app.localhost."), RecordType::AAAA)
+            .unwrap();
+        assert_eq!(code, ResponseCode::NoError);
+        assert_eq!(
+            answers.first().and_then(|answer| answer.data()),
+            Some(&RData::AAAA(std::net::Ipv6Addr::LOCALHOST.into()))
+        );
+
+        let (code, answers) = special
+            .records(&name("localhost."), RecordType::MX)
+            .unwrap();
+        assert_eq!(code, ResponseCode::NoError);
+        assert!(answers.is_empty());
+
+        let (code, answers) = special
+            .records(&name("printer.local."), RecordType::A)
+            .unwrap();
+        assert_eq!(code, ResponseCode::NXDomain);
+        assert!(answers.is_empty());
+    }
+}
@@ -0,0 +1,488 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use hickory_proto::{
+    error::ProtoErrorKind,
+    op::{Message, ResponseCode},
+    rr::Record,
+    serialize::binary::{BinDecodable, BinDecoder, BinEncoder},
+};
+use hickory_server::{
+    authority::{MessageRequest, MessageResponse},
+    server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+    time::timeout,
+};
+use tracing::debug;
+
+use crate::metrics;
+
+///
+/// How many queries on the one connection are answered at once, with the rest
+/// waiting to be read until one of those has been answered
+///
+const PIPELINED: usize = 16;
+
+///
+/// How long TCP connections may stay open, and how many there may be at once
+///
+#[derive(Clone, Copy)]
+pub(crate) struct Limits {
+    /// How long a connection is kept open without a query
+    pub(crate) idle: Duration,
+    /// How long a query has to arrive in full, once it's started arriving, and
+    /// how long the client has to take each response
+    pub(crate) read: Duration,
+    /// How many connections may be open at once, across every listener
+    pub(crate) connections: usize,
+}
+
+///
+/// Queries over TCP, which are served here rather than by hickory so that
+/// connections can be limited. Each connection may carry any number of
+/// queries, which are answered as they come in (RFC 7766).
+///
+pub(crate) struct Tcp {
+    limits: Limits,
+    connections: Arc<Semaphore>,
+    shutdown: watch::Sender<bool>,
+    listeners: JoinSet<()>,
+}
+
+impl Tcp {
+    pub(crate) fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            connections: Arc::new(Semaphore::new(limits.connections)),
+            shutdown: watch::Sender::new(false),
+            listeners: JoinSet::new(),
+        }
+    }
+
+    ///
+    /// Answer queries from whoever connects to the listener with the handler
+    ///
+    pub(crate) fn register<H: RequestHandler>(&mut self, listener: TcpListener, handler: Arc<H>) {
+        self.listeners.spawn(accept(
+            listener,
+            handler,
+            self.limits,
+            self.connections.clone(),
+            self.shutdown.subscribe(),
+        ));
+    }
+
+    ///
+    /// Stop accepting connections, and stop reading queries from those already
+    /// open, waiting for the queries already read to be answered
+    ///
+    pub(crate) async fn shutdown(mut self) {
+        self.shutdown.send_replace(true);
+        while self.listeners.join_next().await.is_some() {}
+    }
+}
+
+fn record(outcome: &str) {
+    metrics::TCP_CONNECTIONS
+        .get_or_create(&metrics::Connection {
+            outcome: outcome.to_string(),
+        })
+        .inc();
+}
+
+async fn accept<H: RequestHandler>(
+    listener: TcpListener,
+    handler: Arc<H>,
+    limits: Limits,
+    connections: Arc<Semaphore>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut open = JoinSet::new();
+
+    loop {
+        let (stream, src) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    debug!("Failed to accept a TCP connection: {err}");
+                    continue;
+                }
+            },
+            _ = shutdown.changed() => break,
+        };
+
+        // Connections over the limit are closed straight away, rather than
+        // left waiting, so that the client can try elsewhere
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            debug!("Too many TCP connections, closing the one from {src}");
+            record("rejected");
+            continue;
+        };
+        record("accepted");
+
+        open.spawn(connection(
+            stream,
+            src,
+            handler.clone(),
+            limits,
+            shutdown.clone(),
+            permit,
+        ));
+
+        while open.try_join_next().is_some() {}
+    }
+
+    while open.join_next().await.is_some() {}
+}
+
+async fn connection<H: RequestHandler>(
+    stream: TcpStream,
+    src: SocketAddr,
+    handler: Arc<H>,
+    limits: Limits,
+    mut shutdown: watch::Receiver<bool>,
+    permit: OwnedSemaphorePermit,
+) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (sender, mut responses) = mpsc::channel::<Vec<u8>>(PIPELINED);
+
+    // Responses are written in whatever order they're answered in, which
+    // clients match up by their ID
+    let writing = tokio::spawn(async move {
+        while let Some(response) = responses.recv().await {
+            let Ok(length) = u16::try_from(response.len()) else {
+                continue;
+            };
+
+            // A client that stops taking responses would otherwise hold up
+            // the queries waiting to be answered, and with them shutting down
+            let written = timeout(limits.read, async {
+                writer.write_all(&length.to_be_bytes()).await?;
+                writer.write_all(&response).await
+            })
+            .await;
+            match written {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => break,
+                Err(_) => {
+                    debug!("Gave up writing a response to {src}");
+                    record("timed_out");
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut answering = JoinSet::new();
+
+    loop {
+        while answering.len() >= PIPELINED {
+            answering.join_next().await;
+        }
+
+        // Once responses can no longer be written there's no point reading
+        // any more queries, which would only go unanswered
+        let length = tokio::select! {
+            length = timeout(limits.idle, reader.read_u16()) => length,
+            _ = shutdown.changed() => break,
+            () = sender.closed() => break,
+        };
+        let length = match length {
+            Ok(Ok(length)) => length,
+            // The client closed the connection
+            Ok(Err(_)) => break,
+            Err(_) => {
+                record("idle");
+                break;
+            }
+        };
+
+        let mut message = vec![0; usize::from(length)];
+        match timeout(limits.read, reader.read_exact(&mut message)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(_)) => break,
+            Err(_) => {
+                debug!("Gave up waiting on a query from {src}");
+                record("timed_out");
+                break;
+            }
+        }
+
+        let handler = handler.clone();
+        let handle = Handle {
+            sender: sender.clone(),
+        };
+        answering.spawn(async move { answer(&*handler, &message, src, handle).await });
+    }
+
+    while answering.join_next().await.is_some() {}
+
+    drop(sender);
+    if let Err(err) = writing.await {
+        debug!("Failed to write responses to {src}: {err}");
+    }
+
+    // Only making room for another connection once this one is closed, so that
+    // a client that stops taking responses still counts towards the limit
+    drop(reader);
+    drop(permit);
+}
+
+///
+/// Answer the query, or with a FORMERR should it not be one we can answer
+/// (e.g. one with more than one question), as hickory would
+///
+async fn answer<H: RequestHandler>(handler: &H, message: &[u8], src: SocketAddr, handle: Handle) {
+    match MessageRequest::read(&mut BinDecoder::new(message)) {
+        Ok(message) => {
+            handler
+                .handle_request(&Request::new(message, src, Protocol::Tcp), handle)
+                .await;
+        }
+        Err(err) => match err.kind() {
+            ProtoErrorKind::FormError { header, .. } => {
+                let response =
+                    Message::error_msg(header.id(), header.op_code(), ResponseCode::FormErr);
+
+                if let Ok(response) = response.to_vec() {
+                    let _ = handle.sender.send(response).await;
+                }
+            }
+            _ => debug!("Dropping a malformed query from {src}: {err}"),
+        },
+    }
+}
+
+///
+/// Hands responses to the task writing them to the connection
+///
+#[derive(Clone)]
+struct Handle {
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+#[async_trait::async_trait]
+impl ResponseHandler for Handle {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        let mut buffer = Vec::with_capacity(512);
+        let info = response
+            .destructive_emit(&mut BinEncoder::new(&mut buffer))
+            .map_err(io::Error::other)?;
+
+        self.sender
+            .send(buffer)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use hickory_proto::{
+        op::{Header, Message, Query, ResponseCode},
+        rr::{Name, RecordType},
+    };
+    use hickory_server::{
+        authority::MessageResponseBuilder,
+        server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
+    };
+    use pretty_assertions::assert_eq;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpSocket, TcpStream},
+    };
+
+    use super::{Limits, Tcp};
+
+    ///
+    /// Answers every query with an empty response
+    ///
+    struct Empty;
+
+    #[async_trait::async_trait]
+    impl RequestHandler for Empty {
+        async fn handle_request<R: ResponseHandler>(
+            &self,
+            request: &Request,
+            mut response_handle: R,
+        ) -> ResponseInfo {
+            let mut header = Header::response_from_request(request.header());
+            header.set_response_code(ResponseCode::NoError);
+
+            response_handle
+                .send_response(MessageResponseBuilder::from_message_request(request).build(
+                    header,
+                    &[],
+                    &[],
+                    &[],
+                    &[],
+                ))
+                .await
+                .unwrap()
+        }
+    }
+
+    async fn serve(limits: Limits) -> (Tcp, std::net::SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let mut tcp = Tcp::new(limits);
+        tcp.register(listener, Arc::new(Empty));
+
+        (tcp, address)
+    }
+
+    fn query(id: u16, questions: usize) -> Vec<u8> {
+        let mut message = Message::new();
+        message.set_id(id);
+        for question in 0..questions {
+            message.add_query(Query::query(
+                Name::from_ascii(format!("{question}.example.com.")).unwrap(),
+                RecordType::A,
+            ));
+        }
+
+        let message = message.to_vec().unwrap();
+        let mut framed = u16::try_from(message.len()).unwrap().to_be_bytes().to_vec();
+        framed.extend(message);
+        framed
+    }
+
+    async fn response(stream: &mut TcpStream) -> Message {
+        let length = stream.read_u16().await.unwrap();
+        let mut message = vec![0; usize::from(length)];
+        stream.read_exact(&mut message).await.unwrap();
+
+        Message::from_vec(&message).unwrap()
+    }
+
+    const LIMITS: Limits = Limits {
+        idle: Duration::from_secs(5),
+        read: Duration::from_secs(5),
+        connections: 4,
+    };
+
+    #[tokio::test]
+    async fn pipelining() {
+        let (tcp, address) = serve(LIMITS).await;
+        let mut stream = TcpStream::connect(address).await.unwrap();
+
+        // Every query is sent before any of them are answered
+        stream
+            .write_all(&[query(1, 1), query(2, 1), query(3, 2)].concat())
+            .await
+            .unwrap();
+
+        let mut answers = Vec::new();
+        for _ in 0..3 {
+            let response = response(&mut stream).await;
+            answers.push((response.id(), response.response_code()));
+        }
+        answers.sort_by_key(|(id, _)| *id);
+
+        assert_eq!(
+            answers,
+            [
+                (1, ResponseCode::NoError),
+                (2, ResponseCode::NoError),
+                // More than one question is a format error
+                (3, ResponseCode::FormErr)
+            ]
+        );
+
+        tcp.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let (tcp, address) = serve(Limits {
+            idle: Duration::from_millis(100),
+            connections: 1,
+            ..LIMITS
+        })
+        .await;
+
+        let mut first = TcpStream::connect(address).await.unwrap();
+        first.write_all(&query(1, 1)).await.unwrap();
+        assert_eq!(response(&mut first).await.id(), 1);
+
+        // Over the limit, so closed straight away
+        let mut second = TcpStream::connect(address).await.unwrap();
+        assert_eq!(second.read(&mut [0; 2]).await.unwrap(), 0);
+
+        // Closed once it's been idle for long enough, making room for another
+        assert_eq!(first.read(&mut [0; 2]).await.unwrap(), 0);
+        let mut third = TcpStream::connect(address).await.unwrap();
+        third.write_all(&query(2, 1)).await.unwrap();
+        assert_eq!(response(&mut third).await.id(), 2);
+
+        tcp.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn stalled() {
+        let (tcp, address) = serve(Limits {
+            idle: Duration::from_secs(10),
+            read: Duration::from_millis(200),
+            connections: 1,
+        })
+        .await;
+
+        // A small receive buffer, so that it fills up quickly once the client
+        // stops taking responses
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let mut stalled = socket.connect(address).await.unwrap();
+        let (mut reader, mut writer) = stalled.split();
+
+        let queries = (0..60_000).flat_map(|id| query(id, 1)).collect::<Vec<_>>();
+        let flooding = async {
+            // Fails once the server gives up on the connection
+            let _ = writer.write_all(&queries).await;
+        };
+
+        let checking = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            // Still open, so there's no room for another
+            let mut second = TcpStream::connect(address).await.unwrap();
+            assert_eq!(second.read(&mut [0; 2]).await.unwrap(), 0);
+
+            // Once the server has given up writing to it, it's closed
+            // altogether, rather than left reading queries
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let mut buffer = [0; 4096];
+            tokio::time::timeout(Duration::from_secs(2), async {
+                while matches!(reader.read(&mut buffer).await, Ok(read) if read > 0) {}
+            })
+            .await
+            .unwrap();
+        };
+
+        tokio::join!(flooding, checking);
+
+        let mut third = TcpStream::connect(address).await.unwrap();
+        third.write_all(&query(1, 1)).await.unwrap();
+        assert_eq!(response(&mut third).await.id(), 1);
+
+        tcp.shutdown().await;
+    }
+}
@@ -0,0 +1,223 @@
+use std::net::IpAddr;
+
+use hickory_proto::{
+    op::{Message, MessageType, ResponseCode},
+    rr::{
+        rdata::{
+            svcb::{SvcParamKey, SVCB},
+            HTTPS,
+        },
+        RData, Record, RecordType,
+    },
+    xfer::DnsResponse,
+};
+use hickory_server::server::Request;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+///
+/// What's done with queries of the types
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TypeAction {
+    /// Answer with NOTIMP, as though we don't support the type
+    #[default]
+    NotImp,
+    /// Answer with REFUSED
+    Refuse,
+    /// Resolve them as usual, but strip the Encrypted Client Hello configs
+    /// from HTTPS/SVCB answers, so that clients can't hide which names they
+    /// connect to
+    StripEch,
+}
+
+impl TypeAction {
+    ///
+    /// The code queries are answered with, should they not be resolved at all
+    ///
+    pub const fn refusal(self) -> Option<ResponseCode> {
+        match self {
+            Self::NotImp => Some(ResponseCode::NotImp),
+            Self::Refuse => Some(ResponseCode::Refused),
+            Self::StripEch => None,
+        }
+    }
+}
+
+///
+/// Special treatment for queries of some types, e.g. answering ANY with NOTIMP
+/// rather than in full (RFC 8482)
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypeFilter {
+    /// e.g. ["ANY", "HTTPS"]
+    pub types: Vec<RecordType>,
+    #[serde(default)]
+    pub action: TypeAction,
+    /// The networks it applies to. If empty, it applies to everyone.
+    #[serde(default)]
+    pub clients: Vec<IpNet>,
+}
+
+impl TypeFilter {
+    #[inline]
+    pub fn applies(&self, query_type: RecordType, client: IpAddr) -> bool {
+        self.types.contains(&query_type)
+            && (self.clients.is_empty()
+                || self.clients.iter().any(|network| network.contains(&client)))
+    }
+}
+
+///
+/// What's done with the query, going by the first of the filters that applies
+/// to it
+///
+pub fn action(
+    filters: &[TypeFilter],
+    query_type: RecordType,
+    client: IpAddr,
+) -> Option<TypeAction> {
+    filters
+        .iter()
+        .find(|filter| filter.applies(query_type, client))
+        .map(|filter| filter.action)
+}
+
+///
+/// An empty answer to the request, with the code
+///
+pub fn refuse(request: &Request, code: ResponseCode) -> DnsResponse {
+    let message = Message::new()
+        .set_header(
+            *request
+                .header()
+                .clone()
+                .set_answer_count(0)
+                .set_message_type(MessageType::Response)
+                .set_response_code(code),
+        )
+        .add_query(request.query().original().clone())
+        .clone();
+
+    DnsResponse::new(message.clone(), message.to_vec().unwrap_or_default())
+}
+
+///
+/// The answers, with any Encrypted Client Hello configs taken out of the
+/// HTTPS/SVCB records among them
+///
+pub fn strip_ech(answers: &[Record]) -> Vec<Record> {
+    let strip = |svcb: &SVCB| {
+        SVCB::new(
+            svcb.svc_priority(),
+            svcb.target_name().clone(),
+            svcb.svc_params()
+                .iter()
+                .filter(|(key, _)| *key != SvcParamKey::EchConfig)
+                .cloned()
+                .collect(),
+        )
+    };
+
+    answers
+        .iter()
+        .map(|record| {
+            let data = match record.data() {
+                Some(RData::HTTPS(HTTPS(svcb))) => RData::HTTPS(HTTPS(strip(svcb))),
+                Some(RData::SVCB(svcb)) => RData::SVCB(strip(svcb)),
+                _ => return record.clone(),
+            };
+
+            record.clone().set_data(Some(data)).clone()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use hickory_proto::{
+        op::ResponseCode,
+        rr::{
+            rdata::{
+                svcb::{Alpn, EchConfig, SvcParamKey, SvcParamValue, SVCB},
+                A, HTTPS,
+            },
+            Name, RData, Record, RecordType,
+        },
+    };
+    use pretty_assertions::assert_eq;
+
+    use super::{action, strip_ech, TypeAction, TypeFilter};
+
+    #[test]
+    fn actions() {
+        let filters = [
+            TypeFilter {
+                types: vec![RecordType::HTTPS, RecordType::SVCB],
+                action: TypeAction::StripEch,
+                clients: vec!["192.168.1.128/25".parse().unwrap()],
+            },
+            TypeFilter {
+                types: vec![RecordType::ANY, RecordType::HTTPS],
+                action: TypeAction::NotImp,
+                clients: Vec::new(),
+            },
+        ];
+
+        let children = "192.168.1.130".parse().unwrap();
+        let adults = "192.168.1.20".parse().unwrap();
+
+        assert_eq!(
+            action(&filters, RecordType::HTTPS, children),
+            Some(TypeAction::StripEch)
+        );
+        assert_eq!(
+            action(&filters, RecordType::HTTPS, adults),
+            Some(TypeAction::NotImp)
+        );
+        assert_eq!(action(&filters, RecordType::SVCB, adults), None);
+        assert_eq!(action(&filters, RecordType::A, children), None);
+        assert_eq!(TypeAction::Refuse.refusal(), Some(ResponseCode::Refused));
+        assert_eq!(TypeAction::StripEch.refusal(), None);
+    }
+
+    #[test]
+    fn stripping() {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let svcb = SVCB::new(
+            1,
+            Name::root(),
+            vec![
+                (
+                    SvcParamKey::Alpn,
+                    SvcParamValue::Alpn(Alpn(vec![String::from("h2")])),
+                ),
+                (
+                    SvcParamKey::EchConfig,
+                    SvcParamValue::EchConfig(EchConfig(vec![1, 2, 3])),
+                ),
+            ],
+        );
+
+        let answers = strip_ech(&[
+            Record::from_rdata(name.clone(), 300, RData::HTTPS(HTTPS(svcb))),
+            Record::from_rdata(name, 300, RData::A(A::new(93, 184, 216, 34))),
+        ]);
+
+        let Some(RData::HTTPS(HTTPS(svcb))) = answers[0].data() else {
+            panic!("Expected an HTTPS record");
+        };
+        assert_eq!(
+            svcb.svc_params()
+                .iter()
+                .map(|(key, _)| *key)
+                .collect::<Vec<_>>(),
+            vec![SvcParamKey::Alpn]
+        );
+        assert_eq!(answers[0].ttl(), 300);
+        assert!(matches!(answers[1].data(), Some(RData::A(_))));
+    }
+}
@@ -0,0 +1,323 @@
+//!
+//! dnstap (<https://dnstap.info>) over a unix socket, using the bidirectional
+//! Frame Streams handshake collectors such as `dnstap` and `fstrm_capture` expect.
+//!
+//! The protobuf is small and fixed, so it's written by hand.
+//!
+
+use std::{
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use hickory_proto::{
+    op::{Message, MessageType, Query, ResponseCode},
+    rr::Name,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
+use tracing::{error, info};
+
+use crate::statistics::Request;
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// How long to wait before trying to reconnect to the collector
+const RETRY: Duration = Duration::from_secs(5);
+
+/// How long to wait for the collector to acknowledge us
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+// Frame Streams control frames
+const ACCEPT: u32 = 1;
+const START: u32 = 2;
+const STOP: u32 = 3;
+const READY: u32 = 4;
+const FINISH: u32 = 5;
+const FIELD_CONTENT_TYPE: u32 = 1;
+
+// dnstap enums
+const DNSTAP_MESSAGE: u64 = 1;
+const CLIENT_RESPONSE: u64 = 6;
+const INET: u64 = 1;
+const INET6: u64 = 2;
+
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn key(buf: &mut Vec<u8>, field: u32, wire_type: u32) {
+    varint(buf, u64::from((field << 3) | wire_type));
+}
+
+fn number(buf: &mut Vec<u8>, field: u32, value: u64) {
+    key(buf, field, 0);
+    varint(buf, value);
+}
+
+fn fixed32(buf: &mut Vec<u8>, field: u32, value: u32) {
+    key(buf, field, 5);
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn bytes(buf: &mut Vec<u8>, field: u32, value: &[u8]) {
+    key(buf, field, 2);
+    varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+fn time(buf: &mut Vec<u8>, seconds: u32, nanoseconds: u32, time: SystemTime) {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    number(buf, seconds, since.as_secs());
+    fixed32(buf, nanoseconds, since.subsec_nanos());
+}
+
+fn socket_protocol(protocol: &str) -> Option<u64> {
+    match protocol.to_ascii_lowercase().as_str() {
+        "udp" => Some(1),
+        "tcp" => Some(2),
+        "tls" => Some(3),
+        "https" => Some(4),
+        "quic" => Some(7),
+        _ => None,
+    }
+}
+
+///
+/// The request as it was asked, and how it was answered, in wire format
+///
+fn messages(request: &Request) -> (Vec<u8>, Vec<u8>) {
+    let mut query = Message::new();
+    if let Ok(name) = Name::from_ascii(&request.question) {
+        query.add_query(Query::query(name, request.query_type));
+    }
+
+    let code = (0..=23u16)
+        .map(Into::<ResponseCode>::into)
+        .find(|code| code.to_string() == request.status)
+        .unwrap_or(ResponseCode::NoError);
+
+    let mut response = query.clone();
+    response
+        .set_message_type(MessageType::Response)
+        .set_response_code(code)
+        .add_answers(request.answers.iter().cloned());
+
+    (
+        query.to_vec().unwrap_or_default(),
+        response.to_vec().unwrap_or_default(),
+    )
+}
+
+///
+/// The request as a dnstap CLIENT_RESPONSE message
+///
+fn encode(request: &Request) -> Vec<u8> {
+    let (query, response) = messages(request);
+    let answered = request.timestamp;
+    let asked = answered
+        .checked_sub(Duration::from_nanos(request.elapsed as u64))
+        .unwrap_or(answered);
+
+    let mut message = Vec::new();
+    number(&mut message, 1, CLIENT_RESPONSE);
+    match request.client.parse::<IpAddr>() {
+        Ok(IpAddr::V4(client)) => {
+            number(&mut message, 2, INET);
+            bytes(&mut message, 4, &client.octets());
+        }
+        Ok(IpAddr::V6(client)) => {
+            number(&mut message, 2, INET6);
+            bytes(&mut message, 4, &client.octets());
+        }
+        Err(_) => {}
+    }
+    if let Some(protocol) = socket_protocol(&request.protocol) {
+        number(&mut message, 3, protocol);
+    }
+    time(&mut message, 8, 9, asked);
+    bytes(&mut message, 10, &query);
+    time(&mut message, 12, 13, answered);
+    bytes(&mut message, 14, &response);
+
+    let mut dnstap = Vec::new();
+    bytes(&mut dnstap, 1, b"blackhole");
+    bytes(&mut dnstap, 2, env!("CARGO_PKG_VERSION").as_bytes());
+    bytes(&mut dnstap, 14, &message);
+    number(&mut dnstap, 15, DNSTAP_MESSAGE);
+    dnstap
+}
+
+fn control(kind: u32) -> Vec<u8> {
+    let mut frame = kind.to_be_bytes().to_vec();
+    if kind != FINISH && kind != STOP {
+        frame.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+        frame.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        frame.extend_from_slice(CONTENT_TYPE);
+    }
+
+    let mut escaped = 0u32.to_be_bytes().to_vec();
+    escaped.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    escaped.extend_from_slice(&frame);
+    escaped
+}
+
+fn data(payload: &[u8]) -> Vec<u8> {
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(payload);
+    frame
+}
+
+///
+/// Read a control frame from the collector, returning its type
+///
+async fn read_control(stream: &mut UnixStream) -> io::Result<u32> {
+    if stream.read_u32().await? != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected a control frame",
+        ));
+    }
+
+    let mut frame = vec![0; stream.read_u32().await? as usize];
+    stream.read_exact(&mut frame).await?;
+
+    frame
+        .first_chunk::<4>()
+        .map(|kind| u32::from_be_bytes(*kind))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Empty control frame"))
+}
+
+async fn connect(path: &Path) -> io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(path).await?;
+
+    stream.write_all(&control(READY)).await?;
+    match tokio::time::timeout(TIMEOUT, read_control(&mut stream)).await {
+        Ok(Ok(ACCEPT)) => {}
+        Ok(Ok(kind)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected ACCEPT, got control frame {kind}"),
+            ));
+        }
+        Ok(Err(err)) => return Err(err),
+        Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+    }
+    stream.write_all(&control(START)).await?;
+
+    Ok(stream)
+}
+
+pub(super) struct Dnstap {
+    path: PathBuf,
+    stream: Option<UnixStream>,
+    /// When to next try connecting, should the collector not be there
+    retry: Option<Instant>,
+}
+
+impl Dnstap {
+    pub(super) const fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            stream: None,
+            retry: None,
+        }
+    }
+
+    ///
+    /// Send the request to the collector, connecting first if need be. Requests
+    /// made while the collector isn't there are dropped.
+    ///
+    pub(super) async fn write(&mut self, request: &Request) {
+        if self.stream.is_none() && self.retry.is_none_or(|retry| retry <= Instant::now()) {
+            match connect(&self.path).await {
+                Ok(stream) => {
+                    info!("Sending dnstap to {}", self.path.display());
+                    self.stream = Some(stream);
+                    self.retry = None;
+                }
+                Err(err) => {
+                    error!("Unable to connect to {}: {err}", self.path.display());
+                    self.retry = Some(Instant::now() + RETRY);
+                }
+            }
+        }
+
+        if let Some(stream) = &mut self.stream {
+            if let Err(err) = stream.write_all(&data(&encode(request))).await {
+                error!("Unable to send dnstap to {}: {err}", self.path.display());
+                self.stream = None;
+                self.retry = Some(Instant::now() + RETRY);
+            }
+        }
+    }
+
+    ///
+    /// Let the collector know we're done
+    ///
+    pub(super) async fn close(mut self) {
+        if let Some(stream) = &mut self.stream {
+            if stream.write_all(&control(STOP)).await.is_ok() {
+                let _ = tokio::time::timeout(TIMEOUT, read_control(stream)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use crate::statistics::Request;
+
+    #[test]
+    fn varint() {
+        for (value, expected) in [(1, vec![0x01]), (127, vec![0x7F]), (300, vec![0xAC, 0x02])] {
+            let mut buf = Vec::new();
+            super::varint(&mut buf, value);
+            assert_eq!(buf, expected);
+        }
+    }
+
+    #[test]
+    fn frames() {
+        let stop = super::control(super::STOP);
+        assert_eq!(stop, [0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3]);
+
+        let ready = super::control(super::READY);
+        assert_eq!(&ready[..12], [0, 0, 0, 0, 0, 0, 0, 34, 0, 0, 0, 4]);
+        assert!(ready.ends_with(super::CONTENT_TYPE));
+
+        assert_eq!(super::data(b"abc"), [0, 0, 0, 3, b'a', b'b', b'c']);
+    }
+
+    #[test]
+    fn encode() {
+        let request = Request {
+            client: String::from("10.0.0.1"),
+            question: String::from("example.com."),
+            protocol: String::from("UDP"),
+            ..Default::default()
+        };
+
+        let dnstap = super::encode(&request);
+
+        // identity = "blackhole"
+        assert_eq!(&dnstap[..11], b"\x0a\x09blackhole");
+        // type = MESSAGE
+        assert!(dnstap.ends_with(&[0x78, 0x01]));
+
+        let client = [0x22, 0x04, 10, 0, 0, 1];
+        assert!(dnstap.windows(client.len()).any(|window| window == client));
+        // socket_protocol = UDP
+        assert!(dnstap.windows(2).any(|window| window == [0x18, 0x01]));
+    }
+}
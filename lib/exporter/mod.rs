@@ -0,0 +1,191 @@
+//!
+//! Sends each request on to wherever DNS telemetry is collected, as dnstap
+//! over a unix socket and/or batches of JSON POSTed to a collector.
+//!
+//! Requests are handed over through a bounded buffer, so that a slow (or
+//! missing) collector never holds up answering requests. Should the buffer
+//! fill up, requests are dropped rather than waited on.
+//!
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        LazyLock, RwLock,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{error, warn};
+
+use crate::{metrics, statistics::Request};
+
+use dnstap::Dnstap;
+
+mod dnstap;
+
+static SENDER: LazyLock<RwLock<Option<mpsc::Sender<Request>>>> = LazyLock::new(RwLock::default);
+
+/// How many requests have been dropped since it was last reported
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+const fn default_buffer() -> usize {
+    10_000
+}
+
+const fn default_batch() -> usize {
+    100
+}
+
+const fn default_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+///
+/// Where to POST batches of requests to, as JSON
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Http {
+    pub url: String,
+    /// The most requests to send at once
+    #[serde(default = "default_batch")]
+    pub batch: usize,
+    /// How long to wait before sending a batch that isn't full
+    #[serde(with = "humantime_serde", default = "default_interval")]
+    pub interval: Duration,
+}
+
+///
+/// Options for exporting the request log
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// How many requests can be waiting to be exported before any more are
+    /// dropped
+    #[serde(default = "default_buffer")]
+    pub buffer: usize,
+    /// The unix socket a dnstap collector is listening on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dnstap: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http: Option<Http>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            buffer: default_buffer(),
+            dnstap: None,
+            http: None,
+        }
+    }
+}
+
+///
+/// Start exporting to wherever the options say, stopping any exports already
+/// running once they've caught up
+///
+pub fn configure(options: &Options) {
+    let sender = (options.dnstap.is_some() || options.http.is_some()).then(|| {
+        let (sender, receiver) = mpsc::channel(options.buffer.max(1));
+        tokio::spawn(export(options.clone(), receiver));
+        sender
+    });
+
+    if let Ok(mut lock) = SENDER.write() {
+        *lock = sender;
+    }
+}
+
+///
+/// Queue the request for exporting, should anything be exported
+///
+pub fn record(request: &Request) {
+    let Some(sender) = SENDER.read().ok().and_then(|sender| sender.clone()) else {
+        return;
+    };
+
+    if let Err(TrySendError::Full(_)) = sender.try_send(request.clone()) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        metrics::EXPORT_DROPPED.inc();
+    }
+}
+
+async fn post(client: &reqwest::Client, http: &Http, batch: &mut Vec<Request>) {
+    if batch.is_empty() {
+        return;
+    }
+
+    if let Err(err) = client
+        .post(&http.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&batch).unwrap_or_default())
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+    {
+        error!(
+            "Unable to export {} requests to {}: {err}",
+            batch.len(),
+            http.url
+        );
+    }
+
+    batch.clear();
+}
+
+async fn export(options: Options, mut receiver: mpsc::Receiver<Request>) {
+    let mut dnstap = options.dnstap.map(Dnstap::new);
+    let client = reqwest::Client::new();
+    let mut batch = Vec::new();
+    let mut interval = tokio::time::interval(
+        options
+            .http
+            .as_ref()
+            .map_or(default_interval(), |http| http.interval),
+    );
+
+    loop {
+        tokio::select! {
+            request = receiver.recv() => {
+                let Some(request) = request else {
+                    break;
+                };
+
+                if let Some(dnstap) = &mut dnstap {
+                    dnstap.write(&request).await;
+                }
+
+                if let Some(http) = &options.http {
+                    batch.push(request);
+                    if batch.len() >= http.batch {
+                        post(&client, http, &mut batch).await;
+                    }
+                }
+            }
+            _ = interval.tick() => {
+                let dropped = DROPPED.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    warn!("Exporting is falling behind, dropped {dropped} requests");
+                }
+
+                if let Some(http) = &options.http {
+                    post(&client, http, &mut batch).await;
+                }
+            }
+        }
+    }
+
+    // Everything still waiting is sent before stopping
+    if let Some(http) = &options.http {
+        post(&client, http, &mut batch).await;
+    }
+
+    if let Some(dnstap) = dnstap {
+        dnstap.close().await;
+    }
+}
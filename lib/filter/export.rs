@@ -0,0 +1,279 @@
+use std::{
+    fmt::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use serde::Deserialize;
+
+use super::rules::{Rule, Rules, TTL};
+
+///
+/// The formats the blocklist can be exported in
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// `0.0.0.0 example.com`
+    #[default]
+    Hosts,
+    /// `example.com`
+    Domains,
+    /// `||example.com^`
+    Adblock,
+}
+
+impl Format {
+    const fn comment(self) -> &'static str {
+        match self {
+            Self::Hosts | Self::Domains => "#",
+            Self::Adblock => "!",
+        }
+    }
+}
+
+///
+/// Render the rules as an RFC 1035 master (zone) file, containing the records
+/// we would answer with for every locally defined or sinkholed domain.
+///
+/// Rules that can't be represented in a zone file (e.g. those with a partial
+/// wildcard label such as `ads*.example.com`) are listed as comments.
+///
+pub fn zone(rules: &Rules) -> String {
+    let mut rules = rules
+        .iter()
+        .filter(|rule| rule.answers_locally())
+        .collect::<Vec<_>>();
+    rules.sort_by(|a, b| a.domain.cmp(&b.domain));
+
+    let mut zone = format!(
+        "; Exported by Blackhole {}\n$ORIGIN .\n$TTL {TTL}\n",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    for rule in rules {
+        let Some(name) = owner(&rule.domain) else {
+            let _ = writeln!(zone, "; Unrepresentable rule: {}", rule.domain);
+            continue;
+        };
+
+        // A wildcard alone doesn't cover the domain itself, so zones need both
+        let names = if rule.zone() {
+            vec![format!("*.{name}"), name]
+        } else {
+            vec![name]
+        };
+
+        for name in names {
+            if let Some(ptr) = ptr(rule) {
+                let _ = writeln!(zone, "{name}\t{TTL}\tIN\tPTR\t{ptr}.");
+            } else if let Some(cname) = cname(rule) {
+                let _ = writeln!(zone, "{name}\t{TTL}\tIN\tCNAME\t{cname}.");
+            } else {
+                let (v4, v6) = addresses(rule);
+                let _ = writeln!(zone, "{name}\t{TTL}\tIN\tA\t{v4}");
+                let _ = writeln!(zone, "{name}\t{TTL}\tIN\tAAAA\t{v6}");
+            }
+        }
+    }
+
+    zone
+}
+
+///
+/// Render the domains the rules block as a flat list, so that other devices
+/// (or another resolver) can block the same domains.
+///
+/// Only rules that apply to every client and query are included. Hosts and
+/// domain lists can't cover subdomains, so only have the domain itself, and
+/// can't have wildcards at all, so those rules are listed as comments.
+///
+pub fn blocklist(rules: &Rules, format: Format) -> String {
+    let mut rules = rules
+        .iter()
+        .filter(|rule| rule.sinkholes() && rule.scope.is_none())
+        .collect::<Vec<_>>();
+    rules.sort_by(|a, b| a.domain.cmp(&b.domain));
+
+    let comment = format.comment();
+    let mut list = format!(
+        "{comment} Exported by Blackhole {}\n",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    for rule in rules {
+        let domain = rule.domain.trim_end_matches('.').to_ascii_lowercase();
+
+        let _ = match format {
+            Format::Adblock if rule.zone() => writeln!(list, "||{domain}^"),
+            Format::Adblock => writeln!(list, "|{domain}^"),
+            _ if domain.contains('*') => writeln!(list, "{comment} Unrepresentable rule: {domain}"),
+            Format::Hosts => writeln!(list, "0.0.0.0 {domain}"),
+            Format::Domains => writeln!(list, "{domain}"),
+        };
+    }
+
+    list
+}
+
+///
+/// The owner name of the rule's records, if it can be represented in a zone
+/// file. Wildcards are only valid as the entire leftmost label.
+///
+fn owner(domain: &str) -> Option<String> {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+
+    domain
+        .split('.')
+        .enumerate()
+        .all(|(idx, label)| {
+            !label.is_empty() && (!label.contains('*') || (idx == 0 && label == "*"))
+        })
+        .then(|| format!("{domain}."))
+}
+
+fn ptr(rule: &Rule) -> Option<&str> {
+    rule.action
+        .as_ref()
+        .and_then(|action| action.ptr.as_deref())
+        .map(|ptr| ptr.trim_end_matches('.'))
+}
+
+fn cname(rule: &Rule) -> Option<&str> {
+    rule.action
+        .as_ref()
+        .and_then(|action| action.cname.as_deref())
+        .map(|cname| cname.trim_end_matches('.'))
+}
+
+fn addresses(rule: &Rule) -> (Ipv4Addr, Ipv6Addr) {
+    let rewrite = rule
+        .action
+        .as_ref()
+        .and_then(|action| action.rewrite.clone())
+        .unwrap_or_default();
+
+    (
+        match rewrite.v4 {
+            IpAddr::V4(addr) => addr,
+            IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        },
+        match rewrite.v6 {
+            IpAddr::V6(addr) => addr,
+            IpAddr::V4(_) => Ipv6Addr::UNSPECIFIED,
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use crate::filter::{
+        rules::{Kind, Rules, Type},
+        Custom,
+    };
+
+    use super::Format;
+
+    #[test]
+    fn blocklist() {
+        let mut rules = Rules::default();
+        rules.insert(
+            vec![
+                Type::Domain(String::from("ads.example.com")),
+                Type::Host("192.168.1.10".parse().unwrap(), String::from("nas.home")),
+                Type::Domain(String::from("ads*.example.org")),
+            ],
+            None,
+        );
+        rules.replace(&Custom {
+            domain: String::from("*.doubleclick.net"),
+            kind: Kind::Deny,
+            ..Default::default()
+        });
+
+        let lines = |format| {
+            super::blocklist(&rules, format)
+                .lines()
+                .skip(1)
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+
+        // Only what's blocked, rather than pointed somewhere useful
+        assert_eq!(
+            lines(Format::Hosts),
+            vec![
+                "0.0.0.0 ads.example.com",
+                "# Unrepresentable rule: ads*.example.org",
+                "0.0.0.0 doubleclick.net",
+            ]
+        );
+        assert_eq!(
+            lines(Format::Domains),
+            vec![
+                "ads.example.com",
+                "# Unrepresentable rule: ads*.example.org",
+                "doubleclick.net",
+            ]
+        );
+        assert_eq!(
+            lines(Format::Adblock),
+            vec![
+                "|ads.example.com^",
+                "|ads*.example.org^",
+                "||doubleclick.net^",
+            ]
+        );
+    }
+
+    #[test]
+    fn zone() {
+        let mut rules = Rules::default();
+        rules.insert(
+            vec![
+                Type::Domain(String::from("ads.example.com")),
+                Type::Host("192.168.1.10".parse().unwrap(), String::from("nas.home")),
+                Type::Domain(String::from("*.tracker.net")),
+                Type::Domain(String::from("ads*.example.org")),
+            ],
+            None,
+        );
+        rules.replace(&Custom {
+            domain: String::from("cloud.example.com"),
+            cname: Some(String::from("nas.home")),
+            ..Default::default()
+        });
+        rules.replace(&Custom {
+            domain: String::from("*.doubleclick.net"),
+            kind: Kind::Deny,
+            ..Default::default()
+        });
+        rules.generate_ptr();
+
+        let zone = super::zone(&rules);
+        let mut lines = zone.lines().skip(1);
+
+        assert_eq!(lines.next(), Some("$ORIGIN ."));
+        assert_eq!(lines.next(), Some("$TTL 600"));
+        assert_eq!(
+            lines.collect::<Vec<_>>(),
+            vec![
+                "*.tracker.net.\t600\tIN\tA\t0.0.0.0",
+                "*.tracker.net.\t600\tIN\tAAAA\t::",
+                "10.1.168.192.in-addr.arpa.\t600\tIN\tPTR\tnas.home.",
+                "; Unrepresentable rule: ads*.example.org",
+                "ads.example.com.\t600\tIN\tA\t0.0.0.0",
+                "ads.example.com.\t600\tIN\tAAAA\t::",
+                "cloud.example.com.\t600\tIN\tCNAME\tnas.home.",
+                "*.doubleclick.net.\t600\tIN\tA\t0.0.0.0",
+                "*.doubleclick.net.\t600\tIN\tAAAA\t::",
+                "doubleclick.net.\t600\tIN\tA\t0.0.0.0",
+                "doubleclick.net.\t600\tIN\tAAAA\t::",
+                "nas.home.\t600\tIN\tA\t192.168.1.10",
+                "nas.home.\t600\tIN\tAAAA\t::",
+            ]
+        );
+    }
+}
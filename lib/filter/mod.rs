@@ -1,27 +1,60 @@
 use std::{
     collections::hash_map::DefaultHasher,
     hash::{Hash, Hasher},
-    io::Read,
-    path::Path,
-    sync::LazyLock,
-    time::SystemTime,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, RwLock as StdRwLock},
+    time::{Duration, Instant, SystemTime},
 };
 
-use ahash::AHashSet;
+use ahash::{AHashMap, AHashSet};
+use hickory_proto::rr::{Name, RecordType};
 use hickory_server::server::Request;
-use regex::Regex;
+use ipnet::IpNet;
+use reqwest::{
+    header::{HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    StatusCode,
+};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::RwLock, task::JoinError};
-use tracing::{error, info, instrument};
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    sync::{RwLock, Semaphore},
+    task::JoinError,
+    time::sleep,
+};
+use tracing::{error, info, instrument, warn};
 
-use crate::{config::Config, metrics, schedule::Sched};
+use crate::{
+    config::Config,
+    health::Health,
+    metrics,
+    schedule::{Sched, Scheduler, Window},
+};
 
-use self::rules::{Rule, Rules};
+use self::{
+    patterns::Patterns,
+    rules::{Kind, Rule, Rules, Source},
+};
 
+pub mod export;
+pub mod patterns;
 pub mod rules;
 
-static FILTER: LazyLock<RwLock<Filter>> = LazyLock::new(RwLock::default);
+///
+/// The rules currently in use, which are only ever replaced as a whole once a
+/// new set is ready, so that requests are always filtered while lists reload
+///
+static FILTER: LazyLock<StdRwLock<Arc<Filter<'static>>>> = LazyLock::new(StdRwLock::default);
+static LISTS: LazyLock<RwLock<Lists>> = LazyLock::new(RwLock::default);
+static BLOCKING: LazyLock<StdRwLock<Blocking>> = LazyLock::new(StdRwLock::default);
+/// The windows currently open, as last worked out by the scheduler
+static OPEN: LazyLock<StdRwLock<Vec<Window>>> = LazyLock::new(StdRwLock::default);
+
+/// A minimal blocklist compiled into the binary, used until the configured
+/// lists are available (or when there are none configured)
+const BUILTIN_LIST: &str = include_str!("builtin.txt");
 
 #[cfg_attr(any(debug_assertions, test), derive(Debug))]
 #[derive(Clone, Eq, Serialize, Deserialize)]
@@ -29,10 +62,44 @@ pub struct List {
     pub name: String,
     pub url: String,
     pub enabled: bool,
+    /// How long to go before downloading the list again, in place of the
+    /// Filters schedule. Lists are only checked when that schedule runs though,
+    /// so this can't be any shorter than it.
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub schedule: Option<Duration>,
     #[serde(skip)]
     pub entries: usize,
 }
 
+impl List {
+    ///
+    /// The path to the list on disk, should it be a local list (i.e. either a path,
+    /// or a `file://` URI) rather than one we need to download
+    ///
+    pub fn local(&self) -> Option<PathBuf> {
+        if let Some(path) = self.url.strip_prefix("file://") {
+            Some(PathBuf::from(path))
+        } else if self.url.contains("://") {
+            None
+        } else {
+            Some(PathBuf::from(&self.url))
+        }
+    }
+
+    ///
+    /// Where to read the list from, with those we download being kept in the
+    /// directory
+    ///
+    pub fn path(&self, directory: &Path) -> PathBuf {
+        self.local()
+            .unwrap_or_else(|| directory.join(self.to_string()))
+    }
+}
+
 impl ToString for List {
     fn to_string(&self) -> String {
         let mut hasher = DefaultHasher::new();
@@ -54,11 +121,201 @@ impl Hash for List {
     }
 }
 
+///
+/// A rule defined directly in the config, rather than coming from a list
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Custom {
+    pub domain: String,
+    #[serde(default)]
+    pub kind: Kind,
+    /// Answer A requests with this address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v4: Option<Ipv4Addr>,
+    /// Answer AAAA requests with this address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v6: Option<Ipv6Addr>,
+    /// Redirect requests to this domain instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cname: Option<String>,
+    /// Apply the rule to every subdomain of the domain too, which giving the
+    /// domain as `*.<domain>` is shorthand for
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub zone: bool,
+}
+
+///
+/// What to do with queries that no rule matches
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Unmatched {
+    /// Let them through to the upstreams
+    #[default]
+    Allow,
+    /// Block them, so that only explicitly allowed domains resolve
+    Deny,
+}
+
+///
+/// Whether the rules act as a blocklist (the default), or an allowlist
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Policy {
+    #[serde(default)]
+    pub unmatched: Unmatched,
+    /// The networks the policy applies to. If empty, it applies to everyone.
+    #[serde(default)]
+    pub clients: Vec<IpNet>,
+}
+
+impl Policy {
+    ///
+    /// Whether queries from the client that no rule matches should be blocked
+    ///
+    #[inline]
+    pub fn denies_unmatched(&self, client: IpAddr) -> bool {
+        self.unmatched == Unmatched::Deny
+            && (self.clients.is_empty()
+                || self.clients.iter().any(|network| network.contains(&client)))
+    }
+}
+
+///
+/// Whether domains are currently being blocked, and if not, until when
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq))]
+#[derive(Clone, Copy, Serialize)]
+pub struct Blocking {
+    pub enabled: bool,
+    /// When blocking turns itself back on, should it only be disabled for a while
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<SystemTime>,
+}
+
+impl Default for Blocking {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            until: None,
+        }
+    }
+}
+
+impl Blocking {
+    ///
+    /// Whether blocking is in effect at the given time. It is once a pause is up,
+    /// even if the scheduler hasn't gotten around to turning it back on yet.
+    ///
+    #[inline]
+    pub fn active(&self, now: SystemTime) -> bool {
+        self.enabled || self.until.is_some_and(|until| until <= now)
+    }
+}
+
+///
+/// The outcome of the last attempt to fetch a list
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq))]
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Status {
+    /// The HTTP status code of the last fetch, if we got that far
+    pub code: Option<u16>,
+    /// When the list was last fetched
+    pub fetched: Option<SystemTime>,
+    /// The number of entries loaded from the list
+    pub entries: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_modified: Option<String>,
+}
+
 #[cfg_attr(any(debug_assertions, test), derive(Debug))]
 #[derive(Default)]
 pub struct Filter<'a> {
-    pub lists: AHashSet<List>,
     pub rules: Rules<'a>,
+    /// Only consulted when none of the rules match
+    pub patterns: Patterns,
+    /// The rules of the lists only enforced while a window is open, by list
+    pub overlays: AHashMap<String, Rules<'a>>,
+}
+
+///
+/// The lists that have been downloaded, and how each of them fared
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Default)]
+struct Lists {
+    lists: AHashSet<List>,
+    /// The fetch status of each list, keyed by the list's file name
+    statuses: AHashMap<String, Status>,
+}
+
+const fn default_retries() -> u32 {
+    3
+}
+
+const fn default_backoff() -> Duration {
+    Duration::from_secs(1)
+}
+
+const fn default_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+const fn default_concurrency() -> usize {
+    4
+}
+
+///
+/// Options for how lists are downloaded
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Downloads {
+    /// How many times to retry a download that failed for what's likely a
+    /// temporary reason (e.g. a timeout, or a 5xx response)
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// How long to wait before the first retry, doubling with each one after
+    #[serde(with = "humantime_serde", default = "default_backoff")]
+    pub backoff: Duration,
+    /// How long to wait for a download to complete
+    #[serde(with = "humantime_serde", default = "default_timeout")]
+    pub timeout: Duration,
+    /// How many lists to download at once
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Where downloaded lists are kept, the working directory by default. Lists
+    /// left behind are only cleaned up when this is set, as the working
+    /// directory could hold anything.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<PathBuf>,
+}
+
+impl Downloads {
+    #[inline]
+    pub fn directory(&self) -> &Path {
+        self.directory.as_deref().unwrap_or_else(|| Path::new("."))
+    }
+}
+
+impl Default for Downloads {
+    fn default() -> Self {
+        Self {
+            retries: default_retries(),
+            backoff: default_backoff(),
+            timeout: default_timeout(),
+            concurrency: default_concurrency(),
+            directory: None,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -66,21 +323,37 @@ pub enum Error {
     #[error("IO Error: {0}")]
     Io(#[from] std::io::Error),
     #[error("{0}")]
-    RequestError(Box<ureq::Error>),
-    #[error("{0}")]
-    DownloadError(String),
+    RequestError(#[from] reqwest::Error),
+    #[error("{0}: {1}")]
+    DownloadError(u16, String),
     #[error("{0}")]
     FilterError(String),
 }
 
-impl From<ureq::Error> for Error {
-    fn from(value: ureq::Error) -> Self {
-        Self::RequestError(Box::new(value))
+impl Error {
+    ///
+    /// Whether trying again later might succeed
+    ///
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::RequestError(err) => {
+                err.is_timeout() || err.is_connect() || err.is_request() || err.is_body()
+            }
+            Self::DownloadError(code, _) => *code == 429 || *code >= 500,
+            Self::Io(_) | Self::FilterError(_) => false,
+        }
     }
 }
 
 impl<'a> Filter<'a> {
     pub async fn init() {
+        // Start off with whatever we already have (which for a fresh install will
+        // just be the builtin list) so that we're not letting everything through
+        // while the lists are downloaded
+        if let Err(err) = Self::import().await {
+            error!("{err}");
+        }
+
         Self::update().await;
         if let Err(err) = Self::import().await {
             error!("{err}");
@@ -89,13 +362,124 @@ impl<'a> Filter<'a> {
 
     #[instrument(level = "info")]
     pub async fn update() {
-        let tasks = Config::get(|config| config.filters.clone())
-            .await
+        let (filters, directory) =
+            Config::get(|config| (config.filters.clone(), config.downloads.directory.clone()))
+                .await;
+
+        if let Some(directory) = directory {
+            Self::prune(&directory, &filters);
+        }
+        Self::download_all(filters, false).await;
+    }
+
+    ///
+    /// Remove any downloaded lists (along with their statuses) that no longer
+    /// belong to one of the lists, leaving alone anything that isn't ours. Only
+    /// done for a configured directory, as what's in the working directory
+    /// needn't be ours at all.
+    ///
+    fn prune(directory: &Path, lists: &AHashSet<List>) {
+        let known = lists
+            .iter()
+            .map(ToString::to_string)
+            .collect::<AHashSet<_>>();
+
+        let Ok(files) = std::fs::read_dir(directory) else {
+            return;
+        };
+
+        for file in files.filter_map(Result::ok) {
+            let name = file.file_name();
+            let Some((stem, extension)) = name.to_str().and_then(|name| name.split_once('.'))
+            else {
+                continue;
+            };
+
+            let ours = matches!(extension, "txt" | "json")
+                && !stem.is_empty()
+                && stem.bytes().all(|byte| byte.is_ascii_digit());
+
+            if ours && !known.contains(&format!("{stem}.txt")) {
+                let path = file.path();
+                match std::fs::remove_file(&path) {
+                    Ok(()) => info!("Removed {}, as it's no longer a list", path.display()),
+                    Err(err) => warn!(
+                        "Unable to remove {}, which is no longer a list: {err}",
+                        path.display()
+                    ),
+                }
+            }
+        }
+    }
+
+    ///
+    /// Download the lists (or just the one named) straight away, however
+    /// recently they were last downloaded, and then load them
+    ///
+    /// # Errors
+    /// If the lists can't be loaded once downloaded
+    ///
+    /// # Returns
+    /// Whether there were any enabled lists to refresh by that name
+    ///
+    pub async fn refresh(name: Option<String>) -> Result<bool, Error> {
+        let lists = Config::get(|config| {
+            config
+                .filters
+                .iter()
+                .filter(|list| {
+                    list.enabled && name.as_ref().map_or(true, |name| list.name == *name)
+                })
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+        .await;
+
+        if name.is_some() && lists.is_empty() {
+            return Ok(false);
+        }
+
+        Self::download_all(lists, true).await;
+        Self::import().await?;
+
+        Ok(true)
+    }
+
+    async fn download_all(filters: impl IntoIterator<Item = List>, force: bool) {
+        let options = Config::get(|config| config.downloads.clone()).await;
+
+        if let Err(err) = std::fs::create_dir_all(options.directory()) {
+            error!(
+                "Unable to create {} to download lists to: {err}",
+                options.directory().display()
+            );
+        }
+
+        let client = match reqwest::Client::builder().timeout(options.timeout).build() {
+            Ok(client) => client,
+            Err(err) => {
+                error!("Unable to create a client to download lists with: {err}");
+                return;
+            }
+        };
+
+        let options = Arc::new(options);
+        let permits = Arc::new(Semaphore::new(options.concurrency.max(1)));
+
+        let tasks = filters
             .into_iter()
             .filter_map(|filter| {
                 if filter.enabled {
+                    let client = client.clone();
+                    let options = Arc::clone(&options);
+                    let permits = Arc::clone(&permits);
+
                     Some(tokio::spawn(async move {
-                        if let Err(err) = Self::download(filter).await {
+                        let Ok(_permit) = permits.acquire().await else {
+                            return;
+                        };
+
+                        if let Err(err) = Self::download(&client, &options, filter, force).await {
                             error!("{err}");
                         }
                     }))
@@ -110,12 +494,40 @@ impl<'a> Filter<'a> {
         }
     }
 
-    async fn download(list: List) -> Result<(), Error> {
+    async fn download(
+        client: &reqwest::Client,
+        options: &Downloads,
+        list: List,
+        force: bool,
+    ) -> Result<(), Error> {
         #[cfg(debug_assertions)]
         tracing::debug!("Downloading: {list:?}");
 
-        let path = list.to_string();
-        let path = Path::new(&path);
+        if let Some(source) = list.local() {
+            // Local lists are read straight from disk whenever they're imported,
+            // so all we need to do is make sure they're actually there
+            let result = if source.is_file() {
+                Ok(())
+            } else {
+                Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("{} does not exist", source.display()),
+                )))
+            };
+
+            let mut lists = LISTS.write().await;
+            let status = lists.statuses.entry(list.to_string()).or_default();
+            status.fetched = Some(SystemTime::now());
+            status.error = result.as_ref().err().map(ToString::to_string);
+
+            result?;
+            lists.lists.insert(list);
+
+            return Ok(());
+        }
+
+        let path = list.path(options.directory());
+        let path = path.as_path();
 
         let schedule = Config::get(|config| {
             config
@@ -127,123 +539,360 @@ impl<'a> Filter<'a> {
         .await
         .unwrap_or(std::time::Duration::ZERO);
 
-        let is_past_due = if path.exists() {
+        let is_past_due = if force || !path.exists() {
+            true
+        } else {
             SystemTime::now()
                 .duration_since(path.metadata()?.modified()?)
                 .unwrap_or_default()
-                >= schedule
+                >= list.schedule.unwrap_or(schedule)
+        };
+
+        let mut status = Self::load_status(&list, path).await;
+
+        let result = if is_past_due {
+            // There's no point downloading the list if we can't store it
+            Health::writable()?;
+
+            let result = Self::fetch(client, options, &list, path, &mut status).await;
+
+            status.fetched = Some(SystemTime::now());
+            status.error = result.as_ref().err().map(ToString::to_string);
+
+            if let Err(err) = Health::persist(|| {
+                serde_json::to_vec(&status)
+                    .map_err(std::io::Error::from)
+                    .and_then(|contents| std::fs::write(path.with_extension("json"), contents))
+            }) {
+                error!("Unable to save the status of {}: {err}", list.name);
+            }
+
+            result
         } else {
-            true
+            Ok(())
         };
 
-        if is_past_due {
-            info!("Fetching {}", list.url);
+        let mut lists = LISTS.write().await;
+        lists.statuses.insert(list.to_string(), status);
 
-            let response = ureq::get(&list.url).call()?;
+        result?;
+        lists.lists.insert(list);
 
-            if response.status() != 200 {
-                return Err(Error::DownloadError(format!(
-                    "{}: {}",
-                    response.status(),
-                    response.into_string()?
-                )));
-            };
+        Ok(())
+    }
 
-            let mut writer = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(list.to_string())
-                .await?;
-
-            match response
-                .header("Content-Length")
-                .and_then(|s| s.parse::<usize>().ok())
-            {
-                Some(mut len) => {
-                    let mut response = response.into_reader();
-
-                    while len > 0 {
-                        let mut bytes = [0; 8192];
-                        let length = response.read(&mut bytes).unwrap_or_default();
-
-                        match writer.write_all(&bytes[..length]).await {
-                            Err(err) if err.kind() != tokio::io::ErrorKind::Other => {
-                                error!("{err}");
-                                return Err(err.into());
-                            }
-                            Err(_) => {
-                                break;
-                            }
-                            _ => {}
-                        }
+    ///
+    /// Fetch a list, retrying should it fail for what looks to be a temporary reason
+    ///
+    async fn fetch(
+        client: &reqwest::Client,
+        options: &Downloads,
+        list: &List,
+        path: &Path,
+        status: &mut Status,
+    ) -> Result<(), Error> {
+        let mut attempt = 0;
 
-                        len -= length;
-                    }
-                }
-                None => {
-                    writer
-                        .write_all(response.into_string().unwrap().as_bytes())
-                        .await
-                        .expect("");
+        loop {
+            match Self::try_fetch(client, list, path, status).await {
+                Err(err) if attempt < options.retries && err.is_transient() => {
+                    let backoff = options.backoff.saturating_mul(2u32.saturating_pow(attempt));
+                    warn!(
+                        "Unable to fetch {} ({err}), retrying in {backoff:?}",
+                        list.name
+                    );
+
+                    sleep(backoff).await;
+                    attempt += 1;
                 }
+                result => return result,
+            }
+        }
+    }
+
+    ///
+    /// Fetch a list, unless the server tells us it hasn't changed since we last
+    /// fetched it
+    ///
+    async fn try_fetch(
+        client: &reqwest::Client,
+        list: &List,
+        path: &Path,
+        status: &mut Status,
+    ) -> Result<(), Error> {
+        info!("Fetching {}", list.url);
+
+        let mut request = client.get(&list.url);
+        if path.exists() {
+            if let Some(etag) = &status.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+
+            if let Some(last_modified) = &status.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
             }
         }
 
-        FILTER.write().await.lists.insert(list);
+        let mut response = request.send().await.inspect_err(|_| status.code = None)?;
+        let code = response.status();
+        status.code = Some(code.as_u16());
+
+        if code == StatusCode::NOT_MODIFIED {
+            info!("{} is unchanged", list.name);
+
+            // Bump the modification time so we don't check again until it's next due
+            return Health::written(
+                std::fs::File::options()
+                    .write(true)
+                    .open(path)
+                    .and_then(|file| file.set_modified(SystemTime::now())),
+            )
+            .map_err(Error::from);
+        }
+
+        if code != StatusCode::OK {
+            return Err(Error::DownloadError(
+                code.as_u16(),
+                response.text().await.unwrap_or_default(),
+            ));
+        };
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+                .map(String::from)
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+
+        let mut writer = Health::written(
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(path)
+                .await,
+        )?;
+
+        while let Some(chunk) = response.chunk().await? {
+            Health::written(writer.write_all(&chunk).await)?;
+        }
+        Health::written(writer.flush().await)?;
+
+        // Only keep hold of these once the list has been written in full, otherwise
+        // we could end up being told a partially written list is up to date
+        status.etag = etag;
+        status.last_modified = last_modified;
 
         Ok(())
     }
 
+    async fn load_status(list: &List, path: &Path) -> Status {
+        if let Some(status) = LISTS.read().await.statuses.get(&list.to_string()) {
+            return status.clone();
+        }
+
+        std::fs::read(path.with_extension("json"))
+            .ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+            .unwrap_or_default()
+    }
+
     ///
-    /// Load a list into the filter
+    /// Load the lists into a new set of rules, which replaces the current set
+    /// only once it's complete
     ///
     /// # Errors
     /// If it fails to open the list
     ///
     #[instrument]
     pub async fn import() -> Result<(), Error> {
-        let mut count = 0;
-        let rules = {
-            let filter = FILTER.read().await;
+        let (use_builtin_list, auto_ptr, custom, patterns, enabled, directory, windowed) =
+            Config::get(|config| {
+                (
+                    config.use_builtin_list,
+                    config.auto_ptr,
+                    config.rules.clone(),
+                    config.patterns.clone(),
+                    config
+                        .filters
+                        .iter()
+                        .filter(|list| list.enabled)
+                        .cloned()
+                        .collect::<AHashSet<_>>(),
+                    config.downloads.directory().to_path_buf(),
+                    config
+                        .windows
+                        .iter()
+                        .flat_map(|window| window.lists.iter().cloned())
+                        .collect::<AHashSet<_>>(),
+                )
+            })
+            .await;
 
-            filter
-                .lists
-                .iter()
-                .cloned()
-                .try_fold(Rules::default(), |mut rules, mut list| {
-                    info!("Loading filter list: {}", list.name);
+        // Lists that have since been disabled are kept around, so that they're
+        // ready should they be enabled again
+        let mut lists = LISTS.read().await.lists.clone();
+        lists.retain(|list| enabled.contains(list));
 
-                    rules.merge(Rules::try_from(&mut list)?);
-                    count += list.entries;
+        let (filter, entries, mut count) =
+            Self::load(lists, &directory, &windowed, use_builtin_list)?;
+        let Filter {
+            mut rules,
+            overlays,
+            ..
+        } = filter;
 
-                    info!("Loaded {} filter(s) for {}", list.entries, list.name);
+        // Custom rules take precedence over anything from the lists
+        for rule in &custom {
+            rules.replace(rule);
+            count += 1;
+        }
 
-                    Ok::<_, Error>(rules)
-                })?
-        };
+        if auto_ptr {
+            let generated = rules.generate_ptr();
+            info!("Generated {generated} PTR record(s)");
+        }
+
+        rules.shrink();
+
+        let patterns = Patterns::new(&patterns);
+        count += patterns.len();
 
         metrics::RULES.set(count.try_into().unwrap());
 
-        FILTER.write().await.rules = rules;
+        let previous = FILTER
+            .write()
+            .map(|mut filter| {
+                std::mem::replace(
+                    &mut *filter,
+                    Arc::new(Filter {
+                        rules,
+                        patterns,
+                        overlays,
+                    }),
+                )
+            })
+            .ok();
+        // Only let go of the old rules outside of the lock, as there may be a
+        // great many of them to free
+        drop(previous);
+
+        let mut lists = LISTS.write().await;
+        for (list, entries) in entries {
+            lists.statuses.entry(list).or_default().entries = entries;
+        }
 
         Ok(())
     }
 
+    ///
+    /// Load whichever of the lists have been downloaded, along with the builtin
+    /// list (should it be used) until at least one of them has been. Returns the
+    /// rules, how many filters were loaded from each list, and how many there
+    /// were in all.
+    ///
+    /// # Errors
+    /// If a list that has been downloaded can't be read
+    ///
+    fn load(
+        lists: AHashSet<List>,
+        directory: &Path,
+        windowed: &AHashSet<String>,
+        use_builtin_list: bool,
+    ) -> Result<(Filter<'static>, Vec<(String, usize)>, usize), Error> {
+        let mut filter = Filter::default();
+        let mut entries = Vec::new();
+        let mut count = 0;
+
+        for list in lists {
+            // A list that has yet to be downloaded (or has since been removed)
+            // shouldn't stop the rest from being loaded
+            let path = list.path(directory);
+            if !path.is_file() {
+                warn!("Skipping {}, as it hasn't been downloaded", list.name);
+                continue;
+            }
+
+            info!("Loading filter list: {}", list.name);
+
+            let (mut loaded, loaded_entries) = Rules::load(&path, Some(Source::from(&list)))?;
+            // Kept apart from the rest, as they're only enforced while one of
+            // their windows is open
+            if windowed.contains(&list.name) {
+                loaded.shrink();
+                filter.overlays.insert(list.name.clone(), loaded);
+            } else {
+                filter.rules.merge(loaded);
+            }
+            count += loaded_entries;
+            entries.push((list.to_string(), loaded_entries));
+
+            info!("Loaded {loaded_entries} filter(s) for {}", list.name);
+        }
+
+        if use_builtin_list && entries.is_empty() {
+            let loaded = filter
+                .rules
+                .insert(Self::builtin()?, Some(Source::builtin()));
+            count += loaded;
+
+            info!("Loaded {loaded} filter(s) from the builtin list");
+        }
+
+        Ok((filter, entries, count))
+    }
+
+    ///
+    /// Parse the builtin list
+    ///
+    /// # Errors
+    /// This should only fail if the builtin list is itself invalid
+    ///
+    pub fn builtin() -> Result<Vec<rules::Type>, Error> {
+        Rules::parse_lines(BUILTIN_LIST.lines().map(String::from))
+    }
+
     ///
     /// Reset the Global Filter to a blank slate. This is mostly useful
     /// when removing filters
     ///
     pub async fn reset(old: Option<AHashSet<List>>) {
+        let scheduled = old.is_none();
         let lists = match old {
             Some(old_lists) => old_lists,
-            None => FILTER.read().await.lists.iter().cloned().collect(),
+            None => LISTS.read().await.lists.iter().cloned().collect(),
         };
 
-        for list in lists {
+        // Disabled lists keep whatever of them has been downloaded, for when
+        // they're enabled again, as do those with a schedule of their own when
+        // it's the Filters schedule running, as they're only due once it is
+        let (kept, directory) = Config::get(|config| {
+            (
+                config
+                    .filters
+                    .iter()
+                    .filter(|list| !list.enabled || (scheduled && list.schedule.is_some()))
+                    .cloned()
+                    .collect::<AHashSet<_>>(),
+                config.downloads.directory().to_path_buf(),
+            )
+        })
+        .await;
+
+        for list in lists.into_iter().filter(|list| !kept.contains(list)) {
             #[cfg(debug_assertions)]
             tracing::debug!("Removing {list:?} ({})", list.to_string());
 
-            std::fs::remove_file(list.to_string()).unwrap_or_default();
+            // Local lists are never ours to remove
+            if list.local().is_none() {
+                let path = list.path(&directory);
+                std::fs::remove_file(&path).unwrap_or_default();
+                std::fs::remove_file(path.with_extension("json")).unwrap_or_default();
+            }
+            LISTS.write().await.statuses.remove(&list.to_string());
         }
 
         Self::update().await;
@@ -253,34 +902,83 @@ impl<'a> Filter<'a> {
     }
 
     pub fn filter(&'a self, request: &'a Request) -> &'a Option<Rule> {
-        request
-            .query()
-            .original()
-            .name()
+        self.find(request.query().original().name())
+    }
+
+    ///
+    /// Find the rule (if any) that applies to the name, falling back to the
+    /// closest zone enclosing it should nothing more specific match, and then
+    /// to the patterns
+    ///
+    pub fn find(&'a self, name: &Name) -> &'a Option<Rule> {
+        match Self::lookup_in(&self.rules, name) {
+            rule if rule.is_some() => rule,
+            _ => self.patterns.find(name),
+        }
+    }
+
+    ///
+    /// Find the rule (if any) that applies to the name, falling back to the
+    /// closest zone enclosing it should nothing more specific match
+    ///
+    fn lookup_in<'r>(rules: &'r Rules<'_>, name: &Name) -> &'r Option<Rule> {
+        let mut zone = &None;
+
+        let node = name
             .into_iter()
             .rev()
-            .try_fold(&self.rules, |current_node, entry| {
-                let key_ = String::from_utf8_lossy(entry);
-                current_node.children.get(&key_).ok_or_else(|| {
-                    current_node
-                        .children
-                        .iter()
-                        .filter(|(key, _)| key.contains('*'))
-                        .filter_map(|(key, entry)| Regex::new(key).ok().map(|re| (entry, re)))
-                        .find(|(_, re)| re.is_match(&key_))
-                        .map_or(current_node, |(entry, _)| entry)
-                })
+            .try_fold(rules, |current_node, entry| {
+                if current_node.rule.as_ref().is_some_and(Rule::zone) {
+                    zone = &current_node.rule;
+                }
+
+                current_node
+                    .get(&String::from_utf8_lossy(entry))
+                    .ok_or(current_node)
+            });
+
+        match node.unwrap_or_else(|node| node) {
+            node if node.rule.is_some() => &node.rule,
+            _ => zone,
+        }
+    }
+
+    ///
+    /// The rule (if any) from the lists of the windows open for the client
+    ///
+    fn scheduled(&self, name: &Name, client: IpAddr) -> Option<Rule> {
+        if self.overlays.is_empty() {
+            return None;
+        }
+
+        let open = OPEN.read().ok()?;
+        open.iter()
+            .filter(|window| window.applies(client))
+            .flat_map(|window| &window.lists)
+            .find_map(|list| {
+                self.overlays
+                    .get(list)
+                    .and_then(|rules| Self::lookup_in(rules, name).clone())
             })
-            .map_or_else(|err| &err.rule, |rule| &rule.rule)
     }
 
     ///
-    /// Check if the request's query matches any of the filters we have.
+    /// Replace the windows currently open
+    ///
+    pub(crate) fn open(windows: Vec<Window>) {
+        if let Ok(mut open) = OPEN.write() {
+            *open = windows;
+        }
+    }
+
+    ///
+    /// Check if the request's query, from the client, matches any of the
+    /// filters we have.
     ///
     /// # Examples
     ///
     /// ```
-    /// use blackhole::filter::Filter;
+    /// use blackhole::__private::filter::{Filter, Policy};
     /// use hickory_proto::serialize::binary::{BinDecodable, BinDecoder};
     /// use hickory_server::{
     ///    authority::MessageRequest,
@@ -299,48 +997,233 @@ impl<'a> Filter<'a> {
     ///      Protocol::Udp,
     /// );
     ///
-    /// assert_eq!(Filter::check(&request), None);
+    /// assert_eq!(
+    ///     Filter::check(&request, request.src().ip(), &Policy::default()),
+    ///     None
+    /// );
     /// ```
     ///
     /// # Returns
-    /// If there is a rule that matches, then Some(rule).
-    /// Otherwise, None.
-    ///
-    pub fn check(request: &Request) -> Option<Rule> {
-        // We currently only support A/AAAA query filtering.
-        // TODO: Would this be worth expanding?
-        if request.query().query_type().is_ip_addr() {
-            FILTER
-                .try_read()
-                .map(|filter| filter.filter(request).clone())
-                .unwrap_or_default()
-        } else {
-            None
+    /// If there is a rule that matches (and applies to queries of the type from
+    /// the client), then Some(rule). Otherwise, None, unless the policy denies
+    /// unmatched queries from the client, in which case every query (of any type)
+    /// that isn't explicitly allowed is denied. While blocking is paused, rules
+    /// that would block the domain are ignored.
+    ///
+    pub fn check(request: &Request, client: IpAddr, policy: &Policy) -> Option<Rule> {
+        let query_type = request.query().query_type();
+
+        let deny_unmatched = policy.denies_unmatched(client);
+        if !deny_unmatched && !Self::applies_to(query_type) {
+            return None;
         }
-    }
 
-    pub fn lists() -> AHashSet<List> {
-        FILTER
-            .try_read()
-            .map(|filters| filters.lists.clone().into_iter().collect())
-            .unwrap_or_default()
+        let filter = Self::current();
+        let rule = filter
+            .filter(request)
+            .clone()
+            .or_else(|| filter.scheduled(request.query().original().name(), client))
+            .filter(|rule| rule.applies(query_type, client));
+
+        let rule = if deny_unmatched {
+            Some(rule.unwrap_or_else(|| Rule::unmatched(request.query().original().name())))
+        } else {
+            rule
+        };
+
+        // Rules that point names somewhere useful keep working while blocking is paused
+        rule.filter(|rule| !rule.sinkholes() || Self::blocking().active(SystemTime::now()))
+    }
+
+    ///
+    /// Whether domains are currently being blocked
+    ///
+    pub fn blocking() -> Blocking {
+        BLOCKING
+            .read()
+            .map(|blocking| *blocking)
+            .unwrap_or_default()
+    }
+
+    ///
+    /// Stop blocking domains, either until told otherwise, or for the duration.
+    /// Returns None, leaving blocking as it was, should the duration be too long
+    /// to keep track of
+    ///
+    pub async fn pause(duration: Option<Duration>) -> Option<Blocking> {
+        let until = match duration {
+            Some(duration) => {
+                // The scheduler has to be able to wait that long too
+                Instant::now().checked_add(duration)?;
+                Some(SystemTime::now().checked_add(duration)?)
+            }
+            None => None,
+        };
+        let blocking = Blocking {
+            enabled: false,
+            until,
+        };
+
+        if let Ok(mut lock) = BLOCKING.write() {
+            *lock = blocking;
+        }
+
+        match duration {
+            Some(duration) => {
+                info!("Blocking disabled for {duration:?}");
+                Scheduler::once(Sched::Blocking, duration).await;
+            }
+            None => {
+                info!("Blocking disabled");
+                Scheduler::cancel(&Sched::Blocking).await;
+            }
+        }
+
+        Some(blocking)
+    }
+
+    ///
+    /// Start blocking domains again
+    ///
+    pub async fn resume() -> Blocking {
+        if let Ok(mut lock) = BLOCKING.write() {
+            *lock = Blocking::default();
+        }
+
+        info!("Blocking enabled");
+        Scheduler::cancel(&Sched::Blocking).await;
+
+        Blocking::default()
+    }
+
+    ///
+    /// Whether queries of this type are filtered at all
+    ///
+    #[inline]
+    pub fn applies_to(query_type: RecordType) -> bool {
+        // We currently only support A/AAAA query filtering, along with
+        // PTR for the reverse records of those we define locally, and
+        // HTTPS/SVCB, as their address hints would otherwise bypass the former
+        query_type.is_ip_addr()
+            || matches!(
+                query_type,
+                RecordType::PTR | RecordType::HTTPS | RecordType::SVCB
+            )
+    }
+
+    ///
+    /// The rules from the list, ordered by domain, limited to those whose domain
+    /// contains the search (should there be one). Returns how many rules match
+    /// in all, along with the first `limit` of them.
+    ///
+    pub fn entries(&self, list: &str, search: Option<&str>, limit: usize) -> (usize, Vec<Rule>) {
+        let search = search.map(str::to_ascii_lowercase);
+
+        let mut rules = self
+            .rules
+            .iter()
+            .filter(|rule| rule.list().is_some_and(|source| source.name == list))
+            .filter(|rule| {
+                search
+                    .as_deref()
+                    .map_or(true, |search| rule.domain.contains(search))
+            })
+            .collect::<Vec<_>>();
+        let total = rules.len();
+
+        // There's no need to sort every rule of a large list just to show a few
+        if rules.len() > limit {
+            rules.select_nth_unstable_by(limit, |a, b| a.domain.cmp(&b.domain));
+            rules.truncate(limit);
+        }
+        rules.sort_unstable_by(|a, b| a.domain.cmp(&b.domain));
+
+        (total, rules.into_iter().cloned().collect())
+    }
+
+    ///
+    /// The rules currently loaded from the list, as with [`Filter::entries`]
+    ///
+    pub fn contents(list: &str, search: Option<&str>, limit: usize) -> (usize, Vec<Rule>) {
+        Self::current().entries(list, search, limit)
+    }
+
+    ///
+    /// The rule (if any) that applies to the name, regardless of the record type
+    ///
+    pub async fn lookup(name: &Name) -> Option<Rule> {
+        Self::current().find(name).clone()
+    }
+
+    ///
+    /// Export the currently loaded rules as a zone file
+    ///
+    pub async fn zone() -> String {
+        export::zone(&Self::current().rules)
+    }
+
+    ///
+    /// Export the domains currently being blocked as a flat list
+    ///
+    pub async fn blocklist(format: export::Format) -> String {
+        export::blocklist(&Self::current().rules, format)
+    }
+
+    ///
+    /// The fetch status of each list, keyed by the list's file name
+    ///
+    pub async fn statuses() -> AHashMap<String, Status> {
+        LISTS.read().await.statuses.clone()
+    }
+
+    ///
+    /// The rules currently in use, which stay usable for as long as they're
+    /// held, even should they be replaced in the meantime
+    ///
+    fn current() -> Arc<Filter<'static>> {
+        FILTER
+            .read()
+            .map(|filter| Arc::clone(&filter))
+            .unwrap_or_default()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::Path;
+    use std::{
+        io::{Read, Write},
+        net::Ipv4Addr,
+        path::Path,
+        time::{Duration, SystemTime},
+    };
 
-    use hickory_proto::serialize::binary::{BinDecodable, BinDecoder};
+    use ahash::AHashSet;
+    use hickory_proto::{
+        op::{Message, Query},
+        rr::{
+            rdata::{
+                svcb::{IpHint, SvcParamKey, SvcParamValue},
+                A, HTTPS,
+            },
+            Name, RData, RecordType,
+        },
+        serialize::binary::{BinDecodable, BinDecoder},
+    };
     use hickory_server::{
         authority::MessageRequest,
         server::{Protocol, Request},
     };
     use pretty_assertions::assert_eq;
 
-    use crate::filter::rules::{Kind, Rules};
+    use crate::{
+        filter::rules::{Kind, Modifiers, Rule, Rules, Scope, Source, Type},
+        schedule::Window,
+    };
 
-    use super::Filter;
+    use super::{
+        patterns::{Pattern, Patterns},
+        Blocking, Custom, Downloads, Filter, List, Policy, Status, Unmatched, LISTS,
+    };
 
     #[test]
     fn parsing() {
@@ -350,7 +1233,405 @@ mod tests {
         assert!(entries.is_ok());
 
         let entries = entries.unwrap();
-        assert_eq!(filter.rules.insert(entries), 81562);
+        assert_eq!(filter.rules.insert(entries, None), 81562);
+    }
+
+    #[test]
+    fn loading() {
+        let mut rules = Rules::default();
+        rules.insert(Rules::parse(Path::new("benches/test.txt")).unwrap(), None);
+
+        let (loaded, entries) = Rules::load(Path::new("benches/test.txt"), None).unwrap();
+
+        assert_eq!(entries, 81562);
+        assert!(loaded == rules);
+    }
+
+    #[test]
+    fn entries() {
+        let mut filter = Filter::default();
+        filter.rules.insert(
+            vec![
+                Type::Domain(String::from("ads.example.com")),
+                Type::Domain(String::from("tracker.example.com")),
+                Type::Domain(String::from("ads.example.org")),
+            ],
+            Some(Source {
+                name: String::from("Ads"),
+                url: String::from("https://example.com/ads.txt"),
+            }),
+        );
+        filter
+            .rules
+            .insert(Filter::builtin().unwrap(), Some(Source::builtin()));
+
+        let domains = |(total, rules): (usize, Vec<Rule>)| {
+            (
+                total,
+                rules
+                    .iter()
+                    .map(|rule| rule.domain().to_string())
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        assert_eq!(
+            domains(filter.entries("Ads", None, 2)),
+            (
+                3,
+                vec![
+                    String::from("ads.example.com"),
+                    String::from("ads.example.org")
+                ]
+            )
+        );
+        assert_eq!(
+            domains(filter.entries("Ads", Some("Tracker"), 100)),
+            (1, vec![String::from("tracker.example.com")])
+        );
+        assert_eq!(domains(filter.entries("Missing", None, 100)), (0, vec![]));
+    }
+
+    #[test]
+    fn compact() {
+        // There's one of these for every label of every rule
+        assert!(std::mem::size_of::<Rules>() <= 128);
+    }
+
+    #[test]
+    fn builtin() {
+        let mut filter = Filter::default();
+
+        let entries = Filter::builtin();
+        assert!(entries.is_ok());
+        assert!(filter.rules.insert(entries.unwrap(), None) > 0);
+        assert!(filter.rules.children.contains_key("net"));
+    }
+
+    #[test]
+    fn missing_lists() {
+        let missing = List {
+            name: String::from("Missing"),
+            url: String::from("https://example.com/missing.txt"),
+            enabled: true,
+            schedule: None,
+            entries: 0,
+        };
+        let local = List {
+            name: String::from("Local"),
+            url: String::from("benches/test.txt"),
+            enabled: true,
+            schedule: None,
+            entries: 0,
+        };
+        let directory = std::env::temp_dir().join("blackhole-missing-lists");
+
+        let builtin = Filter::default()
+            .rules
+            .insert(Filter::builtin().unwrap(), None);
+
+        // Nothing's been downloaded yet, so the builtin list stands in
+        let (filter, entries, count) = Filter::load(
+            AHashSet::from_iter([missing.clone()]),
+            &directory,
+            &AHashSet::new(),
+            true,
+        )
+        .unwrap();
+        assert!(entries.is_empty());
+        assert_eq!(count, builtin);
+        assert!(filter.rules.children.contains_key("net"));
+
+        let (filter, entries, count) = Filter::load(
+            AHashSet::from_iter([missing.clone()]),
+            &directory,
+            &AHashSet::new(),
+            false,
+        )
+        .unwrap();
+        assert!(entries.is_empty());
+        assert_eq!(count, 0);
+        assert!(filter.rules.children.is_empty());
+
+        // Once one of them has been, it replaces the builtin list
+        let (_, entries, count) = Filter::load(
+            AHashSet::from_iter([missing, local.clone()]),
+            &directory,
+            &AHashSet::new(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, local.to_string());
+        assert_eq!(count, entries[0].1);
+    }
+
+    #[test]
+    fn ptr() {
+        let mut filter = Filter::default();
+
+        filter.rules.insert(
+            vec![
+                Type::Host("192.168.1.10".parse().unwrap(), String::from("nas.home")),
+                Type::Host("0.0.0.0".parse().unwrap(), String::from("ads.example.com")),
+                Type::Host("fd00::1".parse().unwrap(), String::from("router.home")),
+            ],
+            None,
+        );
+        assert_eq!(filter.rules.generate_ptr(), 2);
+
+        let request = Request::new(
+            MessageRequest::read(&mut BinDecoder::new(&[
+                0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, b'1',
+                b'0', 0x01, b'1', 0x03, b'1', b'6', b'8', 0x03, b'1', b'9', b'2', 0x07, b'i', b'n',
+                b'-', b'a', b'd', b'd', b'r', 0x04, b'a', b'r', b'p', b'a', 0x00, 0x00, 0x0c, 0x00,
+                0x01,
+            ]))
+            .unwrap(),
+            "127.0.0.1:53".parse().unwrap(),
+            Protocol::Udp,
+        );
+
+        let rule = filter.filter(&request).clone().unwrap();
+        let response = rule.apply(&request);
+
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(
+            response.answers()[0].data().unwrap().to_string(),
+            "nas.home."
+        );
+    }
+
+    #[tokio::test]
+    async fn conditional_fetch() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            let mut request = [0; 4096];
+            let length = stream.read(&mut request).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 304 Not Modified\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+
+            String::from_utf8_lossy(&request[..length]).to_ascii_lowercase()
+        });
+
+        let list = List {
+            name: String::from("Conditional"),
+            url: format!("http://{address}/list.txt"),
+            enabled: true,
+            schedule: None,
+            entries: 0,
+        };
+        let path = list.to_string();
+        std::fs::write(&path, "example.com").unwrap();
+
+        let mut status = Status {
+            etag: Some(String::from("\"abc\"")),
+            ..Default::default()
+        };
+        let result = Filter::fetch(
+            &reqwest::Client::new(),
+            &Downloads::default(),
+            &list,
+            Path::new(&path),
+            &mut status,
+        )
+        .await;
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.is_ok());
+        assert!(server.join().unwrap().contains("if-none-match: \"abc\""));
+        assert_eq!(status.code, Some(304));
+        assert_eq!(contents, "example.com");
+    }
+
+    #[tokio::test]
+    async fn retrying() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            for response in [
+                &b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"[..],
+                &b"HTTP/1.1 200 OK\r\nConnection: close\r\nETag: \"def\"\r\nContent-Length: 11\r\n\r\nexample.com"[..],
+            ] {
+                let (mut stream, _) = listener.accept().unwrap();
+
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).unwrap();
+                stream.write_all(response).unwrap();
+            }
+        });
+
+        let list = List {
+            name: String::from("Retrying"),
+            url: format!("http://{address}/list.txt"),
+            enabled: true,
+            schedule: None,
+            entries: 0,
+        };
+        let path = list.to_string();
+
+        let mut status = Status::default();
+        let result = Filter::fetch(
+            &reqwest::Client::new(),
+            &Downloads {
+                backoff: Duration::from_millis(10),
+                ..Default::default()
+            },
+            &list,
+            Path::new(&path),
+            &mut status,
+        )
+        .await;
+
+        let contents = std::fs::read_to_string(&path).unwrap_or_default();
+        std::fs::remove_file(&path).unwrap_or_default();
+        server.join().unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(status.code, Some(200));
+        assert_eq!(status.etag.as_deref(), Some("\"def\""));
+        assert_eq!(contents, "example.com");
+    }
+
+    #[test]
+    fn custom() {
+        let mut filter = Filter::default();
+
+        filter.rules.insert(
+            Rules::parse_lines(
+                [
+                    "||ads.example.com",
+                    "@@||cdn.example.com",
+                    "tracker.example.com",
+                ]
+                .into_iter()
+                .map(String::from),
+            )
+            .unwrap(),
+            None,
+        );
+
+        let kind = |filter: &Filter, domain: &str| {
+            filter
+                .rules
+                .iter()
+                .find(|rule| rule.domain == domain)
+                .map(|rule| (rule.kind.clone(), rule.answers_locally()))
+        };
+
+        assert_eq!(kind(&filter, "ads.example.com"), Some((Kind::Deny, true)));
+        assert_eq!(kind(&filter, "cdn.example.com"), Some((Kind::Allow, false)));
+
+        filter.rules.replace(&Custom {
+            domain: String::from("tracker.example.com"),
+            kind: Kind::Allow,
+            ..Default::default()
+        });
+        filter.rules.replace(&Custom {
+            domain: String::from("new.example.com"),
+            kind: Kind::Deny,
+            ..Default::default()
+        });
+        filter.rules.replace(&Custom {
+            domain: String::from("cloud.example.com"),
+            v4: Some(Ipv4Addr::new(192, 168, 1, 10)),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            kind(&filter, "tracker.example.com"),
+            Some((Kind::Allow, false))
+        );
+        assert_eq!(kind(&filter, "new.example.com"), Some((Kind::Deny, true)));
+        assert_eq!(kind(&filter, "cloud.example.com"), Some((Kind::None, true)));
+    }
+
+    #[tokio::test]
+    async fn local() {
+        for url in ["benches/test.txt", "file://benches/test.txt"] {
+            let list = List {
+                name: String::from("Local"),
+                url: String::from(url),
+                enabled: true,
+                schedule: None,
+                entries: 0,
+            };
+
+            assert_eq!(list.path(Path::new(".")), Path::new("benches/test.txt"));
+            assert!(
+                Filter::download(
+                    &reqwest::Client::new(),
+                    &Downloads::default(),
+                    list.clone(),
+                    false,
+                )
+                .await
+                .is_ok()
+            );
+            assert!(!Path::new(&list.to_string()).exists());
+
+            let mut lists = LISTS.write().await;
+            assert!(lists.lists.remove(&list));
+            assert!(lists.statuses.remove(&list.to_string()).is_some());
+        }
+
+        let list = List {
+            name: String::from("Remote"),
+            url: String::from("https://example.com/list.txt"),
+            enabled: true,
+            schedule: None,
+            entries: 0,
+        };
+        assert_eq!(list.local(), None);
+        assert_eq!(
+            list.path(Path::new("/lists")),
+            Path::new("/lists").join(list.to_string())
+        );
+    }
+
+    #[test]
+    fn pruning() {
+        let directory = std::env::temp_dir().join("blackhole-pruning");
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let list = List {
+            name: String::from("Disabled"),
+            url: String::from("https://example.com/list.txt"),
+            enabled: false,
+            schedule: None,
+            entries: 0,
+        };
+        let kept = list.path(&directory);
+        let orphaned = directory.join("1234.txt");
+        let unrelated = directory.join("notes.txt");
+
+        for path in [
+            &kept,
+            &kept.with_extension("json"),
+            &orphaned,
+            &orphaned.with_extension("json"),
+            &unrelated,
+        ] {
+            std::fs::write(path, "").unwrap();
+        }
+
+        Filter::prune(&directory, &AHashSet::from_iter([list]));
+
+        assert!(kept.exists());
+        assert!(kept.with_extension("json").exists());
+        assert!(!orphaned.exists());
+        assert!(!orphaned.with_extension("json").exists());
+        assert!(unrelated.exists());
+
+        std::fs::remove_dir_all(&directory).unwrap();
     }
 
     #[test]
@@ -370,7 +1651,7 @@ mod tests {
         );
 
         let entries = Rules::parse(Path::new("benches/test.txt")).unwrap();
-        filter.rules.insert(entries);
+        filter.rules.insert(entries, None);
 
         let rule = filter.filter(&request);
         assert!(rule.is_some());
@@ -379,6 +1660,327 @@ mod tests {
         assert_eq!(rule.kind, Kind::Deny);
     }
 
+    #[test]
+    fn adblock() {
+        let mut filter = Filter::default();
+
+        filter.rules.insert(
+            Rules::parse_lines(
+                [
+                    "||ads.example.com^",
+                    "@@||ads.example.com^",
+                    "||tracker.example.com^$important",
+                    "@@||tracker.example.com^",
+                    "||v6.example.com^$dnstype=AAAA",
+                    "||kids.example.com^$client=192.168.1.0/24|~192.168.1.5",
+                    "||nas.example.com^$dnsrewrite=192.168.1.10",
+                    "||alias.example.com^$dnsrewrite=NOERROR;CNAME;example.org",
+                    "||gone.example.com^$dnsrewrite=NXDOMAIN",
+                    "||unsupported.example.com^$denyallow=example.org",
+                ]
+                .into_iter()
+                .map(String::from),
+            )
+            .unwrap(),
+            None,
+        );
+
+        let rule = |domain: &str| {
+            filter
+                .find(&Name::from_ascii(domain).unwrap())
+                .clone()
+                .unwrap()
+        };
+        let client = |ip: &str| ip.parse().unwrap();
+
+        // Exceptions win, unless the rule blocking the domain is important
+        assert_eq!(rule("ads.example.com.").kind, Kind::Allow);
+        assert_eq!(rule("tracker.example.com.").kind, Kind::Deny);
+
+        let v6 = rule("v6.example.com.");
+        assert!(v6.applies(RecordType::AAAA, client("127.0.0.1")));
+        assert!(!v6.applies(RecordType::A, client("127.0.0.1")));
+        // The hints would otherwise hand out the addresses it blocks
+        assert!(v6.applies(RecordType::HTTPS, client("127.0.0.1")));
+        assert!(v6.applies(RecordType::SVCB, client("127.0.0.1")));
+        assert!(!v6.applies(RecordType::MX, client("127.0.0.1")));
+
+        let kids = rule("kids.example.com.");
+        assert!(kids.applies(RecordType::A, client("192.168.1.4")));
+        assert!(!kids.applies(RecordType::A, client("192.168.1.5")));
+        assert!(!kids.applies(RecordType::A, client("10.0.0.1")));
+
+        let nas = rule("nas.example.com.").records(
+            &Name::from_ascii("nas.example.com.").unwrap(),
+            RecordType::A,
+        );
+        assert_eq!(
+            nas[0].data(),
+            Some(&RData::A(A(Ipv4Addr::new(192, 168, 1, 10))))
+        );
+        assert_eq!(
+            rule("alias.example.com.").cname(),
+            Some(Name::from_ascii("example.org.").unwrap())
+        );
+        assert!(rule("gone.example.com.").sinkholes());
+        assert!(
+            filter
+                .find(&Name::from_ascii("unsupported.example.com.").unwrap())
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn dnstype() {
+        let mut filter = Filter::default();
+
+        filter.rules.insert(
+            Rules::parse_lines(
+                [
+                    "||mail.example.com^$dnstype=mx|txt",
+                    "||web.example.com^$dnstype=~a|~aaaa",
+                    "||bogus.example.com^$dnstype=NOTATYPE",
+                ]
+                .into_iter()
+                .map(String::from),
+            )
+            .unwrap(),
+            None,
+        );
+
+        let rule = |domain: &str| filter.find(&Name::from_ascii(domain).unwrap()).clone();
+        let client = "127.0.0.1".parse().unwrap();
+
+        let mail = rule("mail.example.com.").unwrap();
+        assert!(mail.applies(RecordType::MX, client));
+        assert!(mail.applies(RecordType::TXT, client));
+        assert!(!mail.applies(RecordType::A, client));
+
+        let web = rule("web.example.com.").unwrap();
+        assert!(!web.applies(RecordType::A, client));
+        assert!(!web.applies(RecordType::AAAA, client));
+        assert!(web.applies(RecordType::TXT, client));
+
+        // Rules with types we don't know are left out
+        assert!(rule("bogus.example.com.").is_none());
+
+        // The scope survives being saved and loaded again
+        let scope = mail.scope.unwrap();
+        assert_eq!(
+            serde_json::from_str::<Scope>(&serde_json::to_string(&scope).unwrap()).unwrap(),
+            *scope
+        );
+    }
+
+    #[test]
+    fn dnsmasq_and_unbound() {
+        let mut filter = Filter::default();
+
+        filter.rules.insert(
+            Rules::parse_lines(
+                [
+                    "address=/ads.example.com/tracker.example.com/",
+                    "address=/nas.example.com/192.168.1.10",
+                    "address=/null.example.com/#",
+                    "server:",
+                    "    local-zone: \"unbound.example.com.\" always_nxdomain",
+                    "    local-zone: \"ok.unbound.example.com.\" transparent",
+                    "    local-zone: \"printer.example.com.\" redirect",
+                    "    local-data: \"printer.example.com. 3600 IN A 192.168.1.20\"",
+                    "    local-zone: \"view.example.com.\" noview",
+                ]
+                .into_iter()
+                .map(String::from),
+            )
+            .unwrap(),
+            None,
+        );
+
+        let rule = |domain: &str| filter.find(&Name::from_ascii(domain).unwrap()).clone();
+
+        // dnsmasq addresses cover subdomains too
+        for domain in [
+            "ads.example.com.",
+            "sub.tracker.example.com.",
+            "null.example.com.",
+            "sub.unbound.example.com.",
+        ] {
+            assert!(rule(domain).unwrap().sinkholes(), "{domain}");
+        }
+
+        let nas = rule("sub.nas.example.com.").unwrap().records(
+            &Name::from_ascii("sub.nas.example.com.").unwrap(),
+            RecordType::A,
+        );
+        assert_eq!(
+            nas[0].data(),
+            Some(&RData::A(A(Ipv4Addr::new(192, 168, 1, 10))))
+        );
+
+        let printer = rule("printer.example.com.").unwrap().records(
+            &Name::from_ascii("printer.example.com.").unwrap(),
+            RecordType::A,
+        );
+        assert_eq!(
+            printer[0].data(),
+            Some(&RData::A(A(Ipv4Addr::new(192, 168, 1, 20))))
+        );
+
+        assert_eq!(rule("ok.unbound.example.com.").unwrap().kind, Kind::Allow);
+        assert!(rule("view.example.com.").is_none());
+    }
+
+    #[test]
+    fn zones() {
+        let mut filter = Filter::default();
+        filter
+            .rules
+            .insert(vec![Type::Domain(String::from("ads.example.com"))], None);
+        filter.rules.replace(&Custom {
+            domain: String::from("*.doubleclick.net"),
+            kind: Kind::Deny,
+            ..Default::default()
+        });
+        filter.rules.replace(&Custom {
+            domain: String::from("example.com"),
+            kind: Kind::Allow,
+            zone: true,
+            ..Default::default()
+        });
+        filter.rules.replace(&Custom {
+            domain: String::from("allowed.doubleclick.net"),
+            kind: Kind::Allow,
+            ..Default::default()
+        });
+        filter.rules.replace(&Custom {
+            domain: String::from("cdn.static.doubleclick.net"),
+            kind: Kind::Allow,
+            ..Default::default()
+        });
+
+        let kind = |domain: &str| {
+            filter
+                .find(&Name::from_ascii(domain).unwrap())
+                .as_ref()
+                .map(|rule| (rule.domain().to_string(), rule.kind().clone()))
+        };
+
+        assert_eq!(
+            kind("doubleclick.net."),
+            Some((String::from("doubleclick.net"), Kind::Deny))
+        );
+        assert_eq!(
+            kind("a.b.doubleclick.net."),
+            Some((String::from("doubleclick.net"), Kind::Deny))
+        );
+        // More specific rules take precedence over the zone
+        assert_eq!(
+            kind("allowed.doubleclick.net."),
+            Some((String::from("allowed.doubleclick.net"), Kind::Allow))
+        );
+        assert_eq!(
+            kind("static.doubleclick.net."),
+            Some((String::from("doubleclick.net"), Kind::Deny))
+        );
+        assert_eq!(
+            kind("ads.example.com."),
+            Some((String::from("ads.example.com"), Kind::Deny))
+        );
+        assert_eq!(
+            kind("www.example.com."),
+            Some((String::from("example.com"), Kind::Allow))
+        );
+        assert_eq!(kind("doubleclick.com."), None);
+    }
+
+    #[test]
+    fn attribution() {
+        let list = List {
+            name: String::from("Test"),
+            url: String::from("benches/test.txt"),
+            enabled: true,
+            schedule: None,
+            entries: 0,
+        };
+
+        let (mut rules, _) =
+            Rules::load(&list.path(Path::new(".")), Some(Source::from(&list))).unwrap();
+        rules.replace(&Custom {
+            domain: String::from("custom.example.com"),
+            kind: Kind::Deny,
+            ..Default::default()
+        });
+        rules.insert(Filter::builtin().unwrap(), Some(Source::builtin()));
+
+        let filter = Filter {
+            rules,
+            ..Default::default()
+        };
+        let source = |domain: &str| {
+            filter
+                .find(&Name::from_ascii(domain).unwrap())
+                .as_ref()
+                .and_then(Rule::list)
+                .map(|source| source.name.clone())
+        };
+
+        assert_eq!(source("google.com."), Some(String::from("Test")));
+        assert_eq!(source("2mdn.net."), Some(String::from("Builtin")));
+        assert_eq!(source("custom.example.com."), None);
+    }
+
+    #[test]
+    fn blocking() {
+        let now = SystemTime::now();
+        let minutes = Duration::from_secs(5 * 60);
+
+        assert!(Blocking::default().active(now));
+        assert!(!Blocking {
+            enabled: false,
+            until: None,
+        }
+        .active(now));
+        assert!(!Blocking {
+            enabled: false,
+            until: Some(now + minutes),
+        }
+        .active(now));
+        assert!(Blocking {
+            enabled: false,
+            until: Some(now - minutes),
+        }
+        .active(now));
+
+        let mut filter = Filter::default();
+        filter.rules.insert(
+            vec![
+                Type::Domain(String::from("ads.example.com")),
+                Type::Host("0.0.0.0".parse().unwrap(), String::from("tracker.example.com")),
+                Type::Host("192.168.1.10".parse().unwrap(), String::from("nas.home")),
+            ],
+            None,
+        );
+        filter.rules.replace(&Custom {
+            domain: String::from("cloud.example.com"),
+            kind: Kind::Deny,
+            cname: Some(String::from("nas.home")),
+            ..Default::default()
+        });
+
+        let sinkholes = |domain: &str| {
+            filter
+                .find(&Name::from_ascii(domain).unwrap())
+                .as_ref()
+                .is_some_and(Rule::sinkholes)
+        };
+
+        // Local records should keep working while blocking is paused
+        assert!(sinkholes("ads.example.com."));
+        assert!(sinkholes("tracker.example.com."));
+        assert!(!sinkholes("nas.home."));
+        assert!(!sinkholes("cloud.example.com."));
+    }
+
     #[test]
     fn regex_matching() {
         let mut filter = Filter::default();
@@ -396,7 +1998,7 @@ mod tests {
         );
 
         let entries = Rules::parse(Path::new("benches/test.txt")).unwrap();
-        filter.rules.insert(entries);
+        filter.rules.insert(entries, None);
 
         let rule = filter.filter(&request);
         assert!(rule.is_some());
@@ -405,4 +2007,263 @@ mod tests {
         assert_eq!(rule.kind, Kind::Deny);
         assert_eq!(rule.domain, "*mail.com");
     }
+
+    #[test]
+    fn wildcards() {
+        let mut filter = Filter::default();
+        filter.rules.insert(
+            vec![
+                Type::Domain(String::from("ads*.example.org")),
+                Type::Domain(String::from("*track*.example.org")),
+                Type::Domain(String::from("*.tracker.net")),
+                Type::Adblock(
+                    Kind::Allow,
+                    Box::new(Type::Domain(String::from("ads-ok.example.org"))),
+                    Modifiers::default(),
+                ),
+            ],
+            None,
+        );
+
+        let domain = |domain: &str| {
+            filter
+                .find(&Name::from_ascii(domain).unwrap())
+                .as_ref()
+                .map(|rule| rule.domain().to_string())
+        };
+
+        assert_eq!(
+            domain("ads.example.org."),
+            Some(String::from("ads*.example.org"))
+        );
+        assert_eq!(
+            domain("ads-1.example.org."),
+            Some(String::from("ads*.example.org"))
+        );
+        // Wildcards only match the whole label, not just part of it
+        assert_eq!(domain("bads.example.org."), None);
+        assert_eq!(
+            domain("mytracking.example.org."),
+            Some(String::from("*track*.example.org"))
+        );
+        assert_eq!(domain("trac.example.org."), None);
+        // Exact matches take precedence over wildcards
+        assert_eq!(
+            domain("ads-ok.example.org."),
+            Some(String::from("ads-ok.example.org"))
+        );
+        assert_eq!(
+            domain("a.b.tracker.net."),
+            Some(String::from("*.tracker.net"))
+        );
+        assert_eq!(domain("tracker.net."), None);
+    }
+
+    #[test]
+    fn windows() {
+        let mut social = Rules::default();
+        social.insert(
+            vec![Type::Domain(String::from("social.example.com"))],
+            Some(Source {
+                name: String::from("Social"),
+                url: String::from("social.txt"),
+            }),
+        );
+
+        let mut filter = Filter::default();
+        filter.overlays.insert(String::from("Social"), social);
+
+        let name = Name::from_ascii("social.example.com.").unwrap();
+        let kids = "192.168.1.130".parse().unwrap();
+        let adults = "192.168.1.20".parse().unwrap();
+
+        // Nothing is enforced until the window opens
+        Filter::open(Vec::new());
+        assert_eq!(filter.scheduled(&name, kids), None);
+
+        Filter::open(vec![Window {
+            name: String::from("Bedtime"),
+            lists: vec![String::from("Social")],
+            clients: vec!["192.168.1.128/25".parse().unwrap()],
+            days: Vec::new(),
+            start: "21:00".parse().unwrap(),
+            end: "07:00".parse().unwrap(),
+        }]);
+        assert_eq!(
+            filter
+                .scheduled(&name, kids)
+                .map(|rule| rule.kind().clone()),
+            Some(Kind::Deny)
+        );
+        assert_eq!(filter.scheduled(&name, adults), None);
+        // The lists only enforced during a window aren't part of the rules
+        assert_eq!(filter.find(&name), &None);
+
+        Filter::open(Vec::new());
+    }
+
+    #[test]
+    fn patterns() {
+        let mut filter = Filter {
+            patterns: Patterns::new(&[
+                Pattern {
+                    pattern: String::from(r"^ad[sx]?[0-9]*\."),
+                    kind: Kind::Deny,
+                },
+                Pattern {
+                    pattern: String::from(r"^ads\.example\.org$"),
+                    kind: Kind::Allow,
+                },
+                Pattern {
+                    pattern: String::from("(unclosed"),
+                    kind: Kind::Deny,
+                },
+            ]),
+            ..Default::default()
+        };
+        filter
+            .rules
+            .insert(vec![Type::Domain(String::from("ad1.example.com"))], None);
+
+        let rule = |domain: &str| {
+            filter
+                .find(&Name::from_ascii(domain).unwrap())
+                .as_ref()
+                .map(|rule| (rule.domain().to_string(), rule.kind().clone()))
+        };
+
+        // Invalid patterns are left out, rather than failing the whole set
+        assert_eq!(filter.patterns.len(), 2);
+        assert_eq!(
+            rule("AdX12.Example.net."),
+            Some((String::from(r"^ad[sx]?[0-9]*\."), Kind::Deny))
+        );
+        assert_eq!(
+            rule("ads.example.org."),
+            Some((String::from(r"^ads\.example\.org$"), Kind::Allow))
+        );
+        // The rules are always consulted first
+        assert_eq!(
+            rule("ad1.example.com."),
+            Some((String::from("ad1.example.com"), Kind::Deny))
+        );
+        assert_eq!(rule("bad.example.com."), None);
+    }
+
+    #[test]
+    fn policy() {
+        let request = |query_type, client: &str| {
+            let mut message = Message::new();
+            message.add_query(Query::query(
+                Name::from_ascii("unlisted.example.invalid.").unwrap(),
+                query_type,
+            ));
+
+            Request::new(
+                MessageRequest::read(&mut BinDecoder::new(&message.to_vec().unwrap())).unwrap(),
+                client.parse().unwrap(),
+                Protocol::Udp,
+            )
+        };
+        let check = |query_type, client: &str, policy| {
+            let request = request(query_type, client);
+            Filter::check(&request, request.src().ip(), policy)
+        };
+
+        let allow = Policy::default();
+        let deny = Policy {
+            unmatched: Unmatched::Deny,
+            clients: Vec::new(),
+        };
+        let kiosks = Policy {
+            unmatched: Unmatched::Deny,
+            clients: vec!["10.0.50.0/24".parse().unwrap()],
+        };
+
+        assert_eq!(
+            check(RecordType::A, "10.0.50.2:53", &allow),
+            None
+        );
+
+        let rule = check(RecordType::A, "10.0.50.2:53", &deny).unwrap();
+        assert_eq!(rule.kind, Kind::Deny);
+        assert_eq!(rule.domain, "unlisted.example.invalid");
+
+        // Every type is denied, not just those that are usually filtered
+        assert_eq!(
+            check(RecordType::TXT, "10.0.50.2:53", &allow),
+            None
+        );
+        assert!(
+            check(RecordType::TXT, "10.0.50.2:53", &deny)
+                .is_some_and(|rule| rule.kind == Kind::Deny)
+        );
+
+        assert!(check(RecordType::A, "10.0.50.2:53", &kiosks).is_some());
+        assert_eq!(
+            check(RecordType::A, "10.0.1.2:53", &kiosks),
+            None
+        );
+    }
+
+    #[test]
+    fn https() {
+        let mut filter = Filter::default();
+
+        filter.rules.insert(
+            vec![
+                Type::Domain(String::from("ads.example.com")),
+                Type::Host("192.168.1.10".parse().unwrap(), String::from("nas.home")),
+            ],
+            None,
+        );
+
+        let request = |name: &str, query_type| {
+            let mut message = Message::new();
+            message.add_query(Query::query(Name::from_ascii(name).unwrap(), query_type));
+
+            Request::new(
+                MessageRequest::read(&mut BinDecoder::new(&message.to_vec().unwrap())).unwrap(),
+                "127.0.0.1:53".parse().unwrap(),
+                Protocol::Udp,
+            )
+        };
+
+        assert!(Filter::applies_to(RecordType::HTTPS));
+        assert!(Filter::applies_to(RecordType::SVCB));
+
+        for (name, v4) in [
+            ("ads.example.com.", Ipv4Addr::UNSPECIFIED),
+            ("nas.home.", Ipv4Addr::new(192, 168, 1, 10)),
+        ] {
+            for query_type in [RecordType::HTTPS, RecordType::SVCB] {
+                let request = request(name, query_type);
+                let rule = filter.filter(&request).clone().unwrap();
+                assert!(rule.answers_locally());
+
+                let response = rule.apply(&request);
+                assert_eq!(response.answers().len(), 1);
+                assert_eq!(response.answers()[0].record_type(), query_type);
+
+                let svcb = match response.answers()[0].data().unwrap() {
+                    RData::HTTPS(HTTPS(svcb)) | RData::SVCB(svcb) => svcb.clone(),
+                    data => panic!("Unexpected answer: {data}"),
+                };
+
+                // Only the address hints survive, nothing that'd let the client
+                // negotiate its way around the answer
+                assert_eq!(
+                    svcb.svc_params()
+                        .iter()
+                        .map(|(key, _)| *key)
+                        .collect::<Vec<_>>(),
+                    vec![SvcParamKey::Ipv4Hint, SvcParamKey::Ipv6Hint]
+                );
+                assert_eq!(
+                    svcb.svc_params()[0].1,
+                    SvcParamValue::Ipv4Hint(IpHint(vec![A(v4)]))
+                );
+            }
+        }
+    }
 }
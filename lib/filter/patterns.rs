@@ -0,0 +1,115 @@
+use hickory_proto::rr::Name;
+use regex::{Regex, RegexSet};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use super::rules::{Kind, Rule};
+
+const fn default_kind() -> Kind {
+    Kind::Deny
+}
+
+///
+/// A rule for every domain matching a regular expression, for when a domain
+/// (or a wildcard) alone can't describe what should be blocked
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pattern {
+    /// Matched against the whole domain, in lowercase and without the trailing dot
+    pub pattern: String,
+    #[serde(default = "default_kind")]
+    pub kind: Kind,
+}
+
+///
+/// Every pattern compiled together, so that a domain can be matched against all
+/// of them at once
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+pub struct Patterns {
+    set: RegexSet,
+    /// The rule for each of the patterns in the set, in the same order
+    rules: Vec<Option<Rule>>,
+}
+
+impl Default for Patterns {
+    fn default() -> Self {
+        Self {
+            set: RegexSet::empty(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl Patterns {
+    ///
+    /// Compile the patterns, leaving out any that aren't valid
+    ///
+    pub fn new(patterns: &[Pattern]) -> Self {
+        let patterns = patterns
+            .iter()
+            .filter(|pattern| match Regex::new(&pattern.pattern) {
+                Ok(_) => true,
+                Err(err) => {
+                    warn!("Ignoring pattern '{}': {err}", pattern.pattern);
+                    false
+                }
+            })
+            .collect::<Vec<_>>();
+
+        match RegexSet::new(patterns.iter().map(|pattern| &pattern.pattern)) {
+            Ok(set) => Self {
+                set,
+                rules: patterns
+                    .into_iter()
+                    .map(|pattern| {
+                        Some(Rule {
+                            domain: pattern.pattern.clone(),
+                            kind: pattern.kind.clone(),
+                            action: None,
+                            list: None,
+                            zone: false,
+                            important: false,
+                            scope: None,
+                        })
+                    })
+                    .collect(),
+            },
+            Err(err) => {
+                error!("Unable to compile patterns: {err}");
+                Self::default()
+            }
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    ///
+    /// The rule of the pattern matching the name, with those allowing the name
+    /// taking precedence so that exceptions can be made to broader patterns
+    ///
+    pub fn find(&self, name: &Name) -> &Option<Rule> {
+        if self.is_empty() {
+            return &None;
+        }
+
+        let name = name.to_lowercase().to_string();
+        let matches = self.set.matches(name.trim_end_matches('.'));
+
+        matches
+            .iter()
+            .map(|index| &self.rules[index])
+            .find(|rule| rule.as_ref().is_some_and(|rule| rule.kind == Kind::Allow))
+            .or_else(|| matches.iter().next().map(|index| &self.rules[index]))
+            .unwrap_or(&None)
+    }
+}
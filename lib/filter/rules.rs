@@ -1,33 +1,44 @@
 use std::{
     borrow::Cow,
+    collections::HashMap,
     fmt::Display,
+    hash::{BuildHasherDefault, DefaultHasher, Hash, Hasher},
     io::{BufRead, BufReader},
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
 };
 
-use ahash::AHashMap;
+use ahash::AHasher;
 use chumsky::{
     extra,
-    primitive::{any, choice, end, just, one_of},
+    primitive::{any, choice, end, just, none_of, one_of},
     text, IterParser, Parser,
 };
 use hickory_proto::{
     op::{Message, MessageType, ResponseCode},
     rr::{
-        rdata::{A, AAAA},
-        RData, Record, RecordType,
+        rdata::{
+            svcb::{IpHint, SvcParamKey, SvcParamValue, SVCB},
+            A, AAAA, CNAME, HTTPS, PTR,
+        },
+        Name, RData, Record, RecordType,
     },
     xfer::DnsResponse,
 };
 use hickory_server::server::Request;
+use ipnet::IpNet;
 use rayon::{iter::ParallelIterator, prelude::ParallelBridge};
 use serde::{Deserialize, Serialize};
 
-use super::Error;
+use super::{Custom, Error};
 
 const DOMAIN_CHARS: &str = "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ-_*";
 
+/// The TTL of any records we answer with ourselves
+pub const TTL: u32 = 600;
+
 #[cfg_attr(any(debug_assertions, test), derive(Debug))]
 #[derive(Clone, Serialize, PartialEq, Eq, PartialOrd, Deserialize)]
 pub struct Rewrite {
@@ -48,12 +59,18 @@ impl Default for Rewrite {
 #[derive(Clone, Default, Serialize, PartialEq, Eq, PartialOrd, Deserialize)]
 pub(crate) struct Action {
     pub rewrite: Option<Rewrite>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ptr: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cname: Option<String>,
 }
 
 #[cfg_attr(any(debug_assertions, test), derive(Debug))]
-#[derive(Clone, Default, Serialize, PartialEq, Eq, PartialOrd, Deserialize)]
+#[derive(Clone, Default, Serialize, PartialEq, Eq, PartialOrd, Hash, Deserialize)]
 pub enum Kind {
+    #[serde(alias = "allow")]
     Allow,
+    #[serde(alias = "deny")]
     Deny,
     #[default]
     None,
@@ -74,8 +91,207 @@ impl Display for Kind {
 pub enum Type {
     Host(IpAddr, String),
     Domain(String),
-    Adblock(Kind, Box<Type>),
+    Adblock(Kind, Box<Type>, Modifiers),
     Ip(IpAddr),
+    /// A domain along with all of its subdomains, as dnsmasq (`address=`) and
+    /// Unbound (`local-zone:`) configure them
+    Zone(Kind, String, Option<IpAddr>),
+}
+
+impl Type {
+    ///
+    /// The domain the entry is for, if it's for one
+    ///
+    fn domain(&self) -> Option<&str> {
+        match self {
+            Self::Host(_, domain) | Self::Domain(domain) | Self::Zone(_, domain, _) => Some(domain),
+            Self::Adblock(_, ty, _) => ty.domain(),
+            Self::Ip(_) => None,
+        }
+    }
+}
+
+///
+/// The values a rule is limited to, or (with a leading `~`) those it's never
+/// applied to. No values at all means there's no limit.
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Serialize, PartialEq, Eq, PartialOrd, Deserialize)]
+pub struct Only<T> {
+    // Defaulting to `Vec::new` rather than `Default::default` keeps serde from
+    // requiring `T: Default`, which record types aren't
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<T>,
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<T>,
+}
+
+impl<T> Default for Only<T> {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl<T> Only<T> {
+    ///
+    /// Parse values separated by `|`, such as `A|~AAAA`, failing should any of
+    /// them not parse
+    ///
+    fn parse(values: &str, parse: impl Fn(&str) -> Option<T>) -> Option<Self> {
+        let mut only = Self::default();
+
+        for value in values.split('|') {
+            match value.strip_prefix('~') {
+                Some(value) => only.exclude.push(parse(value)?),
+                None => only.include.push(parse(value)?),
+            }
+        }
+
+        Some(only)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn allows(&self, matches: impl Fn(&T) -> bool) -> bool {
+        (self.include.is_empty() || self.include.iter().any(&matches))
+            && !self.exclude.iter().any(matches)
+    }
+}
+
+///
+/// Which queries a rule applies to, should it not apply to all of them
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Default, Serialize, PartialEq, Eq, PartialOrd, Deserialize)]
+pub struct Scope {
+    #[serde(default, skip_serializing_if = "Only::is_empty")]
+    pub types: Only<RecordType>,
+    #[serde(default, skip_serializing_if = "Only::is_empty")]
+    pub clients: Only<IpNet>,
+}
+
+///
+/// What a `$dnsrewrite` modifier answers with
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, PartialEq, Eq)]
+pub enum Target {
+    /// An error (e.g. NXDOMAIN), which we block the domain in place of
+    Block,
+    Address(IpAddr),
+    Name(String),
+}
+
+impl Target {
+    fn parse(value: &str) -> Option<Self> {
+        let value = value.split(';').collect::<Vec<_>>();
+
+        match value[..] {
+            [code] | [code, "", ""]
+                if ["NXDOMAIN", "REFUSED", "SERVFAIL"]
+                    .iter()
+                    .any(|error| code.eq_ignore_ascii_case(error)) =>
+            {
+                Some(Self::Block)
+            }
+            [target] => target
+                .parse()
+                .map(Self::Address)
+                .ok()
+                .or_else(|| fqdn(target).map(|_| Self::Name(target.to_string()))),
+            [code, ty, target] if code.eq_ignore_ascii_case("NOERROR") => {
+                match ty.to_ascii_uppercase().as_str() {
+                    "A" => target.parse().map(|ip| Self::Address(IpAddr::V4(ip))).ok(),
+                    "AAAA" => target.parse().map(|ip| Self::Address(IpAddr::V6(ip))).ok(),
+                    "CNAME" => fqdn(target).map(|_| Self::Name(target.to_string())),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+///
+/// The modifiers (the `$...` suffix) of an adblock-style rule
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Default)]
+pub struct Modifiers {
+    /// Takes precedence over exceptions, unless they're important too
+    pub important: bool,
+    pub scope: Scope,
+    pub rewrite: Option<Target>,
+}
+
+impl Modifiers {
+    ///
+    /// The modifiers, should we understand all of them. Rules with any we don't
+    /// are best left out, rather than applied more broadly than intended.
+    ///
+    fn parse(modifiers: &[(&str, Option<&str>)]) -> Option<Self> {
+        let mut parsed = Self::default();
+
+        for (name, value) in modifiers {
+            match (*name, *value) {
+                ("important", None) => parsed.important = true,
+                ("dnstype", Some(types)) => {
+                    parsed.scope.types = Only::parse(types, |ty| {
+                        RecordType::from_str(&ty.to_ascii_uppercase()).ok()
+                    })?;
+                }
+                ("client", Some(clients)) => {
+                    parsed.scope.clients = Only::parse(clients, |client| {
+                        client
+                            .parse()
+                            .ok()
+                            .or_else(|| client.parse::<IpAddr>().ok().map(IpNet::from))
+                    })?;
+                }
+                ("dnsrewrite", Some(rewrite)) => parsed.rewrite = Some(Target::parse(rewrite)?),
+                _ => return None,
+            }
+        }
+
+        Some(parsed)
+    }
+}
+
+///
+/// The list a rule was loaded from
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Serialize, PartialEq, Eq, PartialOrd, Deserialize)]
+pub struct Source {
+    pub name: String,
+    pub url: String,
+}
+
+impl Source {
+    ///
+    /// The list that ships with us, used when no others are available
+    ///
+    pub fn builtin() -> Self {
+        Self {
+            name: String::from("Builtin"),
+            url: String::from("builtin"),
+        }
+    }
+}
+
+impl From<&super::List> for Source {
+    fn from(list: &super::List) -> Self {
+        Self {
+            name: list.name.clone(),
+            url: list.url.clone(),
+        }
+    }
 }
 
 #[cfg_attr(any(debug_assertions, test), derive(Debug))]
@@ -83,56 +299,261 @@ pub enum Type {
 pub struct Rule {
     pub(crate) domain: String,
     pub(crate) kind: Kind,
-    pub(crate) action: Option<Action>,
+    /// Boxed, as most rules simply block the domain
+    pub(crate) action: Option<Box<Action>>,
+    /// Shared between every rule from the same list, as there can be a great many
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) list: Option<Arc<Source>>,
+    /// Whether the rule covers every subdomain of the domain too
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) zone: bool,
+    /// Whether the rule takes precedence over exceptions for the same domain
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) important: bool,
+    /// Boxed, as very few rules are limited to particular queries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) scope: Option<Box<Scope>>,
+}
+
+///
+/// Parse a domain as a fully qualified name
+///
+fn fqdn(domain: &str) -> Option<Name> {
+    Name::from_ascii(format!("{}.", domain.trim_end_matches('.'))).ok()
 }
 
 impl Rule {
-    fn rule(&self, request: &Request) -> Vec<Record> {
-        match request.query().query_type() {
+    ///
+    /// The rule for names that no other rule matches, when those are denied
+    ///
+    pub(crate) fn unmatched(name: &Name) -> Self {
+        Self {
+            domain: name.to_string().trim_end_matches('.').to_string(),
+            kind: Kind::Deny,
+            action: None,
+            list: None,
+            zone: false,
+            important: false,
+            scope: None,
+        }
+    }
+
+    #[inline]
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    #[inline]
+    pub const fn kind(&self) -> &Kind {
+        &self.kind
+    }
+
+    ///
+    /// Whether the rule applies to every subdomain of its domain, not just the
+    /// domain itself
+    ///
+    #[inline]
+    pub const fn zone(&self) -> bool {
+        self.zone
+    }
+
+    ///
+    /// Whether the rule applies to queries of the type from the client
+    ///
+    pub fn applies(&self, query_type: RecordType, client: IpAddr) -> bool {
+        // HTTPS/SVCB answers carry address hints, so a rule for either address
+        // type covers them too. Excluding a type only ever excludes that type.
+        let hints = matches!(query_type, RecordType::HTTPS | RecordType::SVCB);
+
+        self.scope.as_ref().map_or(true, |scope| {
+            (scope.types.include.is_empty()
+                || scope
+                    .types
+                    .include
+                    .iter()
+                    .any(|ty| *ty == query_type || (hints && ty.is_ip_addr())))
+                && !scope.types.exclude.contains(&query_type)
+                && scope.clients.allows(|network| network.contains(&client))
+        })
+    }
+
+    ///
+    /// Whether this rule should replace the other, for the same domain. Important
+    /// rules take precedence over everything else, and then exceptions over those
+    /// blocking the domain.
+    ///
+    fn overrides(&self, other: &Self) -> bool {
+        (self.important, self.kind == Kind::Allow) > (other.important, other.kind == Kind::Allow)
+    }
+
+    ///
+    /// The list the rule came from, if it came from one at all (as opposed to
+    /// being a custom rule, or generated)
+    ///
+    #[inline]
+    pub fn list(&self) -> Option<&Source> {
+        self.list.as_deref()
+    }
+
+    ///
+    /// The name requests matching this rule are redirected to, if any
+    ///
+    pub fn cname(&self) -> Option<Name> {
+        self.action
+            .as_ref()
+            .and_then(|action| action.cname.as_deref())
+            .and_then(fqdn)
+    }
+
+    ///
+    /// The records we answer a query for the name with
+    ///
+    pub fn records(&self, name: &Name, query_type: RecordType) -> Vec<Record> {
+        if let Some(cname) = self.cname() {
+            return vec![
+                Record::default()
+                    .set_name(name.clone())
+                    .set_rr_type(RecordType::CNAME)
+                    .set_data(Some(RData::CNAME(CNAME(cname))))
+                    .set_ttl(TTL)
+                    .clone(),
+            ];
+        }
+
+        match query_type {
             RecordType::A => vec![
                 Record::default()
-                    .set_name(request.query().original().name().clone())
+                    .set_name(name.clone())
                     .set_rr_type(RecordType::A)
-                    .set_data(Some(RData::A(
-                        match self
-                            .action
-                            .as_ref()
-                            .and_then(|action| action.rewrite.clone())
-                            .unwrap_or_default()
-                            .v4
-                        {
-                            IpAddr::V4(addr) => A(addr),
-                            IpAddr::V6(_) => A(Ipv4Addr::UNSPECIFIED),
-                        },
-                    )))
-                    .set_ttl(600)
+                    .set_data(Some(RData::A(A(self.v4()))))
+                    .set_ttl(TTL)
                     .clone(),
             ],
             RecordType::AAAA => vec![
                 Record::default()
-                    .set_name(request.query().original().name().clone())
+                    .set_name(name.clone())
                     .set_rr_type(RecordType::AAAA)
-                    .set_data(Some(RData::AAAA(
-                        match self
-                            .action
-                            .as_ref()
-                            .and_then(|action| action.rewrite.clone())
-                            .unwrap_or_default()
-                            .v6
-                        {
-                            IpAddr::V4(_) => AAAA(Ipv6Addr::UNSPECIFIED),
-                            IpAddr::V6(addr) => AAAA(addr),
-                        },
-                    )))
-                    .set_ttl(600)
+                    .set_data(Some(RData::AAAA(AAAA(self.v6()))))
+                    .set_ttl(TTL)
                     .clone(),
             ],
-            _ => vec![Record::default()],
+            // Browsers fetch these alongside (and often before) A/AAAA, and will
+            // happily connect using the hints in them, so they need to agree with
+            // the addresses we answer with. Only the address hints are kept, as
+            // the upstream's alpn and ech would let the client sidestep us.
+            RecordType::HTTPS | RecordType::SVCB => {
+                let svcb = SVCB::new(
+                    1,
+                    Name::root(),
+                    vec![
+                        (
+                            SvcParamKey::Ipv4Hint,
+                            SvcParamValue::Ipv4Hint(IpHint(vec![A(self.v4())])),
+                        ),
+                        (
+                            SvcParamKey::Ipv6Hint,
+                            SvcParamValue::Ipv6Hint(IpHint(vec![AAAA(self.v6())])),
+                        ),
+                    ],
+                );
+
+                vec![
+                    Record::default()
+                        .set_name(name.clone())
+                        .set_rr_type(query_type)
+                        .set_data(Some(if query_type == RecordType::HTTPS {
+                            RData::HTTPS(HTTPS(svcb))
+                        } else {
+                            RData::SVCB(svcb)
+                        }))
+                        .set_ttl(TTL)
+                        .clone(),
+                ]
+            }
+            RecordType::PTR => self
+                .action
+                .as_ref()
+                .and_then(|action| action.ptr.as_ref())
+                .and_then(|ptr| fqdn(ptr))
+                .map(|ptr| {
+                    vec![
+                        Record::default()
+                            .set_name(name.clone())
+                            .set_rr_type(RecordType::PTR)
+                            .set_data(Some(RData::PTR(PTR(ptr))))
+                            .set_ttl(TTL)
+                            .clone(),
+                    ]
+                })
+                .unwrap_or_default(),
+            _ => Vec::new(),
         }
     }
 
+    ///
+    /// The IPv4 address we answer with
+    ///
+    fn v4(&self) -> Ipv4Addr {
+        match self
+            .action
+            .as_ref()
+            .and_then(|action| action.rewrite.clone())
+            .unwrap_or_default()
+            .v4
+        {
+            IpAddr::V4(addr) => addr,
+            IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        }
+    }
+
+    ///
+    /// The IPv6 address we answer with
+    ///
+    fn v6(&self) -> Ipv6Addr {
+        match self
+            .action
+            .as_ref()
+            .and_then(|action| action.rewrite.clone())
+            .unwrap_or_default()
+            .v6
+        {
+            IpAddr::V4(_) => Ipv6Addr::UNSPECIFIED,
+            IpAddr::V6(addr) => addr,
+        }
+    }
+
+    ///
+    /// Whether this rule blocks the domain, as opposed to pointing it somewhere
+    /// useful (e.g. a hosts entry for a machine on the local network)
+    ///
+    pub fn sinkholes(&self) -> bool {
+        self.kind == Kind::Deny
+            && self.cname().is_none()
+            && self
+                .action
+                .as_ref()
+                .and_then(|action| action.rewrite.as_ref())
+                .map_or(true, |rewrite| {
+                    [rewrite.v4, rewrite.v6]
+                        .iter()
+                        .all(|ip| ip.is_unspecified() || ip.is_loopback())
+                })
+    }
+
+    ///
+    /// Whether we answer requests matching this rule ourselves, rather than
+    /// letting them through to the upstreams
+    ///
+    #[inline]
+    pub fn answers_locally(&self) -> bool {
+        self.kind == Kind::Deny || self.action.is_some()
+    }
+
     pub fn apply(&self, request: &Request) -> DnsResponse {
-        let answers = self.rule(request);
+        let answers = self.records(
+            request.query().original().name(),
+            request.query().query_type(),
+        );
 
         let message = Message::new()
             .set_header(
@@ -151,20 +572,63 @@ impl Rule {
     }
 }
 
+///
+/// A label containing wildcards (e.g. the `ads*` of `ads*.example.com`), where
+/// each `*` matches any number of characters
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct Wildcard(String);
+
+impl Wildcard {
+    fn matches(&self, label: &str) -> bool {
+        let mut parts = self.0.split('*');
+
+        // There's always at least the one wildcard, so always a first and last part
+        let (Some(first), Some(last)) = (parts.next(), parts.next_back()) else {
+            return false;
+        };
+
+        let Some(mut rest) = label
+            .strip_prefix(first)
+            .and_then(|rest| rest.strip_suffix(last))
+        else {
+            return false;
+        };
+
+        parts.all(|part| match rest.find(part) {
+            Some(index) => {
+                rest = &rest[index + part.len()..];
+                true
+            }
+            None => false,
+        })
+    }
+}
+
+///
+/// The children of a node, hashed without any per-map state as there's a map
+/// for every node in the trie
+///
+pub(crate) type Children<'a> = HashMap<Cow<'a, str>, Rules<'a>, BuildHasherDefault<AHasher>>;
+
 #[cfg_attr(any(debug_assertions, test), derive(Debug))]
 #[derive(Default, Clone, PartialEq)]
 pub struct Rules<'a> {
-    pub(crate) children: AHashMap<Cow<'a, str>, Rules<'a>>,
+    pub(crate) children: Children<'a>,
+    /// Children whose label contains a wildcard, which are only tried (in the
+    /// order they were added) when none of the other children match
+    pub(crate) wildcards: Vec<(Wildcard, Rules<'a>)>,
     pub(crate) rule: Option<Rule>,
 }
 
 #[cfg(debug_assertions)]
 type ParserResult<'a> =
-    impl Parser<'a, &'a str, Vec<Option<Type>>, extra::Err<chumsky::prelude::Rich<'a, char>>>;
+    impl Parser<'a, &'a str, Vec<Vec<Type>>, extra::Err<chumsky::prelude::Rich<'a, char>>>;
 
 #[cfg(not(debug_assertions))]
 type ParserResult<'a> =
-    impl Parser<'a, &'a str, Vec<Option<Type>>, extra::Err<chumsky::prelude::EmptyErr>>;
+    impl Parser<'a, &'a str, Vec<Vec<Type>>, extra::Err<chumsky::prelude::EmptyErr>>;
 
 impl<'a> Rules<'a> {
     fn parser<'b>() -> ParserResult<'b> {
@@ -251,7 +715,9 @@ impl<'a> Rules<'a> {
             h16.then(just("::")).then(h16).to_slice(),
         ));
 
-        let ip = choice((ipv4, ipv6))
+        let address = choice((ipv4, ipv6));
+
+        let ip = address
             .then_ignore(choice((eol, text::whitespace().at_least(1))))
             .from_str::<IpAddr>()
             .unwrapped();
@@ -271,20 +737,125 @@ impl<'a> Rules<'a> {
             .then(domain)
             .map(|(ip, domain)| Type::Host(ip, domain));
 
+        let modifier = one_of("abcdefghijklmnopqrstuvwxyz-_")
+            .repeated()
+            .at_least(1)
+            .to_slice()
+            .then(
+                just('=')
+                    .ignore_then(none_of(", \t\r\n").repeated().at_least(1).to_slice())
+                    .or_not(),
+            );
+
+        let modifiers = just('$')
+            .ignore_then(
+                modifier
+                    .separated_by(just(','))
+                    .at_least(1)
+                    .collect::<Vec<_>>(),
+            )
+            .or_not()
+            .map(|modifiers| Modifiers::parse(&modifiers.unwrap_or_default()));
+
         let adblock = choice((
-            just("@@||").to(Kind::Deny),
-            just("||@@").to(Kind::Deny),
-            just("||").to(Kind::Allow),
+            just("@@||").to(Kind::Allow),
+            just("||@@").to(Kind::Allow),
+            just("||").to(Kind::Deny),
         ))
-        .then(choice((ip.map(Type::Ip), domain.map(Type::Domain))))
-        .map(|(kind, ty)| Type::Adblock(kind, Box::new(ty)));
+        .then(choice((
+            ip.map(Type::Ip),
+            domain.map(Type::Domain).then_ignore(just('^').or_not()),
+        )))
+        .then(modifiers)
+        .map(|((kind, ty), modifiers)| {
+            modifiers.map(|modifiers| Type::Adblock(kind, Box::new(ty), modifiers))
+        });
 
-        choice((hosts, ip.map(Type::Ip), domain.map(Type::Domain), adblock))
-            .map(Some)
-            .or(comment.to(None))
-            .then_ignore(eol)
-            .repeated()
-            .collect()
+        // address=/example.com/example.org/0.0.0.0, with `#` or no address at
+        // all simply blocking the domains
+        let dnsmasq = just("address=/")
+            .ignore_then(
+                domain
+                    .then_ignore(just('/'))
+                    .repeated()
+                    .at_least(1)
+                    .collect::<Vec<_>>(),
+            )
+            .then(choice((just('#').to(None), ip.map(Some))).or_not())
+            .map(|(domains, addr)| {
+                let addr = addr.flatten();
+                domains
+                    .into_iter()
+                    .map(|domain| Type::Zone(Kind::Deny, domain, addr))
+                    .collect::<Vec<_>>()
+            });
+
+        let quote = just('"').or_not();
+        let absolute = domain.then_ignore(just('.').or_not());
+
+        // local-zone: "example.com." always_nxdomain
+        let local_zone = text::inline_whitespace()
+            .ignore_then(just("local-zone:"))
+            .ignore_then(text::inline_whitespace())
+            .ignore_then(quote)
+            .ignore_then(absolute)
+            .then_ignore(quote)
+            .then_ignore(text::inline_whitespace().at_least(1))
+            .then(text::ident().map(|ty: &str| match ty {
+                "deny" | "refuse" | "static" | "redirect" | "inform_deny" | "always_refuse"
+                | "always_nxdomain" | "always_null" | "always_deny" => Some(Kind::Deny),
+                "transparent" | "typetransparent" | "inform" | "always_transparent" => {
+                    Some(Kind::Allow)
+                }
+                _ => None,
+            }))
+            .map(|(domain, kind)| {
+                kind.map(|kind| Type::Zone(kind, domain, None))
+                    .into_iter()
+                    .collect::<Vec<_>>()
+            });
+
+        // local-data: "example.com. 3600 IN A 192.168.1.10"
+        let local_data = text::inline_whitespace()
+            .ignore_then(just("local-data:"))
+            .ignore_then(text::inline_whitespace())
+            .ignore_then(quote)
+            .ignore_then(absolute)
+            .then_ignore(text::inline_whitespace().at_least(1))
+            .then_ignore(
+                text::int(10)
+                    .then(text::inline_whitespace().at_least(1))
+                    .or_not(),
+            )
+            .then_ignore(
+                just("IN")
+                    .then(text::inline_whitespace().at_least(1))
+                    .or_not(),
+            )
+            .then_ignore(choice((just("AAAA"), just("A"))))
+            .then_ignore(text::inline_whitespace().at_least(1))
+            .then(address.from_str::<IpAddr>().unwrapped())
+            .then_ignore(quote)
+            .map(|(domain, ip)| vec![Type::Host(ip, domain)]);
+
+        let server = text::inline_whitespace()
+            .ignore_then(just("server:"))
+            .to(Vec::new());
+
+        choice((
+            dnsmasq,
+            local_zone,
+            local_data,
+            server,
+            hosts.map(|ty| vec![ty]),
+            ip.map(|ip| vec![Type::Ip(ip)]),
+            domain.map(|domain| vec![Type::Domain(domain)]),
+            adblock.map(|ty| ty.into_iter().collect()),
+        ))
+        .or(comment.to(Vec::new()))
+        .then_ignore(eol)
+        .repeated()
+        .collect()
     }
 
     ///
@@ -297,21 +868,100 @@ impl<'a> Rules<'a> {
         let file = std::fs::File::open(file)?;
         let reader = BufReader::new(file);
 
-        reader
+        Self::parse_lines(reader.lines().map_while(Result::ok))
+    }
+
+    ///
+    /// Load a filter list straight into a set of rules, without ever holding
+    /// all of its entries at once, so that memory grows with the rules rather
+    /// than with the size of the list.
+    ///
+    /// Lines are parsed in parallel, with the entries shared out between shards
+    /// by their top level label. As no two shards have a label in common, they
+    /// can be combined at the end without needing to be merged.
+    ///
+    /// Returns the rules, along with the number of entries in the list
+    ///
+    /// # Errors
+    /// If the list can't be read, or any of its lines are invalid
+    ///
+    pub fn load(file: &Path, list: Option<Source>) -> Result<(Self, usize), Error> {
+        let reader = BufReader::new(std::fs::File::open(file)?);
+        let list = list.map(Arc::new);
+
+        let shards = (0..rayon::current_num_threads() * 4)
+            .map(|_| Mutex::new(Self::default()))
+            .collect::<Vec<_>>();
+
+        let count = reader
             .lines()
             .map_while(Result::ok)
+            .enumerate()
+            .par_bridge()
+            .map(|(idx, line)| {
+                let entries = Self::parse_line(&line)
+                    .map_err(|err| Error::FilterError(format!("Line {}: {err}", idx + 1)))?;
+                let count = entries.len();
+
+                for entry in entries {
+                    shards[Self::shard(&entry, shards.len())]
+                        .lock()
+                        .map(|mut rules| rules.add(entry, list.as_ref()))
+                        .unwrap_or_default();
+                }
+
+                Ok::<_, Error>(count)
+            })
+            .try_reduce(|| 0, |count, count_| Ok(count + count_))?;
+
+        let rules = shards
+            .into_iter()
+            .filter_map(|shard| shard.into_inner().ok())
+            .fold(Self::default(), |mut rules, shard| {
+                rules.children.extend(shard.children);
+                rules.wildcards.extend(shard.wildcards);
+                rules
+            });
+
+        Ok((rules, count))
+    }
+
+    ///
+    /// The shard an entry belongs in, which is the same for every entry with
+    /// the same top level label
+    ///
+    fn shard(entry: &Type, shards: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        entry
+            .domain()
+            .and_then(|domain| domain.rsplit('.').next())
+            .hash(&mut hasher);
+
+        usize::try_from(hasher.finish() % shards as u64).unwrap_or_default()
+    }
+
+    ///
+    /// Parse the lines of a filter list into a bunch of individual filters
+    ///
+    /// # Errors
+    /// This will only fail if the lexer fails (i.e. the filter list is invalid)
+    ///
+    pub fn parse_lines<I>(lines: I) -> Result<Vec<Type>, Error>
+    where
+        I: Iterator<Item = String> + Send,
+    {
+        lines
+            .enumerate()
             .par_bridge()
             .try_fold(
                 || Vec::with_capacity(1024 * 8),
-                |mut rules, line| {
-                    let (rules_, errors) = Self::parser().parse(&line).into_output_errors();
-                    if errors.is_empty() {
-                        rules.extend(rules_.into_iter().flatten().flatten());
-                        Ok(rules)
-                    } else {
-                        println!("{errors:#?}");
-                        Err(Error::FilterError(String::from("Invalid filter list")))
-                    }
+                |mut rules, (idx, line)| {
+                    rules.extend(
+                        Self::parse_line(&line).map_err(|err| {
+                            Error::FilterError(format!("Line {}: {err}", idx + 1))
+                        })?,
+                    );
+                    Ok(rules)
                 },
             )
             .try_reduce(
@@ -323,30 +973,82 @@ impl<'a> Rules<'a> {
             )
     }
 
-    fn add(&mut self, entry: Type) {
-        let (addr, ty, domain) = match entry {
-            Type::Host(ip, domain) => (Some(ip), Kind::Deny, domain),
-            Type::Domain(domain) => (None, Kind::Deny, domain),
-            Type::Adblock(kind, ty) => match *ty {
-                Type::Domain(domain) => (None, kind, domain),
-                Type::Ip(_) | Type::Host(_, _) | Type::Adblock(_, _) => return,
+    ///
+    /// Parse a single line of a filter list
+    ///
+    /// # Errors
+    /// If the line is neither a valid entry nor a comment
+    ///
+    pub fn parse_line(line: &str) -> Result<Vec<Type>, Error> {
+        let (rules, errors) = Self::parser().parse(line).into_output_errors();
+
+        if errors.is_empty() {
+            Ok(rules.into_iter().flatten().flatten().collect())
+        } else {
+            Err(Error::FilterError(format!(
+                "Invalid entry '{}'",
+                line.trim()
+            )))
+        }
+    }
+
+    fn add(&mut self, entry: Type, list: Option<&Arc<Source>>) {
+        let (addr, ty, domain, modifiers, zone) = match entry {
+            Type::Host(ip, domain) => (Some(ip), Kind::Deny, domain, Modifiers::default(), false),
+            Type::Domain(domain) => (None, Kind::Deny, domain, Modifiers::default(), false),
+            Type::Adblock(kind, ty, modifiers) => match *ty {
+                Type::Domain(domain) => (None, kind, domain, modifiers, false),
+                Type::Ip(_) | Type::Host(_, _) | Type::Adblock(_, _, _) | Type::Zone(_, _, _) => {
+                    return;
+                }
             },
+            Type::Zone(kind, domain, addr) => (addr, kind, domain, Modifiers::default(), true),
             Type::Ip(_) => return,
         };
 
-        match &mut domain
-            .split('.')
-            .rev()
-            .fold(self, |current_node, part| {
-                current_node
-                    .children
-                    .entry(Cow::Owned(part.replace('*', ".*")))
-                    .or_default()
-            })
-            .rule
-        {
-            Some(rule) => {
-                if let Some(ref mut action) = rule.action {
+        // Exceptions only ever let the domain through, rather than rewriting it
+        let (addr, cname) = match modifiers.rewrite {
+            Some(Target::Address(addr)) if ty != Kind::Allow => (Some(addr), None),
+            Some(Target::Name(cname)) if ty != Kind::Allow => (addr, Some(cname)),
+            _ => (addr, None),
+        };
+
+        let rewrite = addr.map(|addr| match addr {
+            IpAddr::V4(_) => Rewrite {
+                v4: addr,
+                v6: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            },
+            IpAddr::V6(_) => Rewrite {
+                v6: addr,
+                v4: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            },
+        });
+
+        let rule = Rule {
+            action: (rewrite.is_some() || cname.is_some()).then(|| {
+                Box::new(Action {
+                    rewrite,
+                    cname,
+                    ..Default::default()
+                })
+            }),
+            domain,
+            kind: ty,
+            list: list.cloned(),
+            zone,
+            important: modifiers.important,
+            scope: (modifiers.scope != Scope::default()).then(|| Box::new(modifiers.scope)),
+        };
+
+        match &mut self.entry(&rule.domain).rule {
+            Some(existing) if !rule.overrides(existing) => {
+                existing.zone |= rule.zone && existing.kind == rule.kind;
+
+                // e.g. an Unbound `local-data` giving the address for a zone
+                // that was only declared beforehand
+                if existing.action.is_none() && existing.kind == rule.kind {
+                    existing.action = rule.action;
+                } else if let Some(ref mut action) = existing.action {
                     if let Some(ref mut rewrite) = action.rewrite {
                         match addr {
                             None => (),
@@ -356,55 +1058,233 @@ impl<'a> Rules<'a> {
                     }
                 }
             }
-            r => {
-                *r = Some(Rule {
-                    domain,
-                    kind: ty,
-                    action: match addr {
-                        None => None,
-                        Some(addr @ IpAddr::V4(_)) => Some(Action {
-                            rewrite: Some(Rewrite {
-                                v4: addr,
-                                v6: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
-                            }),
-                        }),
-                        Some(addr @ IpAddr::V6(_)) => Some(Action {
-                            rewrite: Some(Rewrite {
-                                v6: addr,
-                                v4: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
-                            }),
-                        }),
-                    },
-                });
-            }
+            existing => *existing = Some(rule),
         }
     }
 
+    fn entry(&mut self, domain: &str) -> &mut Self {
+        domain
+            .split('.')
+            .rev()
+            .fold(self, |current_node, part| current_node.child(part))
+    }
+
+    fn child(&mut self, label: &str) -> &mut Self {
+        if !label.contains('*') {
+            return self
+                .children
+                .entry(Cow::Owned(label.to_string()))
+                .or_default();
+        }
+
+        let index = self
+            .wildcards
+            .iter()
+            .position(|(wildcard, _)| wildcard.0 == label)
+            .unwrap_or_else(|| {
+                self.wildcards
+                    .push((Wildcard(label.to_string()), Self::default()));
+                self.wildcards.len() - 1
+            });
+
+        &mut self.wildcards[index].1
+    }
+
+    ///
+    /// The child for the label, matching it exactly if possible and otherwise
+    /// against any wildcards
+    ///
+    pub(crate) fn get(&self, label: &str) -> Option<&Self> {
+        self.children.get(label).or_else(|| {
+            self.wildcards
+                .iter()
+                .find(|(wildcard, _)| wildcard.matches(label))
+                .map(|(_, rules)| rules)
+        })
+    }
+
+    ///
+    /// Give back the space the trie's maps grew into but aren't using, which
+    /// across millions of nodes adds up to a good deal
+    ///
+    pub fn shrink(&mut self) {
+        self.children.shrink_to_fit();
+        self.wildcards.shrink_to_fit();
+
+        self.children.values_mut().for_each(Self::shrink);
+        self.wildcards
+            .iter_mut()
+            .for_each(|(_, rules)| rules.shrink());
+    }
+
+    ///
+    /// Add a custom rule, replacing whatever rule the domain may already have
+    ///
+    pub fn replace(&mut self, rule: &Custom) {
+        let rewrite = (rule.v4.is_some() || rule.v6.is_some()).then(|| Rewrite {
+            v4: IpAddr::V4(rule.v4.unwrap_or(Ipv4Addr::UNSPECIFIED)),
+            v6: IpAddr::V6(rule.v6.unwrap_or(Ipv6Addr::UNSPECIFIED)),
+        });
+
+        // `*.example.com` is shorthand for the zone, rather than a wildcard label
+        let (domain, zone) = match rule.domain.strip_prefix("*.") {
+            Some(domain) => (domain, true),
+            None => (rule.domain.as_str(), rule.zone),
+        };
+
+        self.entry(domain).rule = Some(Rule {
+            domain: domain.to_string(),
+            kind: rule.kind.clone(),
+            action: (rewrite.is_some() || rule.cname.is_some()).then(|| {
+                Box::new(Action {
+                    rewrite,
+                    cname: rule.cname.clone(),
+                    ..Default::default()
+                })
+            }),
+            list: None,
+            zone,
+            important: false,
+            scope: None,
+        });
+    }
+
+    ///
+    /// Add the entries, attributing the rules they create to the list they
+    /// came from (if any)
+    ///
     #[inline]
-    pub fn insert(&mut self, entries: Vec<Type>) -> usize {
+    pub fn insert(&mut self, entries: Vec<Type>, list: Option<Source>) -> usize {
+        let list = list.map(Arc::new);
+
         entries.into_iter().fold(0, |acc, entry| {
-            self.add(entry);
+            self.add(entry, list.as_ref());
             acc + 1
         })
     }
 
+    ///
+    /// Generate PTR records in the reverse zones for every locally defined
+    /// address (i.e. hosts entries that don't simply sinkhole the domain).
+    /// Existing rules are never overwritten, so the first domain defined for
+    /// an address wins.
+    ///
+    /// Returns the number of PTR records added.
+    ///
+    pub fn generate_ptr(&mut self) -> usize {
+        let records = self
+            .iter()
+            .filter_map(|rule| {
+                let rewrite = rule.action.as_ref()?.rewrite.as_ref()?;
+
+                Some(
+                    [rewrite.v4, rewrite.v6]
+                        .into_iter()
+                        .filter(|ip| !ip.is_unspecified() && !ip.is_loopback())
+                        .map(|ip| (ip, rule.domain.clone())),
+                )
+            })
+            .flatten()
+            .collect::<Vec<_>>();
+
+        records
+            .into_iter()
+            .filter(|(ip, domain)| self.add_ptr(*ip, domain))
+            .count()
+    }
+
+    fn add_ptr(&mut self, ip: IpAddr, domain: &str) -> bool {
+        let name = Name::from(ip).to_string();
+        let name = name.trim_end_matches('.');
+
+        let node = self.entry(name);
+
+        if node.rule.is_some() {
+            return false;
+        }
+
+        node.rule = Some(Rule {
+            domain: name.to_string(),
+            kind: Kind::Allow,
+            action: Some(Box::new(Action {
+                ptr: Some(domain.to_string()),
+                ..Default::default()
+            })),
+            list: None,
+            zone: false,
+            important: false,
+            scope: None,
+        });
+
+        true
+    }
+
+    ///
+    /// Iterate over every rule in the trie
+    ///
+    pub fn iter(&self) -> Iter<'_, 'a> {
+        Iter { stack: vec![self] }
+    }
+
     pub fn merge(&mut self, rules: Rules<'a>) {
-        for (child, rules) in rules.children {
+        for (child, mut rules) in rules.children {
             let new = self.children.entry(child).or_default();
-            new.rule = rules.rule.clone();
+            new.prefer(rules.rule.take());
+            new.merge(rules);
+        }
+
+        for (wildcard, mut rules) in rules.wildcards {
+            let new = self.child(&wildcard.0);
+            new.prefer(rules.rule.take());
             new.merge(rules);
         }
     }
+
+    ///
+    /// Give the node the rule, unless the one it already has takes precedence
+    ///
+    fn prefer(&mut self, rule: Option<Rule>) {
+        let Some(rule) = rule else {
+            return;
+        };
+
+        if !self
+            .rule
+            .as_ref()
+            .is_some_and(|existing| existing.overrides(&rule))
+        {
+            self.rule = Some(rule);
+        }
+    }
 }
 
-impl<'a> TryFrom<&mut super::List> for Rules<'a> {
-    type Error = super::Error;
+pub struct Iter<'r, 'a> {
+    stack: Vec<&'r Rules<'a>>,
+}
+
+impl<'r, 'a> IntoIterator for &'r Rules<'a> {
+    type Item = &'r Rule;
+    type IntoIter = Iter<'r, 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
 
-    fn try_from(value: &mut super::List) -> Result<Self, Self::Error> {
-        let mut rules = Self::default();
-        let entries = Rules::parse(Path::new(&value.to_string()))?;
-        value.entries = rules.insert(entries);
+impl<'r> Iterator for Iter<'r, '_> {
+    type Item = &'r Rule;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(node) = self.stack.pop() {
+            self.stack.extend(node.children.values());
+            self.stack
+                .extend(node.wildcards.iter().map(|(_, rules)| rules));
+
+            if let Some(rule) = &node.rule {
+                return Some(rule);
+            }
+        }
 
-        Ok(rules)
+        None
     }
 }
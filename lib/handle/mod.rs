@@ -0,0 +1,400 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use hickory_proto::rr::Name;
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    config::{self, Config, CONFIG},
+    filter::{rules::Kind, Custom, Filter},
+    shutdown::Exit,
+    statistics::{Query, Statistics},
+};
+
+pub use types::{ListStatus, LoggedRequest, RequestFilter, Subscription, Summary};
+
+mod types;
+
+///
+/// A running instance of Blackhole: the DNS server, the API and the scheduler.
+///
+/// This (along with [`FilterHandle`] and [`StatsHandle`]) is the supported way
+/// of embedding Blackhole, everything else is subject to change between releases.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::PathBuf;
+///
+/// use blackhole::{Blackhole, Config};
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// Config::load(&PathBuf::from("config.toml")).await?;
+///
+/// let mut blackhole = Blackhole::start().await?;
+/// println!("{:?}", blackhole.filters().check("ads.example.com").await);
+///
+/// let exit = blackhole.stop().await;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Or, with the config built up in code rather than loaded, and without the
+/// API, until the future passed to [`Instance::run`] resolves:
+///
+/// ```no_run
+/// use blackhole::{Blackhole, Config};
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let exit = Blackhole::builder()
+///     .config(Config::default())
+///     .dns_port(5353)
+///     .api(false)
+///     .build()
+///     .run(tokio::signal::ctrl_c())
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The config, filter and statistics are shared by the whole process, so
+/// there can only be one instance running at a time. Running more than one
+/// instance in a process isn't supported: starting another while one is
+/// running fails, leaving the one that's running as it was.
+///
+pub struct Blackhole {
+    shutdown: watch::Sender<bool>,
+    handle: JoinHandle<Exit>,
+}
+
+impl Blackhole {
+    ///
+    /// Start serving, using the currently loaded config
+    ///
+    /// # Errors
+    /// If there are issues during startup, e.g. if we are unable to bind to the
+    /// port we're meant to serve on, or if we're already running
+    ///
+    #[coverage(off)]
+    pub async fn start() -> Result<Self, io::Error> {
+        Self::spawn(Running::claim()?, true).await
+    }
+
+    ///
+    /// Configure an instance to embed, see [`Builder`]
+    ///
+    #[inline]
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    #[coverage(off)]
+    async fn spawn(running: Running, api: bool) -> Result<Self, io::Error> {
+        let (shutdown, shutdown_signal) = watch::channel(false);
+        let handle = crate::spawn(running, shutdown_signal, api).await?;
+
+        Ok(Self { shutdown, handle })
+    }
+
+    #[inline]
+    pub const fn filters(&self) -> FilterHandle {
+        FilterHandle(())
+    }
+
+    #[inline]
+    pub const fn stats(&self) -> StatsHandle {
+        StatsHandle(())
+    }
+
+    ///
+    /// Wait for us to stop of our own accord, e.g. because one of the servers
+    /// failed. This is cancel safe, so can be used in a `select!`.
+    ///
+    #[coverage(off)]
+    pub async fn stopped(&mut self) -> Exit {
+        (&mut self.handle).await.unwrap_or(Exit::Panic)
+    }
+
+    ///
+    /// Shut everything down, running the shutdown hooks (e.g. saving the config)
+    ///
+    #[coverage(off)]
+    pub async fn stop(mut self) -> Exit {
+        // If nothing is listening we've already stopped, in which case the
+        // handle holds the reason why
+        let _ = self.shutdown.send(true);
+        self.stopped().await
+    }
+}
+
+///
+/// Whether an instance is running somewhere in the process, which lasts from
+/// when it starts until its shutdown hooks have run
+///
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+///
+/// Our claim on being the instance that's running, given up when dropped
+///
+pub(crate) struct Running(());
+
+impl Running {
+    ///
+    /// # Errors
+    /// If another instance is already running
+    ///
+    pub(crate) fn claim() -> Result<Self, io::Error> {
+        RUNNING
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .map(|_| Self(()))
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "Blackhole is already running in this process",
+                )
+            })
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+///
+/// How to run an embedded instance, in place of loading the config and calling
+/// [`Blackhole::start`]
+///
+#[derive(Default)]
+pub struct Builder {
+    config: Option<Config>,
+    dns_port: Option<u16>,
+    no_api: bool,
+}
+
+impl Builder {
+    ///
+    /// The config to run with, in place of whatever's currently loaded
+    ///
+    #[must_use]
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    ///
+    /// The port to serve DNS on, in place of the one in the config
+    ///
+    #[must_use]
+    pub const fn dns_port(mut self, port: u16) -> Self {
+        self.dns_port = Some(port);
+        self
+    }
+
+    ///
+    /// Whether to serve the API (and the gRPC API, if it's configured), which
+    /// is served by default
+    ///
+    #[must_use]
+    pub const fn api(mut self, enabled: bool) -> Self {
+        self.no_api = !enabled;
+        self
+    }
+
+    #[must_use]
+    pub fn build(self) -> Instance {
+        Instance(self)
+    }
+}
+
+///
+/// An embedded instance, ready to be run
+///
+pub struct Instance(Builder);
+
+impl Instance {
+    ///
+    /// Serve until `shutdown` resolves, or until we stop of our own accord
+    /// (e.g. because one of the servers failed), returning why we stopped
+    ///
+    /// # Errors
+    /// If the config isn't valid, if there are issues during startup (see
+    /// [`Blackhole::start`]), or if we're already running
+    ///
+    #[coverage(off)]
+    pub async fn run(self, shutdown: impl Future) -> Result<Exit, io::Error> {
+        let Builder {
+            config,
+            dns_port,
+            no_api,
+        } = self.0;
+
+        // Claimed before the config is touched, so that the instance that's
+        // already running (if there is one) is left as it was
+        let running = Running::claim()?;
+
+        let mut config = match config {
+            Some(config) => config,
+            None => Config::get(Clone::clone).await,
+        };
+        if let Some(port) = dns_port {
+            config.port = port;
+        }
+
+        if let Some(problem) = config.validate().into_iter().next() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                problem.to_string(),
+            ));
+        }
+
+        let previous = std::mem::replace(&mut *CONFIG.write().await, config);
+        let mut blackhole = match Blackhole::spawn(running, !no_api).await {
+            Ok(blackhole) => blackhole,
+            Err(err) => {
+                *CONFIG.write().await = previous;
+                return Err(err);
+            }
+        };
+
+        Ok(tokio::select! {
+            exit = blackhole.stopped() => exit,
+            _ = shutdown => blackhole.stop().await,
+        })
+    }
+}
+
+///
+/// Inspect and manage the filter
+///
+#[derive(Clone, Copy)]
+pub struct FilterHandle(pub(crate) ());
+
+impl FilterHandle {
+    ///
+    /// What we would do with a query for the domain, if it matches any rule
+    ///
+    pub async fn check(&self, domain: &str) -> Option<Kind> {
+        let name = Name::from_str(domain).ok()?.to_lowercase();
+        Filter::lookup(&name).await.map(|rule| rule.kind)
+    }
+
+    ///
+    /// The custom rules defined in the config
+    ///
+    pub async fn rules(&self) -> Vec<Custom> {
+        Config::get(|config| config.rules.clone()).await
+    }
+
+    ///
+    /// Add a custom rule, replacing any existing rule for the same domain
+    ///
+    /// # Errors
+    /// If the rules couldn't be reloaded
+    ///
+    pub async fn add_rule(&self, rule: Custom) -> Result<(), config::Error> {
+        Config::set(|config| {
+            config
+                .rules
+                .retain(|existing| existing.domain != rule.domain);
+            config.rules.push(rule.clone());
+        })
+        .await
+    }
+
+    ///
+    /// Remove the custom rule for the domain, if there is one
+    ///
+    /// # Errors
+    /// If the rules couldn't be reloaded
+    ///
+    pub async fn remove_rule(&self, domain: &str) -> Result<(), config::Error> {
+        Config::set(|config| {
+            config.rules.retain(|existing| existing.domain != domain);
+        })
+        .await
+    }
+
+    ///
+    /// The fetch status of each list, keyed by the list's file name
+    ///
+    pub async fn statuses(&self) -> HashMap<String, ListStatus> {
+        Filter::statuses()
+            .await
+            .into_iter()
+            .map(|(list, status)| (list, ListStatus::from(&status)))
+            .collect()
+    }
+
+    ///
+    /// The currently loaded rules, as a zone file
+    ///
+    pub async fn zone(&self) -> String {
+        Filter::zone().await
+    }
+
+    ///
+    /// Fetch any lists that are due to be updated, and reload the rules
+    ///
+    /// # Errors
+    /// If the rules couldn't be reloaded
+    ///
+    #[coverage(off)]
+    pub async fn update(&self) -> Result<(), config::Error> {
+        Filter::update().await;
+        Filter::import().await.map_err(config::Error::from)
+    }
+}
+
+///
+/// Read the statistics we've recorded
+///
+#[derive(Clone, Copy)]
+pub struct StatsHandle(pub(crate) ());
+
+impl StatsHandle {
+    ///
+    /// The totals recorded so far
+    ///
+    #[inline]
+    pub fn all(&self) -> Summary {
+        Summary::of(&Statistics::statistics())
+    }
+
+    ///
+    /// The total number of requests matching the filter, along with the requested
+    /// page of them (most recent first)
+    ///
+    pub fn requests(&self, filter: &RequestFilter) -> Option<(usize, Vec<LoggedRequest>)> {
+        Statistics::requests(&Query::from(filter))
+            .map(|(total, requests)| (total, requests.iter().map(LoggedRequest::from).collect()))
+    }
+
+    ///
+    /// The average time taken to handle the requests made within the window
+    ///
+    #[inline]
+    pub fn recent_latency(&self, window: Duration) -> Option<Duration> {
+        Statistics::recent_latency(window)
+    }
+
+    ///
+    /// Subscribe to every request as it is recorded
+    ///
+    #[inline]
+    pub fn subscribe(&self) -> Subscription {
+        Subscription(Statistics::subscribe())
+    }
+
+    #[inline]
+    pub fn clear(&self) {
+        Statistics::clear();
+    }
+}
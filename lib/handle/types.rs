@@ -0,0 +1,253 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ahash::AHashMap;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    filter::Status,
+    statistics::{self, Query, Statistic, AVERAGE_REQUEST_TIME, BLOCKED, CACHE},
+};
+
+///
+/// The totals recorded since the statistics were last cleared
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// How many requests have been answered
+    pub requests: usize,
+    /// How many of them were blocked
+    pub blocked: usize,
+    /// How long it took to answer them, on average
+    pub average: Duration,
+    /// How many were answered from the cache
+    pub cache_hits: usize,
+    /// How many had to be answered by the upstreams
+    pub cache_misses: usize,
+}
+
+impl Summary {
+    pub(crate) fn of(statistics: &AHashMap<&'static str, Statistic>) -> Self {
+        let mut summary = Self::default();
+
+        if let Some(Statistic::Average(average)) = statistics.get(AVERAGE_REQUEST_TIME) {
+            summary.requests = average.count;
+            summary.average = Duration::from_nanos(average.average as u64);
+        }
+
+        if let Some(Statistic::Count(blocked)) = statistics.get(BLOCKED) {
+            summary.blocked = *blocked;
+        }
+
+        if let Some(Statistic::Cache(cache)) = statistics.get(CACHE) {
+            summary.cache_hits = cache.hits;
+            summary.cache_misses = cache.misses;
+        }
+
+        summary
+    }
+}
+
+///
+/// A request, as it was recorded in the request log
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, PartialEq, Eq)]
+pub struct LoggedRequest {
+    pub client: String,
+    /// The client's friendly name, should it have one
+    pub name: Option<String>,
+    pub question: String,
+    /// The type of record asked for (e.g. `AAAA`)
+    pub query_type: String,
+    /// The response code it was answered with
+    pub status: String,
+    pub blocked: bool,
+    pub cached: bool,
+    /// The domain of the rule it matched, if it matched one
+    pub rule: Option<String>,
+    /// The name of the list that rule came from
+    pub list: Option<String>,
+    /// How long it took to answer
+    pub elapsed: Duration,
+    pub timestamp: SystemTime,
+}
+
+impl From<&statistics::Request> for LoggedRequest {
+    fn from(request: &statistics::Request) -> Self {
+        Self {
+            client: request.client.clone(),
+            name: request.name.clone(),
+            question: request.question.clone(),
+            query_type: request.query_type.to_string(),
+            status: request.status.clone(),
+            blocked: request.blocked(),
+            cached: request.cached,
+            rule: request.rule.as_ref().map(|rule| rule.domain.clone()),
+            list: request
+                .rule
+                .as_ref()
+                .and_then(|rule| rule.list.as_ref())
+                .map(|list| list.name.clone()),
+            elapsed: Duration::from_nanos(request.elapsed as u64),
+            timestamp: request.timestamp,
+        }
+    }
+}
+
+///
+/// Which requests to retrieve from the request log, with every filter given
+/// having to match
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Default)]
+pub struct RequestFilter {
+    /// Only include requests from this client
+    pub client: Option<String>,
+    /// Only include requests whose question contains this
+    pub domain: Option<String>,
+    /// Only include requests of this type (e.g. `AAAA`)
+    pub query_type: Option<String>,
+    /// Only include requests that were (or weren't) blocked
+    pub blocked: Option<bool>,
+    /// Only include requests matching a rule from the list with this name
+    pub list: Option<String>,
+    /// Only include requests made at or after this time
+    pub since: Option<SystemTime>,
+    /// Only include requests made at or before this time
+    pub until: Option<SystemTime>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+impl From<&RequestFilter> for Query {
+    fn from(filter: &RequestFilter) -> Self {
+        let seconds = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs())
+        };
+
+        Self {
+            client: filter.client.clone(),
+            domain: filter.domain.clone(),
+            query_type: filter.query_type.clone(),
+            blocked: filter.blocked,
+            list: filter.list.clone(),
+            status: None,
+            since: filter.since.map(seconds),
+            until: filter.until.map(seconds),
+            limit: filter.limit,
+            offset: filter.offset,
+        }
+    }
+}
+
+///
+/// The outcome of the last attempt to fetch a list
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct ListStatus {
+    /// When the list was last fetched
+    pub fetched: Option<SystemTime>,
+    /// The number of entries loaded from the list
+    pub entries: usize,
+    /// Why the last fetch failed, should it have
+    pub error: Option<String>,
+}
+
+impl From<&Status> for ListStatus {
+    fn from(status: &Status) -> Self {
+        Self {
+            fetched: status.fetched,
+            entries: status.entries,
+            error: status.error.clone(),
+        }
+    }
+}
+
+///
+/// Every request as it's recorded, see [`StatsHandle::subscribe`]
+///
+/// [`StatsHandle::subscribe`]: super::StatsHandle::subscribe
+///
+pub struct Subscription(pub(crate) broadcast::Receiver<statistics::Request>);
+
+impl Subscription {
+    ///
+    /// The next request to be recorded, skipping over any that were missed for
+    /// falling too far behind, or None once no more will be
+    ///
+    pub async fn recv(&mut self) -> Option<LoggedRequest> {
+        loop {
+            match self.0.recv().await {
+                Ok(request) => return Some(LoggedRequest::from(&request)),
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use ahash::AHashMap;
+    use pretty_assertions::assert_eq;
+
+    use super::{RequestFilter, Summary};
+    use crate::statistics::{
+        Average, Cache, Query, Statistic, AVERAGE_REQUEST_TIME, BLOCKED, CACHE,
+    };
+
+    #[test]
+    fn summary() {
+        let statistics = AHashMap::from([
+            (
+                AVERAGE_REQUEST_TIME,
+                Statistic::Average(Average {
+                    count: 4,
+                    average: 2_000_000,
+                    latency: None,
+                }),
+            ),
+            (BLOCKED, Statistic::Count(1)),
+            (
+                CACHE,
+                Statistic::Cache(Cache {
+                    hits: 3,
+                    misses: 1,
+                    ..Cache::default()
+                }),
+            ),
+        ]);
+
+        assert_eq!(
+            Summary::of(&statistics),
+            Summary {
+                requests: 4,
+                blocked: 1,
+                average: Duration::from_millis(2),
+                cache_hits: 3,
+                cache_misses: 1,
+            }
+        );
+        assert_eq!(Summary::of(&AHashMap::new()), Summary::default());
+    }
+
+    #[test]
+    fn request_filter() {
+        let query = Query::from(&RequestFilter {
+            blocked: Some(true),
+            since: Some(UNIX_EPOCH + Duration::from_millis(1_500)),
+            limit: Some(10),
+            ..RequestFilter::default()
+        });
+
+        assert_eq!(query.blocked, Some(true));
+        assert_eq!(query.since, Some(1));
+        assert_eq!(query.until, None);
+        assert_eq!(query.limit, Some(10));
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    io,
+    sync::{LazyLock, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Serialize;
+use tracing::{error, info};
+
+use crate::metrics;
+
+static HEALTH: LazyLock<RwLock<Health>> = LazyLock::new(RwLock::default);
+
+/// How long to wait before retrying a write after the first failure
+const BACKOFF: Duration = Duration::from_secs(30);
+/// The longest we'll ever wait between write attempts
+const MAX_BACKOFF: Duration = Duration::from_hours(1);
+
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+struct Degraded {
+    reason: String,
+    since: SystemTime,
+    failures: u32,
+    retry_at: Instant,
+}
+
+#[cfg_attr(
+    any(debug_assertions, test),
+    derive(Debug, PartialEq, Eq, serde::Deserialize)
+)]
+#[derive(Serialize, Clone, Default)]
+pub struct Status {
+    pub degraded: bool,
+    pub reason: Option<String>,
+    pub since: Option<SystemTime>,
+}
+
+///
+/// Keeps track of whether we're currently able to persist anything to disk.
+///
+/// When the disk fills up (or becomes read-only) we keep serving from memory,
+/// and rather than retrying every write (and flooding the logs with the same
+/// error) writes are suspended with an exponential backoff until one succeeds.
+///
+#[derive(Default)]
+pub struct Health {
+    degraded: Option<Degraded>,
+}
+
+fn is_storage_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::StorageFull
+            | io::ErrorKind::QuotaExceeded
+            | io::ErrorKind::FileTooLarge
+            | io::ErrorKind::ReadOnlyFilesystem
+    )
+}
+
+impl Health {
+    fn check(&self) -> Result<(), io::Error> {
+        match &self.degraded {
+            Some(degraded) if degraded.retry_at > Instant::now() => Err(io::Error::other(format!(
+                "Writes are suspended while the disk is unavailable: {}",
+                degraded.reason
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn record<T>(&mut self, result: Result<T, io::Error>) -> Result<T, io::Error> {
+        match &result {
+            Ok(_) => {
+                if self.degraded.take().is_some() {
+                    info!("Disk writes have recovered");
+                    metrics::DEGRADED.set(0);
+                }
+            }
+            Err(err) if is_storage_error(err) => {
+                let degraded = self.degraded.get_or_insert_with(|| Degraded {
+                    reason: err.to_string(),
+                    since: SystemTime::now(),
+                    failures: 0,
+                    retry_at: Instant::now(),
+                });
+
+                degraded.failures = degraded.failures.saturating_add(1);
+                degraded.reason = err.to_string();
+                degraded.retry_at = Instant::now()
+                    + BACKOFF
+                        .saturating_mul(2u32.saturating_pow(degraded.failures - 1))
+                        .min(MAX_BACKOFF);
+
+                error!(
+                    "Unable to write to disk ({err}), suspending writes for {:?}",
+                    degraded.retry_at - Instant::now()
+                );
+                metrics::DEGRADED.set(1);
+            }
+            Err(_) => {}
+        }
+
+        result
+    }
+
+    fn report(&self) -> Status {
+        self.degraded
+            .as_ref()
+            .map_or_else(Status::default, |degraded| Status {
+                degraded: true,
+                reason: Some(degraded.reason.clone()),
+                since: Some(degraded.since),
+            })
+    }
+
+    ///
+    /// Check whether we're currently allowed to write to disk
+    ///
+    /// # Errors
+    /// If writes are currently suspended due to a previous failure
+    ///
+    pub fn writable() -> Result<(), io::Error> {
+        HEALTH.read().map_or(Ok(()), |health| health.check())
+    }
+
+    ///
+    /// Record the result of a write to disk, entering (or leaving) degraded
+    /// mode as appropriate
+    ///
+    /// # Errors
+    /// Passes through the error of the write, if there was one
+    ///
+    pub fn written<T>(result: Result<T, io::Error>) -> Result<T, io::Error> {
+        match HEALTH.write() {
+            Ok(mut health) => health.record(result),
+            Err(_) => result,
+        }
+    }
+
+    ///
+    /// Attempt a write to disk, unless writes are currently suspended
+    ///
+    /// # Errors
+    /// If writes are suspended, or the write itself fails
+    ///
+    pub fn persist<F, T>(write: F) -> Result<T, io::Error>
+    where
+        F: FnOnce() -> Result<T, io::Error>,
+    {
+        Self::writable()?;
+        Self::written(write())
+    }
+
+    #[inline]
+    pub fn status() -> Status {
+        HEALTH
+            .read()
+            .map(|health| health.report())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use pretty_assertions::assert_eq;
+
+    use super::Health;
+
+    #[test]
+    fn degrades_on_storage_errors() {
+        let mut health = Health::default();
+
+        assert!(health.check().is_ok());
+        assert!(
+            health
+                .record::<()>(Err(io::Error::from(io::ErrorKind::NotFound)))
+                .is_err()
+        );
+        assert!(health.check().is_ok());
+        assert!(!health.report().degraded);
+
+        assert!(
+            health
+                .record::<()>(Err(io::Error::from(io::ErrorKind::StorageFull)))
+                .is_err()
+        );
+        assert!(health.check().is_err());
+        assert!(health.report().degraded);
+        assert_eq!(health.degraded.as_ref().map(|d| d.failures), Some(1));
+
+        assert!(
+            health
+                .record::<()>(Err(io::Error::from(io::ErrorKind::StorageFull)))
+                .is_err()
+        );
+        assert_eq!(health.degraded.as_ref().map(|d| d.failures), Some(2));
+
+        assert!(health.record(Ok(())).is_ok());
+        assert!(health.check().is_ok());
+        assert!(!health.report().degraded);
+    }
+}
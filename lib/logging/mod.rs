@@ -0,0 +1,91 @@
+//!
+//! Logs each blocked request to a syslog server, for anything (e.g. a SIEM)
+//! already watching syslog.
+//!
+
+use std::sync::{LazyLock, RwLock};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::statistics::Request;
+
+pub use syslog::{Facility, Transport};
+
+mod syslog;
+
+static SENDER: LazyLock<RwLock<Option<mpsc::Sender<Request>>>> = LazyLock::new(RwLock::default);
+
+/// How many blocked requests can be waiting to be sent before any more are
+/// dropped
+const BUFFER: usize = 1024;
+
+///
+/// Where to send blocked requests to, over syslog (RFC 5424)
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Syslog {
+    /// e.g. `udp://10.0.0.1:514`, `tcp://10.0.0.1:601` or `unix:///dev/log`
+    pub address: String,
+    #[serde(default)]
+    pub facility: Facility,
+    /// The hostname to log as, otherwise left for the server to fill in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+}
+
+///
+/// Options for logging requests elsewhere
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub syslog: Option<Syslog>,
+}
+
+///
+/// Start logging to wherever the options say, stopping any logging already
+/// running once it's caught up
+///
+pub fn configure(options: &Options) {
+    let sender = options.syslog.clone().and_then(|options| {
+        let transport = options.address.parse::<Transport>().ok()?;
+        let (sender, receiver) = mpsc::channel(BUFFER);
+        tokio::spawn(log(options, transport, receiver));
+        Some(sender)
+    });
+
+    if let Ok(mut lock) = SENDER.write() {
+        *lock = sender;
+    }
+}
+
+///
+/// Log the request, should it have been blocked
+///
+pub fn record(request: &Request) {
+    if !request.blocked() {
+        return;
+    }
+
+    if let Some(sender) = SENDER.read().ok().and_then(|sender| sender.clone()) {
+        // Should the server not be keeping up, the request is dropped
+        let _ = sender.try_send(request.clone());
+    }
+}
+
+async fn log(options: Syslog, transport: Transport, mut receiver: mpsc::Receiver<Request>) {
+    let mut client = syslog::Client::new(transport);
+
+    while let Some(request) = receiver.recv().await {
+        client
+            .send(&syslog::format(
+                options.facility,
+                options.hostname.as_deref(),
+                &request,
+            ))
+            .await;
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    fmt::Display,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket, UnixDatagram},
+};
+use tracing::error;
+
+use crate::statistics::Request;
+
+/// How long to wait before trying to reconnect to the server
+const RETRY: Duration = Duration::from_secs(5);
+
+/// The enterprise number set aside for examples (RFC 5612), as we don't have
+/// one of our own
+const ENTERPRISE: u32 = 32473;
+
+/// Blocked requests are logged as notices
+const SEVERITY: u8 = 5;
+
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Facility {
+    User,
+    #[default]
+    Daemon,
+    Auth,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl Facility {
+    const fn code(self) -> u8 {
+        match self {
+            Self::User => 1,
+            Self::Daemon => 3,
+            Self::Auth => 4,
+            Self::Local0 => 16,
+            Self::Local1 => 17,
+            Self::Local2 => 18,
+            Self::Local3 => 19,
+            Self::Local4 => 20,
+            Self::Local5 => 21,
+            Self::Local6 => 22,
+            Self::Local7 => 23,
+        }
+    }
+}
+
+///
+/// Where the syslog server is, given as `udp://host:port`, `tcp://host:port`
+/// or `unix:///path/to/socket`
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq))]
+pub enum Transport {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{address}' isn't a syslog server, e.g. udp://10.0.0.1:514");
+
+        match address.split_once("://") {
+            Some(("udp", address)) => address.parse().map(Self::Udp).map_err(|_| invalid()),
+            Some(("tcp", address)) => address.parse().map(Self::Tcp).map_err(|_| invalid()),
+            Some(("unix", path)) if !path.is_empty() => Ok(Self::Unix(PathBuf::from(path))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Unix(UnixDatagram),
+}
+
+impl Connection {
+    async fn open(transport: &Transport) -> io::Result<Self> {
+        match transport {
+            Transport::Udp(address) => {
+                let socket = if address.is_ipv4() {
+                    UdpSocket::bind("0.0.0.0:0").await?
+                } else {
+                    UdpSocket::bind("[::]:0").await?
+                };
+                socket.connect(address).await?;
+                Ok(Self::Udp(socket))
+            }
+            Transport::Tcp(address) => TcpStream::connect(address).await.map(Self::Tcp),
+            Transport::Unix(path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Ok(Self::Unix(socket))
+            }
+        }
+    }
+
+    async fn send(&mut self, message: &str) -> io::Result<()> {
+        match self {
+            Self::Udp(socket) => socket.send(message.as_bytes()).await.map(|_| ()),
+            // Octet counting (RFC 6587), as messages could contain newlines
+            Self::Tcp(stream) => {
+                stream
+                    .write_all(format!("{} {message}", message.len()).as_bytes())
+                    .await
+            }
+            Self::Unix(socket) => socket.send(message.as_bytes()).await.map(|_| ()),
+        }
+    }
+}
+
+///
+/// Escape a structured data parameter value (RFC 5424 6.3.3)
+///
+fn escape(value: &str) -> String {
+    value.chars().fold(String::new(), |mut escaped, char| {
+        if matches!(char, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(char);
+        escaped
+    })
+}
+
+struct Param<'a>(&'a str, &'a str);
+
+impl Display for Param<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, " {}=\"{}\"", self.0, escape(self.1))
+    }
+}
+
+///
+/// The blocked request as an RFC 5424 message
+///
+pub(super) fn format(facility: Facility, hostname: Option<&str>, request: &Request) -> String {
+    let list = request
+        .rule
+        .as_ref()
+        .and_then(|rule| rule.list())
+        .map(|list| list.name.as_str());
+
+    let mut data = format!(
+        "blocked@{ENTERPRISE}{}{}",
+        Param("client", &request.client),
+        Param("domain", &request.question)
+    );
+    if let Some(list) = list {
+        data.push_str(&Param("list", list).to_string());
+    }
+
+    format!(
+        "<{}>1 {} {} blackhole {} blocked [{data}] Blocked {} for {}{}",
+        facility.code() * 8 + SEVERITY,
+        DateTime::<Utc>::from(request.timestamp).to_rfc3339_opts(SecondsFormat::Millis, true),
+        hostname.unwrap_or("-"),
+        std::process::id(),
+        request.question,
+        request.client,
+        list.map(|list| format!(" ({list})")).unwrap_or_default()
+    )
+}
+
+pub(super) struct Client {
+    transport: Transport,
+    connection: Option<Connection>,
+    /// When to next try connecting, should the server not be there
+    retry: Option<Instant>,
+}
+
+impl Client {
+    pub(super) const fn new(transport: Transport) -> Self {
+        Self {
+            transport,
+            connection: None,
+            retry: None,
+        }
+    }
+
+    ///
+    /// Send the message, connecting first if need be. Messages sent while the
+    /// server isn't there are dropped.
+    ///
+    pub(super) async fn send(&mut self, message: &str) {
+        if self.connection.is_none() && self.retry.is_none_or(|retry| retry <= Instant::now()) {
+            match Connection::open(&self.transport).await {
+                Ok(connection) => {
+                    self.connection = Some(connection);
+                    self.retry = None;
+                }
+                Err(err) => {
+                    error!("Unable to connect to the syslog server: {err}");
+                    self.retry = Some(Instant::now() + RETRY);
+                }
+            }
+        }
+
+        if let Some(connection) = &mut self.connection {
+            if let Err(err) = connection.send(message).await {
+                error!("Unable to send to the syslog server: {err}");
+                self.connection = None;
+                self.retry = Some(Instant::now() + RETRY);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        path::PathBuf,
+        sync::Arc,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use pretty_assertions::assert_eq;
+
+    use super::{Facility, Transport};
+    use crate::{
+        filter::rules::{Kind, Rule, Source},
+        statistics::Request,
+    };
+
+    #[test]
+    fn transport() {
+        assert_eq!(
+            "udp://10.0.0.1:514".parse(),
+            Ok(Transport::Udp("10.0.0.1:514".parse().unwrap()))
+        );
+        assert_eq!(
+            "tcp://[::1]:601".parse(),
+            Ok(Transport::Tcp("[::1]:601".parse().unwrap()))
+        );
+        assert_eq!(
+            "unix:///dev/log".parse(),
+            Ok(Transport::Unix(PathBuf::from("/dev/log")))
+        );
+        assert!("10.0.0.1:514".parse::<Transport>().is_err());
+        assert!("udp://syslog.lan".parse::<Transport>().is_err());
+    }
+
+    #[test]
+    fn format() {
+        let request = Request {
+            client: String::from("10.0.0.1"),
+            question: String::from("ads.example.com"),
+            rule: Some(Rule {
+                domain: String::from("ads.example.com"),
+                kind: Kind::Deny,
+                action: None,
+                list: Some(Arc::new(Source {
+                    name: String::from("Ads \"and\" more"),
+                    url: String::from("https://example.com/ads.txt"),
+                })),
+                zone: false,
+                important: false,
+                scope: None,
+            }),
+            timestamp: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            ..Default::default()
+        };
+
+        let message = super::format(Facility::Daemon, Some("dns.lan"), &request);
+
+        assert_eq!(
+            message,
+            format!(
+                "<29>1 2023-11-14T22:13:20.000Z dns.lan blackhole {} blocked [blocked@32473 \
+                 client=\"10.0.0.1\" domain=\"ads.example.com\" list=\"Ads \\\"and\\\" more\"] \
+                 Blocked ads.example.com for 10.0.0.1 (Ads \"and\" more)",
+                std::process::id()
+            )
+        );
+    }
+}
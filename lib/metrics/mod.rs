@@ -2,37 +2,146 @@ use std::sync::{LazyLock, PoisonError, RwLock, RwLockWriteGuard};
 
 use prometheus_client::{
     encoding::EncodeLabelSet,
-    metrics::{counter::Counter, family::Family, gauge::Gauge, histogram::Histogram},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
     registry::Registry,
 };
+use serde::{Deserialize, Serialize};
 
 pub static REGISTRY: LazyLock<RwLock<Registry>> = LazyLock::new(RwLock::default);
+static MODE: LazyLock<RwLock<Mode>> = LazyLock::new(RwLock::default);
+
+///
+/// How much detail to include in the labels of per-request metrics
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Label requests by client, question, type and rule
+    #[default]
+    Full,
+    /// Label requests only by client and rule, which keeps the number of
+    /// series bounded on busy networks
+    Aggregated,
+}
+
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    #[serde(default)]
+    pub mode: Mode,
+}
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 pub struct Request {
     pub client: String,
+    pub name: String,
     pub question: String,
     pub r#type: String,
     pub rule: String,
 }
 
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct Aggregate {
+    pub client: String,
+    pub name: String,
+    pub rule: String,
+}
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 pub struct Cache {
     pub hit: String,
 }
 
-pub static CACHE: LazyLock<Family<Cache, Counter>> = LazyLock::new(Family::default);
-pub static RULES: LazyLock<Gauge> = LazyLock::new(Gauge::default);
-pub static BLOCKED: LazyLock<Counter> = LazyLock::new(Counter::default);
-pub static REQUESTS: LazyLock<Family<Request, Counter>> = LazyLock::new(Family::default);
-pub static DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct Protocol {
+    pub protocol: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct Upstream {
+    pub ip: String,
+    pub port: u16,
+    pub protocol: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct Quantile {
+    pub outcome: String,
+    pub quantile: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct Stage {
+    pub stage: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct Burst {
+    pub kind: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct Connection {
+    pub outcome: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct Queued {
+    pub outcome: String,
+}
+
+type Histograms<L> = Family<L, Histogram, fn() -> Histogram>;
+
+fn duration_histogram() -> Histogram {
     Histogram::new(
         [0.1, 0.2, 0.5, 1.0, 10.0]
             .into_iter()
             // Convert to nanoseconds
             .map(|a| a * 1_000_000_000.0),
     )
-});
+}
+
+///
+/// Stages take anywhere from a microsecond (checking the filter) to seconds
+/// (a slow upstream), so these buckets start much smaller
+///
+fn stage_histogram() -> Histogram {
+    // 1µs to 10s, in nanoseconds
+    Histogram::new(exponential_buckets(1_000.0, 10.0, 8))
+}
+
+pub static CACHE: LazyLock<Family<Cache, Counter>> = LazyLock::new(Family::default);
+pub static RULES: LazyLock<Gauge> = LazyLock::new(Gauge::default);
+pub static BLOCKED: LazyLock<Counter> = LazyLock::new(Counter::default);
+pub static DEGRADED: LazyLock<Gauge> = LazyLock::new(Gauge::default);
+pub static ANOMALIES: LazyLock<Family<Burst, Gauge>> = LazyLock::new(Family::default);
+pub static EXPORT_DROPPED: LazyLock<Counter> = LazyLock::new(Counter::default);
+pub static CACHE_EVICTIONS: LazyLock<Counter> = LazyLock::new(Counter::default);
+pub static CACHE_EXPIRATIONS: LazyLock<Counter> = LazyLock::new(Counter::default);
+pub static CACHE_ENTRIES: LazyLock<Gauge> = LazyLock::new(Gauge::default);
+pub static CACHE_SIZE: LazyLock<Gauge> = LazyLock::new(Gauge::default);
+pub static REJECTED: LazyLock<Counter> = LazyLock::new(Counter::default);
+pub static TCP_CONNECTIONS: LazyLock<Family<Connection, Counter>> = LazyLock::new(Family::default);
+pub static QUEUE: LazyLock<Family<Queued, Counter>> = LazyLock::new(Family::default);
+pub static REQUESTS: LazyLock<Family<Request, Counter>> = LazyLock::new(Family::default);
+pub static AGGREGATED_REQUESTS: LazyLock<Family<Aggregate, Counter>> =
+    LazyLock::new(Family::default);
+pub static DURATION: LazyLock<Histogram> = LazyLock::new(duration_histogram);
+pub static LATENCY: LazyLock<Family<Quantile, Gauge>> = LazyLock::new(Family::default);
+pub static UPSTREAM_DURATION: LazyLock<Histograms<Upstream>> =
+    LazyLock::new(|| Family::new_with_constructor(duration_histogram));
+pub static UPSTREAM_ERRORS: LazyLock<Family<Upstream, Counter>> = LazyLock::new(Family::default);
+pub static PROTOCOL_REQUESTS: LazyLock<Family<Protocol, Counter>> = LazyLock::new(Family::default);
+pub static PROTOCOL_DURATION: LazyLock<Histograms<Protocol>> =
+    LazyLock::new(|| Family::new_with_constructor(duration_histogram));
+pub static STAGE_DURATION: LazyLock<Histograms<Stage>> =
+    LazyLock::new(|| Family::new_with_constructor(stage_histogram));
 
 ///
 /// Initialise the metrics registry
@@ -42,22 +151,124 @@ pub static DURATION: LazyLock<Histogram> = LazyLock::new(|| {
 /// is held by the current thread. However, this should be virtually impossible as
 /// this is meant to only ever be called once
 ///
-pub fn init() -> Result<(), PoisonError<RwLockWriteGuard<'static, Registry>>> {
+pub fn init(options: &Options) -> Result<(), PoisonError<RwLockWriteGuard<'static, Registry>>> {
+    configure(options);
+
     let mut registry = REGISTRY.write()?;
 
     registry.register("blackhole_requests", "Number of requests", REQUESTS.clone());
+    registry.register(
+        "blackhole_aggregated_requests",
+        "Number of requests per client and rule",
+        AGGREGATED_REQUESTS.clone(),
+    );
     registry.register(
         "blackhole_request_duration",
         "Duration of requests",
         DURATION.clone(),
     );
+    registry.register(
+        "blackhole_request_latency",
+        "Duration of requests over the last five minutes, per outcome and quantile",
+        LATENCY.clone(),
+    );
     registry.register(
         "blackhole_requests_blocked",
         "Number of requests blocked",
         BLOCKED.clone(),
     );
+    registry.register(
+        "blackhole_protocol_requests",
+        "Number of requests per protocol",
+        PROTOCOL_REQUESTS.clone(),
+    );
+    registry.register(
+        "blackhole_protocol_request_duration",
+        "Duration of requests per protocol",
+        PROTOCOL_DURATION.clone(),
+    );
+    registry.register(
+        "blackhole_stage_duration",
+        "Duration of each stage of answering requests (filter, cache and upstream)",
+        STAGE_DURATION.clone(),
+    );
+    registry.register(
+        "blackhole_upstream_duration",
+        "Duration of requests forwarded to each upstream",
+        UPSTREAM_DURATION.clone(),
+    );
+    registry.register(
+        "blackhole_upstream_errors",
+        "Number of failed requests to each upstream",
+        UPSTREAM_ERRORS.clone(),
+    );
+    registry.register(
+        "blackhole_rejected",
+        "Number of requests rejected by the ACL",
+        REJECTED.clone(),
+    );
+    registry.register(
+        "blackhole_export_dropped",
+        "Number of requests dropped as the exporter couldn't keep up",
+        EXPORT_DROPPED.clone(),
+    );
     registry.register("blackhole_rules", "Number of rules", RULES.clone());
     registry.register("blackhole_cache", "Cache effectiveness", CACHE.clone());
+    registry.register(
+        "blackhole_cache_evictions",
+        "Number of responses forgotten to make room in the cache",
+        CACHE_EVICTIONS.clone(),
+    );
+    registry.register(
+        "blackhole_cache_expirations",
+        "Number of cached responses forgotten as they'd expired",
+        CACHE_EXPIRATIONS.clone(),
+    );
+    registry.register(
+        "blackhole_cache_entries",
+        "Number of responses currently cached",
+        CACHE_ENTRIES.clone(),
+    );
+    registry.register(
+        "blackhole_cache_size_bytes",
+        "Roughly how much memory the cache takes up",
+        CACHE_SIZE.clone(),
+    );
+    registry.register(
+        "blackhole_degraded",
+        "Whether writes to disk are currently suspended",
+        DEGRADED.clone(),
+    );
+    registry.register(
+        "blackhole_anomaly",
+        "Whether a burst of SERVFAIL or NXDOMAIN responses is ongoing",
+        ANOMALIES.clone(),
+    );
+    registry.register(
+        "blackhole_tcp_connections",
+        "TCP connections, by whether they were accepted, rejected or timed out",
+        TCP_CONNECTIONS.clone(),
+    );
+    registry.register(
+        "blackhole_request_queue",
+        "Requests that had to wait their turn, or were dropped for too many waiting",
+        QUEUE.clone(),
+    );
 
     Ok(())
 }
+
+///
+/// Apply any changes to the metrics options
+///
+#[inline]
+pub fn configure(options: &Options) {
+    if let Ok(mut mode) = MODE.write() {
+        *mode = options.mode;
+    }
+}
+
+#[inline]
+pub fn mode() -> Mode {
+    MODE.read().map(|mode| *mode).unwrap_or_default()
+}
@@ -0,0 +1,163 @@
+use std::{
+    net::IpAddr,
+    sync::{Arc, LazyLock},
+};
+
+use ahash::AHashMap;
+use hickory_proto::rr::Name;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::filter::rules::{Action, Kind, Rule, Source};
+
+///
+/// The names each search engine serves from, and the name that has SafeSearch
+/// enforced for them
+///
+const ENGINES: [(&[&str], &str); 4] = [
+    (
+        &["google.com", "www.google.com"],
+        "forcesafesearch.google.com",
+    ),
+    (&["bing.com", "www.bing.com"], "strict.bing.com"),
+    (
+        &[
+            "duckduckgo.com",
+            "www.duckduckgo.com",
+            "start.duckduckgo.com",
+        ],
+        "safe.duckduckgo.com",
+    ),
+    (
+        &[
+            "youtube.com",
+            "www.youtube.com",
+            "m.youtube.com",
+            "youtubei.googleapis.com",
+            "youtube.googleapis.com",
+            "www.youtube-nocookie.com",
+        ],
+        "restrict.youtube.com",
+    ),
+];
+
+///
+/// A rule pointing each of the search engines' names at their SafeSearch
+/// equivalent, built once as they never change
+///
+static RULES: LazyLock<AHashMap<&'static str, Rule>> = LazyLock::new(|| {
+    let list = Arc::new(Source {
+        name: String::from("SafeSearch"),
+        url: String::from("builtin"),
+    });
+
+    ENGINES
+        .iter()
+        .flat_map(|(domains, target)| domains.iter().map(move |domain| (*domain, *target)))
+        .map(|(domain, target)| {
+            (
+                domain,
+                Rule {
+                    domain: domain.to_string(),
+                    kind: Kind::Allow,
+                    action: Some(Box::new(Action {
+                        cname: Some(target.to_string()),
+                        ..Default::default()
+                    })),
+                    list: Some(Arc::clone(&list)),
+                    zone: false,
+                    important: false,
+                    scope: None,
+                },
+            )
+        })
+        .collect()
+});
+
+///
+/// Options for enforcing SafeSearch on the search engines that support it
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The networks it's enforced for. If empty, it's enforced for everyone.
+    #[serde(default)]
+    pub clients: Vec<IpNet>,
+}
+
+impl Options {
+    ///
+    /// Whether SafeSearch is enforced for the client
+    ///
+    #[inline]
+    pub fn enforced(&self, client: IpAddr) -> bool {
+        self.enabled
+            && (self.clients.is_empty()
+                || self.clients.iter().any(|network| network.contains(&client)))
+    }
+}
+
+///
+/// The rule pointing the name at its SafeSearch equivalent, should it belong
+/// to one of the search engines
+///
+pub fn rule(name: &Name) -> Option<Rule> {
+    let name = name.to_lowercase().to_string();
+
+    RULES.get(name.trim_end_matches('.')).cloned()
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use hickory_proto::rr::Name;
+    use pretty_assertions::assert_eq;
+
+    use super::{rule, Options};
+
+    #[test]
+    fn rules() {
+        let target = |domain: &str| {
+            rule(&Name::from_str(domain).unwrap())
+                .and_then(|rule| rule.cname())
+                .map(|cname| cname.to_string())
+        };
+
+        assert_eq!(
+            target("www.Google.com."),
+            Some(String::from("forcesafesearch.google.com."))
+        );
+        assert_eq!(target("bing.com"), Some(String::from("strict.bing.com.")));
+        assert_eq!(
+            target("duckduckgo.com"),
+            Some(String::from("safe.duckduckgo.com."))
+        );
+        assert_eq!(
+            target("m.youtube.com"),
+            Some(String::from("restrict.youtube.com."))
+        );
+        assert_eq!(target("mail.google.com"), None);
+        // Answered with the CNAME, rather than counted as blocked
+        assert!(rule(&Name::from_str("bing.com").unwrap()).is_some_and(|rule| !rule.sinkholes()));
+    }
+
+    #[test]
+    fn enforced() {
+        let everyone = Options {
+            enabled: true,
+            clients: Vec::new(),
+        };
+        let children = Options {
+            enabled: true,
+            clients: vec!["192.168.1.128/25".parse().unwrap()],
+        };
+
+        assert!(everyone.enforced("192.168.1.20".parse().unwrap()));
+        assert!(!Options::default().enforced("192.168.1.20".parse().unwrap()));
+        assert!(children.enforced("192.168.1.130".parse().unwrap()));
+        assert!(!children.enforced("192.168.1.20".parse().unwrap()));
+    }
+}
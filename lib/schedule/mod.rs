@@ -1,34 +1,169 @@
 use std::{
+    hash::{BuildHasher, RandomState},
+    str::FromStr,
     sync::LazyLock,
     time::{Duration, Instant, SystemTime},
 };
 
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
+use chrono::{Local, Timelike};
 use serde::{Deserialize, Serialize};
-use tokio::{sync::RwLock, time::sleep};
-use tracing::{debug, instrument};
+use tokio::{
+    sync::{Notify, RwLock},
+    time::sleep,
+};
+use tracing::{debug, error, info, instrument, warn};
 
 use crate::{
+    cache::Cache,
     config::Config,
     filter::Filter,
     statistics::{self, Statistics},
 };
 
+pub use window::Window;
+
+mod window;
+
 static SCHEDULER: LazyLock<RwLock<Scheduler>> = LazyLock::new(RwLock::default);
+static WAKE: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+/// How often expired responses are swept from the cache, unless it's been
+/// given a schedule
+const SWEEP: Duration = Duration::from_secs(60);
+
+const fn default_window() -> Duration {
+    Duration::from_secs(60)
+}
+
+const fn default_retry() -> Duration {
+    Duration::from_secs(60)
+}
+
+const fn default_max_deferral() -> Duration {
+    Duration::from_hours(1)
+}
+
+///
+/// Options for deferring tasks while the server is under load
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Defer tasks while requests are taking longer than this on average
+    #[serde(with = "humantime_serde", default)]
+    pub defer_above: Option<Duration>,
+    /// How far back to look when working out the average request time
+    #[serde(with = "humantime_serde", default = "default_window")]
+    pub window: Duration,
+    /// How long to wait before trying a deferred task again
+    #[serde(with = "humantime_serde", default = "default_retry")]
+    pub retry: Duration,
+    /// The longest a task will be deferred before it's run regardless
+    #[serde(with = "humantime_serde", default = "default_max_deferral")]
+    pub max_deferral: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            defer_above: None,
+            window: default_window(),
+            retry: default_retry(),
+            max_deferral: default_max_deferral(),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq, PartialOrd, Hash)]
 pub enum Sched {
     Filters,
     Logs,
+    /// Save the running totals, so that they survive restarts
+    Statistics,
+    /// Turn blocking back on once it's been disabled for a while
+    #[serde(skip)]
+    Blocking,
+    /// Work out which of the windows are open, and so which lists are enforced
+    #[serde(skip)]
+    Windows,
+    /// Forget cached responses that have expired, every minute unless given a
+    /// schedule of its own
+    Cache,
+}
+
+impl FromStr for Sched {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "filters" => Ok(Self::Filters),
+            "logs" => Ok(Self::Logs),
+            "statistics" => Ok(Self::Statistics),
+            "cache" => Ok(Self::Cache),
+            _ => Err(format!("Unknown schedule: {s}")),
+        }
+    }
 }
 
 impl Sched {
+    ///
+    /// Whether this can be put off while the server is busy
+    ///
+    #[inline]
+    const fn deferrable(&self) -> bool {
+        matches!(self, Self::Filters | Self::Logs)
+    }
+
+    ///
+    /// Run the task, returning why should it fail
+    ///
     #[instrument]
-    async fn run(&self) {
+    pub(crate) async fn run(&self) -> Result<(), String> {
         match self {
             Self::Filters => {
                 Filter::reset(None).await;
             }
+            Self::Blocking => {
+                Filter::resume().await;
+            }
+            Self::Windows => {
+                let windows = Config::get(|config| config.windows.clone()).await;
+                let now = Local::now();
+
+                Filter::open(
+                    windows
+                        .into_iter()
+                        .filter(|window| window.open(&now.naive_local()))
+                        .collect(),
+                );
+
+                // Windows open and close on the minute, so there's no need to
+                // check any more often than that
+                if Config::get(|config| !config.windows.is_empty()).await {
+                    Scheduler::once(
+                        Self::Windows,
+                        Duration::from_secs(60 - u64::from(now.second())),
+                    )
+                    .await;
+                }
+            }
+            Self::Cache => {
+                Cache::sweep().await;
+
+                if !SCHEDULER.read().await.schedules.contains_key(self) {
+                    Scheduler::once(Self::Cache, SWEEP).await;
+                }
+            }
+            Self::Statistics => {
+                if let Some(snapshot) =
+                    Config::get(|config| config.statistics.snapshot.clone()).await
+                {
+                    Statistics::save(&snapshot).map_err(|err| {
+                        format!("Unable to save statistics to {}: {err}", snapshot.display())
+                    })?;
+                }
+            }
             Self::Logs => {
                 let schedule = Config::get(|config| {
                     config
@@ -54,6 +189,8 @@ impl Sched {
                 });
             }
         }
+
+        Ok(())
     }
 
     #[inline]
@@ -63,84 +200,421 @@ impl Sched {
             Self::Filters => {
                 Filter::init().await;
             }
-            Self::Logs => {}
+            Self::Logs | Self::Blocking | Self::Statistics | Self::Windows | Self::Cache => {}
         }
     }
 }
 
-#[cfg_attr(any(debug_assertions, test), derive(PartialEq, Eq))]
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct Schedule {
     pub name: Sched,
     #[serde(with = "humantime_serde", default)]
     pub schedule: Duration,
+    /// Up to how much later than scheduled to run each time, so that instances
+    /// sharing a schedule don't all run at the same instant
+    #[serde(
+        with = "humantime_serde",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub jitter: Option<Duration>,
+}
+
+impl Schedule {
+    ///
+    /// How long until the task next runs, including a random amount of jitter
+    ///
+    fn delay(&self) -> Duration {
+        let jitter = self
+            .jitter
+            .and_then(|jitter| u64::try_from(jitter.as_nanos()).ok())
+            .filter(|jitter| *jitter > 0)
+            .map_or(Duration::ZERO, |jitter| {
+                Duration::from_nanos(RandomState::new().hash_one(Instant::now()) % jitter)
+            });
+
+        self.schedule + jitter
+    }
+}
+
+///
+/// How long to wait before retrying a task that's failed the number of times
+/// in a row, doubling each time, though never longer than it'd usually wait
+///
+fn backoff(retry: Duration, failures: u32, every: Duration) -> Duration {
+    retry
+        .saturating_mul(2_u32.saturating_pow(failures.saturating_sub(1)))
+        .min(every.max(retry))
+}
+
+///
+/// When a task is next due to run, and how it's been going
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize)]
+pub struct Status {
+    pub name: Sched,
+    /// How often it runs, or none for those only running the once
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Duration>,
+    pub next: SystemTime,
+    /// How many times in a row it's failed
+    pub failures: u32,
+    /// Whether it's currently being put off due to load
+    pub deferred: bool,
 }
 
 #[derive(Default)]
 pub struct Scheduler {
-    schedules: AHashMap<Sched, (Instant, Duration)>,
+    schedules: AHashMap<Sched, (Instant, Schedule)>,
+    /// When each currently deferred task was first deferred
+    deferred: AHashMap<Sched, Instant>,
+    /// Tasks to run on their next turn regardless of load
+    forced: AHashSet<Sched>,
+    /// Tasks to run only the once, and when
+    once: AHashMap<Sched, Instant>,
+    /// How many times in a row each task has failed
+    failures: AHashMap<Sched, u32>,
 }
 
 impl Scheduler {
     #[instrument]
     async fn run() {
         loop {
-            let mut soonest = Instant::now();
+            let mut soonest = None;
 
             let schedules = { SCHEDULER.read().await.schedules.clone() };
 
-            for (schedule, (at, time)) in schedules {
-                if at <= Instant::now() {
-                    debug!("Running schedule: {schedule:?}");
-                    schedule.run().await;
-                    debug!("Schedule completed");
+            for (name, (at, schedule)) in schedules {
+                let next = if at > Instant::now() {
+                    at
+                } else if let Some(retry) = Self::defer(&name).await {
+                    retry
+                } else if Self::attempt(&name).await {
+                    Self::schedule(schedule).await
+                } else {
+                    Self::retry(&name).await
+                };
 
-                    let next = Self::schedule(Schedule {
-                        name: schedule,
-                        schedule: time,
-                    })
-                    .await;
+                soonest = Some(soonest.map_or(next, |soonest: Instant| soonest.min(next)));
+            }
+
+            let (due, next) = {
+                let mut scheduler = SCHEDULER.write().await;
+                let now = Instant::now();
+
+                let due = scheduler
+                    .once
+                    .iter()
+                    .filter(|(_, at)| **at <= now)
+                    .map(|(schedule, _)| schedule.clone())
+                    .collect::<Vec<_>>();
+                for schedule in &due {
+                    scheduler.once.remove(schedule);
+                }
 
-                    if next < soonest {
-                        soonest = next;
+                (due, scheduler.once.values().min().copied())
+            };
+
+            for schedule in due {
+                debug!("Running one-off schedule: {schedule:?}");
+                Self::attempt(&schedule).await;
+            }
+
+            if let Some(next) = next {
+                soonest = Some(soonest.map_or(next, |soonest: Instant| soonest.min(next)));
+            }
+
+            match soonest {
+                Some(soonest) => {
+                    tokio::select! {
+                        () = sleep(soonest.saturating_duration_since(Instant::now())) => {}
+                        () = WAKE.notified() => {}
                     }
-                } else if at < soonest {
-                    soonest = at;
                 }
+                None => WAKE.notified().await,
+            }
+        }
+    }
+
+    ///
+    /// Run the task off on its own, so that should it fail (or panic) the rest
+    /// of the schedules carry on regardless. Returns whether it succeeded.
+    ///
+    async fn attempt(schedule: &Sched) -> bool {
+        debug!("Running schedule: {schedule:?}");
+
+        let task = schedule.clone();
+        let failure = match tokio::spawn(async move { task.run().await }).await {
+            Ok(Ok(())) => None,
+            Ok(Err(err)) => Some(err),
+            Err(err) => Some(err.to_string()),
+        };
+
+        let mut scheduler = SCHEDULER.write().await;
+        match failure {
+            None => {
+                debug!("Schedule completed");
+                scheduler.failures.remove(schedule);
+                true
             }
+            Some(err) => {
+                error!("{schedule:?} failed: {err}");
+                *scheduler.failures.entry(schedule.clone()).or_default() += 1;
+                false
+            }
+        }
+    }
+
+    ///
+    /// Put a task that's just failed off for a while before trying it again,
+    /// returning when that'll be
+    ///
+    async fn retry(schedule: &Sched) -> Instant {
+        let retry = Config::get(|config| config.scheduler.retry).await;
+
+        let mut scheduler = SCHEDULER.write().await;
+        let failures = scheduler.failures.get(schedule).copied().unwrap_or(1);
+
+        let Some((when, every)) = scheduler.schedules.get_mut(schedule) else {
+            return Instant::now() + retry;
+        };
+
+        let delay = backoff(retry, failures, every.schedule);
+        warn!("Retrying {schedule:?} in {delay:?}, after {failures} failure(s)");
+
+        *when = Instant::now() + delay;
+        *when
+    }
+
+    ///
+    /// Decide whether a task that's due should be put off due to the current load,
+    /// returning when to try it again if so
+    ///
+    async fn defer(schedule: &Sched) -> Option<Instant> {
+        let options = Config::get(|config| config.scheduler.clone()).await;
+
+        let mut scheduler = SCHEDULER.write().await;
+        if scheduler.forced.remove(schedule) || !schedule.deferrable() {
+            scheduler.deferred.remove(schedule);
+            return None;
+        }
+
+        let latency = options
+            .defer_above
+            .zip(Statistics::recent_latency(options.window))
+            .filter(|(threshold, latency)| latency > threshold)
+            .map(|(_, latency)| latency);
+
+        let Some(latency) = latency else {
+            scheduler.deferred.remove(schedule);
+            return None;
+        };
+
+        let since = *scheduler
+            .deferred
+            .entry(schedule.clone())
+            .or_insert_with(Instant::now);
+
+        if since.elapsed() >= options.max_deferral {
+            warn!(
+                "{schedule:?} has been deferred for {:?}, running it anyway",
+                since.elapsed()
+            );
+            scheduler.deferred.remove(schedule);
+            return None;
+        }
+
+        info!("Deferring {schedule:?}, requests are currently taking {latency:?} on average");
+
+        let retry = Instant::now() + options.retry;
+        scheduler
+            .schedules
+            .entry(schedule.clone())
+            .and_modify(|(when, _)| *when = retry);
+
+        Some(retry)
+    }
+
+    ///
+    /// Run a task as soon as possible, regardless of the current load
+    ///
+    /// Returns false if the task isn't scheduled
+    ///
+    pub async fn force(schedule: Sched) -> bool {
+        let mut scheduler = SCHEDULER.write().await;
+
+        let Some((when, _)) = scheduler.schedules.get_mut(&schedule) else {
+            return false;
+        };
+
+        *when = Instant::now();
+        scheduler.forced.insert(schedule);
+        WAKE.notify_one();
+
+        true
+    }
+
+    ///
+    /// Run a task once after the delay, replacing when it was due to run should
+    /// it already be waiting to. Delays too long to wait are never run
+    ///
+    pub async fn once(schedule: Sched, delay: Duration) {
+        let Some(when) = Instant::now().checked_add(delay) else {
+            warn!("Not running {schedule:?}, as {delay:?} is too long to wait");
+            return;
+        };
+
+        SCHEDULER.write().await.once.insert(schedule, when);
+        WAKE.notify_one();
+    }
+
+    ///
+    /// Stop a task that was due to run once from running
+    ///
+    pub async fn cancel(schedule: &Sched) {
+        if SCHEDULER.write().await.once.remove(schedule).is_some() {
+            WAKE.notify_one();
+        }
+    }
+
+    ///
+    /// Replace the current schedules, keeping when those that haven't changed
+    /// are next due to run
+    ///
+    pub async fn reschedule(wanted: Vec<Schedule>) {
+        let sweeps = wanted.iter().any(|schedule| schedule.name == Sched::Cache);
+
+        {
+            let mut scheduler = SCHEDULER.write().await;
+            scheduler
+                .schedules
+                .retain(|_, (_, current)| wanted.iter().any(|schedule| schedule == current));
+            scheduler.deferred.clear();
+        }
+
+        for schedule in wanted {
+            if !SCHEDULER
+                .read()
+                .await
+                .schedules
+                .contains_key(&schedule.name)
+            {
+                info!(
+                    "Scheduling {:?} every {:?}",
+                    schedule.name, schedule.schedule
+                );
+                Self::schedule(schedule).await;
+            }
+        }
 
-            sleep(soonest - Instant::now()).await;
+        if !sweeps {
+            Self::once(Sched::Cache, SWEEP).await;
         }
+
+        WAKE.notify_one();
     }
 
     async fn schedule(schedule: Schedule) -> Instant {
         debug!("Rescheduling {schedule:?}");
 
+        let when = Instant::now().checked_add(schedule.delay()).unwrap();
         SCHEDULER
             .write()
             .await
             .schedules
-            .entry(schedule.name)
-            .and_modify(|(when, sched)| {
-                *when = Instant::now().checked_add(*sched).unwrap();
-                *sched = schedule.schedule;
-            })
-            .or_insert_with(|| {
-                (
-                    Instant::now().checked_add(schedule.schedule).unwrap(),
-                    schedule.schedule,
-                )
-            })
-            .0
+            .insert(schedule.name.clone(), (when, schedule));
+
+        when
+    }
+
+    ///
+    /// When each task is next due to run, soonest first
+    ///
+    pub async fn status() -> Vec<Status> {
+        let scheduler = SCHEDULER.read().await;
+        let (now, system) = (Instant::now(), SystemTime::now());
+
+        let status = |name: &Sched, schedule: Option<Duration>, when: &Instant| Status {
+            name: name.clone(),
+            schedule,
+            next: system + when.saturating_duration_since(now),
+            failures: scheduler.failures.get(name).copied().unwrap_or_default(),
+            deferred: scheduler.deferred.contains_key(name),
+        };
+
+        let mut statuses = scheduler
+            .schedules
+            .iter()
+            .map(|(name, (when, schedule))| status(name, Some(schedule.schedule), when))
+            .chain(
+                scheduler
+                    .once
+                    .iter()
+                    .map(|(name, when)| status(name, None, when)),
+            )
+            .collect::<Vec<_>>();
+        statuses.sort_by_key(|status| status.next);
+
+        statuses
     }
 
     pub async fn init(schedules: Vec<Schedule>) {
         debug!("Running init for Schedules");
+        let sweeps = schedules
+            .iter()
+            .any(|schedule| schedule.name == Sched::Cache);
+
         for schedule in schedules {
             schedule.name.init().await;
             Self::schedule(schedule).await;
         }
 
+        Self::attempt(&Sched::Windows).await;
+        if !sweeps {
+            Self::once(Sched::Cache, SWEEP).await;
+        }
+
         Self::run().await;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use pretty_assertions::assert_eq;
+
+    use super::{backoff, Sched, Schedule};
+
+    #[test]
+    fn jitter() {
+        let mut schedule = Schedule {
+            name: Sched::Filters,
+            schedule: Duration::from_secs(60 * 60),
+            jitter: None,
+        };
+        assert_eq!(schedule.delay(), schedule.schedule);
+
+        schedule.jitter = Some(Duration::from_secs(5 * 60));
+        for _ in 0..32 {
+            let delay = schedule.delay();
+            assert!(delay >= schedule.schedule);
+            assert!(delay < schedule.schedule + Duration::from_secs(5 * 60));
+        }
+    }
+
+    #[test]
+    fn backoff_doubles() {
+        let minute = Duration::from_secs(60);
+        let hour = Duration::from_secs(60 * 60);
+
+        assert_eq!(backoff(minute, 1, hour), minute);
+        assert_eq!(backoff(minute, 2, hour), minute * 2);
+        assert_eq!(backoff(minute, 4, hour), minute * 8);
+        // Never waiting longer than it otherwise would
+        assert_eq!(backoff(minute, 10, hour), hour);
+        assert_eq!(backoff(minute, u32::MAX, hour), hour);
+        assert_eq!(backoff(hour, 3, minute), hour);
+    }
+}
@@ -0,0 +1,110 @@
+use std::net::IpAddr;
+
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+///
+/// A time of day (and days of the week) during which some lists are enforced,
+/// e.g. blocking social media overnight for the kids
+///
+/// Windows which end earlier in the day than they start run overnight, and
+/// belong to the day they start on.
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct Window {
+    pub name: String,
+    /// The names of the lists enforced during the window, and only then
+    pub lists: Vec<String>,
+    /// The networks the window applies to. If empty, it applies to everyone.
+    #[serde(default)]
+    pub clients: Vec<IpNet>,
+    /// The days the window opens on. If empty, it opens every day.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    /// In local time, e.g. "21:00"
+    pub start: NaiveTime,
+    /// In local time. The same as the start for the whole day.
+    pub end: NaiveTime,
+}
+
+impl Window {
+    fn on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    ///
+    /// Whether the window is open at the (local) time
+    ///
+    pub fn open(&self, now: &NaiveDateTime) -> bool {
+        let (day, time) = (now.weekday(), now.time());
+
+        if self.start < self.end {
+            self.on(day) && self.start <= time && time < self.end
+        } else {
+            (self.on(day) && self.start <= time) || (self.on(day.pred()) && time < self.end)
+        }
+    }
+
+    ///
+    /// Whether the window applies to queries from the client
+    ///
+    #[inline]
+    pub fn applies(&self, client: IpAddr) -> bool {
+        self.clients.is_empty() || self.clients.iter().any(|network| network.contains(&client))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+
+    use super::Window;
+
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        // The 1st of January 2024 was a Monday
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_time(time.parse().unwrap())
+    }
+
+    fn window(start: &str, end: &str, days: Vec<Weekday>) -> Window {
+        Window {
+            name: String::from("Bedtime"),
+            lists: vec![String::from("Social")],
+            clients: vec!["192.168.1.128/25".parse().unwrap()],
+            days,
+            start: start.parse::<NaiveTime>().unwrap(),
+            end: end.parse::<NaiveTime>().unwrap(),
+        }
+    }
+
+    #[test]
+    fn open() {
+        let school = window("09:00", "15:00", vec![Weekday::Mon, Weekday::Tue]);
+        assert!(school.open(&at(1, "09:00")));
+        assert!(school.open(&at(2, "14:59")));
+        assert!(!school.open(&at(1, "15:00")));
+        assert!(!school.open(&at(3, "10:00")));
+
+        // Overnight, belonging to the day it starts on
+        let bedtime = window("21:00", "07:00", vec![Weekday::Sun]);
+        assert!(bedtime.open(&at(7, "21:30")));
+        assert!(bedtime.open(&at(8, "06:59")));
+        assert!(!bedtime.open(&at(8, "21:30")));
+        assert!(!bedtime.open(&at(7, "06:59")));
+
+        let always = window("00:00", "00:00", Vec::new());
+        assert!(always.open(&at(3, "00:00")));
+        assert!(always.open(&at(5, "23:59")));
+    }
+
+    #[test]
+    fn applies() {
+        let bedtime = window("21:00", "07:00", Vec::new());
+
+        assert!(bedtime.applies("192.168.1.130".parse().unwrap()));
+        assert!(!bedtime.applies("192.168.1.20".parse().unwrap()));
+    }
+}
@@ -0,0 +1,163 @@
+use std::{future::Future, pin::Pin, sync::LazyLock, time::Duration};
+
+use tokio::sync::Mutex;
+use tracing::{debug, error};
+
+type Hook = Box<dyn Fn() -> Pin<Box<dyn Future<Output = Result<(), String>> + Send>> + Send + Sync>;
+
+static HOOKS: LazyLock<Mutex<Vec<(&'static str, Hook)>>> = LazyLock::new(Mutex::default);
+
+/// How long to give the shutdown hooks after panicking, as whatever panicked
+/// may have been holding on to something they need
+const PANIC_TIMEOUT: Duration = Duration::from_secs(5);
+
+///
+/// The reasons we might exit, each with a distinct exit code so that supervisors
+/// can decide whether restarting is worthwhile (codes follow sysexits.h)
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// We were asked to shut down, and did so cleanly
+    Clean,
+    /// The config file couldn't be loaded
+    Config,
+    /// We couldn't bind to one of the addresses we serve on
+    Bind,
+    /// One of the servers stopped unexpectedly
+    Unavailable,
+    /// Something panicked
+    Panic,
+}
+
+impl Exit {
+    #[inline]
+    pub const fn code(self) -> u8 {
+        match self {
+            Self::Clean => 0,
+            Self::Unavailable => 69,
+            Self::Panic => 70,
+            Self::Bind => 71,
+            Self::Config => 78,
+        }
+    }
+}
+
+impl From<Exit> for std::process::ExitCode {
+    fn from(exit: Exit) -> Self {
+        Self::from(exit.code())
+    }
+}
+
+///
+/// Register a hook to be run when shutting down, for anything that needs to be
+/// flushed to disk before we exit. Hooks are run in the reverse order to which
+/// they were registered.
+///
+pub async fn register<F, Fut>(name: &'static str, hook: F)
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), String>> + Send + 'static,
+{
+    HOOKS
+        .lock()
+        .await
+        .push((name, Box::new(move || Box::pin(hook()))));
+}
+
+///
+/// Run every registered shutdown hook
+///
+/// Returns whether they all succeeded
+///
+pub async fn run() -> bool {
+    let hooks = std::mem::take(&mut *HOOKS.lock().await);
+
+    let mut succeeded = true;
+    for (name, hook) in hooks.into_iter().rev() {
+        debug!("Running shutdown hook: {name}");
+
+        if let Err(err) = hook().await {
+            error!("Shutdown hook {name} failed: {err}");
+            succeeded = false;
+        }
+    }
+
+    succeeded
+}
+
+///
+/// Run the shutdown hooks should anything panic, and then exit with
+/// [`Exit::Panic`]. Release builds abort on panic, so without this nothing would
+/// be saved, and we'd exit by way of SIGABRT rather than with that code.
+///
+/// This replaces the process wide panic hook (after running the previous one),
+/// so is left to the binary to install rather than done when starting.
+///
+#[coverage(off)]
+pub fn on_panic() {
+    let previous = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+
+        // The panic may well have been on one of the runtime's threads, which
+        // can't block on the hooks itself
+        let hooks = std::thread::spawn(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .is_ok_and(|runtime| {
+                    runtime.block_on(async {
+                        tokio::time::timeout(PANIC_TIMEOUT, run())
+                            .await
+                            .unwrap_or(false)
+                    })
+                })
+        });
+
+        if !hooks.join().unwrap_or(false) {
+            error!("Not everything could be saved after panicking");
+        }
+
+        std::process::exit(Exit::Panic.code().into());
+    }));
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use pretty_assertions::assert_eq;
+
+    use super::Exit;
+
+    #[tokio::test]
+    async fn hooks() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        for name in ["first", "second"] {
+            let order = Arc::clone(&order);
+            super::register(name, move || {
+                let order = Arc::clone(&order);
+                async move {
+                    order.lock().unwrap().push(name);
+                    if name == "first" {
+                        Err(String::from("Failed"))
+                    } else {
+                        Ok(())
+                    }
+                }
+            })
+            .await;
+        }
+
+        assert!(!super::run().await);
+        assert_eq!(*order.lock().unwrap(), ["second", "first"]);
+
+        // Hooks are only ever run once
+        assert!(super::run().await);
+        assert_eq!(order.lock().unwrap().len(), 2);
+        assert_eq!(Exit::Config.code(), 78);
+    }
+}
@@ -1,3 +1,15 @@
+//!
+//! A DNS filtering server.
+//!
+//! When embedding Blackhole, load the [`Config`] and then
+//! [`Blackhole::start`] it, or configure it with [`Blackhole::builder`]. The
+//! [`FilterHandle`] and [`StatsHandle`] it hands out are the supported ways of
+//! managing it while it runs.
+//!
+//! The config, filter and statistics are shared by the whole process, so only
+//! one instance can run in a process at a time. Embedding more than one
+//! instance isn't supported.
+//!
 #![allow(incomplete_features)]
 #![forbid(unsafe_code)]
 #![feature(
@@ -9,110 +21,350 @@
     type_alias_impl_trait
 )]
 
-use std::{
-    io,
-    net::{IpAddr, Ipv6Addr, ToSocketAddrs},
-    time::Duration,
-};
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
 
-use config::Config;
-use dns::Server;
+use dns::{Protocol, Server, Tcp};
 use hickory_server::ServerFuture;
 use schedule::Scheduler;
+use socket2::{Domain, Socket, Type};
+use statistics::Statistics;
 use tokio::{
     net::{TcpListener, UdpSocket},
     sync::watch::Receiver,
-    task::JoinHandle,
+    task::{JoinError, JoinHandle},
 };
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+pub(crate) mod anomaly;
+pub(crate) mod api;
+pub(crate) mod cache;
+pub(crate) mod clients;
+pub(crate) mod config;
+pub(crate) mod dns;
+pub(crate) mod exporter;
+pub(crate) mod filter;
+mod handle;
+pub(crate) mod health;
+pub(crate) mod logging;
+pub(crate) mod metrics;
+pub(crate) mod safesearch;
+pub(crate) mod schedule;
+pub(crate) mod shutdown;
+pub(crate) mod statistics;
+pub(crate) mod tasks;
+
+pub use config::{Config, Error as ConfigError};
+pub use filter::{rules::Kind, Custom};
+pub use handle::{
+    Blackhole, Builder, FilterHandle, Instance, ListStatus, LoggedRequest, RequestFilter,
+    StatsHandle, Subscription, Summary,
+};
+pub use shutdown::{on_panic, Exit};
+
+///
+/// What the binary (and the benchmarks) need beyond the embedding API. This
+/// isn't part of the public API, and can change in any release.
+///
+#[doc(hidden)]
+pub mod __private {
+    pub mod config {
+        pub use crate::config::{
+            AdGuard, Config, Environment, Error, Imported, Load, Overrides, Protocol,
+        };
+    }
+
+    pub mod filter {
+        pub use crate::filter::{Filter, List, Policy, Unmatched};
+
+        pub mod patterns {
+            pub use crate::filter::patterns::Patterns;
+        }
+
+        pub mod rules {
+            pub use crate::filter::rules::{Kind, Rule, Rules, Source, Type};
+        }
+    }
+
+    pub mod statistics {
+        pub use crate::statistics::Request;
+    }
+}
+
+/// How long to wait for the DNS server to stop, and the requests it was in the
+/// middle of answering to finish, before running the shutdown hooks regardless
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[coverage(off)]
+fn stopped(name: &str, result: Result<Exit, JoinError>) -> Exit {
+    match result {
+        Ok(exit) => {
+            if exit != Exit::Clean {
+                error!("{name} stopped unexpectedly");
+            }
+
+            exit
+        }
+        // Only reachable when embedded with panics that unwind, as the binary
+        // has its panic hook run the shutdown hooks and exit instead
+        Err(err) if err.is_panic() => {
+            error!("{name} panicked");
+            Exit::Panic
+        }
+        Err(err) => {
+            error!("{name} stopped unexpectedly: {err}");
+            Exit::Unavailable
+        }
+    }
+}
+
+///
+/// Bind a DNS server to each of the sockets, which all share the one handler.
+/// UDP is served by hickory, and TCP by us, so that connections can be limited.
+///
+#[coverage(off)]
+async fn serve(
+    sockets: Vec<(SocketAddr, Protocol)>,
+) -> Result<(ServerFuture<Server>, Tcp), io::Error> {
+    let mut server = ServerFuture::new(Server {});
+    let (limits, workers) =
+        Config::get(|config| (config.bind.tcp_limits(), config.bind.udp_workers)).await;
+    let mut tcp = Tcp::new(limits);
+    let handler = Arc::new(Server {});
+
+    for (address, protocol) in sockets {
+        let bound = match protocol {
+            Protocol::Udp if workers > 1 => udp_sockets(address, workers).map(|sockets| {
+                for socket in sockets {
+                    server.register_socket(socket);
+                }
+            }),
+            Protocol::Udp => UdpSocket::bind(address)
+                .await
+                .map(|socket| server.register_socket(socket)),
+            Protocol::Tcp => TcpListener::bind(address)
+                .await
+                .map(|listener| tcp.register(listener, handler.clone())),
+        };
+
+        if let Err(err) = bound {
+            error!("Failed to listen on {address} ({protocol}): {err}");
+            return Err(err);
+        }
+
+        info!("Running DNS server on {address} ({protocol})");
+    }
+
+    Ok((server, tcp))
+}
+
+///
+/// Bind the sockets to serve UDP on, each sharing the address, so that the
+/// kernel spreads queries across them
+///
+#[coverage(off)]
+fn udp_sockets(address: SocketAddr, workers: usize) -> Result<Vec<UdpSocket>, io::Error> {
+    (0..workers)
+        .map(|_| {
+            let socket = Socket::new(
+                Domain::for_address(address),
+                Type::DGRAM,
+                Some(socket2::Protocol::UDP),
+            )?;
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&address.into())?;
+
+            UdpSocket::from_std(socket.into())
+        })
+        .collect()
+}
+
+///
+/// Where the DNS server listens, as configured
+///
+#[coverage(off)]
+async fn sockets() -> Vec<(SocketAddr, Protocol)> {
+    Config::get(|config| config.bind.sockets(config.port, &config.listeners)).await
+}
+
+///
+/// Stop accepting queries, and give those already being answered a chance to
+/// finish, so that they're answered (and recorded) before the shutdown hooks run
+///
+#[coverage(off)]
+async fn drain(dns_server: JoinHandle<Exit>) {
+    let drained = tokio::time::timeout(DRAIN_TIMEOUT, async move {
+        if !dns_server.is_finished() {
+            if let Err(err) = dns_server.await {
+                error!("DNS Server failed while stopping: {err}");
+            }
+        }
+
+        dns::drained().await;
+    })
+    .await;
 
-pub mod api;
-pub mod cache;
-pub mod config;
-pub mod dns;
-pub mod filter;
-pub mod metrics;
-pub mod schedule;
-pub mod statistics;
+    if drained.is_err() {
+        warn!(
+            "Gave up waiting on {} request(s) still being answered",
+            dns::in_flight()
+        );
+    }
+}
 
 ///
 /// Spawn all servers, the API, and initialise the scheduler
 ///
+/// The returned handle resolves to the reason we stopped once the shutdown
+/// hooks have been run.
+///
 /// # Errors
 /// If there are issues during startup
 ///
 #[coverage(off)]
-pub async fn spawn(mut shutdown_signal: Receiver<bool>) -> Result<JoinHandle<()>, io::Error> {
-    let port = Config::get(|config| config.port).await;
+pub(crate) async fn spawn(
+    running: handle::Running,
+    mut shutdown_signal: Receiver<bool>,
+    serve_api: bool,
+) -> Result<JoinHandle<Exit>, io::Error> {
+    shutdown::register("config", || async {
+        Config::save().await.map_err(|err| err.to_string())
+    })
+    .await;
+
+    metrics::init(&Config::get(|config| config.metrics.clone()).await)
+        .map_err(|err| io::Error::new(io::ErrorKind::Interrupted, err.to_string()))?;
+    statistics::configure(&Config::get(|config| config.statistics.clone()).await);
+    exporter::configure(&Config::get(|config| config.exporter.clone()).await);
+    logging::configure(&Config::get(|config| config.logging.clone()).await);
+    dns::queue::configure(&Config::get(|config| config.queue).await);
 
-    metrics::init().map_err(|err| io::Error::new(io::ErrorKind::Interrupted, err.to_string()))?;
+    if let Some(snapshot) = Config::get(|config| config.statistics.snapshot.clone()).await {
+        match Statistics::restore(&snapshot) {
+            Ok(()) => info!("Restored statistics from {}", snapshot.display()),
+            // We've simply not saved any yet
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => error!("Unable to restore statistics from {}: {err}", snapshot.display()),
+        }
+    }
+
+    shutdown::register("statistics", || async {
+        match Config::get(|config| config.statistics.snapshot.clone()).await {
+            Some(snapshot) => Statistics::save(&snapshot).map_err(|err| err.to_string()),
+            None => Ok(()),
+        }
+    })
+    .await;
 
     let scheduler = tokio::spawn({
         async move {
             Scheduler::init(Config::get(|config| config.schedules.clone()).await).await;
+            Exit::Unavailable
         }
     });
 
-    let dns_server = {
-        let address = (IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
-        let mut server = ServerFuture::new(Server {});
-        match UdpSocket::bind(address).await {
-            Ok(socket) => {
-                server.register_socket(socket);
-            }
-            Err(err) => {
-                error!("Failed to bind udp socket: {err}");
-                return Err(err);
-            }
-        }
+    let mut dns_server = {
+        let (mut server, mut tcp) = serve(sockets().await).await?;
+        let mut shutdown_signal = shutdown_signal.clone();
 
-        match TcpListener::bind(address).await {
-            Ok(listener) => {
-                server.register_listener(listener, Duration::from_secs(30));
-            }
-            Err(err) => {
-                error!("Failed to bind tcp listener: {err}");
-                return Err(err);
-            }
-        }
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    result = server.block_until_done() => {
+                        if let Err(err) = result {
+                            error!("DNS Server failure: {err}");
+                        }
 
-        info!(
-            "Running DNS server on {:?}",
-            address
-                .to_socket_addrs()?
-                .next()
-                .ok_or_else(|| io::Error::new(
-                    io::ErrorKind::AddrNotAvailable,
-                    "Invalid DNS Server Address"
-                ))?
-        );
+                        return Exit::Unavailable;
+                    }
+                    _ = shutdown_signal.changed() => {
+                        if let Err(err) = server.shutdown_gracefully().await {
+                            error!("Failed to stop the DNS server: {err}");
+                        }
+                        tcp.shutdown().await;
 
-        tokio::spawn(async move {
-            if let Err(err) = server.block_until_done().await {
-                error!("DNS Server failure: {err}");
+                        return Exit::Clean;
+                    }
+                    () = dns::REBIND.notified() => {
+                        // Only stop serving on the old sockets once we know
+                        // we can serve on the new ones
+                        match serve(sockets().await).await {
+                            Ok((rebound, rebound_tcp)) => {
+                                if let Err(err) = server.shutdown_gracefully().await {
+                                    error!("Failed to stop the old DNS server: {err}");
+                                }
+                                std::mem::replace(&mut tcp, rebound_tcp).shutdown().await;
+
+                                server = rebound;
+                            }
+                            Err(err) => {
+                                error!("Unable to rebind, keeping the old sockets: {err}");
+                            }
+                        }
+                    }
+                }
             }
         })
     };
 
     let api_shutdown_signal = shutdown_signal.clone();
     let api = tokio::spawn(async move {
-        if let Err(err) = api::Server.run(api_shutdown_signal).await {
-            error!("API failure: {err}");
+        if !serve_api {
+            return std::future::pending().await;
         }
+
+        // The API only ever stops cleanly when we're shutting down
+        api::Server.run(api_shutdown_signal).await.map_or_else(
+            |err| {
+                error!("API failure: {err}");
+                Exit::Bind
+            },
+            |()| Exit::Clean,
+        )
     });
 
+    #[cfg(feature = "grpc")]
+    let grpc = {
+        let shutdown_signal = shutdown_signal.clone();
+        tokio::spawn(async move {
+            match Config::get(|config| config.api.grpc.clone()).await {
+                Some(options) if serve_api => {
+                    api::grpc::run(options, shutdown_signal).await.map_or_else(
+                        |err| {
+                            error!("gRPC API failure: {err}");
+                            Exit::Bind
+                        },
+                        |()| Exit::Clean,
+                    )
+                }
+                _ => std::future::pending().await,
+            }
+        })
+    };
+    #[cfg(not(feature = "grpc"))]
+    let grpc = std::future::pending::<Result<Exit, JoinError>>();
+
     Ok(tokio::spawn(async move {
-        tokio::select! {
-            _ = api => {}
-            _ = dns_server => {}
-            _ = scheduler => {}
-            _ = shutdown_signal.changed() => {}
+        let exit = tokio::select! {
+            result = api => stopped("API", result),
+            result = grpc => stopped("gRPC API", result),
+            result = &mut dns_server => stopped("DNS Server", result),
+            result = scheduler => stopped("Scheduler", result),
+            _ = shutdown_signal.changed() => Exit::Clean,
+        };
+
+        if exit == Exit::Clean {
+            drain(dns_server).await;
         }
 
-        Config::save().await.unwrap_or_else(|err| {
-            error!("{err}");
-        });
+        if !shutdown::run().await {
+            error!("Not everything could be saved while shutting down");
+        }
 
         drop(shutdown_signal);
+        drop(running);
+
+        exit
     }))
 }
@@ -0,0 +1,228 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+///
+/// The finest resolution history is kept at, in seconds
+///
+const RESOLUTION: u64 = 60;
+
+///
+/// How far back history is kept, in seconds
+///
+const RETENTION: u64 = 7 * 24 * 60 * 60;
+
+///
+/// The most buckets a single query will return, past which the interval is
+/// widened to fit
+///
+const MAX_BUCKETS: u64 = 2048;
+
+const fn default_interval() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
+const fn default_window() -> Duration {
+    Duration::from_hours(24)
+}
+
+///
+/// Which part of the history to show, and how finely
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, Serialize))]
+#[derive(Deserialize, Clone)]
+pub struct Span {
+    /// How long each bucket covers, rounded up to the nearest minute
+    #[serde(with = "humantime_serde", default = "default_interval")]
+    pub interval: Duration,
+    /// How far back to go
+    #[serde(with = "humantime_serde", default = "default_window")]
+    pub window: Duration,
+}
+
+impl Default for Span {
+    fn default() -> Self {
+        Self {
+            interval: default_interval(),
+            window: default_window(),
+        }
+    }
+}
+
+///
+/// The number of requests (and how many of those were blocked) made within
+/// the interval starting at `start`, in seconds since the epoch
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq, Deserialize))]
+#[derive(Serialize, Clone, Copy, Default)]
+pub struct Bucket {
+    pub start: u64,
+    pub requests: usize,
+    pub blocked: usize,
+}
+
+///
+/// Request counts over time, kept a minute at a time as requests are recorded
+/// so that they don't need to be worked out from the request log
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Default)]
+pub struct History {
+    /// Oldest first, without any gaps filled in
+    buckets: VecDeque<Bucket>,
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+impl History {
+    pub fn record(&mut self, timestamp: SystemTime, blocked: bool) {
+        let start = seconds(timestamp) / RESOLUTION * RESOLUTION;
+
+        // Requests are recorded as they complete, so they can arrive slightly
+        // out of order, though almost always belong in the most recent bucket
+        let index = self
+            .buckets
+            .iter()
+            .rposition(|bucket| bucket.start <= start);
+
+        let bucket = match index {
+            Some(index) if self.buckets[index].start == start => &mut self.buckets[index],
+            index => {
+                let index = index.map_or(0, |index| index + 1);
+                self.buckets.insert(
+                    index,
+                    Bucket {
+                        start,
+                        ..Default::default()
+                    },
+                );
+                &mut self.buckets[index]
+            }
+        };
+
+        bucket.requests += 1;
+        bucket.blocked += usize::from(blocked);
+
+        if let Some(newest) = self.buckets.back().map(|bucket| bucket.start) {
+            while self
+                .buckets
+                .front()
+                .is_some_and(|bucket| bucket.start + RETENTION < newest)
+            {
+                self.buckets.pop_front();
+            }
+        }
+    }
+
+    ///
+    /// The history within the window, oldest first, including those intervals
+    /// without any requests
+    ///
+    pub fn query(&self, span: &Span, now: SystemTime) -> Vec<Bucket> {
+        let now = seconds(now);
+        let window = span.window.as_secs().min(RETENTION);
+
+        let interval = span
+            .interval
+            .as_secs()
+            .max(window.div_ceil(MAX_BUCKETS))
+            .div_ceil(RESOLUTION)
+            .max(1)
+            * RESOLUTION;
+
+        let first = now.saturating_sub(window) / interval * interval;
+        let mut buckets = (first..=now)
+            .step_by(usize::try_from(interval).unwrap_or(usize::MAX))
+            .map(|start| Bucket {
+                start,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        for bucket in self.buckets.iter().skip_while(|bucket| bucket.start < first) {
+            let Some(into) = usize::try_from((bucket.start - first) / interval)
+                .ok()
+                .and_then(|index| buckets.get_mut(index))
+            else {
+                break;
+            };
+
+            into.requests += bucket.requests;
+            into.blocked += bucket.blocked;
+        }
+
+        buckets
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use pretty_assertions::assert_eq;
+
+    use super::{Bucket, History, Span, RETENTION};
+
+    #[test]
+    fn history() {
+        let at = |seconds: u64| UNIX_EPOCH + Duration::from_secs(seconds);
+        let mut history = History::default();
+
+        history.record(at(3600), false);
+        history.record(at(3659), true);
+        history.record(at(3720), false);
+        // Out of order, but still in an existing minute
+        history.record(at(3630), false);
+        // Out of order, and in a minute we haven't seen yet
+        history.record(at(3000), true);
+
+        assert_eq!(
+            history
+                .buckets
+                .iter()
+                .map(|bucket| (bucket.start, bucket.requests, bucket.blocked))
+                .collect::<Vec<_>>(),
+            [(3000, 1, 1), (3600, 3, 1), (3720, 1, 0)]
+        );
+
+        let span = Span {
+            interval: Duration::from_secs(10 * 60),
+            window: Duration::from_secs(30 * 60),
+        };
+        assert_eq!(
+            history.query(&span, at(4000)),
+            [
+                Bucket {
+                    start: 1800,
+                    requests: 0,
+                    blocked: 0,
+                },
+                Bucket {
+                    start: 2400,
+                    requests: 0,
+                    blocked: 0,
+                },
+                Bucket {
+                    start: 3000,
+                    requests: 1,
+                    blocked: 1,
+                },
+                Bucket {
+                    start: 3600,
+                    requests: 4,
+                    blocked: 1,
+                },
+            ]
+        );
+
+        // Anything older than we keep is dropped as newer requests come in
+        history.record(at(3720 + RETENTION), false);
+        assert_eq!(history.buckets.front().map(|bucket| bucket.start), Some(3720));
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::Request;
+use crate::metrics;
+
+/// How long (in seconds) each slot of the window covers
+const SLOT: u64 = 60;
+
+/// How many slots percentiles are taken over
+const SLOTS: u64 = 5;
+
+/// Each power of two is split into 2^PRECISION buckets, which keeps
+/// percentiles within ~6% of the actual value
+const PRECISION: u32 = 4;
+
+const QUANTILES: [(&str, f64); 3] = [("0.5", 0.5), ("0.9", 0.9), ("0.99", 0.99)];
+
+///
+/// How the request was answered
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Cached,
+    Blocked,
+    Forwarded,
+}
+
+impl Outcome {
+    const ALL: [Self; 3] = [Self::Cached, Self::Blocked, Self::Forwarded];
+
+    fn of(request: &Request) -> Self {
+        if request.blocked() {
+            Self::Blocked
+        } else if request.cached {
+            Self::Cached
+        } else {
+            Self::Forwarded
+        }
+    }
+
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Cached => "cached",
+            Self::Blocked => "blocked",
+            Self::Forwarded => "forwarded",
+        }
+    }
+}
+
+///
+/// How long requests took to answer (in nanoseconds), at the 50th, 90th and
+/// 99th percentiles
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq))]
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub struct Percentiles {
+    pub p50: usize,
+    pub p90: usize,
+    pub p99: usize,
+}
+
+///
+/// The percentiles over the last few minutes, overall and for each outcome
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq))]
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Latencies {
+    pub all: Percentiles,
+    pub cached: Percentiles,
+    pub blocked: Percentiles,
+    pub forwarded: Percentiles,
+}
+
+///
+/// A log-linear histogram, so that it takes the same (small) amount of memory
+/// however many requests it has seen
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Default)]
+struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+const fn index(value: u64) -> usize {
+    if value < 1 << PRECISION {
+        return value as usize;
+    }
+
+    let power = u64::BITS - 1 - value.leading_zeros();
+    let mantissa = (value >> (power - PRECISION)) & ((1 << PRECISION) - 1);
+
+    (((power - PRECISION + 1) as usize) << PRECISION) | mantissa as usize
+}
+
+///
+/// The middle of the values that fall into the bucket
+///
+const fn value(index: usize) -> u64 {
+    if index < 1 << PRECISION {
+        return index as u64;
+    }
+
+    let power = (index >> PRECISION) as u32 + PRECISION - 1;
+    let mantissa = (index & ((1 << PRECISION) - 1)) as u64;
+    let width = 1 << (power - PRECISION);
+
+    (((1 << PRECISION) | mantissa) << (power - PRECISION)) + width / 2
+}
+
+impl Histogram {
+    fn record(&mut self, value: u64) {
+        let index = index(value);
+        if self.counts.len() <= index {
+            self.counts.resize(index + 1, 0);
+        }
+
+        self.counts[index] += 1;
+        self.total += 1;
+    }
+
+    fn merge(&mut self, other: &Self) {
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.total += other.total;
+    }
+
+    fn quantile(&self, quantile: f64) -> u64 {
+        let target = ((self.total as f64 * quantile).ceil() as u64).max(1);
+
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return value(index);
+            }
+        }
+
+        0
+    }
+
+    fn percentiles(&self) -> Percentiles {
+        let [p50, p90, p99] = QUANTILES.map(|(_, quantile)| self.quantile(quantile) as usize);
+
+        Percentiles { p50, p90, p99 }
+    }
+}
+
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Default)]
+struct Slot {
+    start: u64,
+    /// One for each outcome, in the same order as `Outcome::ALL`
+    histograms: [Histogram; 3],
+}
+
+///
+/// How long requests have taken to answer over the last few minutes, kept a
+/// minute at a time so that older requests can be dropped as the window moves
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, Default)]
+pub struct Latency {
+    /// Oldest first
+    slots: VecDeque<Slot>,
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+impl Latency {
+    pub fn record(&mut self, request: &Request) {
+        let start = seconds(request.timestamp) / SLOT * SLOT;
+
+        // Requests are recorded as they complete, so they can arrive slightly
+        // out of order, though almost always belong in the most recent slot
+        let index = self.slots.iter().rposition(|slot| slot.start <= start);
+        let slot = match index {
+            Some(index) if self.slots[index].start == start => &mut self.slots[index],
+            index => {
+                let index = index.map_or(0, |index| index + 1);
+                self.slots.insert(
+                    index,
+                    Slot {
+                        start,
+                        ..Default::default()
+                    },
+                );
+                &mut self.slots[index]
+            }
+        };
+
+        let outcome = Outcome::of(request);
+        slot.histograms[outcome as usize].record(request.elapsed as u64);
+
+        if let Some(newest) = self.slots.back().map(|slot| slot.start) {
+            let cutoff = newest.saturating_sub(SLOT * SLOTS);
+            self.slots.retain(|slot| slot.start > cutoff);
+        }
+    }
+
+    ///
+    /// The percentiles over the window leading up to `now`
+    ///
+    pub fn percentiles(&self, now: SystemTime) -> Latencies {
+        let cutoff = seconds(now).saturating_sub(SLOT * SLOTS);
+
+        let mut histograms: [Histogram; 3] = Default::default();
+        for slot in self.slots.iter().filter(|slot| slot.start > cutoff) {
+            for (histogram, other) in histograms.iter_mut().zip(&slot.histograms) {
+                histogram.merge(other);
+            }
+        }
+
+        let mut all = Histogram::default();
+        for histogram in &histograms {
+            all.merge(histogram);
+        }
+
+        let [cached, blocked, forwarded] = histograms.each_ref().map(Histogram::percentiles);
+
+        Latencies {
+            all: all.percentiles(),
+            cached,
+            blocked,
+            forwarded,
+        }
+    }
+
+    ///
+    /// Bring the latency metrics up to date
+    ///
+    pub fn record_metrics(&self, now: SystemTime) {
+        let latencies = self.percentiles(now);
+
+        for outcome in Outcome::ALL {
+            let Percentiles { p50, p90, p99 } = match outcome {
+                Outcome::Cached => latencies.cached,
+                Outcome::Blocked => latencies.blocked,
+                Outcome::Forwarded => latencies.forwarded,
+            };
+
+            for ((quantile, _), value) in QUANTILES.into_iter().zip([p50, p90, p99]) {
+                metrics::LATENCY
+                    .get_or_create(&metrics::Quantile {
+                        outcome: String::from(outcome.as_str()),
+                        quantile: String::from(quantile),
+                    })
+                    .set(value as i64);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use pretty_assertions::assert_eq;
+
+    use super::{Histogram, Latency, Percentiles, SLOT, SLOTS};
+    use crate::{
+        filter::rules::{Kind, Rule},
+        statistics::Request,
+    };
+
+    #[test]
+    fn buckets() {
+        for value in [0, 1, 15, 16, 17, 1_000, 123_456_789, u64::MAX / 2] {
+            let estimate = super::value(super::index(value));
+            assert!(
+                estimate.abs_diff(value) <= value / 16,
+                "{value} was estimated as {estimate}"
+            );
+        }
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut histogram = Histogram::default();
+        for value in 1..=1000 {
+            histogram.record(value * 1_000);
+        }
+
+        let Percentiles { p50, p90, p99 } = histogram.percentiles();
+        assert!((470_000..=530_000).contains(&p50), "{p50}");
+        assert!((850_000..=950_000).contains(&p90), "{p90}");
+        assert!((940_000..=1_050_000).contains(&p99), "{p99}");
+        assert_eq!(Histogram::default().percentiles(), Percentiles::default());
+    }
+
+    #[test]
+    fn outcomes() {
+        let now = SystemTime::now();
+        let mut latency = Latency::default();
+
+        for (elapsed, cached, blocked) in [(1_000, true, false), (2_000, false, true)] {
+            latency.record(&Request {
+                elapsed,
+                cached,
+                rule: blocked.then(|| Rule {
+                    domain: String::from("ads.example.com"),
+                    kind: Kind::Deny,
+                    action: None,
+                    list: None,
+                    zone: false,
+                    important: false,
+                    scope: None,
+                }),
+                timestamp: now,
+                ..Default::default()
+            });
+        }
+
+        // Too long ago to be in the window
+        latency.record(&Request {
+            elapsed: 1_000_000_000,
+            timestamp: now - Duration::from_secs(SLOT * (SLOTS + 1)),
+            ..Default::default()
+        });
+
+        let latencies = latency.percentiles(now);
+        assert!(latencies.cached.p50.abs_diff(1_000) <= 64);
+        assert!(latencies.blocked.p50.abs_diff(2_000) <= 128);
+        assert_eq!(latencies.forwarded, Percentiles::default());
+        assert!(latencies.all.p99.abs_diff(2_000) <= 128);
+    }
+}
@@ -0,0 +1,336 @@
+#[cfg(any(debug_assertions, test))]
+use std::fmt::Debug;
+
+use std::{
+    collections::VecDeque,
+    mem::size_of,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use hickory_proto::rr::Record;
+use serde::{Serialize, Serializer};
+use tracing::error;
+
+use super::Request;
+use crate::filter::rules::Rule;
+
+static COMPRESS: AtomicBool = AtomicBool::new(false);
+
+/// The most requests to keep, or 0 for no limit
+static MAX_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Roughly the most memory (in bytes) to use, or 0 for no limit
+static MAX_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+///
+/// How many requests are compressed together. Larger blocks compress better,
+/// but more has to be decompressed to read any one request.
+///
+const BLOCK_SIZE: usize = 512;
+
+///
+/// Compress the request log from now on (or stop compressing it)
+///
+#[inline]
+pub fn compress(enabled: bool) {
+    COMPRESS.store(enabled, Ordering::Relaxed);
+}
+
+///
+/// Limit how large the log can grow, dropping the oldest requests to make room
+///
+#[inline]
+pub fn limit(requests: Option<usize>, memory: Option<usize>) {
+    MAX_REQUESTS.store(requests.unwrap_or_default(), Ordering::Relaxed);
+    MAX_MEMORY.store(memory.unwrap_or_default(), Ordering::Relaxed);
+}
+
+///
+/// Roughly how much memory the request takes up
+///
+fn size(request: &Request) -> usize {
+    size_of::<Request>()
+        + request.client.capacity()
+        + request.name.as_ref().map_or(0, String::capacity)
+        + request.mac.as_ref().map_or(0, String::capacity)
+        + request.question.capacity()
+        + request.answers.capacity() * size_of::<Record>()
+        + request
+            .rule
+            .as_ref()
+            .map_or(0, |rule| size_of::<Rule>() + rule.domain().len())
+        + request.status.capacity()
+        + request.protocol.capacity()
+}
+
+///
+/// A block of requests, serialised and then compressed
+///
+#[derive(Clone)]
+struct Block {
+    len: usize,
+    data: Arc<[u8]>,
+}
+
+impl Block {
+    fn compress(requests: &[Request]) -> Option<Self> {
+        match serde_json::to_vec(requests) {
+            Ok(data) => Some(Self {
+                len: requests.len(),
+                data: lz4_flex::compress_prepend_size(&data).into(),
+            }),
+            Err(err) => {
+                error!("Unable to compress requests: {err}");
+                None
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        size_of::<Self>() + self.data.len()
+    }
+
+    fn decompress(&self) -> Vec<Request> {
+        lz4_flex::decompress_size_prepended(&self.data)
+            .map_err(|err| err.to_string())
+            .and_then(|data| serde_json::from_slice(&data).map_err(|err| err.to_string()))
+            .unwrap_or_else(|err| {
+                error!("Unable to decompress requests: {err}");
+                Vec::new()
+            })
+    }
+}
+
+///
+/// The request log, oldest first.
+///
+/// When compression is enabled, requests are kept as they are until there are
+/// enough of them to fill a block, at which point they're compressed together.
+/// Reading the log decompresses blocks as they're reached, so reading the most
+/// recent requests doesn't require decompressing everything.
+///
+/// Should the log be limited in size, the oldest requests are dropped as new
+/// ones come in (a whole block at a time, once compressed), rather than
+/// waiting for the Logs schedule to prune them.
+///
+#[derive(Clone, Default)]
+pub struct Log {
+    blocks: VecDeque<Block>,
+    recent: VecDeque<Request>,
+    /// Roughly how much memory the requests take up
+    bytes: usize,
+}
+
+impl Log {
+    fn with(requests: Vec<Request>, compress: bool) -> Self {
+        let mut log = Self {
+            blocks: VecDeque::new(),
+            bytes: requests.iter().map(size).sum(),
+            recent: requests.into(),
+        };
+
+        if compress {
+            log.seal();
+        }
+
+        log.evict(
+            MAX_REQUESTS.load(Ordering::Relaxed),
+            MAX_MEMORY.load(Ordering::Relaxed),
+        );
+
+        log
+    }
+
+    pub fn push(&mut self, request: Request) {
+        self.bytes += size(&request);
+        self.recent.push_back(request);
+
+        if self.recent.len() >= BLOCK_SIZE && COMPRESS.load(Ordering::Relaxed) {
+            self.seal();
+        }
+
+        self.evict(
+            MAX_REQUESTS.load(Ordering::Relaxed),
+            MAX_MEMORY.load(Ordering::Relaxed),
+        );
+    }
+
+    ///
+    /// Compress as many full blocks of the recent requests as there are
+    ///
+    fn seal(&mut self) {
+        let sealed = self.recent.len() - self.recent.len() % BLOCK_SIZE;
+        let mut drained = 0;
+
+        for requests in self.recent.make_contiguous()[..sealed].chunks(BLOCK_SIZE) {
+            let Some(block) = Block::compress(requests) else {
+                break;
+            };
+
+            self.bytes = (self.bytes + block.size())
+                .saturating_sub(requests.iter().map(size).sum::<usize>());
+            self.blocks.push_back(block);
+            drained += requests.len();
+        }
+
+        self.recent.drain(..drained);
+    }
+
+    ///
+    /// Drop the oldest requests until the log is within the limits (0 being no
+    /// limit)
+    ///
+    fn evict(&mut self, requests: usize, memory: usize) {
+        while (requests > 0 && self.len() > requests) || (memory > 0 && self.bytes > memory) {
+            if let Some(block) = self.blocks.pop_front() {
+                self.bytes = self.bytes.saturating_sub(block.size());
+            } else if let Some(request) = self.recent.pop_front() {
+                self.bytes = self.bytes.saturating_sub(size(&request));
+            } else {
+                break;
+            }
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.blocks.iter().map(|block| block.len).sum::<usize>() + self.recent.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    ///
+    /// Every request in the log, oldest first
+    ///
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = Request> + '_ {
+        self.blocks
+            .iter()
+            .flat_map(Block::decompress)
+            .chain(self.recent.iter().cloned())
+    }
+
+    pub fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&Request) -> bool,
+    {
+        *self = self.iter().filter(f).collect();
+    }
+}
+
+impl FromIterator<Request> for Log {
+    fn from_iter<T: IntoIterator<Item = Request>>(iter: T) -> Self {
+        Self::with(iter.into_iter().collect(), COMPRESS.load(Ordering::Relaxed))
+    }
+}
+
+impl From<Vec<Request>> for Log {
+    #[inline]
+    fn from(requests: Vec<Request>) -> Self {
+        requests.into_iter().collect()
+    }
+}
+
+impl Extend<Request> for Log {
+    fn extend<T: IntoIterator<Item = Request>>(&mut self, iter: T) {
+        for request in iter {
+            self.push(request);
+        }
+    }
+}
+
+impl Serialize for Log {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+#[cfg(any(debug_assertions, test))]
+impl<'de> serde::Deserialize<'de> for Log {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<Request>::deserialize(deserializer).map(Self::from)
+    }
+}
+
+#[cfg(any(debug_assertions, test))]
+impl Debug for Log {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(any(debug_assertions, test))]
+impl PartialEq for Log {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+#[cfg(any(debug_assertions, test))]
+impl Eq for Log {}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use pretty_assertions::assert_eq;
+
+    use super::{Log, BLOCK_SIZE};
+    use crate::statistics::Request;
+
+    #[test]
+    fn compression() {
+        let start = SystemTime::now();
+        let requests = (0..BLOCK_SIZE * 2 + 10)
+            .map(|n| Request {
+                question: format!("{n}.example.com."),
+                timestamp: start + Duration::from_secs(n as u64),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        let mut log = Log::with(requests.clone(), true);
+
+        assert_eq!(log.blocks.len(), 2);
+        assert_eq!(log.recent.len(), 10);
+        assert_eq!(log.len(), requests.len());
+        assert_eq!(log.iter().collect::<Vec<_>>(), requests);
+        assert_eq!(
+            log.iter().next_back().map(|request| request.question),
+            Some(format!("{}.example.com.", BLOCK_SIZE * 2 + 9))
+        );
+
+        log.retain(|request| request.timestamp >= start + Duration::from_secs(BLOCK_SIZE as u64));
+        assert_eq!(log.iter().collect::<Vec<_>>(), requests[BLOCK_SIZE..]);
+    }
+
+    #[test]
+    fn limits() {
+        let requests = (0..BLOCK_SIZE + 10)
+            .map(|n| Request {
+                question: format!("{n}.example.com."),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        let mut log = Log::with(requests.clone(), false);
+        log.evict(100, 0);
+        assert_eq!(log.len(), 100);
+        assert_eq!(log.iter().collect::<Vec<_>>(), requests[BLOCK_SIZE - 90..]);
+
+        let bytes = log.bytes;
+        log.evict(0, bytes / 2);
+        assert!(log.bytes <= bytes / 2);
+        assert!((1..100).contains(&log.len()));
+
+        // Once compressed, whole blocks are dropped at a time
+        let mut log = Log::with(requests, true);
+        log.evict(BLOCK_SIZE, 0);
+        assert_eq!(log.len(), 10);
+    }
+}
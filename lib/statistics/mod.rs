@@ -2,27 +2,126 @@
 use std::fmt::Debug;
 
 use std::{
-    sync::{LazyLock, RwLock},
-    time::SystemTime,
+    io,
+    path::{Path, PathBuf},
+    sync::{LazyLock, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use ahash::AHashMap;
 use hickory_proto::rr::{Record, RecordType};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
 use tracing::{debug, instrument};
 
 use crate::{
+    exporter,
     filter::rules::{Kind, Rule},
-    metrics,
+    health::Health,
+    logging, metrics,
 };
 
+pub use history::{Bucket, Span};
+pub use latency::Latencies;
+pub use log::Log;
+pub use privacy::Privacy;
+
+use history::History;
+use latency::Latency;
+
+mod history;
+mod latency;
+mod log;
+mod pending;
+mod privacy;
+
 static STATISTICS: LazyLock<RwLock<Statistics>> = LazyLock::new(RwLock::default);
 
+/// How many statistics can be waiting before they're added to the totals,
+/// rather than waiting for them to next be read
+const FLUSH: usize = 256;
+static STREAM: LazyLock<broadcast::Sender<Request>> = LazyLock::new(|| broadcast::channel(1024).0);
+
 pub const REQUESTS: &str = "requests";
 pub const AVERAGE_REQUEST_TIME: &str = "average";
 pub const CACHE: &str = "cache";
+pub const PROTOCOLS: &str = "protocols";
+pub const BLOCKED: &str = "blocked";
+
+///
+/// Options for how statistics are kept
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    /// Keep the request log compressed in memory, at the cost of some CPU
+    /// whenever it's read
+    #[serde(default)]
+    pub compress: bool,
+    /// Where to save the running totals, so that they survive restarts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<PathBuf>,
+    #[serde(default)]
+    pub privacy: Privacy,
+    /// Clients whose requests are never kept (whatever the privacy level),
+    /// only counted in the running totals
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unlogged: Vec<IpNet>,
+    /// The most requests to keep in the request log, dropping the oldest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_requests: Option<usize>,
+    /// Roughly the most memory (in bytes) the request log can take up,
+    /// dropping the oldest requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_memory: Option<usize>,
+}
+
+///
+/// Apply any changes to the statistics options
+///
+#[inline]
+pub fn configure(options: &Options) {
+    log::compress(options.compress);
+    log::limit(options.max_requests, options.max_memory);
+    privacy::set(options.privacy, &options.unlogged);
+}
 
 impl Statistic {
+    ///
+    /// Count the request towards the running totals, without keeping it
+    ///
+    fn record_totals(request: &Request, stats: &mut AHashMap<&'static str, Self>) {
+        Self::Protocols(AHashMap::from_iter([(
+            request.protocol.clone(),
+            Average {
+                count: 1,
+                average: request.elapsed,
+                latency: None,
+            },
+        )]))
+        .record(stats);
+
+        if request.blocked() {
+            match stats.entry(BLOCKED).or_insert(Self::Count(0)) {
+                Self::Count(blocked) => *blocked += 1,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    fn record_request(request: Request, stats: &mut AHashMap<&'static str, Self>) {
+        Self::record_totals(&request, stats);
+
+        match stats
+            .entry(REQUESTS)
+            .or_insert_with(|| Self::Requests(Log::default()))
+        {
+            Self::Requests(r) => r.push(request),
+            _ => unreachable!(),
+        }
+    }
+
     fn record(self, stats: &mut AHashMap<&'static str, Self>) {
         match self {
             Self::Cache(cache) => match stats
@@ -30,15 +129,28 @@ impl Statistic {
                 .or_insert_with(|| Self::Cache(Cache::default()))
             {
                 Self::Cache(exists) => {
-                    metrics::CACHE
-                        .get_or_create(&metrics::Cache {
-                            hit: (cache.hits > 0).to_string(),
-                        })
-                        .inc();
+                    for (hit, count) in [(true, cache.hits), (false, cache.misses)] {
+                        if count > 0 {
+                            metrics::CACHE
+                                .get_or_create(&metrics::Cache {
+                                    hit: hit.to_string(),
+                                })
+                                .inc_by(count as u64);
+                        }
+                    }
+                    metrics::CACHE_EVICTIONS.inc_by(cache.evictions as u64);
+                    metrics::CACHE_EXPIRATIONS.inc_by(cache.expirations as u64);
+                    metrics::CACHE_ENTRIES.set(cache.entries as i64);
+                    metrics::CACHE_SIZE.set(cache.size as i64);
 
                     exists.hits += cache.hits;
                     exists.misses += cache.misses;
-                    exists.size += cache.size;
+                    exists.evictions += cache.evictions;
+                    exists.expirations += cache.expirations;
+                    // These are what the cache is like now, rather than what
+                    // has changed
+                    exists.size = cache.size;
+                    exists.entries = cache.entries;
                 }
                 _ => unreachable!(),
             },
@@ -55,64 +167,41 @@ impl Statistic {
                     .or_insert_with(|| Self::Average(Average::default()))
                 {
                     Self::Average(av) => {
-                        let count = av.count + average.count;
-                        av.average =
-                            (av.average * av.count + average.count * average.average) / count;
-                        av.count = count;
+                        av.add(&average);
 
                         metrics::DURATION.observe(average.average as f64);
                     }
                     _ => unreachable!(),
                 }
             }
-            Self::Request(request) => match stats
-                .entry(REQUESTS)
-                .or_insert_with(|| Self::Requests(Vec::with_capacity(128)))
+            Self::Request(request) => Self::record_request(*request, stats),
+            Self::Protocols(protocols) => match stats
+                .entry(PROTOCOLS)
+                .or_insert_with(|| Self::Protocols(AHashMap::default()))
             {
-                Self::Requests(r) => {
-                    metrics::REQUESTS
-                        .get_or_create(&metrics::Request {
-                            client: request.client.clone(),
-                            question: request.question.clone(),
-                            r#type: request.query_type.to_string(),
-                            rule: request
-                                .rule
-                                .as_ref()
-                                .map_or_else(|| String::from("None"), |rule| rule.kind.to_string()),
-                        })
-                        .inc();
-
-                    if request
-                        .rule
-                        .as_ref()
-                        .map_or(false, |rule| rule.kind == Kind::Deny)
-                    {
-                        metrics::BLOCKED.inc();
-                    }
+                Self::Protocols(existing) => {
+                    for (protocol, average) in protocols {
+                        let label = metrics::Protocol {
+                            protocol: protocol.clone(),
+                        };
+                        metrics::PROTOCOL_REQUESTS
+                            .get_or_create(&label)
+                            .inc_by(average.count as u64);
+                        metrics::PROTOCOL_DURATION
+                            .get_or_create(&label)
+                            .observe(average.average as f64);
 
-                    r.push(request);
+                        existing.entry(protocol).or_default().add(&average);
+                    }
                 }
                 _ => unreachable!(),
             },
             Self::Requests(requests) => match stats
                 .entry(REQUESTS)
-                .or_insert_with(|| Self::Requests(Vec::with_capacity(128)))
+                .or_insert_with(|| Self::Requests(Log::default()))
             {
                 Self::Requests(r) => {
-                    for request in &requests {
-                        metrics::REQUESTS
-                            .get_or_create(&metrics::Request {
-                                client: request.client.clone(),
-                                question: request.question.clone(),
-                                r#type: request.query_type.to_string(),
-                                rule: request.rule.as_ref().map_or_else(
-                                    || String::from("None"),
-                                    |rule| rule.kind.to_string(),
-                                ),
-                            })
-                            .inc();
-                    }
-                    r.extend(requests);
+                    r.extend(requests.iter().inspect(Request::record_metrics));
                 }
                 _ => unreachable!(),
             },
@@ -120,33 +209,107 @@ impl Statistic {
     }
 }
 
-#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq, Deserialize))]
-#[derive(Serialize, Clone, Default)]
+#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq))]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Average {
     pub count: usize,
     pub average: usize,
+    /// Only included when retrieving the average request time, as the
+    /// percentiles are kept separately
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency: Option<Latencies>,
 }
 
-#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq, Deserialize))]
-#[derive(Serialize, Clone, Default)]
+impl Average {
+    fn add(&mut self, other: &Self) {
+        let count = self.count + other.count;
+        if let Some(average) =
+            (self.average * self.count + other.count * other.average).checked_div(count)
+        {
+            self.average = average;
+        }
+        self.count = count;
+    }
+}
+
+#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq))]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Cache {
+    /// Roughly how much memory (in bytes) the cache currently takes up
     pub size: usize,
+    /// How many responses are currently cached
+    #[serde(default)]
+    pub entries: usize,
     pub hits: usize,
     pub misses: usize,
+    /// How many responses were forgotten to make room for others
+    #[serde(default)]
+    pub evictions: usize,
+    /// How many responses were forgotten as they'd expired
+    #[serde(default)]
+    pub expirations: usize,
+}
+
+///
+/// How long each stage of answering a request took, in nanoseconds. Stages
+/// that weren't reached (e.g. upstream, for requests answered from the cache)
+/// are left out.
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq))]
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub struct Timings {
+    /// Checking the filter (and anything else that could answer locally)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<usize>,
+    /// Looking the request up in the cache
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<usize>,
+    /// Forwarding the request to the upstreams
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<usize>,
+}
+
+impl Timings {
+    fn record_metrics(self) {
+        for (stage, elapsed) in [
+            ("filter", self.filter),
+            ("cache", self.cache),
+            ("upstream", self.upstream),
+        ] {
+            if let Some(elapsed) = elapsed {
+                metrics::STAGE_DURATION
+                    .get_or_create(&metrics::Stage {
+                        stage: String::from(stage),
+                    })
+                    .observe(elapsed as f64);
+            }
+        }
+    }
 }
 
 #[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq))]
 #[derive(Serialize, Clone, Deserialize)]
 pub struct Request {
     pub client: String,
+    /// The client's friendly name, should it have one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The client's hardware address, should its DHCP lease give it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
     pub question: String,
     pub query_type: RecordType,
     pub answers: Vec<Record>,
     pub rule: Option<Rule>,
     pub status: String,
     pub elapsed: usize,
+    /// Where the time went
+    #[serde(default)]
+    pub timings: Timings,
     pub timestamp: SystemTime,
     pub cached: bool,
+    #[serde(default)]
+    pub protocol: String,
 }
 
 #[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq, Deserialize))]
@@ -154,36 +317,445 @@ pub struct Request {
 pub enum Statistic {
     Count(usize),
     Average(Average),
-    Request(Request),
-    Requests(Vec<Request>),
+    Request(Box<Request>),
+    Requests(Log),
     Cache(Cache),
+    Protocols(AHashMap<String, Average>),
+}
+
+///
+/// The running totals, as saved to disk so that they survive restarts. The
+/// request log itself isn't included, nor is the size of the cache, as it
+/// starts out empty.
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq))]
+#[derive(Serialize, Deserialize, Default)]
+struct Snapshot {
+    #[serde(default)]
+    average: Average,
+    #[serde(default)]
+    cache: Cache,
+    #[serde(default)]
+    protocols: AHashMap<String, Average>,
+    #[serde(default)]
+    blocked: usize,
+}
+
+impl Snapshot {
+    fn take(stats: &AHashMap<&'static str, Statistic>) -> Self {
+        let mut snapshot = Self::default();
+
+        if let Some(Statistic::Average(average)) = stats.get(AVERAGE_REQUEST_TIME) {
+            snapshot.average = average.clone();
+        }
+
+        if let Some(Statistic::Cache(cache)) = stats.get(CACHE) {
+            snapshot.cache = Cache {
+                size: 0,
+                entries: 0,
+                ..cache.clone()
+            };
+        }
+
+        if let Some(Statistic::Protocols(protocols)) = stats.get(PROTOCOLS) {
+            snapshot.protocols = protocols.clone();
+        }
+
+        if let Some(Statistic::Count(blocked)) = stats.get(BLOCKED) {
+            snapshot.blocked = *blocked;
+        }
+
+        snapshot
+    }
+
+    ///
+    /// Add the totals to whatever has been recorded since we started. This
+    /// bypasses `Statistic::record`, as the metrics only cover this run.
+    ///
+    fn restore(self, stats: &mut AHashMap<&'static str, Statistic>) {
+        match stats
+            .entry(AVERAGE_REQUEST_TIME)
+            .or_insert_with(|| Statistic::Average(Average::default()))
+        {
+            Statistic::Average(average) => average.add(&self.average),
+            _ => unreachable!(),
+        }
+
+        match stats
+            .entry(CACHE)
+            .or_insert_with(|| Statistic::Cache(Cache::default()))
+        {
+            Statistic::Cache(cache) => {
+                cache.hits += self.cache.hits;
+                cache.misses += self.cache.misses;
+                cache.evictions += self.cache.evictions;
+                cache.expirations += self.cache.expirations;
+            }
+            _ => unreachable!(),
+        }
+
+        match stats
+            .entry(PROTOCOLS)
+            .or_insert_with(|| Statistic::Protocols(AHashMap::default()))
+        {
+            Statistic::Protocols(protocols) => {
+                for (protocol, average) in self.protocols {
+                    protocols.entry(protocol).or_default().add(&average);
+                }
+            }
+            _ => unreachable!(),
+        }
+
+        match stats.entry(BLOCKED).or_insert(Statistic::Count(0)) {
+            Statistic::Count(blocked) => *blocked += self.blocked,
+            _ => unreachable!(),
+        }
+    }
+}
+
+///
+/// Filters applied when querying the request log
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, Serialize))]
+#[derive(Deserialize, Default, Clone)]
+pub struct Query {
+    /// Only include requests from this client
+    pub client: Option<String>,
+    /// Only include requests whose question contains this
+    pub domain: Option<String>,
+    /// Only include requests of this type (e.g. `AAAA`)
+    #[serde(rename = "type")]
+    pub query_type: Option<String>,
+    /// Only include requests that were (or weren't) blocked
+    pub blocked: Option<bool>,
+    /// Only include requests matching a rule from the list with this name
+    pub list: Option<String>,
+    /// Only include requests with this response status
+    pub status: Option<String>,
+    /// Only include requests made at or after this time (seconds since the epoch)
+    pub since: Option<u64>,
+    /// Only include requests made at or before this time (seconds since the epoch)
+    pub until: Option<u64>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+///
+/// What to rank in the most frequent requests
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, Serialize))]
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Ranking {
+    /// The domains asked about the most
+    #[default]
+    Domains,
+    /// The clients asking the most
+    Clients,
+    /// The blocked domains asked about the most
+    Blocked,
+}
+
+const fn default_top_limit() -> usize {
+    10
+}
+
+///
+/// Which of the most frequent requests to show
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, Serialize))]
+#[derive(Deserialize, Clone)]
+pub struct Top {
+    #[serde(default)]
+    pub kind: Ranking,
+    #[serde(default = "default_top_limit")]
+    pub limit: usize,
+    /// Only include requests made within this long ago
+    #[serde(with = "humantime_serde", default)]
+    pub window: Option<Duration>,
+}
+
+impl Default for Top {
+    fn default() -> Self {
+        Self {
+            kind: Ranking::default(),
+            limit: default_top_limit(),
+            window: None,
+        }
+    }
+}
+
+///
+/// How many times a domain (or client) appears in the request log
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug, PartialEq, Eq, Deserialize))]
+#[derive(Serialize, Clone)]
+pub struct Ranked {
+    pub name: String,
+    pub count: usize,
+}
+
+impl Top {
+    fn rank(
+        &self,
+        requests: impl DoubleEndedIterator<Item = Request>,
+        now: SystemTime,
+    ) -> Vec<Ranked> {
+        let cutoff = self.window.and_then(|window| now.checked_sub(window));
+
+        let mut counts = AHashMap::<String, usize>::default();
+        // Requests are recorded as they complete, so the most recent are at the end
+        for request in requests
+            .rev()
+            .take_while(|request| cutoff.map_or(true, |cutoff| request.timestamp >= cutoff))
+        {
+            let name = match self.kind {
+                Ranking::Domains => request.question,
+                Ranking::Blocked if request.blocked() => request.question,
+                Ranking::Blocked => continue,
+                Ranking::Clients => request.client,
+            };
+
+            *counts
+                .entry(name.trim_end_matches('.').to_ascii_lowercase())
+                .or_default() += 1;
+        }
+
+        let mut ranked = counts
+            .into_iter()
+            .map(|(name, count)| Ranked { name, count })
+            .collect::<Vec<_>>();
+        ranked.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+        ranked.truncate(self.limit);
+
+        ranked
+    }
+}
+
+///
+/// The time the seconds since the epoch come to, should it be one that can be
+/// represented at all
+///
+fn since_epoch(seconds: u64) -> Option<SystemTime> {
+    UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+impl Query {
+    ///
+    /// # Errors
+    /// Should `since` or `until` be too far from the epoch to be a time
+    ///
+    pub fn validate(&self) -> Result<(), String> {
+        for (key, seconds) in [("since", self.since), ("until", self.until)] {
+            if seconds.is_some_and(|seconds| since_epoch(seconds).is_none()) {
+                return Err(format!("{key} is out of range"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn matches(&self, request: &Request) -> bool {
+        self.client
+            .as_ref()
+            .map_or(true, |client| request.client == *client)
+            && self.domain.as_ref().map_or(true, |domain| {
+                request
+                    .question
+                    .to_ascii_lowercase()
+                    .contains(&domain.to_ascii_lowercase())
+            })
+            && self.query_type.as_ref().map_or(true, |ty| {
+                request.query_type.to_string().eq_ignore_ascii_case(ty)
+            })
+            && self
+                .blocked
+                .map_or(true, |blocked| request.blocked() == blocked)
+            && self.list.as_ref().map_or(true, |list| {
+                request
+                    .rule
+                    .as_ref()
+                    .and_then(Rule::list)
+                    .is_some_and(|source| source.name == *list)
+            })
+            && self
+                .status
+                .as_ref()
+                .map_or(true, |status| request.status.eq_ignore_ascii_case(status))
+            // Times beyond what can be represented are later than any request
+            && self.since.map_or(true, |since| {
+                since_epoch(since).is_some_and(|since| request.timestamp >= since)
+            })
+            && self.until.map_or(true, |until| {
+                since_epoch(until).map_or(true, |until| request.timestamp <= until)
+            })
+    }
+}
+
+impl Request {
+    #[inline]
+    pub fn blocked(&self) -> bool {
+        self.rule
+            .as_ref()
+            .map_or(false, |rule| rule.kind == Kind::Deny)
+    }
+
+    fn record_metrics(&self) {
+        let rule = self
+            .rule
+            .as_ref()
+            .map_or_else(|| String::from("None"), |rule| rule.kind.to_string());
+
+        match metrics::mode() {
+            metrics::Mode::Full => metrics::REQUESTS
+                .get_or_create(&metrics::Request {
+                    client: self.client.clone(),
+                    name: self.name.clone().unwrap_or_default(),
+                    question: self.question.clone(),
+                    r#type: self.query_type.to_string(),
+                    rule,
+                })
+                .inc(),
+            metrics::Mode::Aggregated => metrics::AGGREGATED_REQUESTS
+                .get_or_create(&metrics::Aggregate {
+                    client: self.client.clone(),
+                    name: self.name.clone().unwrap_or_default(),
+                    rule,
+                })
+                .inc(),
+        };
+    }
+}
+
+fn latency(
+    requests: impl DoubleEndedIterator<Item = Request>,
+    cutoff: SystemTime,
+) -> Option<Duration> {
+    // Requests are recorded as they complete, so the most recent are at the end
+    let (count, total) = requests
+        .rev()
+        .take_while(|request| request.timestamp >= cutoff)
+        .fold((0u64, 0u64), |(count, total), request| {
+            (count + 1, total + request.elapsed as u64)
+        });
+
+    total.checked_div(count).map(Duration::from_nanos)
 }
 
 pub struct Statistics {
     statistics: AHashMap<&'static str, Statistic>,
+    history: History,
+    latency: Latency,
 }
 
 impl Default for Statistics {
     fn default() -> Self {
         Self {
             statistics: AHashMap::with_capacity(1024),
+            history: History::default(),
+            latency: Latency::default(),
         }
     }
 }
 
 impl Statistics {
+    ///
+    /// Record the statistic. This never waits on the totals being read; the
+    /// statistic is held on to until the totals are next brought up to date,
+    /// either when they're read or once enough are waiting.
+    ///
     #[inline]
-    pub fn record(value: Statistic) {
-        if let Ok(mut lock) = STATISTICS.write() {
-            value.record(&mut lock.statistics);
+    pub fn record(mut value: Statistic) {
+        let mut kept = true;
+        if let Statistic::Request(request) = &mut value {
+            let privacy = privacy::level(&request.client);
+            privacy.redact(request);
+            kept = privacy.keeps_requests();
+
+            if request.blocked() {
+                metrics::BLOCKED.inc();
+            }
+            request.timings.record_metrics();
+
+            if kept {
+                request.record_metrics();
+                exporter::record(request);
+                logging::record(request);
+
+                if STREAM.receiver_count() > 0 {
+                    // This can only fail if every receiver has since gone away
+                    let _ = STREAM.send(*request.clone());
+                }
+            }
+        }
+
+        if pending::push((value, kept)) >= FLUSH {
+            // Should the totals be busy, whoever has them (or the next to
+            // record) will bring them up to date instead
+            if let Ok(mut lock) = STATISTICS.try_write() {
+                lock.flush();
+            }
         }
     }
 
+    ///
+    /// Add everything waiting to the totals
+    ///
+    fn flush(&mut self) {
+        let mut requests = Vec::new();
+
+        for (value, kept) in pending::drain() {
+            match value {
+                Statistic::Request(request) => requests.push((request, kept)),
+                value => value.record(&mut self.statistics),
+            }
+        }
+
+        // Each shard is in order, but they may have been recorded in between
+        // each other
+        requests.sort_by_key(|(request, _)| request.timestamp);
+
+        for (request, kept) in requests {
+            self.history.record(request.timestamp, request.blocked());
+            self.latency.record(&request);
+
+            if kept {
+                Statistic::record_request(*request, &mut self.statistics);
+            } else {
+                Statistic::record_totals(&request, &mut self.statistics);
+            }
+        }
+    }
+
+    ///
+    /// The totals, brought up to date with anything waiting
+    ///
+    fn read() -> Option<RwLockReadGuard<'static, Self>> {
+        if pending::waiting() {
+            if let Ok(mut lock) = STATISTICS.write() {
+                lock.flush();
+            }
+        }
+
+        STATISTICS.read().ok()
+    }
+
+    ///
+    /// The totals, brought up to date with anything waiting, to be changed
+    ///
+    fn write() -> Option<RwLockWriteGuard<'static, Self>> {
+        let mut lock = STATISTICS.write().ok()?;
+        lock.flush();
+        Some(lock)
+    }
+
     #[instrument]
     pub fn retrieve(statistic: &str, from: Option<usize>, to: Option<usize>) -> Option<Statistic> {
         debug!("Retrieving statistics");
 
-        match &STATISTICS.read().unwrap().statistics.get(statistic) {
+        let statistics = Self::read()?;
+
+        match &statistics.statistics.get(statistic) {
             Some(Statistic::Requests(ref requests)) => {
                 let len = requests.len();
 
@@ -193,37 +765,187 @@ impl Statistics {
                     .iter()
                     .skip(from)
                     .take(to - from)
-                    .cloned()
                     .collect::<Vec<_>>();
 
                 requests.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
 
-                Some(Statistic::Requests(requests))
+                Some(Statistic::Requests(requests.into()))
+            }
+            Some(Statistic::Average(average)) if statistic == AVERAGE_REQUEST_TIME => {
+                Some(Statistic::Average(statistics.with_latency(average)))
             }
             stat => stat.cloned(),
         }
     }
 
+    ///
+    /// Query the request log, returning the total number of matching requests along
+    /// with the requested page of them (most recent first).
+    ///
+    /// Returns None if no requests have been recorded yet.
+    ///
+    #[instrument]
+    pub fn requests(query: &Query) -> Option<(usize, Vec<Request>)> {
+        debug!("Querying requests");
+
+        let statistics = Self::read()?;
+        let Some(Statistic::Requests(requests)) = statistics.statistics.get(REQUESTS) else {
+            return None;
+        };
+
+        let mut requests = requests
+            .iter()
+            .filter(|request| query.matches(request))
+            .collect::<Vec<_>>();
+        let total = requests.len();
+
+        requests.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
+        Some((
+            total,
+            requests
+                .into_iter()
+                .skip(query.offset.unwrap_or_default())
+                .take(query.limit.unwrap_or(usize::MAX))
+                .collect(),
+        ))
+    }
+
+    ///
+    /// The domains (or clients) that appear the most in the request log
+    ///
+    pub fn top(top: &Top) -> Vec<Ranked> {
+        let Some(statistics) = Self::read() else {
+            return Vec::new();
+        };
+
+        match statistics.statistics.get(REQUESTS) {
+            Some(Statistic::Requests(requests)) => top.rank(requests.iter(), SystemTime::now()),
+            _ => Vec::new(),
+        }
+    }
+
+    ///
+    /// The average time taken to handle the requests made within the window
+    ///
+    /// Returns None if there haven't been any
+    ///
+    pub fn recent_latency(window: Duration) -> Option<Duration> {
+        let cutoff = SystemTime::now().checked_sub(window)?;
+
+        let statistics = Self::read()?;
+        let Some(Statistic::Requests(requests)) = statistics.statistics.get(REQUESTS) else {
+            return None;
+        };
+
+        latency(requests.iter(), cutoff)
+    }
+
+    ///
+    /// Subscribe to every request as it is recorded
+    ///
+    #[inline]
+    pub fn subscribe() -> broadcast::Receiver<Request> {
+        STREAM.subscribe()
+    }
+
     #[inline]
     pub fn statistics() -> AHashMap<&'static str, Statistic> {
-        STATISTICS
-            .read()
-            .map(|statistics| statistics.statistics.clone())
+        Self::read()
+            .map(|statistics| {
+                let mut cloned = statistics.statistics.clone();
+                if let Some(Statistic::Average(average)) = cloned.get_mut(AVERAGE_REQUEST_TIME) {
+                    *average = statistics.with_latency(average);
+                }
+                cloned
+            })
+            .unwrap_or_default()
+    }
+
+    ///
+    /// The average, along with the latency percentiles over the last few
+    /// minutes
+    ///
+    fn with_latency(&self, average: &Average) -> Average {
+        Average {
+            latency: Some(self.latency.percentiles(SystemTime::now())),
+            ..average.clone()
+        }
+    }
+
+    ///
+    /// Bring the latency metrics up to date. This is done as they're scraped,
+    /// as requests age out of the window even while none are being recorded.
+    ///
+    pub fn record_latency_metrics() {
+        if let Some(statistics) = Self::read() {
+            statistics.latency.record_metrics(SystemTime::now());
+        }
+    }
+
+    ///
+    /// The number of requests made (and blocked) over time
+    ///
+    pub fn history(span: &Span) -> Vec<Bucket> {
+        Self::read()
+            .map(|statistics| statistics.history.query(span, SystemTime::now()))
             .unwrap_or_default()
     }
 
     #[inline]
     pub fn clear() {
-        if let Ok(mut lock) = STATISTICS.write() {
+        if let Some(mut lock) = Self::write() {
             lock.statistics = AHashMap::default();
+            lock.history = History::default();
+            lock.latency = Latency::default();
         }
     }
 
+    ///
+    /// Save the running totals, so that they can be restored once we restart
+    ///
+    /// # Errors
+    /// If the snapshot can't be written
+    ///
+    pub fn save(path: &Path) -> Result<(), io::Error> {
+        let snapshot = Self::read()
+            .map(|statistics| Snapshot::take(&statistics.statistics))
+            .unwrap_or_default();
+        let contents = serde_json::to_vec(&snapshot)?;
+
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+
+        Health::persist(|| {
+            std::fs::write(&temporary, contents).and_then(|()| std::fs::rename(&temporary, path))
+        })
+        .inspect_err(|_| {
+            std::fs::remove_file(&temporary).unwrap_or_default();
+        })
+    }
+
+    ///
+    /// Restore the running totals saved before we last stopped
+    ///
+    /// # Errors
+    /// If the snapshot can't be read, e.g. because there isn't one yet
+    ///
+    pub fn restore(path: &Path) -> Result<(), io::Error> {
+        let snapshot = serde_json::from_slice::<Snapshot>(&std::fs::read(path)?)?;
+
+        if let Some(mut lock) = Self::write() {
+            snapshot.restore(&mut lock.statistics);
+        }
+
+        Ok(())
+    }
+
     pub fn modify<F>(statistic: &str, f: F)
     where
         F: FnOnce(&mut Statistic),
     {
-        if let Ok(mut lock) = STATISTICS.write() {
+        if let Some(mut lock) = Self::write() {
             lock.statistics
                 .get_mut(statistic)
                 .map(f)
@@ -231,3 +953,378 @@ impl Statistics {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use ahash::AHashMap;
+    use hickory_proto::rr::RecordType;
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        filter::rules::{Kind, Rule, Source},
+        metrics,
+    };
+
+    use super::{
+        Average, Cache, Query, Ranked, Ranking, Request, Snapshot, Statistic, Statistics, Top,
+        AVERAGE_REQUEST_TIME, BLOCKED, CACHE, PROTOCOLS,
+    };
+
+    #[tokio::test]
+    async fn subscribing() {
+        let mut receiver = Statistics::subscribe();
+
+        let request = Request {
+            question: String::from("stream.example.com."),
+            ..Default::default()
+        };
+        Statistics::record(Statistic::Request(Box::new(request.clone())));
+
+        // Other tests may be recording requests at the same time
+        loop {
+            let streamed = receiver.recv().await.unwrap();
+            if streamed.question == request.question {
+                assert_eq!(streamed, request);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn aggregated_metrics() {
+        let request = Request {
+            client: String::from("10.0.0.250"),
+            question: String::from("aggregated.example.com."),
+            ..Default::default()
+        };
+        let label = metrics::Aggregate {
+            client: request.client.clone(),
+            name: String::default(),
+            rule: String::from("None"),
+        };
+
+        metrics::configure(&metrics::Options {
+            mode: metrics::Mode::Aggregated,
+        });
+        request.record_metrics();
+        metrics::configure(&metrics::Options::default());
+
+        assert_eq!(metrics::AGGREGATED_REQUESTS.get_or_create(&label).get(), 1);
+        assert_eq!(
+            metrics::REQUESTS
+                .get_or_create(&metrics::Request {
+                    client: request.client,
+                    name: label.name,
+                    question: request.question,
+                    r#type: request.query_type.to_string(),
+                    rule: label.rule,
+                })
+                .get(),
+            0
+        );
+    }
+
+    #[test]
+    fn latency() {
+        let now = SystemTime::now();
+        let requests = [
+            Request {
+                elapsed: 1_000_000_000,
+                timestamp: now - Duration::from_mins(2),
+                ..Default::default()
+            },
+            Request {
+                elapsed: 10_000_000,
+                timestamp: now - Duration::from_secs(30),
+                ..Default::default()
+            },
+            Request {
+                elapsed: 30_000_000,
+                timestamp: now,
+                ..Default::default()
+            },
+        ];
+
+        assert_eq!(
+            super::latency(requests.clone().into_iter(), now - Duration::from_mins(1)),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(
+            super::latency(requests.into_iter(), now + Duration::from_secs(1)),
+            None
+        );
+    }
+
+    #[test]
+    fn protocols() {
+        let mut stats = AHashMap::default();
+
+        for (protocol, elapsed) in [("UDP", 10), ("TCP", 30), ("UDP", 20)] {
+            Statistic::Request(Box::new(Request {
+                protocol: String::from(protocol),
+                elapsed,
+                ..Default::default()
+            }))
+            .record(&mut stats);
+        }
+
+        match stats.get(super::PROTOCOLS) {
+            Some(Statistic::Protocols(protocols)) => {
+                assert_eq!(
+                    protocols.get("UDP"),
+                    Some(&Average {
+                        count: 2,
+                        average: 15,
+                        ..Default::default()
+                    })
+                );
+                assert_eq!(
+                    protocols.get("TCP"),
+                    Some(&Average {
+                        count: 1,
+                        average: 30,
+                        ..Default::default()
+                    })
+                );
+            }
+            statistic => panic!("Unexpected statistic: {statistic:?}"),
+        }
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut stats = AHashMap::default();
+
+        for blocked in [true, false, true] {
+            Statistic::Request(Box::new(Request {
+                rule: blocked.then(|| Rule {
+                    domain: String::from("ads.example.com"),
+                    kind: Kind::Deny,
+                    action: None,
+                    list: None,
+                    zone: false,
+                    important: false,
+                    scope: None,
+                }),
+                protocol: String::from("udp"),
+                elapsed: 10,
+                ..Default::default()
+            }))
+            .record(&mut stats);
+            Statistic::Average(Average {
+                count: 1,
+                average: 10,
+                ..Default::default()
+            })
+            .record(&mut stats);
+        }
+        Statistic::Cache(Cache {
+            hits: 2,
+            misses: 1,
+            evictions: 3,
+            size: 512,
+            entries: 4,
+            ..Default::default()
+        })
+        .record(&mut stats);
+
+        let snapshot = Snapshot::take(&stats);
+        assert_eq!(snapshot.blocked, 2);
+        assert_eq!(snapshot.cache.size, 0);
+        assert_eq!(snapshot.cache.entries, 0);
+
+        let snapshot =
+            serde_json::from_slice::<Snapshot>(&serde_json::to_vec(&snapshot).unwrap()).unwrap();
+
+        // Restoring adds to whatever was recorded since starting up
+        let mut restored = AHashMap::default();
+        Statistic::Average(Average {
+            count: 1,
+            average: 40,
+            ..Default::default()
+        })
+        .record(&mut restored);
+        snapshot.restore(&mut restored);
+
+        assert_eq!(
+            restored.get(AVERAGE_REQUEST_TIME),
+            Some(&Statistic::Average(Average {
+                count: 4,
+                average: 17,
+                ..Default::default()
+            }))
+        );
+        assert_eq!(
+            restored.get(CACHE),
+            Some(&Statistic::Cache(Cache {
+                hits: 2,
+                misses: 1,
+                evictions: 3,
+                ..Default::default()
+            }))
+        );
+        assert_eq!(restored.get(BLOCKED), Some(&Statistic::Count(2)));
+        assert!(matches!(
+            restored.get(PROTOCOLS),
+            Some(Statistic::Protocols(protocols)) if protocols["udp"].count == 3
+        ));
+    }
+
+    #[test]
+    fn top() {
+        let now = SystemTime::now();
+        let request = |client: &str, question: &str, blocked: bool, ago: u64| Request {
+            client: String::from(client),
+            question: String::from(question),
+            rule: blocked.then(|| Rule {
+                domain: question.trim_end_matches('.').to_string(),
+                kind: Kind::Deny,
+                action: None,
+                list: None,
+                zone: false,
+                important: false,
+                scope: None,
+            }),
+            timestamp: now - Duration::from_secs(ago),
+            ..Default::default()
+        };
+
+        // Oldest first, as they are in the log
+        let requests = vec![
+            request("192.168.1.3", "old.example.com.", false, 3 * 60 * 60),
+            request("192.168.1.2", "ads.example.com.", true, 60),
+            request("192.168.1.2", "Example.com.", false, 50),
+            request("192.168.1.3", "example.com.", false, 40),
+            request("192.168.1.2", "ads.example.com.", true, 30),
+            request("192.168.1.2", "tracker.example.com.", true, 20),
+        ];
+
+        let rank = |top: Top| top.rank(requests.clone().into_iter(), now);
+        let ranked = |ranked: &[(&str, usize)]| {
+            ranked
+                .iter()
+                .map(|(name, count)| Ranked {
+                    name: String::from(*name),
+                    count: *count,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            rank(Top::default()),
+            ranked(&[
+                ("ads.example.com", 2),
+                ("example.com", 2),
+                ("old.example.com", 1),
+                ("tracker.example.com", 1),
+            ])
+        );
+        assert_eq!(
+            rank(Top {
+                kind: Ranking::Clients,
+                window: Some(Duration::from_hours(1)),
+                ..Default::default()
+            }),
+            ranked(&[("192.168.1.2", 4), ("192.168.1.3", 1)])
+        );
+        assert_eq!(
+            rank(Top {
+                kind: Ranking::Blocked,
+                limit: 1,
+                ..Default::default()
+            }),
+            ranked(&[("ads.example.com", 2)])
+        );
+    }
+
+    #[test]
+    fn query_matching() {
+        let request = Request {
+            client: String::from("192.168.1.2"),
+            question: String::from("Ads.Example.com."),
+            query_type: RecordType::AAAA,
+            rule: Some(Rule {
+                domain: String::from("ads.example.com"),
+                kind: Kind::Deny,
+                action: None,
+                list: Some(Arc::new(Source {
+                    name: String::from("Ads"),
+                    url: String::from("https://example.com/ads.txt"),
+                })),
+                zone: false,
+                important: false,
+                scope: None,
+            }),
+            status: String::from("No Error"),
+            ..Default::default()
+        };
+
+        let matches = |query: Query| query.matches(&request);
+
+        assert!(matches(Query::default()));
+        assert!(matches(Query {
+            client: Some(String::from("192.168.1.2")),
+            domain: Some(String::from("example")),
+            query_type: Some(String::from("aaaa")),
+            blocked: Some(true),
+            list: Some(String::from("Ads")),
+            status: Some(String::from("no error")),
+            ..Default::default()
+        }));
+        assert!(!matches(Query {
+            client: Some(String::from("192.168.1.3")),
+            ..Default::default()
+        }));
+        assert!(!matches(Query {
+            domain: Some(String::from("tracker")),
+            ..Default::default()
+        }));
+        assert!(!matches(Query {
+            query_type: Some(String::from("A")),
+            ..Default::default()
+        }));
+        assert!(!matches(Query {
+            blocked: Some(false),
+            ..Default::default()
+        }));
+        assert!(!matches(Query {
+            list: Some(String::from("Trackers")),
+            ..Default::default()
+        }));
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let hour = Duration::from_hours(1);
+
+        assert!(matches(Query {
+            since: Some(now.saturating_sub(hour).as_secs()),
+            until: Some((now + hour).as_secs()),
+            ..Default::default()
+        }));
+        assert!(!matches(Query {
+            since: Some((now + hour).as_secs()),
+            ..Default::default()
+        }));
+        assert!(!matches(Query {
+            until: Some(now.saturating_sub(hour).as_secs()),
+            ..Default::default()
+        }));
+
+        let far = Query {
+            since: Some(u64::MAX),
+            ..Default::default()
+        };
+        assert!(far.validate().is_err());
+        assert!(!matches(far));
+        assert!(matches(Query {
+            until: Some(u64::MAX),
+            ..Default::default()
+        }));
+    }
+}
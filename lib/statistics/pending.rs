@@ -0,0 +1,71 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        LazyLock, Mutex,
+    },
+    thread::available_parallelism,
+};
+
+use super::Statistic;
+
+/// Statistics waiting to be added to the totals, spread over a shard per core
+/// so that threads recording at the same time rarely wait on each other
+static SHARDS: LazyLock<Box<[Mutex<Vec<Pending>>]>> = LazyLock::new(|| {
+    (0..available_parallelism().map_or(1, usize::from))
+        .map(|_| Mutex::default())
+        .collect()
+});
+
+/// How many statistics are waiting, across every shard
+static WAITING: AtomicUsize = AtomicUsize::new(0);
+
+/// Which shard the next thread to record will use
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static SHARD: usize = NEXT.fetch_add(1, Ordering::Relaxed) % SHARDS.len();
+}
+
+///
+/// A statistic waiting to be added, along with whether the request (should it
+/// be one) is kept in the log, or only counted towards the totals
+///
+pub(super) type Pending = (Statistic, bool);
+
+///
+/// Hold on to the statistic until the totals are next brought up to date,
+/// returning how many are now waiting
+///
+pub(super) fn push(pending: Pending) -> usize {
+    let shard = SHARD.with(|shard| *shard);
+
+    if let Ok(mut lock) = SHARDS[shard].lock() {
+        lock.push(pending);
+    }
+
+    WAITING.fetch_add(1, Ordering::AcqRel) + 1
+}
+
+///
+/// Whether there's anything waiting to be added to the totals
+///
+#[inline]
+pub(super) fn waiting() -> bool {
+    WAITING.load(Ordering::Acquire) > 0
+}
+
+///
+/// Take everything waiting, from every shard
+///
+pub(super) fn drain() -> Vec<Pending> {
+    let mut drained = Vec::with_capacity(WAITING.load(Ordering::Acquire));
+
+    for shard in SHARDS.iter() {
+        if let Ok(mut lock) = shard.lock() {
+            WAITING.fetch_sub(lock.len(), Ordering::AcqRel);
+            drained.append(&mut lock);
+        }
+    }
+
+    drained
+}
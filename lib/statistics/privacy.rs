@@ -0,0 +1,153 @@
+use std::{net::IpAddr, sync::RwLock};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use super::Request;
+
+static PRIVACY: RwLock<Privacy> = RwLock::new(Privacy::Full);
+
+/// Clients that have opted out of having their requests kept
+static UNLOGGED: RwLock<Vec<IpNet>> = RwLock::new(Vec::new());
+
+///
+/// How much is kept about each request, in the request log, the metrics, and
+/// anything they're exported to
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Privacy {
+    /// Everything
+    #[default]
+    Full,
+    /// Only the network clients are in (the /24 for IPv4, /48 for IPv6), and
+    /// not their names or hardware addresses
+    AnonymizeClients,
+    /// Nothing about the client at all
+    DomainsOnly,
+    /// No requests are kept, only the running totals
+    Nothing,
+}
+
+impl Privacy {
+    ///
+    /// Remove whatever the request shouldn't be kept with
+    ///
+    pub(super) fn redact(self, request: &mut Request) {
+        match self {
+            Self::Full => return,
+            Self::AnonymizeClients => request.client = anonymize(&request.client),
+            Self::DomainsOnly | Self::Nothing => request.client.clear(),
+        }
+
+        request.name = None;
+        request.mac = None;
+
+        if self == Self::Nothing {
+            request.question.clear();
+            request.answers.clear();
+        }
+    }
+
+    ///
+    /// Whether requests are kept at all
+    ///
+    pub(super) fn keeps_requests(self) -> bool {
+        self != Self::Nothing
+    }
+}
+
+fn anonymize(client: &str) -> String {
+    client
+        .parse::<IpAddr>()
+        .ok()
+        .and_then(|ip| IpNet::new(ip, if ip.is_ipv4() { 24 } else { 48 }).ok())
+        .map(|network| network.network().to_string())
+        .unwrap_or_default()
+}
+
+pub(super) fn set(privacy: Privacy, unlogged: &[IpNet]) {
+    if let Ok(mut lock) = PRIVACY.write() {
+        *lock = privacy;
+    }
+
+    if let Ok(mut lock) = UNLOGGED.write() {
+        *lock = unlogged.to_vec();
+    }
+}
+
+///
+/// How much to keep of the client's requests
+///
+pub(super) fn level(client: &str) -> Privacy {
+    let unlogged = client.parse::<IpAddr>().is_ok_and(|client| {
+        UNLOGGED
+            .read()
+            .is_ok_and(|unlogged| unlogged.iter().any(|network| network.contains(&client)))
+    });
+
+    if unlogged {
+        Privacy::Nothing
+    } else {
+        PRIVACY.read().map(|privacy| *privacy).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::Privacy;
+    use crate::statistics::Request;
+
+    fn request() -> Request {
+        Request {
+            client: String::from("192.168.1.23"),
+            name: Some(String::from("laptop")),
+            mac: Some(String::from("aa:bb:cc:dd:ee:ff")),
+            question: String::from("example.com."),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn redact() {
+        let mut full = request();
+        Privacy::Full.redact(&mut full);
+        assert_eq!(full, request());
+
+        let mut anonymized = request();
+        Privacy::AnonymizeClients.redact(&mut anonymized);
+        assert_eq!(anonymized.client, "192.168.1.0");
+        assert_eq!(anonymized.name, None);
+        assert_eq!(anonymized.mac, None);
+        assert_eq!(anonymized.question, "example.com.");
+
+        let mut domains = request();
+        Privacy::DomainsOnly.redact(&mut domains);
+        assert_eq!(domains.client, "");
+        assert_eq!(domains.question, "example.com.");
+
+        let mut nothing = request();
+        Privacy::Nothing.redact(&mut nothing);
+        assert_eq!(nothing.question, "");
+        assert!(!Privacy::Nothing.keeps_requests());
+    }
+
+    #[test]
+    fn unlogged() {
+        super::set(Privacy::Full, &["203.0.113.0/24".parse().unwrap()]);
+
+        assert_eq!(super::level("203.0.113.7"), Privacy::Nothing);
+        assert_eq!(super::level("203.0.114.7"), Privacy::Full);
+
+        super::set(Privacy::Full, &[]);
+    }
+
+    #[test]
+    fn anonymize() {
+        assert_eq!(super::anonymize("2001:db8:1234:5678::1"), "2001:db8:1234::");
+        assert_eq!(super::anonymize("not an address"), "");
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    str::FromStr,
+    sync::{LazyLock, Mutex, PoisonError},
+    time::SystemTime,
+};
+
+use serde::{Serialize, Serializer};
+use tracing::{error, info};
+
+use crate::{cache::Cache, schedule::Sched};
+
+static RUNS: LazyLock<Mutex<Runs>> = LazyLock::new(Mutex::default);
+
+///
+/// How many of the most recent runs are remembered
+///
+const KEPT: usize = 64;
+
+///
+/// A maintenance task that can be run on demand
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Clone, PartialEq, Eq)]
+pub enum Task {
+    /// Any of the tasks that are otherwise run on a schedule
+    Schedule(Sched),
+    /// Forget every cached response
+    CacheFlush,
+    /// Drop requests from the log that are older than it's kept for
+    StatsPrune,
+}
+
+impl FromStr for Task {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cache-flush" => Ok(Self::CacheFlush),
+            "stats-prune" => Ok(Self::StatsPrune),
+            _ => Sched::from_str(s)
+                .map(Self::Schedule)
+                .map_err(|_| format!("Unknown task: {s}")),
+        }
+    }
+}
+
+impl Display for Task {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Schedule(schedule) => f.write_str(&format!("{schedule:?}").to_lowercase()),
+            Self::CacheFlush => f.write_str("cache-flush"),
+            Self::StatsPrune => f.write_str("stats-prune"),
+        }
+    }
+}
+
+impl Serialize for Task {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Task {
+    async fn run(&self) -> Result<(), String> {
+        match self {
+            Self::Schedule(schedule) => schedule.run().await,
+            Self::CacheFlush => {
+                Cache::clear().await;
+                Ok(())
+            }
+            // Pruning the log is what the Logs schedule does
+            Self::StatsPrune => Sched::Logs.run().await,
+        }
+    }
+}
+
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+///
+/// A task that's been asked to run, and how it went
+///
+#[cfg_attr(any(debug_assertions, test), derive(Debug))]
+#[derive(Serialize, Clone)]
+pub struct Run {
+    pub id: u64,
+    pub task: Task,
+    pub state: State,
+    pub started: SystemTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished: Option<SystemTime>,
+    /// Why it failed, should it have
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+struct Runs {
+    next: u64,
+    /// Oldest first
+    runs: VecDeque<Run>,
+}
+
+impl Runs {
+    fn start(&mut self, task: Task) -> Run {
+        self.next += 1;
+
+        let run = Run {
+            id: self.next,
+            task,
+            state: State::Running,
+            started: SystemTime::now(),
+            finished: None,
+            error: None,
+        };
+
+        // Those still running are kept regardless, so that they can be checked on
+        while self.runs.len() >= KEPT {
+            match self.runs.iter().position(|run| run.state != State::Running) {
+                Some(index) => self.runs.remove(index),
+                None => break,
+            };
+        }
+        self.runs.push_back(run.clone());
+
+        run
+    }
+
+    fn finish(&mut self, id: u64, result: Result<(), String>) {
+        if let Some(run) = self.runs.iter_mut().find(|run| run.id == id) {
+            run.finished = Some(SystemTime::now());
+            (run.state, run.error) = match result {
+                Ok(()) => (State::Succeeded, None),
+                Err(err) => (State::Failed, Some(err)),
+            };
+        }
+    }
+}
+
+///
+/// Run the task in the background, returning the run it can be checked on by
+///
+pub fn start(task: Task) -> Run {
+    let run = RUNS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .start(task.clone());
+
+    let id = run.id;
+    tokio::spawn(async move {
+        info!("Running {task} on demand");
+
+        let result = match tokio::spawn(async move { task.run().await }).await {
+            Ok(result) => result,
+            Err(err) => Err(err.to_string()),
+        };
+
+        if let Err(err) = &result {
+            error!("Task {id} failed: {err}");
+        }
+
+        if let Ok(mut runs) = RUNS.lock() {
+            runs.finish(id, result);
+        }
+    });
+
+    run
+}
+
+///
+/// The run with the id, if it's still remembered
+///
+pub fn status(id: u64) -> Option<Run> {
+    RUNS.lock()
+        .ok()?
+        .runs
+        .iter()
+        .find(|run| run.id == id)
+        .cloned()
+}
+
+///
+/// The most recent runs, newest first
+///
+pub fn recent() -> Vec<Run> {
+    RUNS.lock()
+        .map(|runs| runs.runs.iter().rev().cloned().collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use crate::schedule::Sched;
+
+    use super::{Runs, State, Task, KEPT};
+
+    #[test]
+    fn names() {
+        assert_eq!("Cache-Flush".parse(), Ok(Task::CacheFlush));
+        assert_eq!("stats-prune".parse(), Ok(Task::StatsPrune));
+        assert_eq!("filters".parse(), Ok(Task::Schedule(Sched::Filters)));
+        assert!("blocking".parse::<Task>().is_err());
+
+        assert_eq!(Task::Schedule(Sched::Logs).to_string(), "logs");
+        assert_eq!(Task::CacheFlush.to_string(), "cache-flush");
+    }
+
+    #[test]
+    fn runs() {
+        let mut runs = Runs::default();
+
+        let first = runs.start(Task::CacheFlush);
+        for _ in 1..KEPT {
+            let run = runs.start(Task::StatsPrune);
+            runs.finish(run.id, Err(String::from("Nope")));
+        }
+
+        let last = runs.start(Task::CacheFlush);
+        // The oldest finished run makes way, as the first is still running
+        assert_eq!(runs.runs.len(), KEPT);
+        assert_eq!(runs.runs.front().map(|run| run.id), Some(first.id));
+        assert_eq!(runs.runs.get(1).map(|run| run.id), Some(first.id + 2));
+
+        runs.finish(last.id, Ok(()));
+        let finished = runs.runs.back().unwrap();
+        assert_eq!(finished.state, State::Succeeded);
+        assert!(finished.finished.is_some());
+        assert_eq!(runs.runs[2].error.as_deref(), Some("Nope"));
+    }
+}
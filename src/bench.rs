@@ -0,0 +1,285 @@
+use std::{
+    cell::RefCell,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use ahash::AHashMap;
+use blackhole::Exit;
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{Name, RecordType},
+};
+use tokio::{net::UdpSocket, time::MissedTickBehavior};
+
+/// How often queries are sent, with however many are due sent all at once
+const TICK: Duration = Duration::from_millis(10);
+
+/// The percentiles latencies are reported at
+const PERCENTILES: [(&str, f64); 4] = [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p99.9", 0.999)];
+
+///
+/// Everything that's happened to the queries sent so far
+///
+#[derive(Default)]
+struct Results {
+    /// When each query still waiting on an answer was sent, by its ID
+    outstanding: AHashMap<u16, Instant>,
+    /// How long each answered query took to be answered
+    latencies: Vec<Duration>,
+    /// How many answers there were with each response code
+    codes: AHashMap<ResponseCode, usize>,
+    /// Queries that weren't answered in time
+    timed_out: usize,
+}
+
+///
+/// Send queries for the domains to the server at a steady rate, and report how
+/// quickly (and how) they were answered
+///
+#[coverage(off)]
+pub async fn bench(
+    target: SocketAddr,
+    qps: u32,
+    domains: &Path,
+    query_type: RecordType,
+    duration: Duration,
+    timeout: Duration,
+) -> Result<(), Exit> {
+    let names = names(domains)?;
+
+    let local = if target.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    };
+    let socket = UdpSocket::bind(local).await.map_err(|err| {
+        eprintln!("Unable to bind to {local}: {err}");
+        Exit::Bind
+    })?;
+    socket.connect(target).await.map_err(|err| {
+        eprintln!("Unable to connect to {target}: {err}");
+        Exit::Unavailable
+    })?;
+
+    println!(
+        "Sending {qps} queries a second to {target} for {:?}, from {} domain(s)",
+        duration,
+        names.len()
+    );
+
+    let results = RefCell::new(Results::default());
+    let started = Instant::now();
+
+    // Answers are read for as long as queries are being sent, and for as long
+    // as the last of them has to be answered after that
+    let sent = tokio::select! {
+        sent = async {
+            let sent = send(&socket, &results, &names, query_type, qps, duration).await;
+            tokio::time::sleep(timeout).await;
+            sent
+        } => sent?,
+        () = receive(&socket, &results, timeout) => unreachable!(),
+    };
+    let elapsed = started.elapsed().saturating_sub(timeout);
+
+    let mut results = results.into_inner();
+    results.timed_out += results.outstanding.len();
+    report(sent, elapsed, &mut results);
+
+    Ok(())
+}
+
+///
+/// The domains to query, one per line, ignoring blank lines and comments
+///
+fn names(domains: &Path) -> Result<Vec<Name>, Exit> {
+    let contents = std::fs::read_to_string(domains).map_err(|err| {
+        eprintln!("Unable to read {}: {err}", domains.display());
+        Exit::Config
+    })?;
+
+    let mut names = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match Name::from_str(line) {
+            Ok(name) => names.push(name),
+            Err(err) => eprintln!("Skipping {}:{}: {err}", domains.display(), idx + 1),
+        }
+    }
+
+    if names.is_empty() {
+        eprintln!("No domains to query in {}", domains.display());
+        return Err(Exit::Config);
+    }
+
+    Ok(names)
+}
+
+fn query(id: u16, name: &Name, query_type: RecordType) -> Vec<u8> {
+    let mut message = Message::new();
+    message
+        .set_id(id)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(name.clone(), query_type));
+
+    message.to_vec().expect("Unable to encode a query")
+}
+
+///
+/// Send queries for each of the names in turn, `qps` a second, returning how
+/// many were sent
+///
+async fn send(
+    socket: &UdpSocket,
+    results: &RefCell<Results>,
+    names: &[Name],
+    query_type: RecordType,
+    qps: u32,
+    duration: Duration,
+) -> Result<usize, Exit> {
+    let mut names = names.iter().cycle();
+    let mut interval = tokio::time::interval(TICK);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let started = Instant::now();
+    let mut sent = 0;
+    let mut id = 0_u16;
+
+    while started.elapsed() < duration {
+        interval.tick().await;
+
+        let due = started.elapsed().min(duration).as_micros() * u128::from(qps) / 1_000_000;
+        let due = usize::try_from(due).unwrap_or(usize::MAX);
+
+        for name in names.by_ref().take(due.saturating_sub(sent)) {
+            id = id.wrapping_add(1);
+
+            // Should the ID still be in use, that query's never going to be
+            // told apart from this one
+            if results
+                .borrow_mut()
+                .outstanding
+                .insert(id, Instant::now())
+                .is_some()
+            {
+                results.borrow_mut().timed_out += 1;
+            }
+
+            if let Err(err) = socket.send(&query(id, name, query_type)).await {
+                eprintln!("Unable to send a query: {err}");
+                return Err(Exit::Unavailable);
+            }
+            sent += 1;
+        }
+    }
+
+    Ok(sent)
+}
+
+///
+/// Match answers up with the queries they're for, until there are no more
+///
+async fn receive(socket: &UdpSocket, results: &RefCell<Results>, timeout: Duration) {
+    let mut buffer = [0; 4096];
+
+    loop {
+        // Errors are most likely the server refusing the last query, which
+        // shows up as that query timing out
+        let Ok(length) = socket.recv(&mut buffer).await else {
+            continue;
+        };
+        let Ok(message) = Message::from_vec(&buffer[..length]) else {
+            continue;
+        };
+
+        let mut results = results.borrow_mut();
+        let Some(sent) = results.outstanding.remove(&message.id()) else {
+            continue;
+        };
+
+        let latency = sent.elapsed();
+        if latency > timeout {
+            results.timed_out += 1;
+            continue;
+        }
+
+        results.latencies.push(latency);
+        *results.codes.entry(message.response_code()).or_default() += 1;
+    }
+}
+
+fn percentile(sorted: &[Duration], quantile: f64) -> Duration {
+    let idx = ((sorted.len() as f64 * quantile).ceil() as usize).max(1) - 1;
+    sorted[idx.min(sorted.len() - 1)]
+}
+
+fn rate(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 * 100.0 / total as f64
+    }
+}
+
+fn report(sent: usize, elapsed: Duration, results: &mut Results) {
+    let answered = results.latencies.len();
+    let errors = results
+        .codes
+        .iter()
+        .filter(|(code, _)| ![ResponseCode::NoError, ResponseCode::NXDomain].contains(*code))
+        .map(|(_, count)| count)
+        .sum::<usize>();
+
+    println!(
+        "Sent {sent} queries in {elapsed:.1?} ({:.0}/s)",
+        sent as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+    );
+    println!("  answered:  {answered} ({:.2}%)", rate(answered, sent));
+    println!(
+        "  timed out: {} ({:.2}%)",
+        results.timed_out,
+        rate(results.timed_out, sent)
+    );
+    println!("  errors:    {errors} ({:.2}%)", rate(errors, sent));
+
+    if !results.codes.is_empty() {
+        let mut codes = results.codes.iter().collect::<Vec<_>>();
+        codes.sort_by(|a, b| b.1.cmp(a.1));
+
+        println!(
+            "  responses: {}",
+            codes
+                .iter()
+                .map(|(code, count)| format!("{code} {count}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    if results.latencies.is_empty() {
+        return;
+    }
+
+    results.latencies.sort_unstable();
+    println!(
+        "  latency:   {}, max {:.2?}",
+        PERCENTILES
+            .iter()
+            .map(|(label, quantile)| format!(
+                "{label} {:.2?}",
+                percentile(&results.latencies, *quantile)
+            ))
+            .collect::<Vec<_>>()
+            .join(", "),
+        results.latencies[results.latencies.len() - 1]
+    );
+}
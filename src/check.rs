@@ -0,0 +1,121 @@
+use std::path::PathBuf;
+
+use blackhole::{
+    __private::{
+        config::{self, Config},
+        filter::{rules::Rules, List},
+    },
+    Exit,
+};
+
+/// The most parse errors we'll show for any one list
+const MAX_ERRORS: usize = 10;
+
+///
+/// Load the config and parse every list it refers to, reporting anything that's
+/// wrong with them, without serving anything
+///
+#[coverage(off)]
+pub async fn check(file: &str, overrides: config::Overrides, offline: bool) -> Result<(), Exit> {
+    if let Err(err) = Config::load(&(PathBuf::from(file), (config::Environment, overrides))).await {
+        println!("✗ Unable to load {file}: {err}");
+        return Err(Exit::Config);
+    }
+
+    let problems = Config::get(Config::validate).await;
+    for problem in &problems {
+        println!("✗ {problem}");
+    }
+
+    let (mut lists, timeout) = Config::get(|config| {
+        (
+            config.filters.iter().cloned().collect::<Vec<_>>(),
+            config.downloads.timeout,
+        )
+    })
+    .await;
+    lists.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .map_err(|err| {
+            println!("✗ Unable to create a client to download lists with: {err}");
+            Exit::Unavailable
+        })?;
+
+    let mut valid = problems.is_empty();
+    for list in &lists {
+        let contents = if let Some(path) = list.local() {
+            std::fs::read_to_string(&path).map_err(|err| format!("{}: {err}", path.display()))
+        } else if offline {
+            println!("- {}: skipped, as it needs to be downloaded", list.name);
+            continue;
+        } else {
+            download(&client, list).await
+        };
+
+        match contents {
+            Ok(contents) => valid &= report(list, &contents),
+            Err(err) => {
+                println!("✗ {}: {err}", list.name);
+                valid = false;
+            }
+        }
+    }
+
+    let rules = Config::get(|config| config.rules.len()).await;
+    if rules > 0 {
+        println!("✓ {rules} custom rule(s)");
+    }
+
+    if valid { Ok(()) } else { Err(Exit::Config) }
+}
+
+pub async fn download(client: &reqwest::Client, list: &List) -> Result<String, String> {
+    client
+        .get(&list.url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|err| err.to_string())?
+        .text()
+        .await
+        .map_err(|err| err.to_string())
+}
+
+///
+/// Parse the list, printing how many entries it has (or why it's invalid)
+///
+/// Returns whether it was valid
+///
+fn report(list: &List, contents: &str) -> bool {
+    let mut entries = 0;
+    let mut errors = Vec::new();
+
+    for (idx, line) in contents.lines().enumerate() {
+        match Rules::parse_line(line) {
+            Ok(parsed) => entries += parsed.len(),
+            Err(err) => errors.push(format!("{}:{}: {err}", list.url, idx + 1)),
+        }
+    }
+
+    if errors.is_empty() {
+        println!("✓ {}: {entries} entries", list.name);
+        return true;
+    }
+
+    println!(
+        "✗ {}: {} invalid line(s), {entries} valid entries",
+        list.name,
+        errors.len()
+    );
+    for error in errors.iter().take(MAX_ERRORS) {
+        println!("    {error}");
+    }
+    if errors.len() > MAX_ERRORS {
+        println!("    ... and {} more", errors.len() - MAX_ERRORS);
+    }
+
+    false
+}
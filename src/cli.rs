@@ -1,9 +1,26 @@
-use clap::Parser;
+use std::{net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+
+use blackhole::__private::config::Overrides;
+use clap::{Parser, Subcommand};
+use hickory_proto::rr::{Name, RecordType};
 
 fn default_config() -> String {
     "/config/blackhole.toml".into()
 }
 
+fn default_api() -> String {
+    "http://localhost:5000".into()
+}
+
+fn seconds(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .filter(|duration| !duration.is_zero())
+        .ok_or_else(|| format!("{value} isn't a positive number of seconds"))
+}
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
@@ -15,4 +32,155 @@ pub struct Cli {
         default_value_t = default_config()
     )]
     pub config: String,
+
+    #[arg(short, long, help = "The port to serve DNS on")]
+    pub port: Option<u16>,
+
+    #[arg(
+        short,
+        long = "upstream",
+        value_name = "IP[:PORT]",
+        help = "An upstream to forward requests to, replacing those in the config (can be repeated)"
+    )]
+    pub upstreams: Vec<String>,
+
+    #[arg(long, help = "The port to serve the API on")]
+    pub api_port: Option<u16>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Check the config and every filter list it uses, without serving anything
+    Check {
+        #[arg(long, help = "Only check lists that don't need to be downloaded")]
+        offline: bool,
+    },
+    /// Check the config, the ports it serves on, its upstreams, the system's
+    /// resolvers and the directory lists are saved to, explaining how to fix
+    /// whatever's wrong
+    Doctor,
+    /// Show whether a query for the domain would be allowed, denied or rewritten
+    /// (and by which rule), using the rules from the config and its lists
+    Query {
+        #[arg(help = "The domain to check", value_parser = Name::from_str)]
+        domain: Name,
+
+        #[arg(
+            help = "The type of record to check",
+            default_value = "A",
+            value_parser = RecordType::from_str
+        )]
+        query_type: RecordType,
+
+        #[arg(long, help = "Only use lists that don't need to be downloaded")]
+        offline: bool,
+    },
+    /// Resolve a name through a running server, showing how it was answered
+    Resolve {
+        #[arg(help = "The name to resolve")]
+        name: String,
+
+        #[arg(
+            short = 't',
+            long = "type",
+            help = "The type of record to ask for",
+            default_value = "A"
+        )]
+        query_type: String,
+
+        #[arg(long, help = "Bypass the cache, repopulating it with the answer")]
+        fresh: bool,
+
+        #[arg(
+            long,
+            value_name = "URL",
+            help = "Where the server's API is listening",
+            default_value_t = default_api()
+        )]
+        api: String,
+    },
+    /// Send queries to a server at a steady rate, reporting how quickly they
+    /// were answered and how many of them failed
+    Bench {
+        #[arg(
+            long,
+            value_name = "IP:PORT",
+            help = "The server to send queries to",
+            default_value = "127.0.0.1:53"
+        )]
+        target: SocketAddr,
+
+        #[arg(
+            long,
+            help = "How many queries to send a second",
+            default_value_t = 1000,
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        qps: u32,
+
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "The domains to query, one per line, which are cycled through"
+        )]
+        domains: PathBuf,
+
+        #[arg(
+            short = 't',
+            long = "type",
+            help = "The type of record to ask for",
+            default_value = "A",
+            value_parser = RecordType::from_str
+        )]
+        query_type: RecordType,
+
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            help = "How long to send queries for",
+            default_value = "10",
+            value_parser = seconds
+        )]
+        duration: Duration,
+
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            help = "How long to wait on an answer before counting the query as failed",
+            default_value = "2",
+            value_parser = seconds
+        )]
+        timeout: Duration,
+    },
+    /// Import another DNS filter's config into ours (the file given with
+    /// --config), keeping whatever's already there
+    Import {
+        #[command(subcommand)]
+        from: Import,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum Import {
+    /// AdGuard Home's upstreams, filters, rewrites, user rules and clients
+    Adguard {
+        #[arg(
+            value_name = "FILE",
+            help = "The AdGuard Home config (AdGuardHome.yaml)"
+        )]
+        file: PathBuf,
+    },
+}
+
+impl Cli {
+    pub fn overrides(&self) -> Overrides {
+        Overrides {
+            port: self.port,
+            upstreams: self.upstreams.clone(),
+            api_port: self.api_port,
+        }
+    }
 }
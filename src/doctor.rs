@@ -0,0 +1,425 @@
+use std::{
+    fmt::Display,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use blackhole::{
+    __private::config::{self, Config, Protocol},
+    Exit,
+};
+use hickory_proto::{
+    op::{Message, Query, ResponseCode},
+    rr::{Name, RecordType},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+
+/// How long an upstream has to answer a probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Where the system's resolvers are configured
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+///
+/// How many of the checks passed, warned or failed, with each printed as it
+/// comes in
+///
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    warnings: usize,
+    failed: usize,
+}
+
+impl Report {
+    fn pass(&mut self, message: impl Display) {
+        println!("✓ {message}");
+        self.passed += 1;
+    }
+
+    fn warn(&mut self, message: impl Display) {
+        println!("! {message}");
+        self.warnings += 1;
+    }
+
+    fn fail(&mut self, message: impl Display) {
+        println!("✗ {message}");
+        self.failed += 1;
+    }
+}
+
+///
+/// Everything we need from the config, as it's only ever needed the once
+///
+struct Setup {
+    sockets: Vec<(SocketAddr, Protocol)>,
+    /// What else serves on a port (the API, over HTTP and gRPC), and where
+    services: Vec<(&'static str, SocketAddr)>,
+    upstreams: Vec<(SocketAddr, Protocol)>,
+    directory: PathBuf,
+    /// The certificate and key the API is served with over HTTPS
+    tls: Vec<PathBuf>,
+}
+
+///
+/// Check the config, and everything Blackhole needs from the machine it runs
+/// on (the ports it serves on, its upstreams, the system's resolvers and the
+/// directory lists are saved to), printing what to do about anything that's
+/// wrong
+///
+#[coverage(off)]
+pub async fn doctor(file: &str, overrides: config::Overrides) -> Result<(), Exit> {
+    let mut report = Report::default();
+
+    if let Err(err) = Config::load(&(PathBuf::from(file), (config::Environment, overrides))).await {
+        report.fail(format!("Unable to load {file}: {err}"));
+        return Err(Exit::Config);
+    }
+
+    let problems = Config::get(Config::validate).await;
+    if problems.is_empty() {
+        report.pass(format!("{file} is valid"));
+    }
+    for problem in problems {
+        report.fail(problem);
+    }
+
+    let setup = Config::get(|config| Setup {
+        sockets: config.bind.sockets(config.port, &config.listeners),
+        services: std::iter::once(("API", SocketAddr::new(config.api.address, config.api.port)))
+            .chain(
+                config
+                    .api
+                    .grpc
+                    .as_ref()
+                    .map(|grpc| ("gRPC API", SocketAddr::new(grpc.address, grpc.port))),
+            )
+            .collect(),
+        upstreams: config
+            .upstreams
+            .iter()
+            .map(|upstream| {
+                (
+                    SocketAddr::new(upstream.ip, upstream.port),
+                    upstream.protocol,
+                )
+            })
+            .collect(),
+        directory: config.downloads.directory().to_path_buf(),
+        tls: config
+            .api
+            .tls
+            .iter()
+            .flat_map(|tls| [tls.cert.clone(), tls.key.clone()])
+            .collect(),
+    })
+    .await;
+
+    ports(&mut report, &setup);
+    upstreams(&mut report, &setup).await;
+    loops(&mut report, &setup);
+    files(&mut report, &setup);
+
+    println!(
+        "\n{} passed, {} warning(s), {} failed",
+        report.passed, report.warnings, report.failed
+    );
+
+    if report.failed == 0 {
+        Ok(())
+    } else {
+        Err(Exit::Config)
+    }
+}
+
+///
+/// What to do about not being able to serve on the address
+///
+fn unavailable(address: SocketAddr, err: &io::Error) -> String {
+    match err.kind() {
+        io::ErrorKind::AddrInUse => format!(
+            "{address} is already in use, is Blackhole (or another DNS server, e.g. \
+             systemd-resolved) already running?"
+        ),
+        io::ErrorKind::PermissionDenied if address.port() < 1024 => format!(
+            "Not allowed to serve on {address}, ports below 1024 need root or the \
+             CAP_NET_BIND_SERVICE capability"
+        ),
+        io::ErrorKind::AddrNotAvailable => {
+            format!("{address} isn't one of this machine's addresses")
+        }
+        _ => format!("Unable to serve on {address}: {err}"),
+    }
+}
+
+///
+/// Whether each of the addresses we serve on is free to be served on
+///
+fn ports(report: &mut Report, setup: &Setup) {
+    let sockets = setup.sockets.iter().copied().chain(
+        setup
+            .services
+            .iter()
+            .map(|(_, address)| (*address, Protocol::Tcp)),
+    );
+
+    for (address, protocol) in sockets {
+        let bound = match protocol {
+            Protocol::Udp => std::net::UdpSocket::bind(address).map(drop),
+            Protocol::Tcp => std::net::TcpListener::bind(address).map(drop),
+        };
+
+        match bound {
+            Ok(()) => report.pass(format!("{address} ({protocol}) is free to serve on")),
+            Err(err) => report.fail(unavailable(address, &err)),
+        }
+    }
+}
+
+fn probe_query() -> Result<Vec<u8>, String> {
+    let mut message = Message::new();
+    message
+        .set_id(0xb1ac)
+        .set_recursion_desired(true)
+        .add_query(Query::query(Name::root(), RecordType::NS));
+
+    message.to_vec().map_err(|err| err.to_string())
+}
+
+async fn udp(address: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let local = if address.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    };
+
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(address).await?;
+    socket.send(query).await?;
+
+    let mut response = vec![0; 4096];
+    let length = socket.recv(&mut response).await?;
+    response.truncate(length);
+
+    Ok(response)
+}
+
+async fn tcp(address: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(address).await?;
+
+    let length = u16::try_from(query.len()).map_err(io::Error::other)?;
+    stream.write_all(&length.to_be_bytes()).await?;
+    stream.write_all(query).await?;
+
+    let mut response = vec![0; usize::from(stream.read_u16().await?)];
+    stream.read_exact(&mut response).await?;
+
+    Ok(response)
+}
+
+///
+/// Ask the upstream for the root's nameservers, returning how it answered and
+/// how long that took
+///
+async fn probe(
+    address: SocketAddr,
+    protocol: Protocol,
+) -> Result<(ResponseCode, Duration), String> {
+    let query = probe_query()?;
+    let started = Instant::now();
+
+    let response = match protocol {
+        Protocol::Udp => timeout(PROBE_TIMEOUT, udp(address, &query)).await,
+        Protocol::Tcp => timeout(PROBE_TIMEOUT, tcp(address, &query)).await,
+    }
+    .map_err(|_| format!("no answer within {PROBE_TIMEOUT:?}"))?
+    .map_err(|err| err.to_string())?;
+
+    let message = Message::from_vec(&response).map_err(|err| format!("invalid answer: {err}"))?;
+    Ok((message.response_code(), started.elapsed()))
+}
+
+///
+/// Whether each upstream answers over the protocol it's used with. Those used
+/// over UDP need to answer over TCP too, for responses too big for UDP.
+///
+async fn upstreams(report: &mut Report, setup: &Setup) {
+    for &(address, protocol) in &setup.upstreams {
+        let probes = match protocol {
+            Protocol::Udp => [Protocol::Udp, Protocol::Tcp].as_slice(),
+            Protocol::Tcp => [Protocol::Tcp].as_slice(),
+        };
+
+        for &over in probes {
+            // Without TCP, only truncated responses are affected
+            let required = over == protocol;
+
+            match probe(address, over).await {
+                Ok((ResponseCode::NoError, took)) => {
+                    report.pass(format!(
+                        "Upstream {address} answers over {over} ({took:.1?})"
+                    ));
+                }
+                Ok((ResponseCode::Refused, _)) => report.fail(format!(
+                    "Upstream {address} refuses queries over {over}, does it allow queries from \
+                     this machine?"
+                )),
+                Ok((code, _)) => report.warn(format!(
+                    "Upstream {address} answered {code} over {over}, it may not resolve names \
+                     itself"
+                )),
+                Err(err) if required => report.fail(format!(
+                    "Upstream {address} can't be reached over {over} ({err}), check it's \
+                     running and that no firewall is in the way"
+                )),
+                Err(err) => report.warn(format!(
+                    "Upstream {address} can't be reached over {over} ({err}), so responses too \
+                     big for UDP can't be forwarded"
+                )),
+            }
+        }
+    }
+}
+
+///
+/// Whether we'd be the one answering queries sent to the address
+///
+fn ours(address: SocketAddr, setup: &Setup) -> bool {
+    let ip = address.ip().to_canonical();
+
+    setup.sockets.iter().any(|(socket, _)| {
+        socket.port() == address.port()
+            && (socket.ip().to_canonical() == ip
+                || (socket.ip().is_unspecified() && (ip.is_loopback() || ip.is_unspecified())))
+    })
+}
+
+///
+/// The nameservers the system resolves names with
+///
+fn nameservers(contents: &str) -> Vec<IpAddr> {
+    contents
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|address| {
+            // Link-local addresses may be scoped to an interface (fe80::1%eth0)
+            address.trim().split('%').next()?.parse().ok()
+        })
+        .collect()
+}
+
+///
+/// Whether queries would end up being sent back to us, be it because we
+/// forward to ourselves, or because the system resolves names through us
+///
+fn loops(report: &mut Report, setup: &Setup) {
+    let mut looped = false;
+
+    for &(address, _) in &setup.upstreams {
+        if ours(address, setup) {
+            looped = true;
+            report.fail(format!(
+                "Upstream {address} is this server, so queries are forwarded back to it until \
+                 they time out; forward to another server instead"
+            ));
+        }
+    }
+
+    match std::fs::read_to_string(RESOLV_CONF) {
+        Ok(contents) => {
+            let nameservers = nameservers(&contents);
+
+            if !nameservers.is_empty()
+                && nameservers
+                    .iter()
+                    .all(|nameserver| ours(SocketAddr::new(*nameserver, 53), setup))
+            {
+                looped = true;
+                report.warn(format!(
+                    "{RESOLV_CONF} only resolves names through this server, so lists can't be \
+                     downloaded while it's down; add another nameserver after it"
+                ));
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => report.warn(format!("Unable to read {RESOLV_CONF}: {err}")),
+    }
+
+    if !looped {
+        report.pass("No queries are sent back to this server");
+    }
+}
+
+///
+/// Whether a file can be created in the directory
+///
+fn writable(directory: &Path) -> io::Result<()> {
+    let probe = directory.join(".blackhole-doctor");
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)?;
+
+    std::fs::remove_file(probe)
+}
+
+///
+/// Whether lists can be saved to the download directory, and whether the
+/// API's certificate and key can be read
+///
+fn files(report: &mut Report, setup: &Setup) {
+    let directory = &setup.directory;
+
+    if directory.is_dir() {
+        match writable(directory) {
+            Ok(()) => report.pass(format!("Lists can be saved to {}", directory.display())),
+            Err(err) => report.fail(format!(
+                "Lists can't be saved to {} ({err}), make it writable by the user Blackhole \
+                 runs as",
+                directory.display()
+            )),
+        }
+    } else if directory.exists() {
+        report.fail(format!(
+            "{} isn't a directory, so lists can't be saved to it",
+            directory.display()
+        ));
+    } else {
+        // It's created when the lists are first downloaded, which needs
+        // whatever of it does exist to be writable
+        let existing = directory
+            .ancestors()
+            .skip(1)
+            .find(|ancestor| ancestor.is_dir())
+            .unwrap_or_else(|| Path::new("."));
+
+        match writable(existing) {
+            Ok(()) => report.pass(format!(
+                "{} will be created when lists are first downloaded",
+                directory.display()
+            )),
+            Err(err) => report.fail(format!(
+                "{} can't be created in {} ({err}), create it and make it writable by the user \
+                 Blackhole runs as",
+                directory.display(),
+                existing.display()
+            )),
+        }
+    }
+
+    for path in &setup.tls {
+        match std::fs::File::open(path) {
+            Ok(_) => report.pass(format!("{} can be read", path.display())),
+            Err(err) => report.fail(format!(
+                "{} can't be read ({err}), so the API can't be served over HTTPS",
+                path.display()
+            )),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use blackhole::{
+    __private::config::{self, AdGuard, Config, Imported, Load},
+    Exit,
+};
+
+///
+/// Add AdGuard Home's config to ours, writing the result back to the config
+/// file (or creating it, should there not be one yet)
+///
+#[coverage(off)]
+pub async fn adguard(file: &str, from: &Path) -> Result<(), Exit> {
+    let mut config = Config::default();
+    match PathBuf::from(file).load(&mut config).await {
+        Ok(()) => {}
+        Err(config::Error::IO(err)) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => {
+            println!("✗ Unable to load {file}: {err}");
+            return Err(Exit::Config);
+        }
+    }
+
+    let adguard = std::fs::read_to_string(from)
+        .map_err(|err| err.to_string())
+        .and_then(|contents| {
+            serde_yaml::from_str::<AdGuard>(&contents).map_err(|err| err.to_string())
+        })
+        .map_err(|err| {
+            println!("✗ Unable to read {}: {err}", from.display());
+            Exit::Config
+        })?;
+
+    let imported = adguard.import(&mut config);
+
+    let problems = config.validate();
+    for problem in &problems {
+        println!("✗ {problem}");
+    }
+    if !problems.is_empty() {
+        return Err(Exit::Config);
+    }
+
+    toml::to_string_pretty(&config)
+        .map_err(|err| err.to_string())
+        .and_then(|contents| std::fs::write(file, contents).map_err(|err| err.to_string()))
+        .map_err(|err| {
+            println!("✗ Unable to write {file}: {err}");
+            Exit::Config
+        })?;
+
+    report(file, &imported);
+
+    Ok(())
+}
+
+fn report(file: &str, imported: &Imported) {
+    println!(
+        "✓ Imported {} upstream(s), {} list(s), {} rule(s), {} pattern(s) and {} client name(s) into {file}",
+        imported.upstreams, imported.lists, imported.rules, imported.patterns, imported.clients
+    );
+
+    for skipped in &imported.skipped {
+        println!("- Skipped {skipped}");
+    }
+}
@@ -2,19 +2,23 @@
 #![forbid(unsafe_code)]
 #![feature(coverage_attribute)]
 
-use std::{path::PathBuf, time::Duration};
+use std::{io, path::PathBuf, process::ExitCode, time::Duration};
 
+use blackhole::{__private::config, Blackhole, Exit};
 use clap::Parser;
-use tokio::{
-    signal::unix::{signal, SignalKind},
-    sync::watch::channel,
-};
-use tracing::{error, info, metadata::LevelFilter};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, metadata::LevelFilter, warn};
 use tracing_subscriber::{
     prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt, Layer,
 };
 
+mod bench;
+mod check;
 mod cli;
+mod doctor;
+mod import;
+mod query;
+mod resolve;
 
 #[coverage(off)]
 fn enable_tracing() {
@@ -49,42 +53,133 @@ fn enable_tracing() {
 
 #[coverage(off)]
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
+    let cli = cli::Cli::parse();
+
+    match &cli.command {
+        Some(cli::Command::Resolve {
+            name,
+            query_type,
+            fresh,
+            api,
+        }) => {
+            return resolve::resolve(api, name, query_type, *fresh)
+                .await
+                .map_or_else(Into::into, |()| Exit::Clean.into());
+        }
+        Some(cli::Command::Query {
+            domain,
+            query_type,
+            offline,
+        }) => {
+            return query::query(&cli.config, cli.overrides(), domain, *query_type, *offline)
+                .await
+                .map_or_else(Into::into, |()| Exit::Clean.into());
+        }
+        Some(cli::Command::Check { offline }) => {
+            return check::check(&cli.config, cli.overrides(), *offline)
+                .await
+                .map_or_else(Into::into, |()| Exit::Clean.into());
+        }
+        Some(cli::Command::Bench {
+            target,
+            qps,
+            domains,
+            query_type,
+            duration,
+            timeout,
+        }) => {
+            return bench::bench(*target, *qps, domains, *query_type, *duration, *timeout)
+                .await
+                .map_or_else(Into::into, |()| Exit::Clean.into());
+        }
+        Some(cli::Command::Doctor) => {
+            return doctor::doctor(&cli.config, cli.overrides())
+                .await
+                .map_or_else(Into::into, |()| Exit::Clean.into());
+        }
+        Some(cli::Command::Import {
+            from: cli::Import::Adguard { file },
+        }) => {
+            return import::adguard(&cli.config, file)
+                .await
+                .map_or_else(Into::into, |()| Exit::Clean.into());
+        }
+        None => {}
+    }
+
     enable_tracing();
+    blackhole::on_panic();
 
-    let cli = cli::Cli::parse();
+    match config::Config::load(&PathBuf::from(&cli.config)).await {
+        Ok(()) => {}
+        Err(config::Error::IO(err)) if err.kind() == io::ErrorKind::NotFound => {
+            warn!("No config found at {}, using the defaults", cli.config);
+        }
+        Err(err) => {
+            error!("Unable to load config: {err}");
+            return Exit::Config.into();
+        }
+    }
+
+    // The environment takes precedence over the config file, and the command
+    // line over both
+    let overrides = (config::Environment, cli.overrides());
+    if let Err(err) = config::Config::load(&overrides).await {
+        error!("Unable to load config: {err}");
+        return Exit::Config.into();
+    }
 
-    blackhole::config::Config::load(&PathBuf::from(&cli.config))
-        .await
-        .unwrap_or_default();
+    let problems = config::Config::get(config::Config::validate).await;
+    if !problems.is_empty() {
+        for problem in problems {
+            error!("Invalid config, {problem}");
+        }
 
-    let (shutdown, shutdown_signal) = channel(false);
+        return Exit::Config.into();
+    }
 
-    let blackhole_handle = match blackhole::spawn(shutdown_signal).await {
-        Ok(handle) => handle,
+    let mut blackhole = match Blackhole::start().await {
+        Ok(blackhole) => blackhole,
         Err(err) => {
             error!("{err}");
-            return;
+            return Exit::Bind.into();
         }
     };
 
     let mut sigterm = signal(SignalKind::terminate()).unwrap();
     let mut sigint = signal(SignalKind::interrupt()).unwrap();
     let mut sigquit = signal(SignalKind::quit()).unwrap();
+    let mut sighup = signal(SignalKind::hangup()).unwrap();
 
-    tokio::select! {
-        _ = blackhole_handle => {}
-        _ = sigint.recv() => {}
-        _ = sigquit.recv() => {}
-        _ = sigterm.recv() => {}
+    let exit = loop {
+        tokio::select! {
+            exit = blackhole.stopped() => break Some(exit),
+            _ = sighup.recv() => {
+                info!("Reloading config");
+                let profile = (PathBuf::from(&cli.config), (config::Environment, cli.overrides()));
+                if let Err(err) = config::Config::reload(&profile).await {
+                    error!("Unable to reload config: {err}");
+                }
+            }
+            _ = sigint.recv() => break None,
+            _ = sigquit.recv() => break None,
+            _ = sigterm.recv() => break None,
+        }
     };
 
-    info!("Shutting down");
-    if let Err(err) = shutdown.send(true) {
-        error!("Error sending shutdown signal: {err}");
+    let exit = if let Some(exit) = exit {
+        exit
+    } else {
+        info!("Shutting down");
+
+        tokio::time::timeout(Duration::from_secs(10), blackhole.stop())
+            .await
+            .unwrap_or_else(|_| {
+                error!("Timed out while shutting down");
+                Exit::Unavailable
+            })
     };
 
-    tokio::time::timeout(Duration::from_secs(10), shutdown.closed())
-        .await
-        .unwrap_or_default();
+    exit.into()
 }
@@ -0,0 +1,281 @@
+use std::path::{Path, PathBuf};
+
+use blackhole::{
+    __private::{
+        config::{self, Config},
+        filter::{
+            patterns::Patterns,
+            rules::{Kind, Rule, Rules, Source, Type},
+            Filter, List, Policy, Unmatched,
+        },
+    },
+    Exit,
+};
+use hickory_proto::rr::{Name, RecordType};
+
+use crate::check;
+
+///
+/// Load the rules from the config and its lists, and show what we would do with
+/// a query for the name (and why), without sending one
+///
+#[coverage(off)]
+pub async fn query(
+    file: &str,
+    overrides: config::Overrides,
+    name: &Name,
+    query_type: RecordType,
+    offline: bool,
+) -> Result<(), Exit> {
+    if let Err(err) = Config::load(&(PathBuf::from(file), (config::Environment, overrides))).await {
+        eprintln!("Unable to load {file}: {err}");
+        return Err(Exit::Config);
+    }
+
+    let name = name.to_lowercase();
+    println!("{name} {query_type}");
+
+    let (custom, patterns, auto_ptr, policy) = Config::get(|config| {
+        (
+            config.rules.clone(),
+            config.patterns.clone(),
+            config.auto_ptr,
+            config.policy.clone(),
+        )
+    })
+    .await;
+    let deny_unmatched = policy.unmatched == Unmatched::Deny;
+
+    if !deny_unmatched && !Filter::applies_to(query_type) {
+        println!("  verdict: allowed, {query_type} queries are never filtered");
+        return Ok(());
+    }
+    let mut sources = sources(offline).await?;
+
+    // Build the rules up the same way they are when they're imported, where
+    // custom rules take precedence over anything from the lists
+    let mut rules = Rules::default();
+    for (_, filter) in &sources {
+        rules.merge(filter.rules.clone());
+    }
+
+    let mut custom_rules = Rules::default();
+    for rule in &custom {
+        rules.replace(rule);
+        custom_rules.replace(rule);
+    }
+    sources.push((
+        String::from(CUSTOM),
+        Filter {
+            rules: custom_rules,
+            ..Default::default()
+        },
+    ));
+
+    sources.push((
+        String::from(PATTERNS),
+        Filter {
+            patterns: Patterns::new(&patterns),
+            ..Default::default()
+        },
+    ));
+
+    if auto_ptr {
+        rules.generate_ptr();
+    }
+
+    let matches = sources
+        .iter()
+        .filter_map(|(source, filter)| Some((source, filter.find(&name).as_ref()?)))
+        .collect::<Vec<_>>();
+
+    let Some(rule) = Filter {
+        rules,
+        patterns: Patterns::new(&patterns),
+        ..Default::default()
+    }
+    .find(&name)
+    .clone() else {
+        println!("  verdict: {}", unmatched(&policy));
+        return Ok(());
+    };
+
+    println!("  verdict: {}", verdict(&rule));
+    println!("  rule:    {} ({})", rule.kind(), rule.domain());
+
+    let from = matches
+        .iter()
+        .filter(|(_, matched)| **matched == rule)
+        .map(|(source, _)| source.as_str())
+        .collect::<Vec<_>>();
+    println!(
+        "  source:  {}",
+        if from.is_empty() {
+            String::from("generated PTR record")
+        } else {
+            from.join(", ")
+        }
+    );
+
+    for (source, matched) in matches.iter().filter(|(_, matched)| **matched != rule) {
+        println!(
+            "  ignored: {} ({}) from {source}",
+            matched.kind(),
+            matched.domain()
+        );
+    }
+
+    // Patterns are only ever consulted when none of the lists match
+    let listed = matches
+        .iter()
+        .filter(|(source, _)| source.as_str() != PATTERNS)
+        .count();
+    if !from.is_empty()
+        && !from.contains(&CUSTOM)
+        && !from.contains(&PATTERNS)
+        && listed > from.len()
+    {
+        println!(
+            "  note:    lists disagree, which one wins depends on the order they're loaded in"
+        );
+    }
+
+    if rule.answers_locally() {
+        let records = rule.records(&name, query_type);
+        let records = records
+            .iter()
+            .filter(|record| record.data().is_some())
+            .collect::<Vec<_>>();
+
+        if !records.is_empty() {
+            println!("  answers:");
+            for record in records {
+                println!("    {record}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// What the custom rules from the config are reported as
+const CUSTOM: &str = "custom rules";
+/// What the patterns from the config are reported as
+const PATTERNS: &str = "patterns";
+
+fn unmatched(policy: &Policy) -> String {
+    if policy.unmatched == Unmatched::Allow {
+        String::from("allowed, no rule matches")
+    } else if policy.clients.is_empty() {
+        String::from("denied, no rule allows it")
+    } else {
+        format!(
+            "denied for clients in {}, as no rule allows it",
+            policy
+                .clients
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+fn verdict(rule: &Rule) -> &'static str {
+    if *rule.kind() == Kind::Deny {
+        "denied"
+    } else if rule.answers_locally() {
+        "rewritten"
+    } else {
+        "allowed, and forwarded upstream"
+    }
+}
+
+///
+/// The rules from each enabled list (or the builtin list, should none of them be
+/// available)
+///
+async fn sources(offline: bool) -> Result<Vec<(String, Filter<'static>)>, Exit> {
+    let (mut lists, use_builtin_list, timeout, directory) = Config::get(|config| {
+        (
+            config
+                .filters
+                .iter()
+                .filter(|list| list.enabled)
+                .cloned()
+                .collect::<Vec<_>>(),
+            config.use_builtin_list,
+            config.downloads.timeout,
+            config.downloads.directory().to_path_buf(),
+        )
+    })
+    .await;
+    lists.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .map_err(|err| {
+            eprintln!("Unable to create a client to download lists with: {err}");
+            Exit::Unavailable
+        })?;
+
+    let mut sources = Vec::new();
+    for list in &lists {
+        match contents(&client, list, &directory, offline).await {
+            Ok(Some(contents)) => match Rules::parse_lines(contents.lines().map(String::from)) {
+                Ok(entries) => {
+                    sources.push((list.name.clone(), filter(entries, Source::from(list))));
+                }
+                Err(err) => eprintln!("Skipping {}: {err}", list.name),
+            },
+            Ok(None) => eprintln!("Skipping {}, as it needs to be downloaded", list.name),
+            Err(err) => eprintln!("Skipping {}: {err}", list.name),
+        }
+    }
+
+    if use_builtin_list && sources.is_empty() {
+        match Filter::builtin() {
+            Ok(entries) => {
+                sources.push((String::from("builtin list"), filter(entries, Source::builtin())));
+            }
+            Err(err) => eprintln!("Skipping the builtin list: {err}"),
+        }
+    }
+
+    Ok(sources)
+}
+
+fn filter(entries: Vec<Type>, source: Source) -> Filter<'static> {
+    let mut rules = Rules::default();
+    rules.insert(entries, Some(source));
+
+    Filter {
+        rules,
+        ..Default::default()
+    }
+}
+
+///
+/// The contents of the list, preferring what's already been downloaded. Returns
+/// None if it would need to be downloaded, and we're offline.
+///
+async fn contents(
+    client: &reqwest::Client,
+    list: &List,
+    directory: &Path,
+    offline: bool,
+) -> Result<Option<String>, String> {
+    let path = list.path(directory);
+    if list.local().is_some() || path.is_file() {
+        return std::fs::read_to_string(&path)
+            .map(Some)
+            .map_err(|err| format!("{}: {err}", path.display()));
+    }
+
+    if offline {
+        return Ok(None);
+    }
+
+    check::download(client, list).await.map(Some)
+}
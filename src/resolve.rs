@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use blackhole::{
+    __private::{filter::rules::Rule, statistics::Request},
+    Exit,
+};
+
+///
+/// Ask the API how the name resolves, and print the decisions made along the way
+///
+#[coverage(off)]
+pub async fn resolve(api: &str, name: &str, query_type: &str, fresh: bool) -> Result<(), Exit> {
+    let response = reqwest::Client::new()
+        .get(format!("{}/api/resolve/{name}", api.trim_end_matches('/')))
+        .query(&[("type", query_type), ("fresh", &fresh.to_string())])
+        .send()
+        .await
+        .map_err(|err| {
+            eprintln!("Unable to reach the API at {api}: {err}");
+            Exit::Unavailable
+        })?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        eprintln!(
+            "Unable to resolve {name}: {}",
+            response.text().await.unwrap_or_else(|_| status.to_string())
+        );
+        return Err(Exit::Unavailable);
+    }
+
+    let trace = response
+        .bytes()
+        .await
+        .map_err(|err| err.to_string())
+        .and_then(|body| serde_json::from_slice::<Request>(&body).map_err(|err| err.to_string()))
+        .map_err(|err| {
+            eprintln!("Unexpected response from the API: {err}");
+            Exit::Unavailable
+        })?;
+
+    println!("{} {}", trace.question, trace.query_type);
+
+    match &trace.rule {
+        Some(rule) => println!("  rule:    {} ({})", rule.kind(), rule.domain()),
+        None => println!("  rule:    none"),
+    }
+
+    let answered_locally = trace.rule.as_ref().is_some_and(Rule::answers_locally);
+    println!(
+        "  source:  {}",
+        if answered_locally {
+            "rule"
+        } else if trace.cached {
+            "cache"
+        } else if fresh {
+            "upstream (cache refreshed)"
+        } else {
+            "upstream"
+        }
+    );
+
+    println!("  status:  {}", trace.status);
+    println!(
+        "  took:    {:?}",
+        Duration::from_nanos(u64::try_from(trace.elapsed).unwrap_or(u64::MAX))
+    );
+
+    if !trace.answers.is_empty() {
+        println!("  answers:");
+        for answer in &trace.answers {
+            println!("    {answer}");
+        }
+    }
+
+    Ok(())
+}